@@ -0,0 +1,302 @@
+//! Access to a node shared by the API handlers and peer connections.
+//!
+//! The node is owned by a single writer thread applying commands sent over a
+//! channel one at a time, so that no async task blocks the runtime waiting
+//! for a lock or while the node mines or validates. After every batch of
+//! commands that changed the node the writer publishes an immutable
+//! [`NodeSnapshot`], which readers load without waiting for the writer.
+//! Snapshots share the parts that didn't change with the previous one.
+//! Subscribers are told about the changes of every batch, see
+//! [`crate::events`].
+//!
+//! A command that panics may leave the node half changed, so the node is
+//! failed rather than kept serving: the writer stops without publishing the
+//! batch, the callers of its commands and of all later ones get
+//! [`Error::NodeUnavailable`], and reads keep the last published snapshot.
+
+use crate::blockchain::Blockchain;
+use crate::errors::{Error, Result};
+use crate::events::{self, EVENT_CAPACITY, NodeEvent};
+use crate::mempool::Mempool;
+use crate::network::PeerInfo;
+use crate::network::bans::{self, BanList};
+use crate::network::compact::CompactBlockStats;
+use crate::network::p2p::ConnectionManager;
+use crate::node::{Node, pending_nonce};
+use arc_swap::ArcSwap;
+use std::collections::BTreeMap;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::error;
+
+/// Most commands applied before a snapshot is published.
+const MAX_BATCH: usize = 64;
+
+/// Runs after the snapshot including the command's changes is published.
+type Completion = Box<dyn FnOnce() + Send>;
+type Command = Box<dyn FnOnce(&mut Node) -> Completion + Send>;
+
+/// State of the node as of the last applied batch of commands.
+#[derive(Debug, Clone)]
+pub struct NodeSnapshot {
+    pub address: String,
+    #[allow(unused)]
+    pub node_id: String,
+    pub blockchain: Blockchain,
+    pub mempool: Arc<Mempool>,
+    pub peers: Arc<BTreeMap<String, PeerInfo>>,
+    pub bans: Arc<BanList>,
+    pub compact_stats: CompactBlockStats,
+    pub connections: Arc<ConnectionManager>,
+}
+
+impl NodeSnapshot {
+    fn of(node: &Node) -> Self {
+        Self {
+            address: node.address.clone(),
+            node_id: node.node_id().to_owned(),
+            blockchain: node.blockchain.clone(),
+            mempool: Arc::new(node.mempool.clone()),
+            peers: Arc::new(node.peers.clone()),
+            bans: Arc::new(node.bans.clone()),
+            compact_stats: node.compact_stats,
+            connections: node.connections.clone(),
+        }
+    }
+
+    /// Snapshot of `node` reusing the parts of this one it didn't change,
+    /// `None` if it didn't change at all.
+    fn next(&self, node: &Node) -> Option<Self> {
+        let blockchain = self.blockchain.is_unchanged_clone_of(&node.blockchain);
+        let mempool = *self.mempool == node.mempool;
+        let peers = *self.peers == node.peers;
+        let bans = *self.bans == node.bans;
+        if blockchain
+            && mempool
+            && peers
+            && bans
+            && self.address == node.address
+            && self.compact_stats == node.compact_stats
+        {
+            return None;
+        }
+        Some(Self {
+            address: node.address.clone(),
+            node_id: self.node_id.clone(),
+            blockchain: node.blockchain.clone(),
+            mempool: match mempool {
+                true => self.mempool.clone(),
+                false => Arc::new(node.mempool.clone()),
+            },
+            peers: match peers {
+                true => self.peers.clone(),
+                false => Arc::new(node.peers.clone()),
+            },
+            bans: match bans {
+                true => self.bans.clone(),
+                false => Arc::new(node.bans.clone()),
+            },
+            compact_stats: node.compact_stats,
+            connections: node.connections.clone(),
+        })
+    }
+
+    /// See [`Node::next_nonce`].
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        pending_nonce(&self.blockchain, &self.mempool, address)
+    }
+
+    /// See [`Node::is_banned`].
+    pub fn is_banned(&self, address: &str) -> bool {
+        self.bans
+            .is_banned(&bans::host(address), self.blockchain.clock().now())
+    }
+}
+
+/// Cheaply cloneable handle of a node running on its writer thread, which
+/// stops once all handles are dropped.
+#[derive(Clone)]
+pub struct NodeHandle {
+    commands: mpsc::UnboundedSender<Command>,
+    snapshot: Arc<ArcSwap<NodeSnapshot>>,
+    events: broadcast::Sender<NodeEvent>,
+}
+
+impl NodeHandle {
+    pub fn spawn(node: Node) -> Self {
+        let snapshot = Arc::new(ArcSwap::from_pointee(NodeSnapshot::of(&node)));
+        let (commands, queue) = mpsc::unbounded_channel();
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let (published, notify) = (snapshot.clone(), events.clone());
+        std::thread::Builder::new()
+            .name(format!("node-{}", node.name))
+            .spawn(move || run(node, queue, published, notify))
+            .expect("failed to spawn node thread");
+        Self {
+            commands,
+            snapshot,
+            events,
+        }
+    }
+
+    pub fn snapshot(&self) -> Arc<NodeSnapshot> {
+        self.snapshot.load_full()
+    }
+
+    /// Receives the events of the node from now on, see [`events`].
+    pub fn subscribe(&self) -> broadcast::Receiver<NodeEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sends an event not derived from the node's state to the subscribers.
+    pub fn publish(&self, event: NodeEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Applies `command` to the node once the ones sent before are applied
+    /// and returns its result. The snapshot includes its changes by then.
+    pub async fn update<R: Send + 'static>(
+        &self,
+        command: impl FnOnce(&mut Node) -> R + Send + 'static,
+    ) -> Result<R> {
+        let (reply, result) = oneshot::channel();
+        let command: Command = Box::new(move |node| {
+            let value = command(node);
+            Box::new(move || {
+                let _ = reply.send(value);
+            })
+        });
+        self.commands
+            .send(command)
+            .map_err(|_| Error::NodeUnavailable)?;
+        result.await.map_err(|_| Error::NodeUnavailable)
+    }
+}
+
+fn run(
+    mut node: Node,
+    mut queue: mpsc::UnboundedReceiver<Command>,
+    snapshot: Arc<ArcSwap<NodeSnapshot>>,
+    events: broadcast::Sender<NodeEvent>,
+) {
+    while let Some(command) = queue.blocking_recv() {
+        let mut completions = vec![];
+        let Some(completion) = apply(&mut node, command) else {
+            return;
+        };
+        completions.push(completion);
+        // Commands queued meanwhile share the snapshot
+        while completions.len() < MAX_BATCH
+            && let Ok(command) = queue.try_recv()
+        {
+            let Some(completion) = apply(&mut node, command) else {
+                return;
+            };
+            completions.push(completion);
+        }
+        let previous = snapshot.load_full();
+        if let Some(current) = previous.next(&node) {
+            let current = Arc::new(current);
+            snapshot.store(current.clone());
+            if events.receiver_count() > 0 {
+                for event in events::changes(&previous, &current) {
+                    let _ = events.send(event);
+                }
+            }
+        }
+        for complete in completions {
+            complete();
+        }
+    }
+}
+
+/// Applies `command`, `None` if it panicked and the node must stop. Its
+/// reply sender and the ones of the batch are dropped then, failing the
+/// callers' updates.
+fn apply(node: &mut Node, command: Command) -> Option<Completion> {
+    catch_unwind(AssertUnwindSafe(|| command(node)))
+        .inspect_err(|_| error!("Node command panicked, stopping node {}", node.name))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_updates_are_visible_in_snapshot() {
+        let node = NodeHandle::spawn(Node::new("A", 1).unwrap());
+        assert_eq!(node.snapshot().blockchain.height(), 0);
+        let height = node
+            .update(|n| n.add_block(vec![]).map(|b| b.index))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(height, 1);
+        assert_eq!(node.snapshot().blockchain.height(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_is_only_replaced_by_changes() {
+        let node = NodeHandle::spawn(Node::new("A", 1).unwrap());
+        let before = node.snapshot();
+        node.update(|n| n.blockchain.height()).await.unwrap();
+        assert!(Arc::ptr_eq(&before, &node.snapshot()));
+
+        node.update(|n| n.address = "a".into()).await.unwrap();
+        let after = node.snapshot();
+        assert!(!Arc::ptr_eq(&before, &after));
+        assert!(after.blockchain.is_unchanged_clone_of(&before.blockchain));
+        assert!(Arc::ptr_eq(&before.mempool, &after.mempool));
+    }
+
+    #[tokio::test]
+    async fn test_bans_are_visible_in_snapshot() {
+        let node = NodeHandle::spawn(Node::new("A", 1).unwrap());
+        assert!(!node.snapshot().is_banned("10.0.0.1:4000"));
+        node.update(|n| n.bans.ban("10.0.0.1", u64::MAX, "test"))
+            .await
+            .unwrap()
+            .unwrap();
+        let snapshot = node.snapshot();
+        assert!(snapshot.is_banned("10.0.0.1:4000"));
+        assert_eq!(snapshot.bans.active(0).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_panicking_command_stops_node() {
+        let node = NodeHandle::spawn(Node::new("A", 1).unwrap());
+        node.update(|n| n.address = "a".into()).await.unwrap();
+        let failed = node
+            .update(|n| {
+                n.address = "half changed".into();
+                panic!("boom")
+            })
+            .await;
+        assert!(matches!(failed, Err(Error::NodeUnavailable)));
+
+        let later = node.update(|n| n.address = "b".into()).await;
+        assert!(matches!(later, Err(Error::NodeUnavailable)));
+        assert_eq!(node.snapshot().address, "a");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_updates_are_applied_in_order() {
+        let node = NodeHandle::spawn(Node::new("A", 1).unwrap());
+        let updates: Vec<_> = (0..100)
+            .map(|i| {
+                let node = node.clone();
+                tokio::spawn(async move {
+                    node.update(move |n| n.name.push_str(&format!("{i},")))
+                        .await
+                })
+            })
+            .collect();
+        for update in updates {
+            update.await.unwrap().unwrap();
+        }
+        let name = node.update(|n| n.name.clone()).await.unwrap();
+        assert_eq!(name.matches(',').count(), 100);
+    }
+}
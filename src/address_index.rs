@@ -0,0 +1,242 @@
+//! Index of the transactions sending from or paying to each address of a
+//! chain, kept up to date as blocks are added or the chain is replaced.
+
+use crate::block::Block;
+use crate::errors::{Error, Result, ValidationError};
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Sent by the address, including payments to itself.
+    Sent,
+    Received,
+}
+
+/// Transaction of an address, see [`AddressIndex::history`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// Index of the block including the transaction.
+    pub height: u64,
+    /// Position of the transaction in the block.
+    pub position: usize,
+    pub direction: Direction,
+    /// Balance of the address after the transaction.
+    pub balance: i64,
+    /// Nonce the next signed transaction from the address must carry after
+    /// the transaction.
+    pub nonce: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AddressIndex {
+    entries: HashMap<String, Vec<IndexEntry>>,
+}
+
+impl AddressIndex {
+    /// Indexes the transactions of the block following the indexed ones.
+    /// A block overflowing the balance of an address is rejected and leaves
+    /// the index as it was.
+    pub fn add_block(&mut self, block: &Block) -> Result<()> {
+        let indexed = block
+            .transactions
+            .iter()
+            .enumerate()
+            .try_for_each(|(position, tx)| {
+                let overflow = |address: &str| {
+                    Error::InvalidBlock(
+                        block.index,
+                        ValidationError::InvalidTransaction(
+                            position,
+                            Box::new(Error::BalanceOverflow(address.to_owned())),
+                        ),
+                    )
+                };
+                let spent = tx.amount.checked_neg().ok_or_else(|| overflow(&tx.from))?;
+                let nonces = u64::from(tx.is_signed());
+                self.record(
+                    &tx.from,
+                    block.index,
+                    position,
+                    Direction::Sent,
+                    spent,
+                    nonces,
+                )
+                .ok_or_else(|| overflow(&tx.from))?;
+                if tx.to != tx.from {
+                    self.record(
+                        &tx.to,
+                        block.index,
+                        position,
+                        Direction::Received,
+                        tx.amount,
+                        0,
+                    )
+                    .ok_or_else(|| overflow(&tx.to))?;
+                }
+                Ok(())
+            });
+        if indexed.is_err() {
+            self.truncate(block.index);
+        }
+        indexed
+    }
+
+    /// Records the transaction, which uses up `nonces` of the address, `None`
+    /// if the balance overflows.
+    fn record(
+        &mut self,
+        address: &str,
+        height: u64,
+        position: usize,
+        direction: Direction,
+        amount: i64,
+        nonces: u64,
+    ) -> Option<()> {
+        let entries = self.entries.entry(address.to_owned()).or_default();
+        let last = entries.last();
+        let balance = last.map_or(0, |e| e.balance).checked_add(amount)?;
+        let nonce = last.map_or(0, |e| e.nonce) + nonces;
+        entries.push(IndexEntry {
+            height,
+            position,
+            direction,
+            balance,
+            nonce,
+        });
+        Some(())
+    }
+
+    /// Forgets the transactions of blocks at `height` and above.
+    pub fn truncate(&mut self, height: u64) {
+        self.entries.retain(|_, entries| {
+            let kept = entries.partition_point(|e| e.height < height);
+            entries.truncate(kept);
+            !entries.is_empty()
+        });
+    }
+
+    /// Balance of the address after the indexed blocks.
+    pub fn balance(&self, address: &str) -> i64 {
+        self.history(address).last().map_or(0, |e| e.balance)
+    }
+
+    /// Nonce the next signed transaction from the address must carry.
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        self.history(address).last().map_or(0, |e| e.nonce)
+    }
+
+    /// Transactions of the address, oldest first.
+    pub fn history(&self, address: &str) -> &[IndexEntry] {
+        self.entries.get(address).map_or(&[], Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Transaction;
+
+    fn block(index: u64, payments: &[(&str, &str, i64)]) -> Block {
+        Block {
+            index,
+            transactions: payments
+                .iter()
+                .map(|&(from, to, amount)| Transaction {
+                    from: from.into(),
+                    to: to.into(),
+                    amount,
+                    ..Default::default()
+                })
+                .collect(),
+            timestamp: 0,
+            previous_hash: String::new(),
+            hash: String::new(),
+            nonce: 0,
+        }
+    }
+
+    fn balances(index: &AddressIndex, address: &str) -> Vec<(u64, Direction, i64)> {
+        index
+            .history(address)
+            .iter()
+            .map(|e| (e.height, e.direction, e.balance))
+            .collect()
+    }
+
+    #[test]
+    fn test_history_keeps_running_balance() {
+        let mut index = AddressIndex::default();
+        index
+            .add_block(&block(1, &[("A", "B", 10), ("B", "C", 4)]))
+            .unwrap();
+        index
+            .add_block(&block(2, &[("C", "B", 1), ("B", "B", 2)]))
+            .unwrap();
+
+        assert_eq!(
+            balances(&index, "B"),
+            vec![
+                (1, Direction::Received, 10),
+                (1, Direction::Sent, 6),
+                (2, Direction::Received, 7),
+                (2, Direction::Sent, 5),
+            ]
+        );
+        assert_eq!(index.history("B")[1].position, 1);
+        assert!(index.history("D").is_empty());
+    }
+
+    #[test]
+    fn test_truncate_forgets_replaced_blocks() {
+        let mut index = AddressIndex::default();
+        index.add_block(&block(1, &[("A", "B", 10)])).unwrap();
+        index
+            .add_block(&block(2, &[("A", "B", 5), ("A", "C", 1)]))
+            .unwrap();
+
+        index.truncate(2);
+        assert_eq!(balances(&index, "B"), vec![(1, Direction::Received, 10)]);
+        assert!(index.history("C").is_empty());
+
+        index.add_block(&block(2, &[("B", "A", 3)])).unwrap();
+        assert_eq!(
+            balances(&index, "A"),
+            vec![(1, Direction::Sent, -10), (2, Direction::Received, -7)]
+        );
+    }
+
+    #[test]
+    fn test_balance_and_nonce_follow_the_indexed_blocks() {
+        let mut index = AddressIndex::default();
+        let mut signed = block(1, &[("A", "B", 10), ("A", "B", 5), ("B", "A", 1)]);
+        signed.transactions[0].public_key = Some("key".into());
+        signed.transactions[2].public_key = Some("key".into());
+        index.add_block(&signed).unwrap();
+        assert_eq!((index.balance("A"), index.next_nonce("A")), (-14, 1));
+        assert_eq!((index.balance("B"), index.next_nonce("B")), (14, 1));
+
+        index.truncate(1);
+        assert_eq!((index.balance("A"), index.next_nonce("A")), (0, 0));
+    }
+
+    #[test]
+    fn test_overflowing_block_is_rejected() {
+        let mut index = AddressIndex::default();
+        index.add_block(&block(1, &[("A", "B", i64::MAX)])).unwrap();
+
+        let result = index.add_block(&block(2, &[("C", "D", 1), ("A", "B", 1)]));
+        match result {
+            Err(Error::InvalidBlock(2, ValidationError::InvalidTransaction(1, e))) => {
+                assert!(matches!(*e, Error::BalanceOverflow(address) if address == "B"))
+            }
+            v => panic!("Expected error BalanceOverflow, actual {v:?}"),
+        }
+        assert_eq!(
+            balances(&index, "B"),
+            vec![(1, Direction::Received, i64::MAX)]
+        );
+        assert!(index.history("C").is_empty());
+    }
+}
@@ -0,0 +1,165 @@
+//! Transaction history of an address, served from the chain's
+//! [`AddressIndex`](crate::address_index::AddressIndex).
+//!
+//! History is returned newest first in pages, see [`super::blocks`]. The
+//! cursor of a page is `height:position` of its last transaction, the next
+//! page holds the transactions before it.
+
+use super::blocks::{DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT};
+use super::server::AppState;
+use crate::{
+    actor::NodeHandle,
+    address_index::Direction,
+    block::Transaction,
+    blockchain::Blockchain,
+    errors::{Error, Result},
+};
+use axum::extract::{Json, Path, Query, State};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Deserialize)]
+pub(super) struct HistoryQuery {
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct HistoryPage {
+    transactions: Vec<AddressTransaction>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct AddressTransaction {
+    height: u64,
+    block_hash: String,
+    timestamp: u64,
+    direction: Direction,
+    /// Balance of the address after the transaction.
+    balance: i64,
+    transaction: Transaction,
+}
+
+fn parse_cursor(cursor: &str) -> Result<(u64, usize)> {
+    cursor
+        .split_once(':')
+        .and_then(|(height, position)| Some((height.parse().ok()?, position.parse().ok()?)))
+        .ok_or_else(|| Error::InvalidCursor(cursor.to_owned()))
+}
+
+fn history(chain: &Blockchain, address: &str, query: HistoryQuery) -> Result<HistoryPage> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if !(1..=MAX_PAGE_LIMIT).contains(&limit) {
+        Err(Error::InvalidPageLimit(limit, MAX_PAGE_LIMIT))?;
+    }
+    let entries = chain.index().history(address);
+    let end = match query.cursor {
+        Some(cursor) => {
+            let before = parse_cursor(&cursor)?;
+            entries.partition_point(|e| (e.height, e.position) < before)
+        }
+        None => entries.len(),
+    };
+    let start = end.saturating_sub(limit);
+    let blocks = chain.blocks();
+    let transactions = entries[start..end]
+        .iter()
+        .rev()
+        .map(|entry| {
+            let block = &blocks[entry.height as usize];
+            AddressTransaction {
+                height: entry.height,
+                block_hash: block.hash.clone(),
+                timestamp: block.timestamp,
+                direction: entry.direction,
+                balance: entry.balance,
+                transaction: block.transactions[entry.position].clone(),
+            }
+        })
+        .collect();
+    let next_cursor = (start > 0).then(|| {
+        let last = &entries[start];
+        format!("{}:{}", last.height, last.position)
+    });
+    Ok(HistoryPage {
+        transactions,
+        next_cursor,
+    })
+}
+
+#[axum::debug_handler(state = AppState)]
+pub(super) async fn get_address_transactions(
+    State(node): State<NodeHandle>,
+    Path(address): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<HistoryPage>> {
+    Ok(Json(history(&node.snapshot().blockchain, &address, query)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payment(from: &str, to: &str, amount: i64) -> Transaction {
+        Transaction {
+            from: from.into(),
+            to: to.into(),
+            amount,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_history_is_paged_newest_first() {
+        let mut chain = Blockchain::new(1).unwrap();
+        chain
+            .add_block(vec![payment("A", "B", 10), payment("B", "C", 3)])
+            .unwrap();
+        chain.add_block(vec![payment("C", "B", 1)]).unwrap();
+        chain.add_block(vec![payment("A", "C", 1)]).unwrap();
+
+        let query = |cursor| HistoryQuery {
+            limit: Some(2),
+            cursor,
+        };
+        let first = history(&chain, "B", query(None)).unwrap();
+        let summary: Vec<_> = first
+            .transactions
+            .iter()
+            .map(|t| (t.height, t.direction, t.balance))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![(2, Direction::Received, 8), (1, Direction::Sent, 7)]
+        );
+        assert_eq!(first.next_cursor.as_deref(), Some("1:1"));
+        assert_eq!(first.transactions[0].block_hash, chain.blocks()[2].hash);
+
+        let last = history(&chain, "B", query(first.next_cursor)).unwrap();
+        assert_eq!(last.transactions.len(), 1);
+        assert_eq!(last.transactions[0].transaction, payment("A", "B", 10));
+        assert_eq!(last.next_cursor, None);
+        assert_eq!(chain.get_balance("B"), first.transactions[0].balance);
+    }
+
+    #[test]
+    fn test_malformed_cursor_is_rejected() {
+        let chain = Blockchain::new(1).unwrap();
+        for cursor in ["", "1", "a:b"] {
+            let query = HistoryQuery {
+                cursor: Some(cursor.into()),
+                ..Default::default()
+            };
+            assert!(matches!(
+                history(&chain, "A", query),
+                Err(Error::InvalidCursor(_))
+            ));
+        }
+        assert!(
+            history(&chain, "A", HistoryQuery::default())
+                .unwrap()
+                .transactions
+                .is_empty()
+        );
+    }
+}
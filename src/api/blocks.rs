@@ -0,0 +1,218 @@
+//! Queries of single blocks and of ranges of blocks, so that clients don't
+//! have to download the whole chain.
+//!
+//! Ranges are returned in pages of at most `limit` blocks in ascending
+//! order. A page not reaching the end of the range carries a cursor, the
+//! hash of its last block, to pass to get the next page. Cursors of blocks
+//! dropped by a reorganization are rejected.
+
+use super::server::AppState;
+use crate::{
+    actor::NodeHandle,
+    block::Block,
+    blockchain::Blockchain,
+    errors::{Error, Result},
+};
+use axum::extract::{Json, Path, Query, State};
+use serde::{Deserialize, Serialize};
+
+/// Blocks returned when the query doesn't set a limit.
+pub const DEFAULT_PAGE_LIMIT: usize = 20;
+/// Most blocks returned at once.
+pub const MAX_PAGE_LIMIT: usize = 100;
+
+#[derive(Debug, Default, Deserialize)]
+pub(super) struct BlockQuery {
+    /// Height of the first block, 0 by default.
+    from: Option<u64>,
+    /// Height of the last block, the tip by default.
+    to: Option<u64>,
+    limit: Option<usize>,
+    /// Hash of the last block of the previous page.
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct BlockPage {
+    blocks: Vec<Block>,
+    /// Cursor of the next page, none on the last one.
+    next_cursor: Option<String>,
+}
+
+/// Summary of the chain without its blocks.
+#[derive(Debug, Serialize)]
+pub(super) struct ChainInfo {
+    height: u64,
+    tip_hash: String,
+    /// See [`Blockchain::cumulative_work`], as a string since JSON numbers
+    /// lose precision beyond 2^53.
+    cumulative_work: String,
+    difficulty: usize,
+}
+
+fn page(chain: &Blockchain, query: BlockQuery) -> Result<BlockPage> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if !(1..=MAX_PAGE_LIMIT).contains(&limit) {
+        Err(Error::InvalidPageLimit(limit, MAX_PAGE_LIMIT))?;
+    }
+    let blocks = chain.blocks();
+    let mut start = query.from.map_or(0, height);
+    if let Some(cursor) = query.cursor {
+        let last = chain
+            .position(&cursor)
+            .ok_or(Error::InvalidCursor(cursor))?;
+        start = start.max(last + 1);
+    }
+    let end = query
+        .to
+        .map_or(blocks.len(), |to| height(to).saturating_add(1))
+        .min(blocks.len());
+    let page = match start < end {
+        true => &blocks[start..end.min(start + limit)],
+        false => &[],
+    };
+    let next_cursor = match page.last() {
+        Some(last) if start + page.len() < end => Some(last.hash.clone()),
+        _ => None,
+    };
+    Ok(BlockPage {
+        blocks: page.to_vec(),
+        next_cursor,
+    })
+}
+
+fn height(height: u64) -> usize {
+    usize::try_from(height).unwrap_or(usize::MAX)
+}
+
+#[axum::debug_handler(state = AppState)]
+pub(super) async fn get_blocks(
+    State(node): State<NodeHandle>,
+    Query(query): Query<BlockQuery>,
+) -> Result<Json<BlockPage>> {
+    Ok(Json(page(&node.snapshot().blockchain, query)?))
+}
+
+#[axum::debug_handler(state = AppState)]
+pub(super) async fn get_latest_block(State(node): State<NodeHandle>) -> Result<Json<Block>> {
+    let snapshot = node.snapshot();
+    let tip = snapshot
+        .blockchain
+        .blocks()
+        .last()
+        .ok_or(Error::ChainIsEmpty)?;
+    Ok(Json(tip.clone()))
+}
+
+#[axum::debug_handler(state = AppState)]
+pub(super) async fn get_block_by_height(
+    State(node): State<NodeHandle>,
+    Path(n): Path<u64>,
+) -> Result<Json<Block>> {
+    let snapshot = node.snapshot();
+    let block = snapshot
+        .blockchain
+        .blocks()
+        .get(height(n))
+        .ok_or_else(|| Error::BlockNotFound(n.to_string()))?;
+    Ok(Json(block.clone()))
+}
+
+#[axum::debug_handler(state = AppState)]
+pub(super) async fn get_block_by_hash(
+    State(node): State<NodeHandle>,
+    Path(hash): Path<String>,
+) -> Result<Json<Block>> {
+    let snapshot = node.snapshot();
+    let chain = &snapshot.blockchain;
+    let position = chain.position(&hash).ok_or(Error::BlockNotFound(hash))?;
+    Ok(Json(chain.blocks()[position].clone()))
+}
+
+#[axum::debug_handler(state = AppState)]
+pub(super) async fn get_chain_info(State(node): State<NodeHandle>) -> Result<Json<ChainInfo>> {
+    let snapshot = node.snapshot();
+    let chain = &snapshot.blockchain;
+    let tip = chain.blocks().last().ok_or(Error::ChainIsEmpty)?;
+    Ok(Json(ChainInfo {
+        height: chain.height(),
+        tip_hash: tip.hash.clone(),
+        cumulative_work: chain.cumulative_work().to_string(),
+        difficulty: chain.difficulty(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(blocks: usize) -> Blockchain {
+        let mut chain = Blockchain::new(1).unwrap();
+        for _ in 1..blocks {
+            chain.add_block(vec![]).unwrap();
+        }
+        chain
+    }
+
+    fn heights(page: &BlockPage) -> Vec<u64> {
+        page.blocks.iter().map(|b| b.index).collect()
+    }
+
+    #[test]
+    fn test_cursor_walks_range_in_pages() {
+        let chain = chain(7);
+        let query = || BlockQuery {
+            from: Some(1),
+            to: Some(5),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = page(&chain, query()).unwrap();
+        assert_eq!(heights(&first), vec![1, 2]);
+        assert_eq!(first.next_cursor.as_deref(), Some(&*chain.blocks()[2].hash));
+
+        let cursor = first.next_cursor;
+        let second = page(&chain, BlockQuery { cursor, ..query() }).unwrap();
+        assert_eq!(heights(&second), vec![3, 4]);
+
+        let cursor = second.next_cursor;
+        let last = page(&chain, BlockQuery { cursor, ..query() }).unwrap();
+        assert_eq!(heights(&last), vec![5]);
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn test_range_is_clamped_to_chain() {
+        let chain = chain(3);
+        let all = page(&chain, BlockQuery::default()).unwrap();
+        assert_eq!(heights(&all), vec![0, 1, 2]);
+        assert_eq!(all.next_cursor, None);
+
+        let beyond = BlockQuery {
+            from: Some(5),
+            to: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert!(page(&chain, beyond).unwrap().blocks.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_limits_and_cursors_are_rejected() {
+        let chain = chain(2);
+        for limit in [0, MAX_PAGE_LIMIT + 1] {
+            let query = BlockQuery {
+                limit: Some(limit),
+                ..Default::default()
+            };
+            assert!(matches!(
+                page(&chain, query),
+                Err(Error::InvalidPageLimit(l, MAX_PAGE_LIMIT)) if l == limit
+            ));
+        }
+        let query = BlockQuery {
+            cursor: Some("unknown".into()),
+            ..Default::default()
+        };
+        assert!(matches!(page(&chain, query), Err(Error::InvalidCursor(_))));
+    }
+}
@@ -0,0 +1,118 @@
+//! Streams of [`NodeEvent`]s over Server-Sent Events and WebSocket.
+//!
+//! Both take the filter as `types`, a comma-separated list of event types,
+//! and `address` query parameters. WebSocket clients can replace the filter
+//! later by sending it as JSON, e.g. `{"types": ["new_block"], "address":
+//! "..."}`, which is acknowledged with `{"type": "subscribed"}` once events
+//! are filtered by it. Subscribers too slow to keep up miss events.
+
+use super::server::AppState;
+use crate::{
+    actor::NodeHandle,
+    errors::Result,
+    events::{EventFilter, NodeEvent},
+};
+use axum::{
+    extract::{
+        Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    response::{
+        Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::convert::Infallible;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_stream::{Stream, wrappers::BroadcastStream};
+use tracing::warn;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct EventQuery {
+    types: Option<String>,
+    address: Option<String>,
+}
+
+impl EventQuery {
+    fn filter(self) -> Result<EventFilter> {
+        EventFilter::parse(self.types.as_deref(), self.address)
+    }
+}
+
+#[axum::debug_handler(state = AppState)]
+pub(super) async fn get_events(
+    State(node): State<NodeHandle>,
+    Query(query): Query<EventQuery>,
+) -> Result<Sse<impl Stream<Item = std::result::Result<Event, Infallible>>>> {
+    let filter = query.filter()?;
+    let events = BroadcastStream::new(node.subscribe()).filter_map(move |event| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Event subscriber lagged: {e}");
+                return std::future::ready(None);
+            }
+        };
+        let sent = filter.matches(&event).then(|| {
+            Event::default()
+                .event(event.kind().name())
+                .json_data(&event)
+        });
+        std::future::ready(sent.and_then(|sent| sent.ok()).map(Ok))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[axum::debug_handler(state = AppState)]
+pub(super) async fn get_ws(
+    State(node): State<NodeHandle>,
+    Query(query): Query<EventQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response> {
+    let filter = query.filter()?;
+    let events = node.subscribe();
+    Ok(upgrade.on_upgrade(move |socket| stream_events(socket, events, filter)))
+}
+
+async fn stream_events(
+    socket: WebSocket,
+    mut events: broadcast::Receiver<NodeEvent>,
+    mut filter: EventFilter,
+) {
+    let (mut sender, mut receiver) = socket.split();
+    loop {
+        let reply = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if filter.matches(&event) => serde_json::to_value(&event).ok(),
+                Ok(_) => None,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Event subscriber lagged, missed {missed} events");
+                    None
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = receiver.next() => match message {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(subscription) => {
+                        filter = subscription;
+                        Some(json!({"type": "subscribed"}))
+                    }
+                    Err(e) => Some(json!({"type": "error", "message": e.to_string()})),
+                },
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => None,
+            },
+        };
+        if let Some(reply) = reply
+            && sender
+                .send(Message::Text(reply.to_string().into()))
+                .await
+                .is_err()
+        {
+            break;
+        }
+    }
+}
@@ -1,4 +1,10 @@
+mod addresses;
+mod blocks;
+mod events;
 mod midleware;
+mod rpc;
 mod server;
 
+#[allow(unused)]
+pub use server::serve_http;
 pub use server::start_http_server;
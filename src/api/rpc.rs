@@ -0,0 +1,454 @@
+//! JSON-RPC 2.0 interface on `POST /rpc`, with methods mirroring the REST
+//! handlers.
+//!
+//! Params are given by position or by name, e.g. `[10]` or `{"height": 10}`.
+//! Batches are answered in the order of their calls, notifications, calls
+//! without an `id`, get no reply. Errors of the node are reported with the
+//! codes below.
+
+use super::blocks;
+use super::server::{self, AppState};
+use crate::{
+    actor::NodeHandle, block::Transaction, errors::Error, network::transport::SharedPeerClient,
+};
+use axum::{
+    body::Bytes,
+    extract::{Json, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use tracing::warn;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// The requested block or peer doesn't exist.
+pub const NOT_FOUND: i64 = -32001;
+/// The caller is banned.
+pub const FORBIDDEN: i64 = -32002;
+/// The node is shutting down or failed, or its mempool is full.
+pub const UNAVAILABLE: i64 = -32003;
+/// The transaction breaks a rule, e.g. its signature or nonce is invalid or
+/// its sender can't afford it.
+pub const TRANSACTION_REJECTED: i64 = -32004;
+
+type RpcResult<T> = std::result::Result<T, RpcError>;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(super) struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn invalid_request() -> Self {
+        Self::new(INVALID_REQUEST, "Invalid request")
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> Self {
+        let code = match e {
+            Error::InvalidBlock(..)
+            | Error::InvalidPublicKey(_)
+            | Error::InvalidTransactionSignature(_)
+            | Error::TransactionNotSigned(_)
+            | Error::InvalidAmount(..)
+            | Error::InsufficientBalance(..)
+            | Error::BalanceOverflow(_)
+            | Error::InvalidTransactionNonce(..)
+            | Error::InvalidScript(_)
+            | Error::ScriptFailed(..)
+            | Error::InvalidMultisigPolicy(_)
+            | Error::KeyNotInMultisig(_)
+            | Error::InsufficientSignatures(..) => TRANSACTION_REJECTED,
+            Error::HttpParsing(_)
+            | Error::InvalidIpAddress(_)
+            | Error::InvalidBanDuration(_)
+            | Error::InvalidCursor(_)
+            | Error::InvalidPageLimit(..)
+            | Error::InvalidEventType(_) => INVALID_PARAMS,
+            Error::BlockNotFound(_) | Error::PeerNotConnected(_) | Error::NotBanned(_) => NOT_FOUND,
+            Error::PeerBanned(_) => FORBIDDEN,
+            Error::NodeUnavailable | Error::MempoolFull(_) => UNAVAILABLE,
+            _ => INTERNAL_ERROR,
+        };
+        Self::new(code, e.to_string())
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    Result(Value),
+    Error(RpcError),
+}
+
+#[derive(Debug, Serialize)]
+struct Reply {
+    jsonrpc: &'static str,
+    #[serde(flatten)]
+    outcome: Outcome,
+    id: Value,
+}
+
+impl Reply {
+    fn new(id: Value, result: RpcResult<Value>) -> Self {
+        let outcome = match result {
+            Ok(value) => Outcome::Result(value),
+            Err(e) => Outcome::Error(e),
+        };
+        Self {
+            jsonrpc: "2.0",
+            outcome,
+            id,
+        }
+    }
+}
+
+struct Call {
+    method: String,
+    params: Params,
+    /// `None` for notifications.
+    id: Option<Value>,
+}
+
+impl Call {
+    /// Reads a call, or the error reply to an invalid one.
+    fn parse(request: Value) -> std::result::Result<Self, Reply> {
+        let Value::Object(mut fields) = request else {
+            return Err(Reply::new(Value::Null, Err(RpcError::invalid_request())));
+        };
+        let id = fields.remove("id");
+        if !matches!(
+            id,
+            None | Some(Value::Null | Value::Number(_) | Value::String(_))
+        ) {
+            return Err(Reply::new(Value::Null, Err(RpcError::invalid_request())));
+        }
+        let invalid = || {
+            Reply::new(
+                id.clone().unwrap_or_default(),
+                Err(RpcError::invalid_request()),
+            )
+        };
+        if fields.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+            return Err(invalid());
+        }
+        let Some(Value::String(method)) = fields.remove("method") else {
+            return Err(invalid());
+        };
+        let params = fields.remove("params").unwrap_or_default();
+        if !matches!(params, Value::Null | Value::Array(_) | Value::Object(_)) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            method,
+            params: Params(params),
+            id,
+        })
+    }
+}
+
+struct Params(Value);
+
+impl Params {
+    /// Param at `position` of positional params, or called `name` of named
+    /// ones.
+    fn get<T: DeserializeOwned>(&self, position: usize, name: &str) -> RpcResult<T> {
+        let param = match &self.0 {
+            Value::Array(params) => params.get(position),
+            Value::Object(params) => params.get(name),
+            _ => None,
+        };
+        T::deserialize(param.unwrap_or(&Value::Null))
+            .map_err(|e| RpcError::new(INVALID_PARAMS, format!("Param '{name}' is invalid: {e}")))
+    }
+}
+
+fn result<T: Serialize>(reply: crate::errors::Result<Json<T>>) -> RpcResult<Value> {
+    let Json(value) = reply?;
+    Ok(serde_json::to_value(value).map_err(Error::from)?)
+}
+
+async fn dispatch(
+    node: &NodeHandle,
+    peers: &SharedPeerClient,
+    method: &str,
+    params: Params,
+) -> RpcResult<Value> {
+    match method {
+        "getBlockByHeight" => {
+            let height = params.get(0, "height")?;
+            result(blocks::get_block_by_height(State(node.clone()), Path(height)).await)
+        }
+        "getBalance" => {
+            let address = params.get(0, "address")?;
+            result(server::get_balance(State(node.clone()), Path(address)).await)
+        }
+        "sendTransaction" => {
+            let transaction: Transaction = params.get(0, "transaction")?;
+            let id = transaction.id();
+            server::submit_transaction(
+                State(node.clone()),
+                State(peers.clone()),
+                Json(transaction),
+            )
+            .await?;
+            Ok(json!(id))
+        }
+        "getPeers" => result(server::get_peers(State(node.clone())).await),
+        "getMempool" => Ok(json!(node.snapshot().mempool)),
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Method '{method}' not found"),
+        )),
+    }
+}
+
+async fn call(node: &NodeHandle, peers: &SharedPeerClient, request: Value) -> Option<Reply> {
+    let call = match Call::parse(request) {
+        Ok(call) => call,
+        Err(reply) => return Some(reply),
+    };
+    let result = dispatch(node, peers, &call.method, call.params).await;
+    if let Err(e) = &result {
+        warn!("RPC method {} failed: {}", call.method, e.message);
+    }
+    Some(Reply::new(call.id?, result))
+}
+
+/// Replies to a single call or a batch, `None` when there is nothing to
+/// reply, i.e. for notifications only.
+async fn handle(node: &NodeHandle, peers: &SharedPeerClient, body: &[u8]) -> Option<Value> {
+    let request = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = RpcError::new(PARSE_ERROR, format!("Parse error: {e}"));
+            return Some(json!(Reply::new(Value::Null, Err(error))));
+        }
+    };
+    match request {
+        Value::Array(calls) if calls.is_empty() => Some(json!(Reply::new(
+            Value::Null,
+            Err(RpcError::invalid_request())
+        ))),
+        Value::Array(calls) => {
+            let mut replies = vec![];
+            for request in calls {
+                replies.extend(call(node, peers, request).await);
+            }
+            (!replies.is_empty()).then(|| json!(replies))
+        }
+        request => call(node, peers, request).await.map(|reply| json!(reply)),
+    }
+}
+
+#[axum::debug_handler(state = AppState)]
+pub(super) async fn rpc(
+    State(node): State<NodeHandle>,
+    State(peers): State<SharedPeerClient>,
+    body: Bytes,
+) -> Response {
+    match handle(&node, &peers, &body).await {
+        Some(reply) => Json(reply).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::test_utils::signed_transaction;
+
+    async fn rpc(node: &NodeHandle, body: &str) -> Option<Value> {
+        let peers: SharedPeerClient = node.snapshot().connections.clone();
+        handle(node, &peers, body.as_bytes()).await
+    }
+
+    fn error_code(reply: &Value) -> i64 {
+        reply["error"]["code"].as_i64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_calls_are_answered_with_result_and_id() {
+        let node = NodeHandle::spawn(Node::new("A", 1).unwrap());
+        let reply = rpc(
+            &node,
+            r#"{"jsonrpc": "2.0", "method": "getBlockByHeight", "params": [0], "id": 1}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply["jsonrpc"], "2.0");
+        assert_eq!(reply["id"], 1);
+        assert_eq!(reply["result"]["index"], 0);
+        assert!(reply.get("error").is_none());
+
+        let reply = rpc(
+            &node,
+            r#"{"jsonrpc": "2.0", "method": "getBalance", "params": {"address": "B"}, "id": "b"}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!((&reply["id"], &reply["result"]), (&json!("b"), &json!(0)));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_requests_are_rejected_with_standard_codes() {
+        let node = NodeHandle::spawn(Node::new("A", 1).unwrap());
+        let cases = [
+            (r#"{"jsonrpc": "2.0", "method""#, PARSE_ERROR, Value::Null),
+            ("[]", INVALID_REQUEST, Value::Null),
+            ("1", INVALID_REQUEST, Value::Null),
+            (
+                r#"{"method": "getPeers", "id": 1}"#,
+                INVALID_REQUEST,
+                json!(1),
+            ),
+            (
+                r#"{"jsonrpc": "2.0", "method": 1, "id": 2}"#,
+                INVALID_REQUEST,
+                json!(2),
+            ),
+            (
+                r#"{"jsonrpc": "2.0", "method": "getPeers", "id": [3]}"#,
+                INVALID_REQUEST,
+                Value::Null,
+            ),
+            (
+                r#"{"jsonrpc": "2.0", "method": "getPeers", "params": 1, "id": 4}"#,
+                INVALID_REQUEST,
+                json!(4),
+            ),
+            (
+                r#"{"jsonrpc": "2.0", "method": "mine", "id": 5}"#,
+                METHOD_NOT_FOUND,
+                json!(5),
+            ),
+            (
+                r#"{"jsonrpc": "2.0", "method": "getBlockByHeight", "params": ["a"], "id": 6}"#,
+                INVALID_PARAMS,
+                json!(6),
+            ),
+            (
+                r#"{"jsonrpc": "2.0", "method": "getBalance", "params": [], "id": 7}"#,
+                INVALID_PARAMS,
+                json!(7),
+            ),
+        ];
+        for (body, code, id) in cases {
+            let reply = rpc(&node, body).await.unwrap();
+            assert_eq!(error_code(&reply), code, "{body}");
+            assert_eq!(reply["id"], id, "{body}");
+            assert!(reply.get("result").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_node_errors_are_mapped_to_error_codes() {
+        let node = NodeHandle::spawn(Node::new("A", 1).unwrap());
+        let reply = rpc(
+            &node,
+            r#"{"jsonrpc": "2.0", "method": "getBlockByHeight", "params": [9], "id": 1}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(error_code(&reply), NOT_FOUND);
+        assert_eq!(reply["error"]["message"], "Block '9' not found");
+
+        let codes = [
+            (Error::InvalidPublicKey("k".into()), TRANSACTION_REJECTED),
+            (Error::InvalidPageLimit(0, 100), INVALID_PARAMS),
+            (Error::MempoolFull(1), UNAVAILABLE),
+            (Error::PeerBanned("1.2.3.4".into()), FORBIDDEN),
+            (Error::NodeUnavailable, UNAVAILABLE),
+            (Error::ChainIsEmpty, INTERNAL_ERROR),
+        ];
+        for (e, code) in codes {
+            assert_eq!(RpcError::from(e).code, code);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejected_transactions_are_reported() {
+        let node = NodeHandle::spawn(Node::new("A", 1).unwrap());
+        let key = crate::crypto::generate_signing_key();
+        let cases = [
+            (
+                Transaction {
+                    from: "A".into(),
+                    to: "B".into(),
+                    amount: 0,
+                    ..Default::default()
+                },
+                "Transaction from 'A' has non-positive amount 0",
+            ),
+            (
+                signed_transaction(&key, 10, 0),
+                "can spend 0, transaction spends 10",
+            ),
+        ];
+        for (transaction, message) in cases {
+            let call = json!({
+                "jsonrpc": "2.0",
+                "method": "sendTransaction",
+                "params": {"transaction": transaction},
+                "id": 1,
+            });
+            let reply = rpc(&node, &call.to_string()).await.unwrap();
+            assert_eq!(error_code(&reply), TRANSACTION_REJECTED);
+            assert!(
+                reply["error"]["message"]
+                    .as_str()
+                    .unwrap()
+                    .ends_with(message)
+            );
+            assert!(reply.get("result").is_none());
+        }
+        assert!(node.snapshot().mempool.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_answered_in_order_without_notifications() {
+        let node = NodeHandle::spawn(Node::new("A", 1).unwrap());
+        let transaction = Transaction {
+            from: "A".into(),
+            to: "B".into(),
+            amount: 1,
+            ..Default::default()
+        };
+        let batch = json!([
+            {"jsonrpc": "2.0", "method": "sendTransaction", "params": [transaction]},
+            {"jsonrpc": "2.0", "method": "getMempool", "id": 1},
+            {"jsonrpc": "2.0", "method": "unknown", "id": 2},
+            {"jsonrpc": "2.0", "method": "getPeers", "id": 3},
+            "call",
+        ]);
+        let replies = rpc(&node, &batch.to_string()).await.unwrap();
+        let replies = replies.as_array().unwrap();
+        assert_eq!(replies.len(), 4);
+        assert_eq!(replies[0]["result"], json!([transaction]));
+        assert_eq!(error_code(&replies[1]), METHOD_NOT_FOUND);
+        assert_eq!(replies[2]["result"], json!([]));
+        assert_eq!(error_code(&replies[3]), INVALID_REQUEST);
+
+        let notifications = json!([{"jsonrpc": "2.0", "method": "getPeers"}]);
+        assert_eq!(rpc(&node, &notifications.to_string()).await, None);
+        assert_eq!(
+            rpc(&node, r#"{"jsonrpc": "2.0", "method": "unknown"}"#).await,
+            None
+        );
+    }
+}
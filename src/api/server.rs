@@ -1,34 +1,118 @@
 use crate::{
-    api::midleware::UuidRequestId,
+    actor::NodeHandle,
+    api::{addresses, blocks, events, midleware::UuidRequestId, rpc},
     block::{Block, Transaction},
     blockchain::Blockchain,
     config::Config,
-    errors::Result,
+    errors::{Error, Result},
+    events::{MiningStatus, NodeEvent},
+    network::{
+        PeerInfo,
+        bans::{self, BAN_DURATION, Ban, BanList},
+        compact::CompactBlockStats,
+        discovery::AddressBook,
+        identity::NodeIdentity,
+        liveness::PING_INTERVAL,
+        p2p::{self, ConnectionManager},
+        transport::{SharedPeerClient, deliver},
+    },
     node::Node,
 };
 use axum::{
     Router,
-    extract::{Json, Path, Request, State},
+    extract::{ConnectInfo, FromRef, Json, Path, Request, State},
     http::HeaderName,
-    routing::{get, post},
+    routing::{delete, get, post},
 };
-use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
 use tower_http::{
     request_id::{PropagateRequestIdLayer, SetRequestIdLayer},
     trace::TraceLayer,
 };
-use tracing::info;
+use tracing::{info, warn};
 
-pub type SharedNode = Arc<Mutex<Node>>;
+#[derive(Clone, FromRef)]
+pub(super) struct AppState {
+    node: NodeHandle,
+    peers: SharedPeerClient,
+}
+
+/// Starts accepting peer connections on `conf.p2p_port` and serves the API
+/// of `node` on `conf.port`.
+pub async fn start_http_server(node: NodeHandle, conf: Config) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", conf.port)).await?;
+    serve_http(node, conf, listener).await
+}
 
-pub async fn start_http_server(node: SharedNode, conf: Config) -> Result<()> {
+/// Like [`start_http_server`], serving the API on a bound `listener`, e.g.
+/// one of a free port.
+pub async fn serve_http(node: NodeHandle, conf: Config, listener: TcpListener) -> Result<()> {
+    if let Some(dir) = conf.data_dir.clone() {
+        std::fs::create_dir_all(&dir)?;
+        let identity = NodeIdentity::open(&dir.join("identity.key"))?;
+        let bans = BanList::open(&dir.join("bans.json"))?;
+        let address_book = AddressBook::open(&dir.join("addresses.json"))?;
+        node.update(move |node| {
+            node.identity = identity;
+            node.bans = bans;
+            node.address_book = address_book;
+        })
+        .await?;
+    }
+    let connections = Arc::new(ConnectionManager::new(conf.peer_limits()));
+    node.update(move |node| node.connections = connections)
+        .await?;
+    p2p::listen(node.clone(), &format!("127.0.0.1:{}", conf.p2p_port)).await?;
+    // Reconnect to the best peers known from earlier runs, or to the seeds
+    let seeds = conf.seeds.clone();
+    let dials = node
+        .update(move |node| {
+            node.seeds = seeds;
+            node.discovery_dials()
+        })
+        .await?;
+    for addr in dials {
+        let node = node.clone();
+        tokio::spawn(async move {
+            if let Err(e) = p2p::connect(&node, &addr).await {
+                warn!("Failed to connect to peer {addr}: {e}");
+            }
+        });
+    }
+    p2p::spawn_maintenance(node.clone(), Duration::from_secs(PING_INTERVAL));
+    let peers: SharedPeerClient = node.snapshot().connections.clone();
+    let state = AppState { node, peers };
     let app = Router::new()
         .route("/chain", get(get_chain))
+        .route("/chain/info", get(blocks::get_chain_info))
+        .route("/blocks", get(blocks::get_blocks))
+        .route("/blocks/latest", get(blocks::get_latest_block))
+        .route("/blocks/height/{n}", get(blocks::get_block_by_height))
+        .route("/blocks/hash/{hash}", get(blocks::get_block_by_hash))
         .route("/add_block", post(add_block))
         .route("/sync", post(sync_chain))
         .route("/peer", post(register_peer))
+        .route("/peer/{address}", delete(remove_peer))
+        .route("/peers", get(get_peers))
+        .route("/metrics", get(get_metrics))
+        .route("/bans", get(get_bans).post(add_ban))
+        .route("/bans/{key}", delete(remove_ban))
         .route("/balance/{address}", get(get_balance))
-        .with_state(node.clone())
+        .route(
+            "/addresses/{address}/transactions",
+            get(addresses::get_address_transactions),
+        )
+        .route("/nonce/{address}", get(get_nonce))
+        .route("/transaction", post(submit_transaction))
+        .route("/mine", post(mine_pending))
+        .route("/events", get(events::get_events))
+        .route("/ws", get(events::get_ws))
+        .route("/rpc", post(rpc::rpc))
+        .with_state(state)
         .layer(
             TraceLayer::new_for_http().make_span_with(|req: &Request<_>| {
                 let request_id = req
@@ -50,159 +134,265 @@ pub async fn start_http_server(node: SharedNode, conf: Config) -> Result<()> {
         )))
         .layer(SetRequestIdLayer::x_request_id(UuidRequestId));
 
-    let addr = format!("127.0.0.1:{}", conf.port);
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
     let addr = listener.local_addr()?;
-    {
-        let str_addr = addr.to_string();
-        let peers = {
-            let mut node = node.lock().unwrap();
-            node.address = str_addr.clone();
-            node.peers.iter().cloned().collect::<Vec<_>>()
-        };
-        notify_peers_about_new_peer(&str_addr, peers).await;
-    }
     info!("Starting server at http://{addr}");
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
 
-async fn notify_peers_about_new_peer(new_peer: &str, peers: Vec<String>) {
-    let client = reqwest::Client::new();
-    for peer in peers {
-        match client
-            .post(format!("{peer}/peer"))
-            .json(new_peer)
-            .send()
-            .await
-        {
-            Ok(resp) => {
-                tracing::info!(
-                    "New peer {} registration for {}: {}",
-                    new_peer,
-                    peer,
-                    resp.status()
-                );
-            }
-            Err(err) => {
-                tracing::warn!(
-                    "Failed new peer {} registration for with {}: {}",
-                    new_peer,
-                    peer,
-                    err
-                );
-            }
-        }
-    }
+#[axum::debug_handler(state = AppState)]
+async fn get_chain(State(node): State<NodeHandle>) -> Result<Json<Blockchain>> {
+    Ok(Json(node.snapshot().blockchain.clone()))
 }
 
-#[axum::debug_handler]
-async fn get_chain(State(node): State<SharedNode>) -> Result<Json<Blockchain>> {
-    let chain = node.lock().unwrap().blockchain.clone();
-    Ok(Json(chain))
-}
-
-#[axum::debug_handler]
-async fn get_balance(
-    State(node): State<SharedNode>,
+#[axum::debug_handler(state = AppState)]
+pub(super) async fn get_balance(
+    State(node): State<NodeHandle>,
     Path(address): Path<String>,
 ) -> Result<Json<i64>> {
-    let balance = node.lock().unwrap().blockchain.get_balance(&address);
+    let balance = node.snapshot().blockchain.get_balance(&address);
     info!("Balance for {address}: {balance}");
     Ok(Json(balance))
 }
 
-#[axum::debug_handler]
-async fn register_peer(State(node): State<SharedNode>, Json(data): Json<String>) -> Result<()> {
-    let peers: Vec<String>;
-    let address: String;
-    {
-        let mut node = node.lock().unwrap();
-        node.register_peer(data.clone());
-        peers = node.peers.iter().cloned().collect();
-        address = node.address.clone();
-    }
-    let client = reqwest::Client::new();
-
-    if let Err(err) = client
-        .post(format!("{data}/peer"))
-        .json(&address)
-        .send()
-        .await
-    {
-        tracing::warn!("Failed to sync with {}: {}", &data, err);
-    }
+#[axum::debug_handler(state = AppState)]
+async fn register_peer(State(node): State<NodeHandle>, Json(data): Json<String>) -> Result<()> {
+    p2p::connect(&node, &data).await
+}
 
-    for peer in peers {
-        if peer.eq(&data) {
-            continue;
-        }
-        match client.post(format!("{peer}/peer")).json(&data).send().await {
-            Ok(resp) => {
-                tracing::info!("Synced with peer {}: {}", &peer, resp.status());
-            }
-            Err(err) => {
-                tracing::warn!("Failed to sync with {}: {}", &peer, err);
-            }
+#[axum::debug_handler(state = AppState)]
+async fn remove_peer(State(node): State<NodeHandle>, Path(address): Path<String>) -> Result<()> {
+    node.update(move |node| {
+        let known = node.remove_peer(&address);
+        if !node.connections.disconnect(&address) && !known {
+            Err(Error::PeerNotConnected(address))?;
         }
-    }
+        Ok(())
+    })
+    .await?
+}
+
+#[axum::debug_handler(state = AppState)]
+pub(super) async fn get_peers(State(node): State<NodeHandle>) -> Result<Json<Vec<PeerInfo>>> {
+    let peers = node.snapshot().peers.values().cloned().collect();
+    Ok(Json(peers))
+}
+
+#[derive(Serialize)]
+struct Metrics {
+    compact_blocks: CompactBlockMetrics,
+}
+
+#[derive(Serialize)]
+struct CompactBlockMetrics {
+    #[serde(flatten)]
+    stats: CompactBlockStats,
+    /// Share of compact blocks rebuilt without fetching transactions.
+    success_rate: Option<f64>,
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn get_metrics(State(node): State<NodeHandle>) -> Result<Json<Metrics>> {
+    let stats = node.snapshot().compact_stats;
+    Ok(Json(Metrics {
+        compact_blocks: CompactBlockMetrics {
+            stats,
+            success_rate: stats.success_rate(),
+        },
+    }))
+}
 
+#[axum::debug_handler(state = AppState)]
+async fn get_nonce(
+    State(node): State<NodeHandle>,
+    Path(address): Path<String>,
+) -> Result<Json<u64>> {
+    Ok(Json(node.snapshot().next_nonce(&address)))
+}
+
+#[axum::debug_handler(state = AppState)]
+pub(super) async fn submit_transaction(
+    State(node): State<NodeHandle>,
+    State(peers): State<SharedPeerClient>,
+    Json(data): Json<Transaction>,
+) -> Result<()> {
+    let outbound = node
+        .update(move |node| node.accept_transaction(data, None))
+        .await??;
+    info!("Transaction added to mempool");
+    deliver(peers.as_ref(), outbound).await;
     Ok(())
 }
 
-#[axum::debug_handler]
+#[axum::debug_handler(state = AppState)]
 async fn add_block(
-    State(node): State<SharedNode>,
+    State(node): State<NodeHandle>,
+    State(peers): State<SharedPeerClient>,
     Json(data): Json<Vec<Transaction>>,
 ) -> Result<Json<Block>> {
-    let peers: Vec<String>;
-    let blockchain: Blockchain;
-    let block = {
-        let mut node = node.lock().unwrap();
+    let block = mine(&node, &peers, move |node| {
         let block = node.add_block(data)?.clone();
         info!("Block mined and added");
-        peers = node.peers.iter().cloned().collect();
-        blockchain = node.blockchain.clone();
-        block
-    };
-
-    let client = reqwest::Client::new();
-    for peer in peers {
-        match client
-            .post(format!("{peer}/sync"))
-            .json(&blockchain)
-            .send()
-            .await
-        {
-            Ok(resp) => {
-                tracing::info!("Synced with peer {}: {}", peer, resp.status());
-            }
-            Err(err) => {
-                tracing::warn!("Failed to sync with {}: {}", peer, err);
-            }
-        }
-    }
+        Ok(block)
+    })
+    .await?;
+    Ok(Json(block))
+}
 
+#[axum::debug_handler(state = AppState)]
+async fn mine_pending(
+    State(node): State<NodeHandle>,
+    State(peers): State<SharedPeerClient>,
+) -> Result<Json<Block>> {
+    let block = mine(&node, &peers, |node| {
+        let block = node.mine_pending()?.clone();
+        info!("Block mined from mempool and added");
+        Ok(block)
+    })
+    .await?;
     Ok(Json(block))
 }
 
-#[axum::debug_handler]
+/// Mines a block with `mine`, telling event subscribers how it went, and
+/// announces it to peers.
+async fn mine(
+    node: &NodeHandle,
+    peers: &SharedPeerClient,
+    mine: impl FnOnce(&mut Node) -> Result<Block> + Send + 'static,
+) -> Result<Block> {
+    node.publish(NodeEvent::Mining {
+        status: MiningStatus::Started,
+        block_hash: None,
+    });
+    let mined = node
+        .update(|node| mine(node).map(|block| (block, node.announce_tip())))
+        .await
+        .and_then(|mined| mined);
+    let (status, block_hash) = match &mined {
+        Ok((block, _)) => (MiningStatus::Finished, Some(block.hash.clone())),
+        Err(_) => (MiningStatus::Failed, None),
+    };
+    node.publish(NodeEvent::Mining { status, block_hash });
+    let (block, outbound) = mined?;
+    deliver(peers.as_ref(), outbound).await;
+    Ok(block)
+}
+
+/// Validates the incoming chain outside of the node, which keeps serving
+/// meanwhile, and adopts it if it is still longer than the node's chain.
+#[axum::debug_handler(state = AppState)]
 async fn sync_chain(
-    State(node): State<SharedNode>,
+    State(node): State<NodeHandle>,
+    State(peers): State<SharedPeerClient>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
     Json(incoming_chain): Json<Blockchain>,
 ) -> Result<String> {
-    let mut node = node.lock().unwrap();
-
     tracing::info!(
         "Attempting to sync with incoming chain (length: {})",
         incoming_chain.blocks().len()
     );
 
-    if node.replace_chain(incoming_chain)? {
-        tracing::info!("Chain replaced successfully");
-        Ok("Chain synced".into())
-    } else {
+    let remote = remote.to_string();
+    let snapshot = node.snapshot();
+    if snapshot.is_banned(&remote) {
+        return Err(Error::PeerBanned(bans::host(&remote)));
+    }
+    if incoming_chain.blocks().len() <= snapshot.blockchain.blocks().len() {
         tracing::warn!("Incoming chain rejected");
-        Ok("Incoming chain shorter".into())
+        return Ok("Incoming chain shorter".into());
     }
+    // Only the blocks are taken from the peer, the difficulty is ours
+    let incoming_chain = snapshot
+        .blockchain
+        .with_blocks(incoming_chain.blocks().to_vec());
+    let now = snapshot.blockchain.clock().now();
+    let validated = tokio::task::spawn_blocking(move || {
+        incoming_chain.validate_at(now).map(|()| incoming_chain)
+    })
+    .await
+    .map_err(|_| Error::NodeUnavailable)?;
+    let incoming_chain = match validated {
+        Ok(chain) => chain,
+        Err(e) => {
+            tracing::error!("Failed to replace chain {e:?}");
+            let e = node
+                .update(move |node| {
+                    if let Some(ban) = node.record_misbehavior(&remote, &e) {
+                        node.disconnect_banned(&ban);
+                    }
+                    e
+                })
+                .await?;
+            return Err(e);
+        }
+    };
+    let outbound = node
+        .update(move |node| {
+            node.adopt_chain(incoming_chain)
+                .then(|| node.announce_tip())
+        })
+        .await?;
+    let Some(outbound) = outbound else {
+        tracing::warn!("Incoming chain rejected");
+        return Ok("Incoming chain shorter".into());
+    };
+    tracing::info!("Chain replaced successfully");
+    deliver(peers.as_ref(), outbound).await;
+    Ok("Chain synced".into())
+}
+
+#[derive(Deserialize)]
+struct BanRequest {
+    ip: String,
+    /// Seconds the ban lasts.
+    duration: Option<u64>,
+    reason: Option<String>,
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn get_bans(State(node): State<NodeHandle>) -> Json<Vec<Ban>> {
+    let snapshot = node.snapshot();
+    Json(snapshot.bans.active(snapshot.blockchain.clock().now()))
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn add_ban(
+    State(node): State<NodeHandle>,
+    Json(data): Json<BanRequest>,
+) -> Result<Json<Ban>> {
+    let ip = bans::parse_ip(&data.ip)?;
+    let ban = node
+        .update(move |node| {
+            let duration = data.duration.unwrap_or(BAN_DURATION);
+            let until = node
+                .blockchain
+                .clock()
+                .now()
+                .checked_add(duration)
+                .ok_or(Error::InvalidBanDuration(duration))?;
+            let reason = data.reason.as_deref().unwrap_or("banned by operator");
+            let ban = node.bans.ban(&ip, until, reason)?;
+            node.connections.disconnect_host(&ip);
+            info!("Banned {ip} until {until}");
+            Ok::<_, Error>(ban)
+        })
+        .await??;
+    Ok(Json(ban))
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn remove_ban(State(node): State<NodeHandle>, Path(key): Path<String>) -> Result<()> {
+    let key = bans::parse_key(&key)?;
+    node.update(move |node| {
+        let now = node.blockchain.clock().now();
+        if !node.bans.unban(&key, now)? {
+            Err(Error::NotBanned(key))?;
+        }
+        Ok(())
+    })
+    .await?
 }
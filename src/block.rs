@@ -1,14 +1,303 @@
-use crate::errors::{Error, Result};
+use crate::clock::Clock;
+use crate::crypto;
+use crate::errors::{Error, Result, ValidationError};
+use crate::script::{self, Script, ScriptContext};
+use ed25519_dalek::SigningKey;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::fmt;
+use std::sync::{Mutex, PoisonError};
 use tracing::instrument;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash, Default)]
 pub struct Transaction {
     pub from: String,
     pub to: String,
     pub amount: i64,
+    #[serde(default)]
+    pub nonce: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multisig: Option<Multisig>,
+    /// Transaction can't be included in a block before this point.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock_until: Option<LockTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<ScriptSpend>,
+}
+
+/// Spending from an address guarded by `locking` script, `from` must be the
+/// script address.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash, Default)]
+pub struct ScriptSpend {
+    pub locking: Script,
+    /// Push-only script providing data the locking script consumes
+    pub unlocking: Script,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum LockTime {
+    /// Index of the first block the transaction can be included in.
+    Height(u64),
+    /// Unix timestamp of the first block the transaction can be included in.
+    Timestamp(u64),
+}
+
+/// Number of preceding blocks whose median timestamp a block can't precede.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// How far ahead of the local clock a block timestamp may be, in seconds.
+pub const MAX_FUTURE_DRIFT: u64 = 2 * 60 * 60;
+/// Sanity lower bound for block timestamps (2024-01-01T00:00:00Z).
+pub const MIN_BLOCK_TIMESTAMP: u64 = 1_704_067_200;
+/// Timestamp of the genesis block, fixed so that all nodes of a network
+/// start from the same chain.
+pub const GENESIS_TIMESTAMP: u64 = MIN_BLOCK_TIMESTAMP;
+
+pub const MAX_TRANSACTIONS_PER_BLOCK: usize = 1_000;
+/// Largest size of a JSON serialized block in bytes.
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+/// Largest number of keys a multisig address can be defined by.
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
+/// M-of-n policy of a multisig address with signatures collected so far.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash, Default)]
+pub struct Multisig {
+    pub threshold: usize,
+    pub public_keys: Vec<String>,
+    #[serde(default)]
+    pub signatures: Vec<KeySignature>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash)]
+pub struct KeySignature {
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl Multisig {
+    /// Keys are sorted so the same set of keys always gives the same address.
+    pub fn new(threshold: usize, mut public_keys: Vec<String>) -> Result<Self> {
+        public_keys.sort();
+        let multisig = Self {
+            threshold,
+            public_keys,
+            signatures: vec![],
+        };
+        multisig.validate_policy()?;
+        Ok(multisig)
+    }
+
+    fn validate_policy(&self) -> Result<()> {
+        let keys = self.public_keys.len();
+        if self.threshold == 0 || self.threshold > keys || keys > MAX_MULTISIG_KEYS {
+            return Err(Error::InvalidMultisigPolicy(format!(
+                "{}-of-{keys} is not allowed",
+                self.threshold
+            )));
+        }
+        if !self.public_keys.is_sorted() || self.public_keys.windows(2).any(|w| w[0] == w[1]) {
+            return Err(Error::InvalidMultisigPolicy(
+                "public keys must be sorted and distinct".into(),
+            ));
+        }
+        for public_key in &self.public_keys {
+            crypto::parse_public_key(public_key)?;
+        }
+        Ok(())
+    }
+
+    pub fn address(&self) -> String {
+        let mut address = crypto::sha256_hex(format!(
+            "multisig:{}:{}",
+            self.threshold,
+            self.public_keys.join(",")
+        ));
+        address.truncate(crypto::ADDRESS_LEN);
+        address
+    }
+
+    /// Number of policy keys with a valid signature of `payload`.
+    fn valid_signatures(&self, payload: &[u8]) -> Result<usize> {
+        let mut signed: Vec<&str> = vec![];
+        for entry in &self.signatures {
+            if !self.public_keys.contains(&entry.public_key)
+                || signed.contains(&entry.public_key.as_str())
+            {
+                continue;
+            }
+            let public_key = crypto::parse_public_key(&entry.public_key)?;
+            if crypto::verify(&public_key, payload, &entry.signature) {
+                signed.push(&entry.public_key);
+            }
+        }
+        Ok(signed.len())
+    }
+}
+
+impl Transaction {
+    /// Hash identifying the transaction, including its signatures.
+    pub fn id(&self) -> String {
+        crypto::sha256_hex(serde_json::to_vec(self).expect("transaction is always serializable"))
+    }
+
+    fn signing_payload(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            &self.from,
+            &self.to,
+            self.amount,
+            self.nonce,
+            self.lock_until,
+        ))
+        .expect("transaction payload is always serializable")
+    }
+
+    /// Whether the transaction can be included in a block with given index and timestamp.
+    pub fn is_final(&self, height: u64, timestamp: u64) -> bool {
+        match self.lock_until {
+            None => true,
+            Some(LockTime::Height(h)) => height >= h,
+            Some(LockTime::Timestamp(t)) => timestamp >= t,
+        }
+    }
+
+    /// Amounts the transaction adds to the balances of its addresses. Paying
+    /// oneself only spends, like in [`crate::blockchain::Blockchain::get_balance`].
+    pub fn balance_changes(&self) -> impl Iterator<Item = (&str, i64)> {
+        let received = (self.to != self.from).then_some((self.to.as_str(), self.amount));
+        std::iter::once((self.from.as_str(), self.amount.saturating_neg())).chain(received)
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.public_key.is_some()
+            || self.signature.is_some()
+            || self.multisig.is_some()
+            || self.script.is_some()
+    }
+
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.public_key = Some(hex::encode(key.verifying_key().as_bytes()));
+        self.signature = Some(crypto::sign(key, &self.signing_payload()));
+    }
+
+    /// Adds signature of one of the multisig co-signers.
+    pub fn sign_multisig(&mut self, key: &SigningKey) -> Result<()> {
+        let payload = self.signing_payload();
+        let public_key = hex::encode(key.verifying_key().as_bytes());
+        let multisig = self
+            .multisig
+            .as_mut()
+            .ok_or_else(|| Error::InvalidMultisigPolicy("transaction is not multisig".into()))?;
+        if !multisig.public_keys.contains(&public_key) {
+            return Err(Error::KeyNotInMultisig(public_key));
+        }
+        multisig.signatures.retain(|s| s.public_key != public_key);
+        multisig.signatures.push(KeySignature {
+            signature: crypto::sign(key, &payload),
+            public_key,
+        });
+        Ok(())
+    }
+
+    /// Whether `signature` is a valid signature of the transaction by a key of
+    /// its multisig policy.
+    pub fn is_valid_multisig_signature(&self, signature: &KeySignature) -> bool {
+        let Some(multisig) = &self.multisig else {
+            return false;
+        };
+        multisig.public_keys.contains(&signature.public_key)
+            && crypto::parse_public_key(&signature.public_key).is_ok_and(|public_key| {
+                crypto::verify(&public_key, &self.signing_payload(), &signature.signature)
+            })
+    }
+
+    /// Verifies the transaction without the context of a block, time
+    /// dependent script conditions are considered satisfied.
+    pub fn verify(&self) -> Result<()> {
+        self.verify_at(u64::MAX, u64::MAX)
+    }
+
+    /// Amounts must be positive. Unsigned transactions are only accepted from
+    /// plain name addresses, see [`crypto::is_derived_address`]. Signed ones
+    /// must be signed by the key the `from` address is derived from, multisig
+    /// ones by at least threshold distinct keys of the policy `from` is
+    /// derived from and script ones must satisfy the locking script of `from`
+    /// when included in a block with given index and timestamp.
+    pub fn verify_at(&self, height: u64, timestamp: u64) -> Result<()> {
+        if self.amount <= 0 {
+            return Err(Error::InvalidAmount(self.from.clone(), self.amount));
+        }
+        if !self.is_signed() {
+            if crypto::is_derived_address(&self.from) {
+                return Err(Error::TransactionNotSigned(self.from.clone()));
+            }
+            return Ok(());
+        }
+        let invalid = || Error::InvalidTransactionSignature(self.from.clone());
+        let single = self.public_key.is_some() || self.signature.is_some();
+        let kinds = [single, self.multisig.is_some(), self.script.is_some()];
+        if kinds.into_iter().filter(|k| *k).count() > 1 {
+            return Err(invalid());
+        }
+        if let Some(spend) = &self.script {
+            if spend.locking.address() != self.from {
+                return Err(invalid());
+            }
+            let ctx = ScriptContext {
+                payload: &self.signing_payload(),
+                height,
+                timestamp,
+            };
+            return script::evaluate(&spend.unlocking, &spend.locking, &ctx)
+                .map_err(|e| Error::ScriptFailed(self.from.clone(), e));
+        }
+        if let Some(multisig) = &self.multisig {
+            multisig.validate_policy()?;
+            if multisig.address() != self.from {
+                return Err(invalid());
+            }
+            let valid = multisig.valid_signatures(&self.signing_payload())?;
+            if valid < multisig.threshold {
+                return Err(Error::InsufficientSignatures(
+                    self.from.clone(),
+                    valid,
+                    multisig.threshold,
+                ));
+            }
+            return Ok(());
+        }
+        let (Some(public_key), Some(signature)) = (&self.public_key, &self.signature) else {
+            return Err(invalid());
+        };
+        let public_key = crypto::parse_public_key(public_key)?;
+        if crypto::address_from_public_key(&public_key) != self.from
+            || !crypto::verify(&public_key, &self.signing_payload(), signature)
+        {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+
+    /// Signature of the payload the transaction is authorized with, for use
+    /// in unlocking scripts.
+    pub fn script_signature(&self, key: &SigningKey) -> Vec<u8> {
+        hex::decode(crypto::sign(key, &self.signing_payload())).expect("signature is hex")
+    }
+}
+
+/// Block without its transactions.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub index: u64,
+    pub timestamp: u64,
+    pub previous_hash: String,
+    pub hash: String,
+    pub nonce: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -23,10 +312,15 @@ pub struct Block {
 
 impl Block {
     #[instrument(name = "create_new_block", level = "debug")]
-    pub fn new(index: u64, previous_hash: String, transactions: Vec<Transaction>) -> Self {
+    pub fn new(
+        index: u64,
+        previous_hash: String,
+        transactions: Vec<Transaction>,
+        clock: &dyn Clock,
+    ) -> Self {
         let mut block = Self {
             index,
-            timestamp: chrono::Utc::now().timestamp() as u64,
+            timestamp: clock.now(),
             previous_hash,
             hash: String::new(),
             transactions,
@@ -36,7 +330,38 @@ impl Block {
         block
     }
 
-    fn compute_hash(&self) -> String {
+    /// First block of every chain mined with `difficulty`. Mined once per
+    /// difficulty, chains are validated against it.
+    pub fn genesis(difficulty: usize) -> Result<Self> {
+        static MINED: Mutex<BTreeMap<usize, Block>> = Mutex::new(BTreeMap::new());
+        let mut mined = MINED.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(block) = mined.get(&difficulty) {
+            return Ok(block.clone());
+        }
+        let mut block = Self {
+            index: 0,
+            timestamp: GENESIS_TIMESTAMP,
+            previous_hash: "0".to_string(),
+            hash: String::new(),
+            transactions: vec![],
+            nonce: 0,
+        };
+        block.mine_block(difficulty)?;
+        mined.insert(difficulty, block.clone());
+        Ok(block)
+    }
+
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            timestamp: self.timestamp,
+            previous_hash: self.previous_hash.clone(),
+            hash: self.hash.clone(),
+            nonce: self.nonce,
+        }
+    }
+
+    pub(crate) fn compute_hash(&self) -> String {
         let data = format!(
             "{}{}{}{:?}{}",
             self.index, self.timestamp, self.previous_hash, self.transactions, self.nonce
@@ -60,18 +385,99 @@ impl Block {
 
     #[instrument(level = "debug", name = "validate_block")]
     pub fn validate(&self, previous_hash: &str, difficulty: usize) -> Result<()> {
+        let invalid = |rule| Error::InvalidBlock(self.index, rule);
         if self.previous_hash != previous_hash {
-            Err(Error::BlockHasInvalidPreviusBlockHash(
-                self.index,
-                self.hash.to_owned(),
+            Err(invalid(ValidationError::InvalidPreviousHash(
+                self.previous_hash.to_owned(),
                 previous_hash.to_owned(),
-            ))?;
+            )))?;
+        }
+        self.validate_proof_of_work(difficulty)?;
+        self.validate_structure()?;
+        self.validate_transactions()
+    }
+
+    /// Checks the hash matches the block contents and satisfies `difficulty`,
+    /// which is cheap enough to do before anything else.
+    pub fn validate_proof_of_work(&self, difficulty: usize) -> Result<()> {
+        let invalid = |rule| Error::InvalidBlock(self.index, rule);
+        if self.hash.len() != 64 || !self.hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            Err(invalid(ValidationError::MalformedHash(self.hash.clone())))?;
         }
-        if self.hash[..difficulty] != "0".repeat(difficulty) {
-            Err(Error::UnsatisfiedHashDifficulty(self.index, difficulty))?;
+        if difficulty > self.hash.len() || !self.hash.bytes().take(difficulty).all(|b| b == b'0') {
+            Err(invalid(ValidationError::UnsatisfiedDifficulty(difficulty)))?;
         }
         if self.hash != self.compute_hash() {
-            Err(Error::BlockHasInvalidHash(self.index, self.hash.to_owned()))?;
+            Err(invalid(ValidationError::InvalidHash(self.hash.to_owned())))?;
+        }
+        Ok(())
+    }
+
+    /// Checks the block fits into the transaction count and size limits.
+    pub fn validate_structure(&self) -> Result<()> {
+        let invalid = |rule| Error::InvalidBlock(self.index, rule);
+        if self.transactions.len() > MAX_TRANSACTIONS_PER_BLOCK {
+            Err(invalid(ValidationError::TooManyTransactions(
+                self.transactions.len(),
+                MAX_TRANSACTIONS_PER_BLOCK,
+            )))?;
+        }
+        let size = serde_json::to_vec(self)?.len();
+        if size > MAX_BLOCK_SIZE {
+            Err(invalid(ValidationError::BlockTooLarge(
+                size,
+                MAX_BLOCK_SIZE,
+            )))?;
+        }
+        Ok(())
+    }
+
+    /// Median timestamp of the last [`MEDIAN_TIME_SPAN`] blocks.
+    pub fn median_time_past(blocks: &[Block]) -> u64 {
+        let start = blocks.len().saturating_sub(MEDIAN_TIME_SPAN);
+        let mut timestamps: Vec<u64> = blocks[start..].iter().map(|b| b.timestamp).collect();
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+    }
+
+    /// Checks the block timestamp against the blocks preceding it and the
+    /// local clock `now`.
+    #[instrument(skip(self, previous), fields(index = self.index), level = "debug")]
+    pub fn validate_timestamp(&self, previous: &[Block], now: u64) -> Result<()> {
+        let invalid = |rule| Error::InvalidBlock(self.index, rule);
+        if self.timestamp < MIN_BLOCK_TIMESTAMP {
+            Err(invalid(ValidationError::TimestampTooEarly(self.timestamp)))?;
+        }
+        let max_timestamp = now + MAX_FUTURE_DRIFT;
+        if self.timestamp > max_timestamp {
+            Err(invalid(ValidationError::TimestampTooFarInFuture(
+                self.timestamp,
+                max_timestamp,
+            )))?;
+        }
+        let median = Self::median_time_past(previous);
+        if self.timestamp < median {
+            Err(invalid(ValidationError::TimestampBeforeMedianTimePast(
+                self.timestamp,
+                median,
+            )))?;
+        }
+        Ok(())
+    }
+
+    /// Checks transactions are authorized and unlocked at the block height and time.
+    pub fn validate_transactions(&self) -> Result<()> {
+        let invalid = |rule| Error::InvalidBlock(self.index, rule);
+        for (position, tx) in self.transactions.iter().enumerate() {
+            if let Err(e) = tx.verify_at(self.index, self.timestamp) {
+                Err(invalid(ValidationError::InvalidTransaction(
+                    position,
+                    Box::new(e),
+                )))?;
+            }
+            if !tx.is_final(self.index, self.timestamp) {
+                Err(invalid(ValidationError::LockedTransaction(tx.from.clone())))?;
+            }
         }
         Ok(())
     }
@@ -95,7 +501,11 @@ impl fmt::Display for Block {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::errors::Error;
+    use crate::clock::ManualClock;
+    use crate::errors::{Error, ValidationError};
+    use crate::test_utils::signed_transaction;
+
+    static CLOCK: ManualClock = ManualClock::new(MIN_BLOCK_TIMESTAMP + 1_000_000);
 
     #[test]
     fn test_new_block_initialization() {
@@ -106,7 +516,9 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }],
+            &CLOCK,
         );
         assert_eq!(block.index, 1);
         assert_eq!(block.previous_hash, "0000");
@@ -116,11 +528,25 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }]
         );
         assert!(!block.hash.is_empty());
     }
 
+    #[test]
+    fn test_blocks_created_at_same_time_are_identical() {
+        let clock = ManualClock::new(MIN_BLOCK_TIMESTAMP);
+        let first = Block::new(1, "0000".to_string(), vec![], &clock);
+        let second = Block::new(1, "0000".to_string(), vec![], &clock);
+        assert_eq!(first, second);
+        assert_eq!(first.timestamp, MIN_BLOCK_TIMESTAMP);
+
+        clock.advance(1);
+        let later = Block::new(1, "0000".to_string(), vec![], &clock);
+        assert_ne!(first.hash, later.hash);
+    }
+
     #[test]
     fn test_compute_hash_changes_with_nonce() {
         let mut block = Block::new(
@@ -130,7 +556,9 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }],
+            &CLOCK,
         );
         let original_hash = block.hash.clone();
         block.nonce += 1;
@@ -147,7 +575,9 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }],
+            &CLOCK,
         );
         let difficulty = 2;
         block.mine_block(difficulty).unwrap();
@@ -164,7 +594,9 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }],
+            &CLOCK,
         );
         block.mine_block(difficulty).unwrap();
 
@@ -182,15 +614,17 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }],
+            &CLOCK,
         );
         block.mine_block(difficulty).unwrap();
         block.hash = "0001234_fake_hash".to_string();
 
         let result = block.validate("0000", difficulty);
         match result {
-            Err(Error::BlockHasInvalidHash(1, _)) => {}
-            v => panic!("Expected error BlockHasInvalidHash, actual {v:?}"),
+            Err(Error::InvalidBlock(1, ValidationError::MalformedHash(_))) => {}
+            v => panic!("Expected error MalformedHash, actual {v:?}"),
         }
     }
 
@@ -203,14 +637,16 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }],
+            &CLOCK,
         );
         block.mine_block(1).unwrap();
 
         let result = block.validate("0000", 3);
         match result {
-            Err(Error::UnsatisfiedHashDifficulty(1, _)) => {}
-            v => panic!("Expected error UnsatisfiedHashDifficulty, actual {v:?}"),
+            Err(Error::InvalidBlock(1, ValidationError::UnsatisfiedDifficulty(_))) => {}
+            v => panic!("Expected error UnsatisfiedDifficulty, actual {v:?}"),
         }
     }
 
@@ -224,16 +660,483 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }],
+            &CLOCK,
         );
         block.mine_block(difficulty).unwrap();
 
         let result = block.validate("0000", difficulty);
         match result {
-            Err(Error::BlockHasInvalidPreviusBlockHash(1, _, prev)) => {
+            Err(Error::InvalidBlock(1, ValidationError::InvalidPreviousHash(_, prev))) => {
                 assert_eq!(prev, "0000");
             }
-            v => panic!("Expected error BlockHasInvalidPreviusBlockHash, actual {v:?}"),
+            v => panic!("Expected error InvalidPreviousHash, actual {v:?}"),
+        }
+    }
+
+    #[test]
+    fn test_validate_malformed_hash_does_not_panic() {
+        let mut block = Block::new(1, "0000".to_string(), vec![], &CLOCK);
+        block.mine_block(1).unwrap();
+
+        for hash in ["", "zz", &"g".repeat(64)] {
+            block.hash = hash.to_string();
+            match block.validate("0000", 1) {
+                Err(Error::InvalidBlock(1, ValidationError::MalformedHash(h))) => {
+                    assert_eq!(h, hash)
+                }
+                v => panic!("Expected error MalformedHash, actual {v:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate_difficulty_above_hash_length() {
+        let mut block = Block::new(1, "0000".to_string(), vec![], &CLOCK);
+        block.mine_block(1).unwrap();
+
+        assert!(matches!(
+            block.validate("0000", 65),
+            Err(Error::InvalidBlock(
+                1,
+                ValidationError::UnsatisfiedDifficulty(65)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_validate_well_formed_wrong_hash() {
+        let mut block = Block::new(1, "0000".to_string(), vec![], &CLOCK);
+        block.mine_block(1).unwrap();
+        block.hash = format!("0{}", "f".repeat(63));
+
+        assert!(matches!(
+            block.validate("0000", 1),
+            Err(Error::InvalidBlock(1, ValidationError::InvalidHash(_)))
+        ));
+    }
+
+    #[test]
+    fn test_validate_too_many_transactions() {
+        let transactions = vec![Transaction::default(); MAX_TRANSACTIONS_PER_BLOCK + 1];
+        let block = Block::new(1, "0000".to_string(), transactions, &CLOCK);
+
+        assert!(matches!(
+            block.validate_structure(),
+            Err(Error::InvalidBlock(
+                1,
+                ValidationError::TooManyTransactions(1001, MAX_TRANSACTIONS_PER_BLOCK)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_validate_block_too_large() {
+        let transaction = Transaction {
+            from: "A".repeat(MAX_BLOCK_SIZE),
+            ..Default::default()
+        };
+        let mut block = Block::new(1, "0000".to_string(), vec![transaction], &CLOCK);
+        block.mine_block(1).unwrap();
+
+        assert!(matches!(
+            block.validate("0000", 1),
+            Err(Error::InvalidBlock(
+                1,
+                ValidationError::BlockTooLarge(_, MAX_BLOCK_SIZE)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_signed_transaction_verifies() {
+        let key = crypto::generate_signing_key();
+        let tx = signed_transaction(&key, 10, 0);
+        assert!(tx.verify().is_ok());
+    }
+
+    #[test]
+    fn test_tampered_transaction_fails_verification() {
+        let key = crypto::generate_signing_key();
+        let mut tx = signed_transaction(&key, 10, 0);
+        tx.amount = 1000;
+        assert!(matches!(
+            tx.verify(),
+            Err(Error::InvalidTransactionSignature(_))
+        ));
+    }
+
+    #[test]
+    fn test_transaction_signed_by_foreign_key_fails_verification() {
+        let key = crypto::generate_signing_key();
+        let mut tx = signed_transaction(&key, 10, 0);
+        tx.from = "A".to_string();
+        tx.sign(&key);
+        assert!(matches!(
+            tx.verify(),
+            Err(Error::InvalidTransactionSignature(_))
+        ));
+    }
+
+    #[test]
+    fn test_unsigned_transaction_from_derived_address_fails_verification() {
+        let key = crypto::generate_signing_key();
+        let mut tx = signed_transaction(&key, 10, 0);
+        tx.public_key = None;
+        tx.signature = None;
+        assert!(matches!(
+            tx.verify(),
+            Err(Error::TransactionNotSigned(from)) if from == tx.from
+        ));
+
+        tx.from = "A".to_string();
+        assert!(tx.verify().is_ok());
+    }
+
+    #[test]
+    fn test_non_positive_amount_fails_verification() {
+        let key = crypto::generate_signing_key();
+        for amount in [0, -10, i64::MIN] {
+            let tx = signed_transaction(&key, amount, 0);
+            assert!(matches!(
+                tx.verify(),
+                Err(Error::InvalidAmount(_, a)) if a == amount
+            ));
+        }
+    }
+
+    #[test]
+    fn test_validate_block_with_invalid_signature() {
+        let key = crypto::generate_signing_key();
+        let mut tx = signed_transaction(&key, 10, 0);
+        tx.signature = None;
+        let mut block = Block::new(1, "0000".to_string(), vec![tx], &CLOCK);
+        block.mine_block(1).unwrap();
+
+        let result = block.validate("0000", 1);
+        assert!(matches!(
+            result,
+            Err(Error::InvalidBlock(1, ValidationError::InvalidTransaction(0, e)))
+                if matches!(*e, Error::InvalidTransactionSignature(_))
+        ));
+    }
+
+    fn multisig_transaction(keys: &[SigningKey], threshold: usize) -> Transaction {
+        let multisig = Multisig::new(
+            threshold,
+            keys.iter()
+                .map(|k| hex::encode(k.verifying_key().as_bytes()))
+                .collect(),
+        )
+        .unwrap();
+        Transaction {
+            from: multisig.address(),
+            to: "B".to_string(),
+            amount: 10,
+            multisig: Some(multisig),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_multisig_policy_limits() {
+        let key = hex::encode(crypto::generate_signing_key().verifying_key().as_bytes());
+        assert!(Multisig::new(1, vec![key.clone()]).is_ok());
+        assert!(matches!(
+            Multisig::new(0, vec![key.clone()]),
+            Err(Error::InvalidMultisigPolicy(_))
+        ));
+        assert!(matches!(
+            Multisig::new(2, vec![key.clone()]),
+            Err(Error::InvalidMultisigPolicy(_))
+        ));
+        assert!(matches!(
+            Multisig::new(1, vec![key.clone(), key]),
+            Err(Error::InvalidMultisigPolicy(_))
+        ));
+    }
+
+    #[test]
+    fn test_multisig_address_ignores_key_order() {
+        let keys: Vec<_> = (0..3)
+            .map(|_| hex::encode(crypto::generate_signing_key().verifying_key().as_bytes()))
+            .collect();
+        let mut reversed = keys.clone();
+        reversed.reverse();
+        assert_eq!(
+            Multisig::new(2, keys.clone()).unwrap().address(),
+            Multisig::new(2, reversed).unwrap().address()
+        );
+        assert_ne!(
+            Multisig::new(2, keys.clone()).unwrap().address(),
+            Multisig::new(3, keys).unwrap().address()
+        );
+    }
+
+    #[test]
+    fn test_multisig_transaction_requires_threshold_signatures() {
+        let keys: Vec<_> = (0..3).map(|_| crypto::generate_signing_key()).collect();
+        let mut tx = multisig_transaction(&keys, 2);
+
+        tx.sign_multisig(&keys[0]).unwrap();
+        assert!(matches!(
+            tx.verify(),
+            Err(Error::InsufficientSignatures(_, 1, 2))
+        ));
+
+        tx.sign_multisig(&keys[0]).unwrap();
+        assert!(matches!(
+            tx.verify(),
+            Err(Error::InsufficientSignatures(_, 1, 2))
+        ));
+
+        tx.sign_multisig(&keys[2]).unwrap();
+        assert!(tx.verify().is_ok());
+    }
+
+    #[test]
+    fn test_multisig_duplicate_signatures_are_counted_once() {
+        let keys: Vec<_> = (0..2).map(|_| crypto::generate_signing_key()).collect();
+        let mut tx = multisig_transaction(&keys, 2);
+        tx.sign_multisig(&keys[0]).unwrap();
+        let duplicate = tx.multisig.as_ref().unwrap().signatures[0].clone();
+        tx.multisig.as_mut().unwrap().signatures.push(duplicate);
+
+        assert!(matches!(
+            tx.verify(),
+            Err(Error::InsufficientSignatures(_, 1, 2))
+        ));
+    }
+
+    #[test]
+    fn test_multisig_rejects_foreign_signers_and_policies() {
+        let keys: Vec<_> = (0..2).map(|_| crypto::generate_signing_key()).collect();
+        let mut tx = multisig_transaction(&keys, 1);
+        let outsider = crypto::generate_signing_key();
+        assert!(matches!(
+            tx.sign_multisig(&outsider),
+            Err(Error::KeyNotInMultisig(_))
+        ));
+
+        tx.sign_multisig(&keys[1]).unwrap();
+        assert!(tx.verify().is_ok());
+        tx.multisig.as_mut().unwrap().threshold = 2;
+        assert!(matches!(
+            tx.verify(),
+            Err(Error::InvalidTransactionSignature(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_block_with_unsigned_multisig_transaction() {
+        let keys: Vec<_> = (0..3).map(|_| crypto::generate_signing_key()).collect();
+        let mut tx = multisig_transaction(&keys, 2);
+        tx.sign_multisig(&keys[1]).unwrap();
+        let mut block = Block::new(1, "0000".to_string(), vec![tx], &CLOCK);
+        block.mine_block(1).unwrap();
+
+        assert!(matches!(
+            block.validate("0000", 1),
+            Err(Error::InvalidBlock(1, ValidationError::InvalidTransaction(0, e)))
+                if matches!(*e, Error::InsufficientSignatures(..))
+        ));
+    }
+
+    #[test]
+    fn test_transaction_lock_time_finality() {
+        let mut tx = Transaction {
+            lock_until: Some(LockTime::Height(5)),
+            ..Default::default()
+        };
+        assert!(!tx.is_final(4, u64::MAX));
+        assert!(tx.is_final(5, 0));
+
+        tx.lock_until = Some(LockTime::Timestamp(1_000));
+        assert!(!tx.is_final(u64::MAX, 999));
+        assert!(tx.is_final(0, 1_000));
+    }
+
+    #[test]
+    fn test_lock_time_is_signed() {
+        let key = crypto::generate_signing_key();
+        let mut tx = signed_transaction(&key, 10, 0);
+        tx.lock_until = Some(LockTime::Height(1));
+        tx.sign(&key);
+        assert!(tx.verify().is_ok());
+
+        tx.lock_until = None;
+        assert!(matches!(
+            tx.verify(),
+            Err(Error::InvalidTransactionSignature(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_block_with_locked_transaction() {
+        let tx = Transaction {
+            from: "A".to_string(),
+            to: "B".to_string(),
+            amount: 100,
+            lock_until: Some(LockTime::Height(2)),
+            ..Default::default()
+        };
+        let mut block = Block::new(1, "0000".to_string(), vec![tx.clone()], &CLOCK);
+        block.mine_block(1).unwrap();
+        assert!(matches!(
+            block.validate("0000", 1),
+            Err(Error::InvalidBlock(
+                1,
+                ValidationError::LockedTransaction(_)
+            ))
+        ));
+
+        let mut block = Block::new(2, "0000".to_string(), vec![tx], &CLOCK);
+        block.mine_block(1).unwrap();
+        assert!(block.validate("0000", 1).is_ok());
+    }
+
+    fn script_transaction(locking: &str) -> Transaction {
+        let locking = Script::from_asm(locking).unwrap();
+        Transaction {
+            from: locking.address(),
+            to: "B".to_string(),
+            amount: 10,
+            script: Some(ScriptSpend {
+                locking,
+                unlocking: Script::default(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_script_transaction_with_signature() {
+        let key = crypto::generate_signing_key();
+        let mut tx = script_transaction(&format!(
+            "0x{} OP_CHECKSIG",
+            hex::encode(key.verifying_key().as_bytes())
+        ));
+        assert!(matches!(
+            tx.verify(),
+            Err(Error::ScriptFailed(
+                _,
+                script::ScriptError::StackUnderflow(_)
+            ))
+        ));
+
+        let signature = tx.script_signature(&key);
+        tx.script.as_mut().unwrap().unlocking = Script::default().push_data(&signature).unwrap();
+        assert!(tx.verify().is_ok());
+
+        tx.amount = 1000;
+        assert!(matches!(
+            tx.verify(),
+            Err(Error::ScriptFailed(
+                _,
+                script::ScriptError::EvaluatedToFalse
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_script_transaction_must_spend_from_script_address() {
+        let mut tx = script_transaction("OP_TRUE");
+        assert!(tx.verify().is_ok());
+
+        tx.from = "A".to_string();
+        assert!(matches!(
+            tx.verify(),
+            Err(Error::InvalidTransactionSignature(_))
+        ));
+
+        let key = crypto::generate_signing_key();
+        let mut tx = script_transaction("OP_TRUE");
+        tx.public_key = Some(hex::encode(key.verifying_key().as_bytes()));
+        assert!(matches!(
+            tx.verify(),
+            Err(Error::InvalidTransactionSignature(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_block_evaluates_scripts_at_block_height() {
+        let tx = script_transaction("2 OP_CHECKLOCKTIMEVERIFY");
+        assert!(tx.verify().is_ok());
+
+        let mut block = Block::new(1, "0000".to_string(), vec![tx.clone()], &CLOCK);
+        block.mine_block(1).unwrap();
+        assert!(matches!(
+            block.validate("0000", 1),
+            Err(Error::InvalidBlock(1, ValidationError::InvalidTransaction(0, e)))
+                if matches!(*e, Error::ScriptFailed(_, script::ScriptError::LockTimeNotReached(2)))
+        ));
+
+        let mut block = Block::new(2, "0000".to_string(), vec![tx], &CLOCK);
+        block.mine_block(1).unwrap();
+        assert!(block.validate("0000", 1).is_ok());
+    }
+
+    fn block_at(index: u64, timestamp: u64) -> Block {
+        let mut block = Block::new(index, "0000".to_string(), vec![], &CLOCK);
+        block.timestamp = timestamp;
+        block
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        assert_eq!(Block::median_time_past(&[]), 0);
+        let blocks: Vec<_> = [5, 1, 4, 2, 3]
+            .into_iter()
+            .enumerate()
+            .map(|(i, t)| block_at(i as u64, t))
+            .collect();
+        assert_eq!(Block::median_time_past(&blocks), 3);
+
+        // Only the last MEDIAN_TIME_SPAN blocks count
+        let blocks: Vec<_> = (0..20).map(|t| block_at(t, t * 10)).collect();
+        assert_eq!(Block::median_time_past(&blocks), 140);
+    }
+
+    #[test]
+    fn test_validate_timestamp() {
+        let now = MIN_BLOCK_TIMESTAMP + 1_000_000;
+        let previous: Vec<_> = (0..3).map(|i| block_at(i, now - 100 + i * 10)).collect();
+
+        assert!(block_at(3, now).validate_timestamp(&previous, now).is_ok());
+        assert!(
+            block_at(3, now + MAX_FUTURE_DRIFT)
+                .validate_timestamp(&previous, now)
+                .is_ok()
+        );
+        // Slightly out of order timestamps are fine as long as they follow the median
+        assert!(
+            block_at(3, now - 90)
+                .validate_timestamp(&previous, now)
+                .is_ok()
+        );
+
+        match block_at(3, now - 91).validate_timestamp(&previous, now) {
+            Err(Error::InvalidBlock(
+                3,
+                ValidationError::TimestampBeforeMedianTimePast(_, median),
+            )) => {
+                assert_eq!(median, now - 90)
+            }
+            v => panic!("Expected error TimestampBeforeMedianTimePast, actual {v:?}"),
+        }
+        match block_at(3, now + MAX_FUTURE_DRIFT + 1).validate_timestamp(&previous, now) {
+            Err(Error::InvalidBlock(3, ValidationError::TimestampTooFarInFuture(_, limit))) => {
+                assert_eq!(limit, now + MAX_FUTURE_DRIFT)
+            }
+            v => panic!("Expected error TimestampTooFarInFuture, actual {v:?}"),
         }
+        assert!(matches!(
+            block_at(3, 0).validate_timestamp(&[], now),
+            Err(Error::InvalidBlock(
+                3,
+                ValidationError::TimestampTooEarly(0)
+            ))
+        ));
     }
 }
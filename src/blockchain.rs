@@ -1,64 +1,305 @@
+use crate::address_index::AddressIndex;
 use crate::block::{Block, Transaction};
-use crate::errors::{Error, Result};
+use crate::clock::{self, SharedClock};
+use crate::crypto;
+use crate::errors::{Error, Result, ValidationError};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
 use tracing::{error, instrument};
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+/// Chain of blocks. Clones share the blocks and the index until either one
+/// changes, which makes snapshots of the chain cheap.
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Blockchain {
-    chain: Vec<Block>,
+    chain: Arc<Vec<Block>>,
     difficulty: usize,
+    #[serde(skip, default = "clock::system")]
+    clock: SharedClock,
+    /// Index of the transactions of the chain's addresses. Chains received
+    /// from peers are indexed when adopted.
+    #[serde(skip)]
+    index: Arc<AddressIndex>,
+}
+
+impl PartialEq for Blockchain {
+    fn eq(&self, other: &Self) -> bool {
+        self.chain == other.chain && self.difficulty == other.difficulty
+    }
 }
 
 impl Blockchain {
-    #[instrument(name = "create_new_blockchain", level = "debug")]
+    #[allow(unused)]
     pub fn new(difficulty: usize) -> Result<Self> {
-        let mut genesis_block = Block::new(0, "0".to_string(), vec![]);
-        genesis_block.mine_block(difficulty)?;
+        Self::with_clock(difficulty, clock::system())
+    }
+
+    /// Creates a chain whose blocks are stamped and validated with `clock`.
+    #[instrument(name = "create_new_blockchain", level = "debug")]
+    pub fn with_clock(difficulty: usize, clock: SharedClock) -> Result<Self> {
+        let genesis = Block::genesis(difficulty)?;
+        let mut index = AddressIndex::default();
+        index.add_block(&genesis)?;
         Ok(Self {
-            chain: vec![genesis_block],
+            chain: Arc::new(vec![genesis]),
             difficulty,
+            clock,
+            index: Arc::new(index),
         })
     }
 
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    pub fn difficulty(&self) -> usize {
+        self.difficulty
+    }
+
+    pub fn genesis_hash(&self) -> &str {
+        &self.chain[0].hash
+    }
+
     pub fn blocks(&self) -> &[Block] {
         &self.chain[..]
     }
 
+    /// Index of the chain tip.
+    pub fn height(&self) -> u64 {
+        self.chain.len() as u64 - 1
+    }
+
+    /// Expected number of hashes tried to mine the chain, `16^difficulty`
+    /// for each block.
+    pub fn cumulative_work(&self) -> u128 {
+        let per_block = 16u128.saturating_pow(self.difficulty as u32);
+        per_block.saturating_mul(self.chain.len() as u128)
+    }
+
+    /// Position of the block with given hash in the chain.
+    pub fn position(&self, hash: &str) -> Option<usize> {
+        self.chain.iter().rposition(|b| b.hash == hash)
+    }
+
+    /// Chain of other blocks sharing this chain's difficulty and clock.
+    pub fn with_blocks(&self, blocks: Vec<Block>) -> Self {
+        Self {
+            chain: Arc::new(blocks),
+            difficulty: self.difficulty,
+            clock: self.clock.clone(),
+            index: Arc::default(),
+        }
+    }
+
+    /// Whether `other` is a clone of this chain neither was changed since.
+    pub fn is_unchanged_clone_of(&self, other: &Blockchain) -> bool {
+        Arc::ptr_eq(&self.chain, &other.chain) && self.difficulty == other.difficulty
+    }
+
+    fn chain_mut(&mut self) -> &mut Vec<Block> {
+        Arc::make_mut(&mut self.chain)
+    }
+
+    pub fn index(&self) -> &AddressIndex {
+        &self.index
+    }
+
     pub fn get_balance(&self, address: &str) -> i64 {
-        self.chain
+        self.index.balance(address)
+    }
+
+    /// Nonce the next signed transaction from `address` must carry.
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        self.index.next_nonce(address)
+    }
+
+    /// Verifies signatures and checks that signed transactions continue the
+    /// nonce sequence of their senders. Derived addresses can't spend more
+    /// than their balance on the chain, transactions paying them are not
+    /// spendable until they are in a block. No balance may overflow.
+    pub fn check_transactions(&self, transactions: &[Transaction]) -> Result<()> {
+        let mut ledger = Ledger::new(self);
+        for tx in transactions {
+            tx.verify()?;
+            ledger.check(tx)?;
+            ledger.record(tx, |_| true);
+        }
+        Ok(())
+    }
+
+    /// Checks `transaction` like [`Blockchain::check_transactions`] would
+    /// following the `pending` ones, which were checked already and are only
+    /// looked at for the addresses of `transaction`.
+    pub fn check_pending_transaction(
+        &self,
+        pending: &[Transaction],
+        transaction: &Transaction,
+    ) -> Result<()> {
+        transaction.verify()?;
+        let mut ledger = Ledger::new(self);
+        let involved = |address: &str| address == transaction.from || address == transaction.to;
+        for tx in pending {
+            ledger.record(tx, involved);
+        }
+        ledger.check(transaction)
+    }
+
+    /// Whether each of the transactions can follow the valid ones before it
+    /// on top of the chain, like [`Blockchain::check_transactions`] without
+    /// verifying them again, e.g. those of the mempool after the chain
+    /// changed.
+    pub fn valid_in_order(&self, transactions: &[Transaction]) -> Vec<bool> {
+        let mut ledger = Ledger::new(self);
+        transactions
             .iter()
-            .flat_map(|b| b.transactions.iter())
-            .filter(|t| t.from == address || t.to == address)
-            .fold(0i64, |acc, t| {
-                if t.from == address {
-                    acc - t.amount
-                } else {
-                    acc + t.amount
+            .map(|tx| {
+                let valid = ledger.check(tx).is_ok();
+                if valid {
+                    ledger.record(tx, |_| true);
                 }
+                valid
             })
+            .collect()
     }
 
     #[instrument(skip(self), level = "debug", name = "add_block_to_blockchain")]
     pub fn add_block(&mut self, transactions: Vec<Transaction>) -> Result<&Block> {
+        self.check_transactions(&transactions)?;
         let latest_block = self.chain.last().ok_or(Error::ChainIsEmpty)?;
         let mut new_block = Block::new(
             latest_block.index + 1,
             latest_block.hash.clone(),
             transactions,
+            self.clock.as_ref(),
         );
+        new_block.validate_structure()?;
+        new_block.validate_transactions()?;
+        new_block.validate_timestamp(&self.chain, new_block.timestamp)?;
         new_block.mine_block(self.difficulty)?;
-        self.chain.push(new_block);
+        Arc::make_mut(&mut self.index).add_block(&new_block)?;
+        self.chain_mut().push(new_block);
         Ok(self.blocks().last().unwrap())
     }
 
     #[allow(unused)]
-    #[instrument(level = "debug")]
     pub fn validate(&self) -> Result<()> {
-        let mut it = self.chain.iter();
-        let mut prev_block = it.next().ok_or(Error::ChainIsEmpty)?;
-        for block in it {
-            block.validate(&prev_block.hash, self.difficulty)?;
-            prev_block = block;
+        self.validate_at(self.clock.now())
+    }
+
+    /// Validates the chain with `now` as the local clock time block
+    /// timestamps are checked against.
+    #[instrument(skip(self), level = "debug")]
+    pub fn validate_at(&self, now: u64) -> Result<()> {
+        let genesis = self.chain.first().ok_or(Error::ChainIsEmpty)?;
+        if genesis.index != 0 {
+            Err(Error::InvalidBlock(
+                0,
+                ValidationError::IndexNotContinuous(genesis.index, 0),
+            ))?;
+        }
+        if *genesis != Block::genesis(self.difficulty)? {
+            Err(Error::InvalidBlock(
+                0,
+                ValidationError::UnknownGenesis(genesis.hash.clone()),
+            ))?;
+        }
+        for (i, block) in self.chain.iter().enumerate().skip(1) {
+            self.validate_next(&self.chain[..i], block, now)?;
+        }
+        self.validate_ledger()
+    }
+
+    /// Checks `block` can follow the `previous` ones, apart from the
+    /// transaction nonces and balances.
+    fn validate_next(&self, previous: &[Block], block: &Block, now: u64) -> Result<()> {
+        let height = previous.len() as u64;
+        if block.index != height {
+            Err(Error::InvalidBlock(
+                height,
+                ValidationError::IndexNotContinuous(block.index, height),
+            ))?;
+        }
+        let parent = previous.last().ok_or(Error::ChainIsEmpty)?;
+        block.validate(&parent.hash, self.difficulty)?;
+        block.validate_timestamp(previous, now)
+    }
+
+    /// Chain of the first `fork` blocks of this one followed by `branch`,
+    /// which is validated on top of them like [`Blockchain::validate_at`]
+    /// would. The shared blocks were validated already and are not looked
+    /// at again.
+    pub fn with_branch(&self, fork: usize, branch: Vec<Block>, now: u64) -> Result<Blockchain> {
+        let mut index = AddressIndex::clone(&self.index);
+        index.truncate(fork as u64);
+        let mut candidate = Self {
+            chain: Arc::new(self.chain[..fork].to_vec()),
+            difficulty: self.difficulty,
+            clock: self.clock.clone(),
+            index: Arc::new(index),
+        };
+        for block in branch {
+            candidate.validate_next(&candidate.chain, &block, now)?;
+            let mut ledger = Ledger::new(&candidate);
+            for (position, tx) in block.transactions.iter().enumerate() {
+                ledger.check(tx).map_err(|e| {
+                    Error::InvalidBlock(
+                        block.index,
+                        ValidationError::InvalidTransaction(position, Box::new(e)),
+                    )
+                })?;
+                ledger.record(tx, |_| true);
+            }
+            Arc::make_mut(&mut candidate.index).add_block(&block)?;
+            candidate.chain_mut().push(block);
+        }
+        Ok(candidate)
+    }
+
+    /// Checks nonces and balances the way [`Blockchain::check_transactions`]
+    /// does for each block on top of the blocks before it.
+    fn validate_ledger(&self) -> Result<()> {
+        let mut nonces: HashMap<&str, u64> = HashMap::new();
+        let mut balances: HashMap<&str, i64> = HashMap::new();
+        for block in self.chain.iter() {
+            let invalid = |position, e| {
+                Error::InvalidBlock(
+                    block.index,
+                    ValidationError::InvalidTransaction(position, Box::new(e)),
+                )
+            };
+            let mut spent: HashMap<&str, i64> = HashMap::new();
+            for (position, tx) in block.transactions.iter().enumerate() {
+                if crypto::is_derived_address(&tx.from) {
+                    let spent = spent.entry(tx.from.as_str()).or_default();
+                    let available = balances.get(tx.from.as_str()).copied().unwrap_or(0) - *spent;
+                    if available < tx.amount {
+                        Err(invalid(
+                            position,
+                            Error::InsufficientBalance(tx.from.clone(), available, tx.amount),
+                        ))?;
+                    }
+                    *spent += tx.amount;
+                }
+                if !tx.is_signed() {
+                    continue;
+                }
+                let expected = nonces.entry(tx.from.as_str()).or_default();
+                if tx.nonce != *expected {
+                    Err(invalid(
+                        position,
+                        Error::InvalidTransactionNonce(tx.from.clone(), tx.nonce, *expected),
+                    ))?;
+                }
+                *expected += 1;
+            }
+            for (position, tx) in block.transactions.iter().enumerate() {
+                for (address, amount) in tx.balance_changes() {
+                    let balance = balances.entry(address).or_default();
+                    *balance = balance.checked_add(amount).ok_or_else(|| {
+                        invalid(position, Error::BalanceOverflow(address.to_owned()))
+                    })?;
+                }
+            }
         }
         Ok(())
     }
@@ -69,20 +310,151 @@ impl Blockchain {
         if other.chain.len() <= self.chain.len() {
             return Ok(false);
         }
-        if let Err(e) = other.validate() {
+        if other.genesis_hash() != self.genesis_hash() {
+            let e = Error::InvalidBlock(
+                0,
+                ValidationError::UnknownGenesis(other.genesis_hash().to_owned()),
+            );
             error!("Failed to replace chain {:?}", e);
             Err(e)?;
         }
-        *self = other;
+        if let Err(e) = other.validate_at(self.clock.now()) {
+            error!("Failed to replace chain {:?}", e);
+            Err(e)?;
+        }
+        Ok(self.adopt(other))
+    }
+
+    /// Number of blocks this chain shares with `other`.
+    pub fn fork_point(&self, other: &Blockchain) -> usize {
+        self.chain
+            .iter()
+            .zip(other.chain.iter())
+            .take_while(|(ours, theirs)| ours.hash == theirs.hash)
+            .count()
+    }
+
+    /// Replaces the chain with `other` if it is longer, trusting that it was
+    /// validated, e.g. outside of the node, see [`Blockchain::validate_at`].
+    /// Chains starting from another genesis are never adopted.
+    pub fn adopt(&mut self, other: Blockchain) -> bool {
+        if other.chain.len() <= self.chain.len() || other.genesis_hash() != self.genesis_hash() {
+            return false;
+        }
+        let fork = self.fork_point(&other);
+        let mut index = AddressIndex::clone(&self.index);
+        index.truncate(fork as u64);
+        for block in &other.chain[fork..] {
+            if let Err(e) = index.add_block(block) {
+                error!("Failed to index adopted chain {:?}", e);
+                return false;
+            }
+        }
+        self.index = Arc::new(index);
+        self.chain = other.chain;
+        true
+    }
+}
+
+/// Balances, spendable amounts and next nonces of addresses after the
+/// transactions recorded on top of a chain, see
+/// [`Blockchain::check_transactions`].
+struct Ledger<'a> {
+    chain: &'a Blockchain,
+    nonces: HashMap<&'a str, u64>,
+    spendable: HashMap<&'a str, i64>,
+    balances: HashMap<&'a str, i64>,
+}
+
+impl<'a> Ledger<'a> {
+    fn new(chain: &'a Blockchain) -> Self {
+        Self {
+            chain,
+            nonces: HashMap::new(),
+            spendable: HashMap::new(),
+            balances: HashMap::new(),
+        }
+    }
+
+    fn balance(&mut self, address: &'a str) -> &mut i64 {
+        let chain = self.chain;
+        self.balances
+            .entry(address)
+            .or_insert_with(|| chain.get_balance(address))
+    }
+
+    fn spendable(&mut self, address: &'a str) -> &mut i64 {
+        let chain = self.chain;
+        self.spendable
+            .entry(address)
+            .or_insert_with(|| chain.get_balance(address))
+    }
+
+    fn nonce(&mut self, address: &'a str) -> &mut u64 {
+        let chain = self.chain;
+        self.nonces
+            .entry(address)
+            .or_insert_with(|| chain.next_nonce(address))
+    }
+
+    /// Checks the transaction can follow the recorded ones.
+    fn check(&mut self, tx: &'a Transaction) -> Result<()> {
+        for (address, amount) in tx.balance_changes() {
+            self.balance(address)
+                .checked_add(amount)
+                .ok_or_else(|| Error::BalanceOverflow(address.to_owned()))?;
+        }
+        if crypto::is_derived_address(&tx.from) {
+            let available = *self.spendable(&tx.from);
+            if available < tx.amount {
+                Err(Error::InsufficientBalance(
+                    tx.from.clone(),
+                    available,
+                    tx.amount,
+                ))?;
+            }
+        }
+        if tx.is_signed() {
+            let expected = *self.nonce(&tx.from);
+            if tx.nonce != expected {
+                Err(Error::InvalidTransactionNonce(
+                    tx.from.clone(),
+                    tx.nonce,
+                    expected,
+                ))?;
+            }
+        }
+        Ok(())
+    }
 
-        Ok(true)
+    /// Applies a checked transaction to the addresses `involved` selects.
+    fn record(&mut self, tx: &'a Transaction, involved: impl Fn(&str) -> bool) {
+        for (address, amount) in tx.balance_changes() {
+            if involved(address) {
+                let balance = self.balance(address);
+                *balance = balance.saturating_add(amount);
+            }
+        }
+        if !involved(&tx.from) {
+            return;
+        }
+        if crypto::is_derived_address(&tx.from) {
+            *self.spendable(&tx.from) -= tx.amount;
+        }
+        if tx.is_signed() {
+            *self.nonce(&tx.from) += 1;
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::errors::Error;
+    use crate::block::{MAX_FUTURE_DRIFT, MIN_BLOCK_TIMESTAMP};
+    use crate::clock::{Clock, ManualClock};
+    use crate::errors::{Error, ValidationError};
+    use crate::test_utils::{funding, signed_transaction};
+    use std::sync::Arc;
 
     #[test]
     fn test_new_blockchain_creates_genesis_block() {
@@ -107,6 +479,7 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }])
             .unwrap();
         blockchain
@@ -114,6 +487,7 @@ mod tests {
                 from: "B".to_string(),
                 to: "C".to_string(),
                 amount: 100,
+                ..Default::default()
             }])
             .unwrap();
 
@@ -124,6 +498,7 @@ mod tests {
                 from: "B".to_string(),
                 to: "C".to_string(),
                 amount: 100,
+                ..Default::default()
             }]
         );
         assert_eq!(blockchain.chain[2].index, 2);
@@ -139,6 +514,7 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }])
             .unwrap();
 
@@ -149,6 +525,7 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }]
         );
     }
@@ -162,6 +539,7 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }])
             .unwrap();
         blockchain
@@ -169,6 +547,7 @@ mod tests {
                 from: "B".to_string(),
                 to: "C".to_string(),
                 amount: 100,
+                ..Default::default()
             }])
             .unwrap();
 
@@ -185,6 +564,7 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }])
             .unwrap();
         blockchain
@@ -192,35 +572,50 @@ mod tests {
                 from: "B".to_string(),
                 to: "C".to_string(),
                 amount: 100,
+                ..Default::default()
             }])
             .unwrap();
 
-        blockchain.chain[2].hash = "00_bad_hash".to_string();
+        blockchain.chain_mut()[2].hash = "00_bad_hash".to_string();
 
         let result = blockchain.validate();
         match result {
-            Err(Error::BlockHasInvalidHash(index, _)) => assert_eq!(index, 2),
-            v => panic!("Expected error BlockHasInvalidHash, actual {v:?}"),
+            Err(Error::InvalidBlock(index, ValidationError::MalformedHash(_))) => {
+                assert_eq!(index, 2)
+            }
+            v => panic!("Expected error MalformedHash, actual {v:?}"),
         }
     }
 
     #[test]
     fn test_validate_chain_with_wrong_difficulty() {
-        let difficulty = 2;
+        let difficulty = 4;
         let mut blockchain = Blockchain::new(difficulty).unwrap();
-        blockchain
-            .add_block(vec![Transaction {
+        let mut block = Block::new(
+            1,
+            blockchain.chain[0].hash.clone(),
+            vec![Transaction {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
-            }])
-            .unwrap();
-        blockchain.difficulty = 4;
+                ..Default::default()
+            }],
+            blockchain.clock().as_ref(),
+        );
+        // Mined for difficulty 2 only, by chance the hash could satisfy 4
+        block.mine_block(2).unwrap();
+        while block.hash.starts_with("0000") {
+            block.nonce += 1;
+            block.mine_block(2).unwrap();
+        }
+        blockchain.chain_mut().push(block);
 
         let result = blockchain.validate();
         match result {
-            Err(Error::UnsatisfiedHashDifficulty(index, _)) => assert_eq!(index, 1),
-            v => panic!("Expected error UnsatisfiedHashDifficulty, actual {v:?}"),
+            Err(Error::InvalidBlock(index, ValidationError::UnsatisfiedDifficulty(_))) => {
+                assert_eq!(index, 1)
+            }
+            v => panic!("Expected error UnsatisfiedDifficulty, actual {v:?}"),
         }
     }
 
@@ -233,23 +628,505 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }])
             .unwrap();
 
-        blockchain.chain[1].previous_hash = "WRONG".to_string();
+        blockchain.chain_mut()[1].previous_hash = "WRONG".to_string();
 
         let result = blockchain.validate();
         match result {
-            Err(Error::BlockHasInvalidPreviusBlockHash(index, _, _)) => assert_eq!(index, 1),
-            v => panic!("Expected error BlockHasInvalidPreviusBlockHash, actual {v:?}"),
+            Err(Error::InvalidBlock(index, ValidationError::InvalidPreviousHash(_, _))) => {
+                assert_eq!(index, 1)
+            }
+            v => panic!("Expected error InvalidPreviousHash, actual {v:?}"),
+        }
+    }
+
+    #[test]
+    fn test_add_block_rejects_locked_transaction() {
+        let mut blockchain = Blockchain::new(1).unwrap();
+        let tx = Transaction {
+            from: "A".to_string(),
+            to: "B".to_string(),
+            amount: 100,
+            lock_until: Some(crate::block::LockTime::Height(2)),
+            ..Default::default()
+        };
+
+        let result = blockchain.add_block(vec![tx.clone()]);
+        assert!(matches!(
+            result,
+            Err(Error::InvalidBlock(
+                1,
+                ValidationError::LockedTransaction(_)
+            ))
+        ));
+        blockchain.add_block(vec![]).unwrap();
+        blockchain.add_block(vec![tx]).unwrap();
+        assert!(blockchain.validate().is_ok());
+    }
+
+    fn push_block_at(blockchain: &mut Blockchain, timestamp: u64) {
+        let prev = blockchain.chain.last().unwrap();
+        let mut block = Block::new(
+            prev.index + 1,
+            prev.hash.clone(),
+            vec![],
+            blockchain.clock().as_ref(),
+        );
+        block.timestamp = timestamp;
+        block.mine_block(blockchain.difficulty).unwrap();
+        blockchain.chain_mut().push(block);
+    }
+
+    #[test]
+    fn test_validate_chain_timestamps_against_clock() {
+        let mut blockchain = Blockchain::new(1).unwrap();
+        let genesis_time = blockchain.chain[0].timestamp;
+        push_block_at(&mut blockchain, genesis_time + 600);
+        push_block_at(&mut blockchain, genesis_time + 1200);
+
+        assert!(blockchain.validate_at(genesis_time + 1200).is_ok());
+        // The same chain is from the future for a clock lagging behind
+        let lagging = genesis_time + 1200 - crate::block::MAX_FUTURE_DRIFT - 1;
+        match blockchain.validate_at(lagging) {
+            Err(Error::InvalidBlock(2, ValidationError::TimestampTooFarInFuture(_, _))) => {}
+            v => panic!("Expected error TimestampTooFarInFuture, actual {v:?}"),
+        }
+    }
+
+    #[test]
+    fn test_validate_chain_with_block_before_median_time_past() {
+        let mut blockchain = Blockchain::new(1).unwrap();
+        let genesis_time = blockchain.chain[0].timestamp;
+        push_block_at(&mut blockchain, genesis_time + 600);
+        push_block_at(&mut blockchain, genesis_time + 1200);
+        push_block_at(&mut blockchain, genesis_time + 1);
+
+        match blockchain.validate_at(genesis_time + 1200) {
+            Err(Error::InvalidBlock(
+                3,
+                ValidationError::TimestampBeforeMedianTimePast(_, median),
+            )) => {
+                assert_eq!(median, genesis_time + 600)
+            }
+            v => panic!("Expected error TimestampBeforeMedianTimePast, actual {v:?}"),
         }
     }
 
+    #[test]
+    fn test_validate_chain_with_block_from_1970() {
+        let mut blockchain = Blockchain::new(1).unwrap();
+        push_block_at(&mut blockchain, 0);
+
+        let result = blockchain.validate();
+        assert!(matches!(
+            result,
+            Err(Error::InvalidBlock(
+                1,
+                ValidationError::TimestampTooEarly(0)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_validate_chain_with_repeated_index() {
+        let mut blockchain = Blockchain::new(1).unwrap();
+        blockchain.add_block(vec![]).unwrap();
+        let prev = blockchain.chain.last().unwrap();
+        let mut block = Block::new(1, prev.hash.clone(), vec![], blockchain.clock().as_ref());
+        block.mine_block(1).unwrap();
+        blockchain.chain_mut().push(block);
+
+        match blockchain.validate() {
+            Err(Error::InvalidBlock(2, ValidationError::IndexNotContinuous(1, 2))) => {}
+            v => panic!("Expected error IndexNotContinuous, actual {v:?}"),
+        }
+    }
+
+    #[test]
+    fn test_validate_chain_with_non_zero_genesis_index() {
+        let mut blockchain = Blockchain::new(1).unwrap();
+        blockchain.chain_mut()[0].index = 5;
+
+        assert!(matches!(
+            blockchain.validate(),
+            Err(Error::InvalidBlock(
+                0,
+                ValidationError::IndexNotContinuous(5, 0)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_validate_chain_with_foreign_genesis() {
+        let mut blockchain = Blockchain::new(1).unwrap();
+        let mut genesis = blockchain.chain[0].clone();
+        genesis.timestamp += 1;
+        genesis.mine_block(1).unwrap();
+        let hash = genesis.hash.clone();
+        blockchain.chain_mut()[0] = genesis;
+        blockchain.add_block(vec![]).unwrap();
+
+        assert!(matches!(
+            blockchain.validate(),
+            Err(Error::InvalidBlock(0, ValidationError::UnknownGenesis(h))) if h == hash
+        ));
+    }
+
+    #[test]
+    fn test_chain_from_foreign_genesis_is_not_replaced_or_adopted() {
+        let mut blockchain = Blockchain::new(1).unwrap();
+        let mut other = blockchain.clone();
+        other.chain_mut()[0].timestamp += 1;
+        other.chain_mut()[0].mine_block(1).unwrap();
+        other.add_block(vec![]).unwrap();
+
+        assert!(matches!(
+            blockchain.replace_chain(other.clone()),
+            Err(Error::InvalidBlock(0, ValidationError::UnknownGenesis(h))) if h == other.genesis_hash()
+        ));
+        assert!(!blockchain.adopt(other));
+        assert_eq!(blockchain.chain.len(), 1);
+    }
+
+    #[test]
+    fn test_adopted_chain_keeps_the_difficulty() {
+        let mut blockchain = Blockchain::new(2).unwrap();
+        let mut other = blockchain.clone();
+        other.difficulty = 1;
+        other.add_block(vec![]).unwrap();
+
+        assert!(blockchain.adopt(other));
+        assert_eq!(blockchain.difficulty(), 2);
+    }
+
+    #[test]
+    fn test_chains_built_with_manual_clocks_are_identical() {
+        let build = || {
+            let clock = Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP));
+            let mut blockchain = Blockchain::with_clock(1, clock.clone()).unwrap();
+            for _ in 0..3 {
+                clock.advance(600);
+                blockchain.add_block(vec![]).unwrap();
+            }
+            blockchain
+        };
+        let first = build();
+        assert_eq!(first, build());
+        assert_eq!(first.chain[3].timestamp, MIN_BLOCK_TIMESTAMP + 1800);
+        assert!(first.validate().is_ok());
+    }
+
+    #[test]
+    fn test_genesis_does_not_depend_on_clock() {
+        let early = Blockchain::with_clock(1, Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP)));
+        let late = Blockchain::with_clock(1, Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP + 600)));
+        assert_eq!(early.unwrap().genesis_hash(), late.unwrap().genesis_hash());
+        assert_ne!(
+            Blockchain::new(1).unwrap().genesis_hash(),
+            Blockchain::new(2).unwrap().genesis_hash()
+        );
+    }
+
+    #[test]
+    fn test_replace_chain_validates_against_own_clock() {
+        let clock = Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP));
+        let mut blockchain = Blockchain::with_clock(1, clock.clone()).unwrap();
+        let future_clock = Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP + MAX_FUTURE_DRIFT + 1));
+        let mut future = Blockchain::with_clock(1, future_clock).unwrap();
+        future.add_block(vec![]).unwrap();
+
+        assert!(matches!(
+            blockchain.replace_chain(future.clone()),
+            Err(Error::InvalidBlock(
+                1,
+                ValidationError::TimestampTooFarInFuture(..)
+            ))
+        ));
+
+        clock.advance(MAX_FUTURE_DRIFT + 1);
+        assert!(blockchain.replace_chain(future).unwrap());
+        assert_eq!(blockchain.clock().now(), clock.now());
+    }
+
+    #[test]
+    fn test_cumulative_work_grows_with_blocks() {
+        let mut blockchain = Blockchain::new(2).unwrap();
+        assert_eq!(blockchain.cumulative_work(), 256);
+        blockchain.add_block(vec![]).unwrap();
+        assert_eq!(blockchain.cumulative_work(), 512);
+    }
+
+    #[test]
+    fn test_adopt_only_takes_longer_chain() {
+        let mut blockchain = Blockchain::new(1).unwrap();
+        let mut other = blockchain.clone();
+        assert!(!blockchain.adopt(other.clone()));
+
+        other.add_block(vec![]).unwrap();
+        assert!(blockchain.adopt(other.clone()));
+        assert_eq!(blockchain.blocks(), other.blocks());
+    }
+
+    #[test]
+    fn test_adopted_fork_is_reindexed() {
+        let payment = |to: &str, amount| Transaction {
+            from: "A".into(),
+            to: to.into(),
+            amount,
+            ..Default::default()
+        };
+        let mut blockchain = Blockchain::new(1).unwrap();
+        blockchain.add_block(vec![payment("B", 5)]).unwrap();
+        let mut fork = blockchain.clone();
+        blockchain.add_block(vec![payment("C", 1)]).unwrap();
+        fork.add_block(vec![payment("B", 2)]).unwrap();
+        fork.add_block(vec![]).unwrap();
+
+        assert!(blockchain.adopt(fork.with_blocks(fork.blocks().to_vec())));
+        assert!(blockchain.index().history("C").is_empty());
+        let balances: Vec<_> = blockchain
+            .index()
+            .history("B")
+            .iter()
+            .map(|e| (e.height, e.balance))
+            .collect();
+        assert_eq!(balances, vec![(1, 5), (2, 7)]);
+    }
+
+    #[test]
+    fn test_branch_is_validated_on_top_of_the_fork() {
+        let key = crate::crypto::generate_signing_key();
+        let mut blockchain = Blockchain::new(1).unwrap();
+        blockchain.add_block(vec![funding(&key, 15)]).unwrap();
+        let mut fork = blockchain.clone();
+        blockchain
+            .add_block(vec![signed_transaction(&key, 5, 0)])
+            .unwrap();
+        fork.add_block(vec![signed_transaction(&key, 10, 0)])
+            .unwrap();
+        fork.add_block(vec![]).unwrap();
+        let now = blockchain.clock().now();
+
+        let branch = fork.blocks()[2..].to_vec();
+        let candidate = blockchain.with_branch(2, branch.clone(), now).unwrap();
+        assert_eq!(candidate, fork);
+        assert_eq!(candidate.next_nonce(&branch[0].transactions[0].from), 1);
+        assert!(blockchain.adopt(candidate));
+        assert_eq!(blockchain, fork);
+
+        let mut replay = Block::new(
+            3,
+            fork.blocks()[2].hash.clone(),
+            vec![signed_transaction(&key, 1, 0)],
+            blockchain.clock().as_ref(),
+        );
+        replay.mine_block(1).unwrap();
+        assert!(matches!(
+            blockchain.with_branch(3, vec![replay], now),
+            Err(Error::InvalidBlock(
+                3,
+                ValidationError::InvalidTransaction(0, _)
+            ))
+        ));
+        assert!(matches!(
+            blockchain.with_branch(2, fork.blocks()[3..].to_vec(), now),
+            Err(Error::InvalidBlock(
+                2,
+                ValidationError::IndexNotContinuous(3, 2)
+            ))
+        ));
+    }
+
     #[test]
     fn test_validate_empty_chain_fails() {
         let mut blockchain = Blockchain::new(2).unwrap();
-        blockchain.chain = vec![];
+        blockchain.chain = Arc::default();
         let result = blockchain.validate();
         assert!(matches!(result, Err(Error::ChainIsEmpty)));
     }
+
+    #[test]
+    fn test_next_nonce_counts_signed_transactions() {
+        let key = crate::crypto::generate_signing_key();
+        let from = crate::crypto::address_from_public_key(&key.verifying_key());
+        let mut blockchain = Blockchain::new(1).unwrap();
+        blockchain.add_block(vec![funding(&key, 100)]).unwrap();
+        assert_eq!(blockchain.next_nonce(&from), 0);
+
+        blockchain
+            .add_block(vec![
+                signed_transaction(&key, 10, 0),
+                signed_transaction(&key, 10, 1),
+            ])
+            .unwrap();
+        assert_eq!(blockchain.next_nonce(&from), 2);
+        assert!(blockchain.validate().is_ok());
+    }
+
+    #[test]
+    fn test_add_block_rejects_replayed_nonce() {
+        let key = crate::crypto::generate_signing_key();
+        let mut blockchain = Blockchain::new(1).unwrap();
+        blockchain.add_block(vec![funding(&key, 100)]).unwrap();
+        blockchain
+            .add_block(vec![signed_transaction(&key, 10, 0)])
+            .unwrap();
+
+        let result = blockchain.add_block(vec![signed_transaction(&key, 10, 0)]);
+        match result {
+            Err(Error::InvalidTransactionNonce(_, 0, 1)) => {}
+            v => panic!("Expected error InvalidTransactionNonce, actual {v:?}"),
+        }
+        assert_eq!(blockchain.chain.len(), 3);
+    }
+
+    #[test]
+    fn test_validate_chain_with_replayed_nonce() {
+        let key = crate::crypto::generate_signing_key();
+        let mut blockchain = Blockchain::new(1).unwrap();
+        blockchain.add_block(vec![funding(&key, 100)]).unwrap();
+        blockchain
+            .add_block(vec![signed_transaction(&key, 10, 0)])
+            .unwrap();
+        let mut replay = Block::new(
+            3,
+            blockchain.chain[2].hash.clone(),
+            vec![signed_transaction(&key, 10, 0)],
+            blockchain.clock().as_ref(),
+        );
+        replay.mine_block(1).unwrap();
+        blockchain.chain_mut().push(replay);
+
+        let result = blockchain.validate();
+        assert!(matches!(
+            result,
+            Err(Error::InvalidBlock(3, ValidationError::InvalidTransaction(0, e)))
+                if matches!(*e, Error::InvalidTransactionNonce(_, 0, 1))
+        ));
+    }
+
+    #[test]
+    fn test_derived_address_cannot_spend_more_than_its_balance() {
+        let key = crate::crypto::generate_signing_key();
+        let mut blockchain = Blockchain::new(1).unwrap();
+        assert!(matches!(
+            blockchain.check_transactions(&[signed_transaction(&key, 10, 0)]),
+            Err(Error::InsufficientBalance(_, 0, 10))
+        ));
+
+        blockchain.add_block(vec![funding(&key, 15)]).unwrap();
+        let pending = [
+            signed_transaction(&key, 10, 0),
+            signed_transaction(&key, 10, 1),
+        ];
+        assert!(matches!(
+            blockchain.check_transactions(&pending),
+            Err(Error::InsufficientBalance(_, 5, 10))
+        ));
+        assert!(blockchain.check_transactions(&pending[..1]).is_ok());
+    }
+
+    #[test]
+    fn test_pending_transaction_is_checked_after_the_pending_ones() {
+        let key = crate::crypto::generate_signing_key();
+        let other = crate::crypto::generate_signing_key();
+        let mut blockchain = Blockchain::new(1).unwrap();
+        blockchain
+            .add_block(vec![funding(&key, 15), funding(&other, 5)])
+            .unwrap();
+        let pending = [
+            signed_transaction(&other, 5, 0),
+            signed_transaction(&key, 10, 0),
+        ];
+
+        assert!(
+            blockchain
+                .check_pending_transaction(&pending, &signed_transaction(&key, 5, 1))
+                .is_ok()
+        );
+        assert!(matches!(
+            blockchain.check_pending_transaction(&pending, &signed_transaction(&key, 5, 0)),
+            Err(Error::InvalidTransactionNonce(_, 0, 1))
+        ));
+        assert!(matches!(
+            blockchain.check_pending_transaction(&pending, &signed_transaction(&key, 10, 1)),
+            Err(Error::InsufficientBalance(_, 5, 10))
+        ));
+    }
+
+    #[test]
+    fn test_valid_in_order_rejects_transactions_no_longer_valid() {
+        let key = crate::crypto::generate_signing_key();
+        let mut blockchain = Blockchain::new(1).unwrap();
+        blockchain.add_block(vec![funding(&key, 15)]).unwrap();
+        blockchain
+            .add_block(vec![signed_transaction(&key, 5, 0)])
+            .unwrap();
+
+        let pending = vec![
+            signed_transaction(&key, 5, 0),
+            signed_transaction(&key, 5, 1),
+            signed_transaction(&key, 10, 2),
+            signed_transaction(&key, 5, 2),
+        ];
+        assert_eq!(
+            blockchain.valid_in_order(&pending),
+            vec![false, true, false, true]
+        );
+    }
+
+    #[test]
+    fn test_validate_chain_with_overspending_block() {
+        let key = crate::crypto::generate_signing_key();
+        let mut blockchain = Blockchain::new(1).unwrap();
+        blockchain.add_block(vec![funding(&key, 5)]).unwrap();
+        let mut overspend = Block::new(
+            2,
+            blockchain.chain[1].hash.clone(),
+            vec![funding(&key, 5), signed_transaction(&key, 10, 0)],
+            blockchain.clock().as_ref(),
+        );
+        overspend.mine_block(1).unwrap();
+        blockchain.chain_mut().push(overspend);
+
+        let result = blockchain.validate();
+        assert!(matches!(
+            result,
+            Err(Error::InvalidBlock(2, ValidationError::InvalidTransaction(1, e)))
+                if matches!(*e, Error::InsufficientBalance(_, 5, 10))
+        ));
+    }
+
+    #[test]
+    fn test_overflowing_balances_are_rejected() {
+        let payment = |amount| Transaction {
+            from: "A".to_string(),
+            to: "B".to_string(),
+            amount,
+            ..Default::default()
+        };
+        let mut blockchain = Blockchain::new(1).unwrap();
+        blockchain.add_block(vec![payment(i64::MAX)]).unwrap();
+        assert!(matches!(
+            blockchain.add_block(vec![payment(1)]),
+            Err(Error::BalanceOverflow(address)) if address == "B"
+        ));
+
+        let mut overflow = Block::new(
+            2,
+            blockchain.chain[1].hash.clone(),
+            vec![payment(1)],
+            blockchain.clock().as_ref(),
+        );
+        overflow.mine_block(1).unwrap();
+        blockchain.chain_mut().push(overflow);
+        assert!(matches!(
+            blockchain.validate(),
+            Err(Error::InvalidBlock(2, ValidationError::InvalidTransaction(0, e)))
+                if matches!(*e, Error::BalanceOverflow(_))
+        ));
+    }
 }
@@ -0,0 +1,86 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Source of the current time in seconds since the Unix epoch.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> u64;
+
+    /// Current time in milliseconds, for measuring short intervals.
+    fn now_millis(&self) -> u64 {
+        self.now() * 1000
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// Wall clock time of the host.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        chrono::Utc::now().timestamp() as u64
+    }
+
+    fn now_millis(&self) -> u64 {
+        chrono::Utc::now().timestamp_millis() as u64
+    }
+}
+
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// Clock that only moves when told to, for reproducible chains and
+/// simulations.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: AtomicU64,
+}
+
+#[allow(unused)]
+impl ManualClock {
+    pub const fn new(now: u64) -> Self {
+        Self {
+            now: AtomicU64::new(now),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.now.fetch_add(seconds, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock_moves_only_when_told() {
+        let clock = Arc::new(ManualClock::new(100));
+        let shared: SharedClock = clock.clone();
+        assert_eq!(shared.now(), 100);
+
+        clock.advance(20);
+        assert_eq!(shared.now(), 120);
+        assert_eq!(shared.now_millis(), 120_000);
+        clock.set(50);
+        assert_eq!(shared.now(), 50);
+    }
+
+    #[test]
+    fn test_system_clock_is_after_2024() {
+        assert!(SystemClock.now() > crate::block::MIN_BLOCK_TIMESTAMP);
+    }
+}
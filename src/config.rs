@@ -1,8 +1,70 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::network::p2p::PeerLimits;
+use crate::network::secure::EncryptionMode;
+use crate::wallet::WalletArgs;
 
 #[derive(Debug, Parser)]
 #[command(about, long_about = None)]
 pub struct Config {
     #[arg(short, long, env, default_value_t = 3000)]
     pub port: u16,
+    /// Port accepting connections from other nodes
+    #[arg(long, env, default_value_t = 4000)]
+    pub p2p_port: u16,
+    /// Network to join, nodes only connect to peers of the same one
+    #[arg(long, env, default_value = crate::network::DEFAULT_NETWORK_ID)]
+    pub network_id: String,
+    /// Most peers accepted to connect to this node
+    #[arg(long, env, default_value_t = PeerLimits::default().max_inbound)]
+    pub max_inbound_peers: usize,
+    /// Most peers this node connects to
+    #[arg(long, env, default_value_t = PeerLimits::default().max_outbound)]
+    pub max_outbound_peers: usize,
+    /// Nodes to connect to when no other peer is known, separated by commas
+    #[arg(long, env, value_delimiter = ',')]
+    pub seeds: Vec<String>,
+    /// Whether peer connections are encrypted, `required` refuses plaintext
+    /// peers
+    #[arg(long, env, value_enum, default_value_t = EncryptionMode::Optional)]
+    pub encryption: EncryptionMode,
+    /// Directory the node keeps its state in, nothing is persisted without one
+    #[arg(long, env)]
+    pub data_dir: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Any free ports and default limits, for nodes started in tests.
+impl Default for Config {
+    fn default() -> Self {
+        let limits = PeerLimits::default();
+        Self {
+            port: 0,
+            p2p_port: 0,
+            network_id: crate::network::DEFAULT_NETWORK_ID.to_owned(),
+            max_inbound_peers: limits.max_inbound,
+            max_outbound_peers: limits.max_outbound,
+            seeds: Vec::new(),
+            encryption: EncryptionMode::default(),
+            data_dir: None,
+            command: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn peer_limits(&self) -> PeerLimits {
+        PeerLimits {
+            max_inbound: self.max_inbound_peers,
+            max_outbound: self.max_outbound_peers,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Manage wallet keys and send signed transactions
+    Wallet(WalletArgs),
 }
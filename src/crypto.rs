@@ -0,0 +1,87 @@
+use crate::errors::{Error, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+
+/// Length in hex characters of an address derived from a public key.
+pub const ADDRESS_LEN: usize = 40;
+
+pub fn generate_signing_key() -> SigningKey {
+    SigningKey::generate(&mut OsRng)
+}
+
+pub fn sha256_hex(data: impl AsRef<[u8]>) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Address is the first 20 bytes of the SHA-256 of the public key, hex encoded.
+pub fn address_from_public_key(public_key: &VerifyingKey) -> String {
+    let mut address = sha256_hex(public_key.as_bytes());
+    address.truncate(ADDRESS_LEN);
+    address
+}
+
+/// Whether `address` has the form of an address derived from a key, a
+/// multisig policy or a script, as opposed to a plain name.
+pub fn is_derived_address(address: &str) -> bool {
+    address.len() == ADDRESS_LEN && address.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+pub fn sign(key: &SigningKey, message: &[u8]) -> String {
+    hex::encode(key.sign(message).to_bytes())
+}
+
+pub fn parse_public_key(public_key: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(public_key)
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| Error::InvalidPublicKey(public_key.to_owned()))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| Error::InvalidPublicKey(public_key.to_owned()))
+}
+
+pub fn verify(public_key: &VerifyingKey, message: &[u8], signature: &str) -> bool {
+    let Some(bytes) = hex::decode(signature)
+        .ok()
+        .and_then(|b| <[u8; 64]>::try_from(b).ok())
+    else {
+        return false;
+    };
+    public_key
+        .verify(message, &Signature::from_bytes(&bytes))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = generate_signing_key();
+        let signature = sign(&key, b"payload");
+        assert!(verify(&key.verifying_key(), b"payload", &signature));
+        assert!(!verify(&key.verifying_key(), b"other", &signature));
+        assert!(!verify(&key.verifying_key(), b"payload", "not_hex"));
+    }
+
+    #[test]
+    fn test_address_from_public_key() {
+        let key = generate_signing_key();
+        let address = address_from_public_key(&key.verifying_key());
+        assert_eq!(address.len(), ADDRESS_LEN);
+        assert_eq!(address, address_from_public_key(&key.verifying_key()));
+        assert!(is_derived_address(&address));
+        assert!(!is_derived_address("A"));
+    }
+
+    #[test]
+    fn test_parse_public_key() {
+        let key = generate_signing_key();
+        let encoded = hex::encode(key.verifying_key().as_bytes());
+        assert_eq!(parse_public_key(&encoded).unwrap(), key.verifying_key());
+        assert!(matches!(
+            parse_public_key("abcd"),
+            Err(Error::InvalidPublicKey(_))
+        ));
+    }
+}
@@ -7,12 +7,92 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     #[error("Chain is empty")]
     ChainIsEmpty,
-    #[error("Block with index {0} has invalid hash {1}")]
-    BlockHasInvalidHash(u64, String),
-    #[error("Block with index {0} has invalid previous block hash: actual: '{1}', expected: '{2}'")]
-    BlockHasInvalidPreviusBlockHash(u64, String, String),
-    #[error("Block with index {0} doesn't satisfy difficulty '{1}'")]
-    UnsatisfiedHashDifficulty(u64, usize),
+    #[error("Block at height {0} is invalid: {1}")]
+    InvalidBlock(u64, ValidationError),
+    #[error("Public key '{0}' is invalid")]
+    InvalidPublicKey(String),
+    #[error("Transaction from '{0}' has invalid signature")]
+    InvalidTransactionSignature(String),
+    #[error("Transaction from '{0}' is not signed")]
+    TransactionNotSigned(String),
+    #[error("Transaction from '{0}' has non-positive amount {1}")]
+    InvalidAmount(String, i64),
+    #[error("Address '{0}' can spend {1}, transaction spends {2}")]
+    InsufficientBalance(String, i64, i64),
+    #[error("Balance of '{0}' overflows")]
+    BalanceOverflow(String),
+    #[error("Transaction from '{0}' has invalid nonce: actual: {1}, expected: {2}")]
+    InvalidTransactionNonce(String, u64, u64),
+    #[error("Mempool is full, limit {0} transactions")]
+    MempoolFull(usize),
+    #[error("Script is invalid: {0}")]
+    InvalidScript(String),
+    #[error("Script of '{0}' failed: {1}")]
+    ScriptFailed(String, crate::script::ScriptError),
+    #[error("Multisig policy is invalid: {0}")]
+    InvalidMultisigPolicy(String),
+    #[error("Public key '{0}' is not part of the multisig policy")]
+    KeyNotInMultisig(String),
+    #[error("Transaction from '{0}' has {1} valid signatures, {2} required")]
+    InsufficientSignatures(String, usize, usize),
+    #[error("Wallet has no key for address '{0}'")]
+    WalletKeyNotFound(String),
+    #[error("Wallet has no keys")]
+    WalletIsEmpty,
+    #[error("Wallet has no seed, initialize it from a mnemonic first")]
+    WalletHasNoSeed,
+    #[error("Wallet seed is already initialized")]
+    WalletSeedAlreadyExists,
+    #[error("Mnemonic is invalid: {0}")]
+    InvalidMnemonic(String),
+    #[error("Wallet password is required")]
+    WalletPasswordRequired,
+    #[error("Wallet password is invalid or keystore is corrupted")]
+    InvalidWalletPassword,
+    #[error("Node responded with {0}: {1}")]
+    NodeRequestFailed(u16, String),
+    #[error("Peer message is malformed: {0}")]
+    InvalidMessage(String),
+    #[error("Peer message has {0} bytes, limit {1}")]
+    MessageTooLarge(usize, usize),
+    #[error("Peer '{0}' is not connected")]
+    PeerNotConnected(String),
+    #[error("Peer '{0}' is incompatible: {1}")]
+    IncompatiblePeer(String, String),
+    #[error("Peer '{0}' sent a message before completing the handshake")]
+    HandshakeRequired(String),
+    #[error("Too many {0} peers, limit {1}")]
+    TooManyPeers(String, usize),
+    #[error("Peer '{0}' relayed more than {1} transactions a minute")]
+    RelayLimitExceeded(String, u32),
+    #[error("Peer headers are not linked")]
+    HeadersNotLinked,
+    #[error("Peer '{0}' is banned")]
+    PeerBanned(String),
+    #[error("IP address '{0}' is invalid")]
+    InvalidIpAddress(String),
+    #[error("Ban duration of {0} seconds is too long")]
+    InvalidBanDuration(u64),
+    #[error("'{0}' is not banned")]
+    NotBanned(String),
+    #[error("Node identity is invalid: {0}")]
+    InvalidIdentity(String),
+    #[error("Peer '{0}' sent a signature not matching its node id")]
+    InvalidPeerSignature(String),
+    #[error("Peer '{0}' is not node '{1}'")]
+    UnexpectedNodeId(String, String),
+    #[error("Peer '{0}' didn't encrypt the connection")]
+    EncryptionRequired(String),
+    #[error("Node is unavailable")]
+    NodeUnavailable,
+    #[error("Block '{0}' not found")]
+    BlockNotFound(String),
+    #[error("Cursor '{0}' is not a block of the chain")]
+    InvalidCursor(String),
+    #[error("Page limit {0} is not between 1 and {1}")]
+    InvalidPageLimit(usize, usize),
+    #[error("Event type '{0}' is unknown")]
+    InvalidEventType(String),
     #[error(transparent)]
     HttpParsing(#[from] axum::http::Error),
     #[error(transparent)]
@@ -20,15 +100,114 @@ pub enum Error {
     #[error(transparent)]
     InvalidConfig(#[from] clap::Error),
     #[error(transparent)]
-    Other(#[from] Box<dyn std::error::Error + 'static>),
+    HttpClient(#[from] reqwest::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Other(#[from] Box<dyn std::error::Error + Send + Sync + 'static>),
+}
+
+/// Consensus rule a block breaks.
+#[derive(thiserror::Error, Debug)]
+pub enum ValidationError {
+    #[error("genesis block '{0}' is not the genesis of this network")]
+    UnknownGenesis(String),
+    #[error("hash '{0}' is not a 64 character hex string")]
+    MalformedHash(String),
+    #[error("hash '{0}' doesn't match block contents")]
+    InvalidHash(String),
+    #[error("invalid previous block hash: actual: '{0}', expected: '{1}'")]
+    InvalidPreviousHash(String, String),
+    #[error("hash doesn't satisfy difficulty '{0}'")]
+    UnsatisfiedDifficulty(usize),
+    #[error("index {0} doesn't continue the chain, expected {1}")]
+    IndexNotContinuous(u64, u64),
+    #[error("block has {0} transactions, limit {1}")]
+    TooManyTransactions(usize, usize),
+    #[error("serialized block has {0} bytes, limit {1}")]
+    BlockTooLarge(usize, usize),
+    #[error("timestamp {0} is before the median time past {1}")]
+    TimestampBeforeMedianTimePast(u64, u64),
+    #[error("timestamp {0} is too far in the future, limit {1}")]
+    TimestampTooFarInFuture(u64, u64),
+    #[error("timestamp {0} is implausibly early")]
+    TimestampTooEarly(u64),
+    #[error("transaction from '{0}' is still locked")]
+    LockedTransaction(String),
+    #[error("transaction {0} is invalid: {1}")]
+    InvalidTransaction(usize, Box<Error>),
+}
+
+impl Error {
+    /// HTTP status of the error as a response of the API.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::HttpParsing(_)
+            | Error::InvalidBlock(..)
+            | Error::HeadersNotLinked
+            | Error::InvalidMessage(_)
+            | Error::MessageTooLarge(..)
+            | Error::InvalidScript(_)
+            | Error::InvalidPublicKey(_)
+            | Error::InvalidTransactionSignature(..)
+            | Error::InvalidTransactionNonce(..)
+            | Error::TransactionNotSigned(_)
+            | Error::InvalidAmount(..)
+            | Error::InsufficientBalance(..)
+            | Error::BalanceOverflow(_)
+            | Error::InvalidMultisigPolicy(_)
+            | Error::KeyNotInMultisig(_)
+            | Error::InsufficientSignatures(..)
+            | Error::ScriptFailed(..)
+            | Error::InvalidIpAddress(_)
+            | Error::InvalidBanDuration(_)
+            | Error::InvalidCursor(_)
+            | Error::InvalidPageLimit(..)
+            | Error::InvalidEventType(_) => StatusCode::BAD_REQUEST,
+            Error::PeerBanned(_) => StatusCode::FORBIDDEN,
+            Error::PeerNotConnected(_) | Error::NotBanned(_) | Error::BlockNotFound(_) => {
+                StatusCode::NOT_FOUND
+            }
+            Error::NodeUnavailable | Error::MempoolFull(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        tracing::error!("RESTful API internal error: {self:#?}");
-        match self {
-            Error::HttpParsing(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
-            _ => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response(),
+        let status = self.status();
+        if status.is_server_error() {
+            tracing::error!("RESTful API internal error: {self:#?}");
+        } else {
+            tracing::debug!("RESTful API request rejected with {status}: {self}");
         }
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_and_locked_blocks_are_bad_requests() {
+        let locked = Error::InvalidBlock(3, ValidationError::LockedTransaction("A".into()));
+        let invalid = Error::InvalidBlock(1, ValidationError::MalformedHash("000".into()));
+        assert_eq!(locked.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_validation_errors_are_bad_requests() {
+        assert_eq!(Error::HeadersNotLinked.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            Error::MessageTooLarge(10, 5).status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            Error::ChainIsEmpty.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
     }
 }
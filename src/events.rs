@@ -0,0 +1,305 @@
+//! Events of a node streamed to API clients.
+//!
+//! Changes of the chain, mempool and peers are found by comparing the
+//! snapshots the node publishes, see [`crate::actor`], so they are reported
+//! whichever command caused them. Mining is reported by the API handlers
+//! mining blocks.
+
+use crate::actor::NodeSnapshot;
+use crate::block::{Block, Transaction};
+use crate::errors::{Error, Result};
+use crate::network::PeerInfo;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// Most events buffered for a subscriber, slower ones miss events.
+pub const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NodeEvent {
+    NewBlock {
+        block: Block,
+    },
+    /// The chain switched to a fork of the block at `fork_height`. The
+    /// blocks of the fork follow as [`NodeEvent::NewBlock`] events.
+    Reorg {
+        fork_height: u64,
+        old_tip: String,
+        new_tip: String,
+    },
+    NewTransaction {
+        transaction: Transaction,
+    },
+    PeerConnected {
+        peer: PeerInfo,
+    },
+    PeerDisconnected {
+        address: String,
+        node_id: String,
+    },
+    Mining {
+        status: MiningStatus,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        block_hash: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MiningStatus {
+    Started,
+    Finished,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    NewBlock,
+    Reorg,
+    NewTransaction,
+    PeerConnected,
+    PeerDisconnected,
+    Mining,
+}
+
+impl EventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::NewBlock => "new_block",
+            Self::Reorg => "reorg",
+            Self::NewTransaction => "new_transaction",
+            Self::PeerConnected => "peer_connected",
+            Self::PeerDisconnected => "peer_disconnected",
+            Self::Mining => "mining",
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        [
+            Self::NewBlock,
+            Self::Reorg,
+            Self::NewTransaction,
+            Self::PeerConnected,
+            Self::PeerDisconnected,
+            Self::Mining,
+        ]
+        .into_iter()
+        .find(|kind| kind.name() == name)
+        .ok_or_else(|| Error::InvalidEventType(name.to_owned()))
+    }
+}
+
+impl NodeEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Self::NewBlock { .. } => EventKind::NewBlock,
+            Self::Reorg { .. } => EventKind::Reorg,
+            Self::NewTransaction { .. } => EventKind::NewTransaction,
+            Self::PeerConnected { .. } => EventKind::PeerConnected,
+            Self::PeerDisconnected { .. } => EventKind::PeerDisconnected,
+            Self::Mining { .. } => EventKind::Mining,
+        }
+    }
+}
+
+/// Events a subscriber wants. The address only narrows block and
+/// transaction events, to blocks with transactions of the address and its
+/// transactions.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct EventFilter {
+    /// All types when empty.
+    #[serde(default)]
+    pub types: HashSet<EventKind>,
+    pub address: Option<String>,
+}
+
+impl EventFilter {
+    /// Filter of comma-separated event type names and an address.
+    pub fn parse(types: Option<&str>, address: Option<String>) -> Result<Self> {
+        let types = types
+            .into_iter()
+            .flat_map(|types| types.split(','))
+            .filter(|name| !name.is_empty())
+            .map(str::parse)
+            .collect::<Result<_>>()?;
+        Ok(Self { types, address })
+    }
+
+    pub fn matches(&self, event: &NodeEvent) -> bool {
+        if !self.types.is_empty() && !self.types.contains(&event.kind()) {
+            return false;
+        }
+        let Some(address) = &self.address else {
+            return true;
+        };
+        let involves = |tx: &Transaction| tx.from == *address || tx.to == *address;
+        match event {
+            NodeEvent::NewBlock { block } => block.transactions.iter().any(involves),
+            NodeEvent::NewTransaction { transaction } => involves(transaction),
+            _ => true,
+        }
+    }
+}
+
+/// Events of the changes from snapshot `old` to `new`.
+pub(crate) fn changes(old: &NodeSnapshot, new: &NodeSnapshot) -> Vec<NodeEvent> {
+    let mut events = vec![];
+    let (old_blocks, new_blocks) = (old.blockchain.blocks(), new.blockchain.blocks());
+    let extends = match old_blocks.last() {
+        Some(tip) => new_blocks
+            .get(old_blocks.len() - 1)
+            .is_some_and(|b| b.hash == tip.hash),
+        None => true,
+    };
+    let fork = match extends {
+        true => old_blocks.len(),
+        false => old_blocks
+            .iter()
+            .zip(new_blocks)
+            .take_while(|(ours, theirs)| ours.hash == theirs.hash)
+            .count(),
+    };
+    if let (false, Some(old_tip), Some(new_tip)) = (extends, old_blocks.last(), new_blocks.last()) {
+        events.push(NodeEvent::Reorg {
+            fork_height: fork.saturating_sub(1) as u64,
+            old_tip: old_tip.hash.clone(),
+            new_tip: new_tip.hash.clone(),
+        });
+    }
+    events.extend(
+        new_blocks
+            .iter()
+            .skip(fork)
+            .map(|block| NodeEvent::NewBlock {
+                block: block.clone(),
+            }),
+    );
+
+    if old.mempool != new.mempool {
+        events.extend(
+            new.mempool
+                .with_ids()
+                .filter(|(id, _)| !old.mempool.contains(id))
+                .map(|(_, tx)| NodeEvent::NewTransaction {
+                    transaction: tx.clone(),
+                }),
+        );
+    }
+
+    for (address, peer) in old.peers.iter() {
+        if !new.peers.contains_key(address) {
+            events.push(NodeEvent::PeerDisconnected {
+                address: address.clone(),
+                node_id: peer.node_id.clone(),
+            });
+        }
+    }
+    for (address, peer) in new.peers.iter() {
+        if !old.peers.contains_key(address) {
+            events.push(NodeEvent::PeerConnected { peer: peer.clone() });
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::NodeHandle;
+    use crate::node::Node;
+
+    fn payment(to: &str) -> Transaction {
+        Transaction {
+            from: "A".into(),
+            to: to.into(),
+            amount: 1,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_changes_of_chain_and_mempool_are_published() {
+        let node = NodeHandle::spawn(Node::new("A", 1).unwrap());
+        let mut events = node.subscribe();
+        node.update(|n| n.submit_transaction(payment("B")))
+            .await
+            .unwrap()
+            .unwrap();
+        let hash = node
+            .update(|n| n.mine_pending().map(|b| b.hash.clone()))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            events.recv().await.unwrap(),
+            NodeEvent::NewTransaction {
+                transaction: payment("B")
+            }
+        );
+        match events.recv().await.unwrap() {
+            NodeEvent::NewBlock { block } => assert_eq!(block.hash, hash),
+            event => panic!("unexpected event {event:?}"),
+        }
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_adopted_fork_is_published_as_reorg() {
+        let node = NodeHandle::spawn(Node::new("A", 1).unwrap());
+        let mut fork = node.snapshot().blockchain.clone();
+        node.update(|n| n.add_block(vec![payment("B")]).map(|_| ()))
+            .await
+            .unwrap()
+            .unwrap();
+        fork.add_block(vec![payment("C")]).unwrap();
+        fork.add_block(vec![]).unwrap();
+
+        let old_tip = node.snapshot().blockchain.blocks()[1].hash.clone();
+        let mut events = node.subscribe();
+        let candidate = fork.clone();
+        assert!(node.update(|n| n.adopt_chain(candidate)).await.unwrap());
+        let received: Vec<_> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert_eq!(received.len(), 3);
+        assert_eq!(
+            received[0],
+            NodeEvent::Reorg {
+                fork_height: 0,
+                old_tip,
+                new_tip: fork.blocks()[2].hash.clone(),
+            }
+        );
+        assert!(matches!(&received[2], NodeEvent::NewBlock { block } if block.index == 2));
+    }
+
+    #[test]
+    fn test_filter_by_type_and_address() {
+        let filter = EventFilter::parse(Some("new_transaction,mining"), Some("B".into())).unwrap();
+        let to = |address| NodeEvent::NewTransaction {
+            transaction: payment(address),
+        };
+        assert!(filter.matches(&to("B")));
+        assert!(!filter.matches(&to("C")));
+        assert!(filter.matches(&NodeEvent::Mining {
+            status: MiningStatus::Started,
+            block_hash: None,
+        }));
+        assert!(!filter.matches(&NodeEvent::PeerDisconnected {
+            address: "1".into(),
+            node_id: "n".into(),
+        }));
+
+        assert!(EventFilter::parse(None, None).unwrap().matches(&to("C")));
+        assert!(matches!(
+            EventFilter::parse(Some("new_block,blocks"), None),
+            Err(Error::InvalidEventType(name)) if name == "blocks"
+        ));
+    }
+}
@@ -1,6 +1,17 @@
+pub mod actor;
+pub mod address_index;
 pub mod api;
 pub mod block;
 pub mod blockchain;
+pub mod clock;
 pub mod config;
+pub mod crypto;
 pub mod errors;
+pub mod events;
+pub mod mempool;
+pub mod network;
 pub mod node;
+pub mod script;
+#[cfg(test)]
+mod test_utils;
+pub mod wallet;
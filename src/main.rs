@@ -1,18 +1,31 @@
+mod actor;
+mod address_index;
 mod api;
 mod block;
 mod blockchain;
+mod clock;
 mod config;
+mod crypto;
 mod errors;
+mod events;
+mod mempool;
+mod network;
 mod node;
+mod script;
+#[cfg(test)]
+mod test_utils;
+mod wallet;
 
-use std::sync::{Arc, Mutex};
-
+use actor::NodeHandle;
 use clap::Parser;
 use errors::Result;
 use node::Node;
 use tracing_subscriber::EnvFilter;
 
-use crate::{api::start_http_server, config::Config};
+use crate::{
+    api::start_http_server,
+    config::{Command, Config},
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -24,11 +37,17 @@ async fn main() -> Result<()> {
         .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
         .compact()
         .init();
-    let conf = Config::try_parse()?;
+    let mut conf = Config::try_parse()?;
+    if let Some(Command::Wallet(args)) = conf.command.take() {
+        return wallet::run(args).await;
+    }
 
     tracing::info!("Logger initialized");
 
-    let node = Arc::new(Mutex::new(Node::new("A", 4)?));
+    let mut node = Node::new("A", 4)?;
+    node.network_id = conf.network_id.clone();
+    node.encryption = conf.encryption;
+    let node = NodeHandle::spawn(node);
 
     start_http_server(node, conf).await?;
     Ok(())
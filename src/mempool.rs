@@ -0,0 +1,129 @@
+//! Transactions waiting to be mined, in the order they arrived in.
+//!
+//! The id of every transaction is computed once when it enters, so that
+//! lookups by id, e.g. for peers announcing or requesting transactions,
+//! don't hash the whole mempool.
+
+use crate::block::Transaction;
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::ops::Deref;
+
+#[derive(Debug, Clone, Default)]
+pub struct Mempool {
+    transactions: Vec<Transaction>,
+    /// Id of the transaction at the same position.
+    ids: Vec<String>,
+    positions: HashMap<String, usize>,
+}
+
+impl Mempool {
+    pub fn push(&mut self, transaction: Transaction) {
+        let id = transaction.id();
+        self.positions.insert(id.clone(), self.transactions.len());
+        self.ids.push(id);
+        self.transactions.push(transaction);
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.positions.contains_key(id)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Transaction> {
+        self.positions.get(id).map(|&i| &self.transactions[i])
+    }
+
+    /// Transactions with their ids.
+    pub fn with_ids(&self) -> impl Iterator<Item = (&str, &Transaction)> {
+        self.ids.iter().map(String::as_str).zip(&self.transactions)
+    }
+
+    /// Keeps the transactions `keep` selects by id and transaction, visiting
+    /// them in order.
+    pub fn retain(&mut self, mut keep: impl FnMut(&str, &Transaction) -> bool) {
+        let transactions = std::mem::take(&mut self.transactions);
+        let ids = std::mem::take(&mut self.ids);
+        self.positions.clear();
+        for (id, tx) in ids.into_iter().zip(transactions) {
+            if keep(&id, &tx) {
+                self.positions.insert(id.clone(), self.transactions.len());
+                self.ids.push(id);
+                self.transactions.push(tx);
+            }
+        }
+    }
+}
+
+impl Deref for Mempool {
+    type Target = [Transaction];
+
+    fn deref(&self) -> &[Transaction] {
+        &self.transactions
+    }
+}
+
+impl From<Vec<Transaction>> for Mempool {
+    fn from(transactions: Vec<Transaction>) -> Self {
+        transactions.into_iter().collect()
+    }
+}
+
+impl FromIterator<Transaction> for Mempool {
+    fn from_iter<I: IntoIterator<Item = Transaction>>(transactions: I) -> Self {
+        let mut mempool = Self::default();
+        for tx in transactions {
+            mempool.push(tx);
+        }
+        mempool
+    }
+}
+
+impl PartialEq for Mempool {
+    fn eq(&self, other: &Self) -> bool {
+        self.ids == other.ids
+    }
+}
+
+impl PartialEq<Vec<Transaction>> for Mempool {
+    fn eq(&self, other: &Vec<Transaction>) -> bool {
+        self.transactions == *other
+    }
+}
+
+impl Serialize for Mempool {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.transactions.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payment(amount: i64) -> Transaction {
+        Transaction {
+            from: "A".into(),
+            to: "B".into(),
+            amount,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_transactions_are_found_by_id() {
+        let mut mempool: Mempool = vec![payment(1), payment(2)].into();
+        mempool.push(payment(3));
+
+        let id = payment(2).id();
+        assert!(mempool.contains(&id));
+        assert_eq!(mempool.get(&id), Some(&payment(2)));
+        assert!(!mempool.contains(&payment(4).id()));
+
+        mempool.retain(|_, tx| tx.amount != 2);
+        assert_eq!(mempool, vec![payment(1), payment(3)]);
+        assert!(!mempool.contains(&id));
+        assert_eq!(mempool.get(&payment(3).id()), Some(&payment(3)));
+        let ids: Vec<_> = mempool.with_ids().map(|(id, _)| id.to_owned()).collect();
+        assert_eq!(ids, vec![payment(1).id(), payment(3).id()]);
+    }
+}
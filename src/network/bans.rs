@@ -0,0 +1,467 @@
+//! Scoring peers that break the rules and banning their node ids or IP
+//! addresses.
+
+use crate::errors::{Error, Result, ValidationError};
+use crate::node::Node;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use tracing::{instrument, warn};
+
+/// Score at which a peer gets banned.
+pub const BAN_THRESHOLD: u32 = 100;
+/// Seconds an automatic ban lasts.
+pub const BAN_DURATION: u64 = 24 * 60 * 60;
+/// Seconds after which a misbehavior score drops by a point, so that only
+/// peers misbehaving faster than that get banned.
+pub const SCORE_DECAY_INTERVAL: u64 = 60;
+
+/// Kind of rule a peer broke.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// Block hash doesn't match its contents or the difficulty.
+    InvalidProofOfWork,
+    /// Signatures not made with the key of the node id they claim.
+    Impersonation,
+    /// Blocks or headers that don't link to their predecessors.
+    BadLinkage,
+    /// Messages or blocks over the size limits.
+    OversizedPayload,
+    /// Messages that can't be decoded.
+    MalformedMessage,
+    /// Blocks or transactions breaking consensus rules other than the above.
+    InvalidData,
+    /// Messages the peer had no reason to send, like transactions this node
+    /// rejects or more of them than it accepts from a peer.
+    Spam,
+}
+
+impl Misbehavior {
+    pub fn score(self) -> u32 {
+        match self {
+            Self::InvalidProofOfWork | Self::Impersonation => 100,
+            Self::BadLinkage | Self::OversizedPayload => 50,
+            Self::MalformedMessage => 20,
+            Self::InvalidData | Self::Spam => 10,
+        }
+    }
+
+    /// Misbehavior a peer is blamed for when its message fails with `error`,
+    /// `None` for errors that are not the peer's fault. Transactions whose
+    /// nonce or balance conflicts with the chain tip or the mempool are not,
+    /// honest peers relay them whenever their view differs from this node's.
+    pub fn of(error: &Error) -> Option<Self> {
+        Some(match error {
+            Error::InvalidBlock(_, rule) => match rule {
+                ValidationError::MalformedHash(_)
+                | ValidationError::InvalidHash(_)
+                | ValidationError::UnsatisfiedDifficulty(_) => Self::InvalidProofOfWork,
+                ValidationError::InvalidPreviousHash(..)
+                | ValidationError::IndexNotContinuous(..) => Self::BadLinkage,
+                ValidationError::TooManyTransactions(..) | ValidationError::BlockTooLarge(..) => {
+                    Self::OversizedPayload
+                }
+                _ => Self::InvalidData,
+            },
+            Error::HeadersNotLinked => Self::BadLinkage,
+            Error::MessageTooLarge(..) => Self::OversizedPayload,
+            Error::InvalidMessage(_) => Self::MalformedMessage,
+            Error::HandshakeRequired(_)
+            | Error::RelayLimitExceeded(..)
+            | Error::TransactionNotSigned(_)
+            | Error::InvalidAmount(..)
+            | Error::BalanceOverflow(_) => Self::Spam,
+            Error::InvalidPeerSignature(_) => Self::Impersonation,
+            Error::InvalidPublicKey(_)
+            | Error::InvalidTransactionSignature(_)
+            | Error::InvalidScript(_)
+            | Error::ScriptFailed(..)
+            | Error::InvalidMultisigPolicy(_)
+            | Error::KeyNotInMultisig(_)
+            | Error::InsufficientSignatures(..) => Self::InvalidData,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ban {
+    pub ip: String,
+    /// Node id of the peer, for bans of peers that completed the handshake.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    /// Unix timestamp the ban expires at.
+    pub until: u64,
+    pub reason: String,
+}
+
+impl Ban {
+    /// Node id of a banned peer that completed the handshake, IP address
+    /// otherwise, which the ban is kept under.
+    fn key(&self) -> &str {
+        self.node_id.as_deref().unwrap_or(&self.ip)
+    }
+}
+
+/// Misbehavior points of a peer, which lose one every
+/// [`SCORE_DECAY_INTERVAL`] seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Score {
+    points: u32,
+    /// Time the points were last decayed at.
+    since: u64,
+}
+
+impl Score {
+    fn decayed(self, now: u64) -> Self {
+        let intervals = now.saturating_sub(self.since) / SCORE_DECAY_INTERVAL;
+        let points = self
+            .points
+            .saturating_sub(u32::try_from(intervals).unwrap_or(u32::MAX));
+        let since = match points {
+            0 => now,
+            _ => self.since + intervals * SCORE_DECAY_INTERVAL,
+        };
+        Self { points, since }
+    }
+}
+
+/// Banned IP addresses and node ids, stored as JSON when opened from a
+/// file, and the misbehavior scores of the ones not banned yet.
+///
+/// Scores and bans of peers that completed the handshake are kept by node
+/// id, so they follow the peer when it comes back from another address and
+/// don't hit other nodes behind the same IP address.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BanList {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    bans: BTreeMap<String, Ban>,
+    #[serde(skip)]
+    scores: HashMap<String, Score>,
+}
+
+/// IP address of a peer address, or the whole address if it has no port.
+pub fn host(address: &str) -> String {
+    match address.parse::<SocketAddr>() {
+        Ok(address) => address.ip().to_string(),
+        Err(_) => address.to_owned(),
+    }
+}
+
+/// Checks `ip` is an IP address and brings it to its canonical form.
+pub fn parse_ip(ip: &str) -> Result<String> {
+    ip.parse::<IpAddr>()
+        .map(|ip| ip.to_string())
+        .map_err(|_| Error::InvalidIpAddress(ip.to_owned()))
+}
+
+/// IP address or node id a ban is kept under, see [`BanList::unban`].
+pub fn parse_key(key: &str) -> Result<String> {
+    match crate::crypto::parse_public_key(key) {
+        Ok(_) => Ok(key.to_owned()),
+        Err(_) => parse_ip(key),
+    }
+}
+
+impl BanList {
+    /// Loads bans from `path` or starts with none if the file doesn't exist.
+    #[instrument(level = "debug")]
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut bans = if path.exists() {
+            serde_json::from_slice(&std::fs::read(path)?)?
+        } else {
+            Self::default()
+        };
+        bans.path = Some(path.to_owned());
+        // Bans of identified peers used to be kept by IP address
+        bans.bans = std::mem::take(&mut bans.bans)
+            .into_values()
+            .map(|ban| (ban.key().to_owned(), ban))
+            .collect();
+        Ok(bans)
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_banned(&self, ip: &str, now: u64) -> bool {
+        self.bans
+            .get(ip)
+            .is_some_and(|ban| ban.node_id.is_none() && ban.until > now)
+    }
+
+    pub fn is_identity_banned(&self, node_id: &str, now: u64) -> bool {
+        self.bans
+            .get(node_id)
+            .is_some_and(|ban| ban.node_id.as_deref() == Some(node_id) && ban.until > now)
+    }
+
+    /// Bans that did not expire yet.
+    pub fn active(&self, now: u64) -> Vec<Ban> {
+        self.bans
+            .values()
+            .filter(|ban| ban.until > now)
+            .cloned()
+            .collect()
+    }
+
+    pub fn ban(&mut self, ip: &str, until: u64, reason: &str) -> Result<Ban> {
+        self.insert(Ban {
+            ip: ip.to_owned(),
+            node_id: None,
+            until,
+            reason: reason.to_owned(),
+        })
+    }
+
+    fn insert(&mut self, ban: Ban) -> Result<Ban> {
+        self.scores.remove(ban.key());
+        self.bans.insert(ban.key().to_owned(), ban.clone());
+        self.save()?;
+        Ok(ban)
+    }
+
+    /// Lifts the ban of an IP address or node id, returns whether it was
+    /// banned.
+    pub fn unban(&mut self, key: &str, now: u64) -> Result<bool> {
+        let banned = self.bans.get(key).is_some_and(|ban| ban.until > now);
+        self.bans.remove(key);
+        self.bans.retain(|_, ban| ban.until > now);
+        self.save()?;
+        Ok(banned)
+    }
+
+    /// Adds the misbehavior to the score of the peer with `node_id`, or of
+    /// `ip` for peers not identified yet, and bans the node id, or the IP
+    /// address, once the score reaches [`BAN_THRESHOLD`].
+    pub fn record(
+        &mut self,
+        ip: &str,
+        node_id: Option<&str>,
+        misbehavior: Misbehavior,
+        now: u64,
+    ) -> Result<Option<Ban>> {
+        let key = node_id.unwrap_or(ip);
+        let score = self.scores.entry(key.to_owned()).or_insert(Score {
+            points: 0,
+            since: now,
+        });
+        *score = score.decayed(now);
+        score.points = score.points.saturating_add(misbehavior.score());
+        if score.points < BAN_THRESHOLD {
+            return Ok(None);
+        }
+        let reason = format!("misbehavior score {}, last {misbehavior:?}", score.points);
+        self.insert(Ban {
+            ip: ip.to_owned(),
+            node_id: node_id.map(str::to_owned),
+            until: now.saturating_add(BAN_DURATION),
+            reason,
+        })
+        .map(Some)
+    }
+
+    /// Misbehavior score of a node id or of an IP address at `now`.
+    #[allow(unused)]
+    pub fn score(&self, key: &str, now: u64) -> u32 {
+        self.scores
+            .get(key)
+            .map_or(0, |score| score.decayed(now).points)
+    }
+}
+
+impl Node {
+    /// Scores the peer at `address` for a message that failed with `error`,
+    /// returns the ban if it got banned.
+    pub fn record_misbehavior(&mut self, address: &str, error: &Error) -> Option<Ban> {
+        let misbehavior = Misbehavior::of(error)?;
+        let ip = host(address);
+        warn!("Peer {address} misbehaved: {misbehavior:?}, {error}");
+        let now = self.blockchain.clock().now();
+        let node_id = self.peers.get(address).map(|peer| peer.node_id.clone());
+        match self.bans.record(&ip, node_id.as_deref(), misbehavior, now) {
+            Ok(ban) => ban,
+            Err(e) => {
+                warn!("Failed to store ban of {ip}: {e}");
+                None
+            }
+        }
+    }
+
+    pub fn is_banned(&self, address: &str) -> bool {
+        self.bans
+            .is_banned(&host(address), self.blockchain.clock().now())
+    }
+
+    /// Closes the connections `ban` applies to, the ones of the banned node
+    /// or all of the banned IP address.
+    pub fn disconnect_banned(&self, ban: &Ban) {
+        match &ban.node_id {
+            Some(node_id) => {
+                for peer in self.peers.values().filter(|p| p.node_id == *node_id) {
+                    self.connections.disconnect(&peer.address);
+                }
+            }
+            None => self.connections.disconnect_host(&ban.ip),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use crate::clock::ManualClock;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{name}-{}.json", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_errors_map_to_misbehavior() {
+        let clock = ManualClock::new(crate::block::MIN_BLOCK_TIMESTAMP);
+        let mut block = Block::new(1, "0".repeat(64), vec![], &clock);
+        block.hash = "f".repeat(64);
+        let error = block.validate(&"0".repeat(64), 1).unwrap_err();
+        assert_eq!(
+            Misbehavior::of(&error),
+            Some(Misbehavior::InvalidProofOfWork)
+        );
+
+        let error = block.validate(&"1".repeat(64), 1).unwrap_err();
+        assert_eq!(Misbehavior::of(&error), Some(Misbehavior::BadLinkage));
+        assert_eq!(
+            Misbehavior::of(&Error::MessageTooLarge(2, 1)),
+            Some(Misbehavior::OversizedPayload)
+        );
+        assert_eq!(
+            Misbehavior::of(&Error::HandshakeRequired("a".into())),
+            Some(Misbehavior::Spam)
+        );
+        assert_eq!(Misbehavior::of(&Error::PeerNotConnected("a".into())), None);
+    }
+
+    #[test]
+    fn test_scores_add_up_to_a_ban() {
+        let mut bans = BanList::default();
+        for _ in 0..4 {
+            assert_eq!(
+                bans.record("1.2.3.4", None, Misbehavior::MalformedMessage, 10)
+                    .unwrap(),
+                None
+            );
+        }
+        assert_eq!(bans.score("1.2.3.4", 10), 80);
+        assert!(!bans.is_banned("1.2.3.4", 10));
+
+        let ban = bans
+            .record("1.2.3.4", None, Misbehavior::MalformedMessage, 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(ban.until, 10 + BAN_DURATION);
+        assert!(bans.is_banned("1.2.3.4", 10));
+        assert_eq!(bans.score("1.2.3.4", 10), 0);
+        assert!(!bans.is_banned("5.6.7.8", 10));
+
+        // Bans are temporary
+        assert!(!bans.is_banned("1.2.3.4", 10 + BAN_DURATION));
+        assert!(bans.active(10 + BAN_DURATION).is_empty());
+    }
+
+    #[test]
+    fn test_identified_peers_are_scored_and_banned_by_node_id() {
+        let mut bans = BanList::default();
+        let spam = Misbehavior::Spam;
+        for ip in (0..9).map(|i| format!("10.0.0.{i}")) {
+            assert_eq!(bans.record(&ip, Some("n0de"), spam, 10).unwrap(), None);
+        }
+        assert_eq!(bans.score("n0de", 10), 90);
+        assert_eq!(bans.score("10.0.0.1", 10), 0);
+
+        let ban = bans
+            .record("10.0.0.9", Some("n0de"), spam, 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(ban.node_id.as_deref(), Some("n0de"));
+        assert!(bans.is_identity_banned("n0de", 10));
+        // Other nodes behind the same IP address are not banned
+        assert!(!bans.is_banned("10.0.0.9", 10));
+        assert!(!bans.is_identity_banned("other", 10));
+        assert!(!bans.is_identity_banned("n0de", 10 + BAN_DURATION));
+
+        assert!(bans.unban("n0de", 10).unwrap());
+        assert!(!bans.is_identity_banned("n0de", 10));
+    }
+
+    #[test]
+    fn test_scores_decay() {
+        let mut bans = BanList::default();
+        let malformed = Misbehavior::MalformedMessage;
+        for _ in 0..4 {
+            bans.record("1.2.3.4", None, malformed, 0).unwrap();
+        }
+        let later = 30 * SCORE_DECAY_INTERVAL + SCORE_DECAY_INTERVAL / 2;
+        assert_eq!(bans.score("1.2.3.4", later), 50);
+
+        // Peers misbehaving once in a while are never banned
+        for i in 1..=20 {
+            let now = later + i * 20 * SCORE_DECAY_INTERVAL;
+            assert_eq!(bans.record("1.2.3.4", None, malformed, now).unwrap(), None);
+        }
+        assert_eq!(bans.score("1.2.3.4", u64::MAX), 0);
+    }
+
+    #[test]
+    fn test_conflicting_transactions_are_not_misbehavior() {
+        let nonce = Error::InvalidTransactionNonce("a".into(), 1, 2);
+        let balance = Error::InsufficientBalance("a".into(), 1, 2);
+        assert_eq!(Misbehavior::of(&nonce), None);
+        assert_eq!(Misbehavior::of(&balance), None);
+        let in_block =
+            Error::InvalidBlock(1, ValidationError::InvalidTransaction(0, Box::new(nonce)));
+        assert_eq!(Misbehavior::of(&in_block), Some(Misbehavior::InvalidData));
+    }
+
+    #[test]
+    fn test_invalid_proof_of_work_bans_at_once() {
+        let mut bans = BanList::default();
+        assert!(
+            bans.record("::1", None, Misbehavior::InvalidProofOfWork, 0)
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_ban_list_is_persisted() {
+        let path = temp_path("bans");
+        let mut bans = BanList::open(&path).unwrap();
+        bans.ban("1.2.3.4", 100, "manual").unwrap();
+        bans.ban("5.6.7.8", 100, "manual").unwrap();
+        assert!(bans.unban("5.6.7.8", 0).unwrap());
+        assert!(!bans.unban("5.6.7.8", 0).unwrap());
+
+        let reopened = BanList::open(&path).unwrap();
+        assert_eq!(reopened.active(0), bans.active(0));
+        assert_eq!(reopened.active(0)[0].ip, "1.2.3.4");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_host_and_ip_parsing() {
+        assert_eq!(host("127.0.0.1:4000"), "127.0.0.1");
+        assert_eq!(host("[::1]:4000"), "::1");
+        assert_eq!(host("node-1"), "node-1");
+        assert_eq!(parse_ip("::0001").unwrap(), "::1");
+        assert!(matches!(
+            parse_ip("localhost"),
+            Err(Error::InvalidIpAddress(_))
+        ));
+    }
+}
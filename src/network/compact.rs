@@ -0,0 +1,561 @@
+//! Compact block relay.
+//!
+//! New blocks are announced as their header and a short id of every
+//! transaction. Peers usually hold most of the transactions in their mempool
+//! already, rebuild the block from it and only request the ones they miss.
+//! Short ids are salted by the sender, so that no one can craft transactions
+//! colliding with others in advance. A block that can't be rebuilt, e.g.
+//! because of a collision, is requested in full. Peers not known to have
+//! all transactions of a block are sent the full block right away.
+
+use super::{CompactBlock, Envelope, Inventory, Message};
+use crate::block::{Block, BlockHeader, MAX_TRANSACTIONS_PER_BLOCK, Transaction};
+use crate::errors::{Error, Result, ValidationError};
+use crate::node::Node;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tracing::{debug, info};
+
+/// Most compact blocks waiting for missing transactions at once.
+pub const MAX_PENDING_BLOCKS: usize = 16;
+/// Most of those blocks announced by a single peer.
+pub const MAX_PENDING_BLOCKS_PER_PEER: usize = 4;
+
+/// Id of a transaction in a compact block with `salt`, the first six bytes
+/// of the hash of the salt and the transaction id.
+pub fn short_id(salt: u64, id: &str) -> u64 {
+    let hash = Sha256::new()
+        .chain_update(salt.to_be_bytes())
+        .chain_update(id.as_bytes())
+        .finalize();
+    let mut bytes = [0; 8];
+    bytes[2..].copy_from_slice(&hash[..6]);
+    u64::from_be_bytes(bytes)
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block, salt: u64) -> Self {
+        Self {
+            header: block.header(),
+            salt,
+            short_ids: block
+                .transactions
+                .iter()
+                .map(|tx| short_id(salt, &tx.id()))
+                .collect(),
+        }
+    }
+}
+
+/// How the compact blocks a node received were rebuilt.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct CompactBlockStats {
+    pub received: u64,
+    /// Rebuilt from the mempool alone.
+    pub reconstructed: u64,
+    /// Rebuilt after fetching the missing transactions.
+    pub fetched: u64,
+    /// Requested in full because they could not be rebuilt.
+    pub failed: u64,
+}
+
+impl CompactBlockStats {
+    /// Share of received blocks rebuilt from the mempool alone.
+    pub fn success_rate(&self) -> Option<f64> {
+        (self.received > 0).then(|| self.reconstructed as f64 / self.received as f64)
+    }
+}
+
+/// Compact block waiting for the transactions missing from the mempool.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingBlock {
+    header: BlockHeader,
+    transactions: Vec<Option<Transaction>>,
+    /// Peer the missing transactions were requested from.
+    from: String,
+    received_at: u64,
+}
+
+impl PendingBlock {
+    fn missing(&self) -> Vec<u32> {
+        (0..self.transactions.len() as u32)
+            .filter(|&i| self.transactions[i as usize].is_none())
+            .collect()
+    }
+}
+
+fn assemble(header: BlockHeader, transactions: Vec<Transaction>) -> Option<Block> {
+    let block = Block {
+        index: header.index,
+        timestamp: header.timestamp,
+        previous_hash: header.previous_hash,
+        hash: header.hash,
+        transactions,
+        nonce: header.nonce,
+    };
+    (block.compute_hash() == block.hash).then_some(block)
+}
+
+impl Node {
+    /// Announces the chain tip to the peers, except to `except`. Peers known
+    /// to have all its transactions get a compact block, the others the full
+    /// block, which they would have to fetch anyway.
+    pub(crate) fn announce_compact_block(&self, except: &str) -> Vec<Envelope> {
+        let block = self.blockchain.blocks().last().unwrap();
+        let ids: Vec<_> = block.transactions.iter().map(Transaction::id).collect();
+        let compact = CompactBlock::new(block, rand::random());
+        self.peers
+            .iter()
+            .filter(|(p, _)| *p != except)
+            .map(|(p, info)| {
+                let message = match ids.iter().all(|id| info.known_inventory.contains(id)) {
+                    true => Message::CompactBlock(compact.clone()),
+                    false => Message::Block(block.clone()),
+                };
+                Envelope::new(p, message)
+            })
+            .collect()
+    }
+
+    /// Rebuilds the block from the mempool, requesting the transactions
+    /// missing from it. Only blocks extending a chain block are rebuilt, the
+    /// chain is synced through headers first otherwise.
+    pub(crate) fn handle_compact_block(
+        &mut self,
+        from: &str,
+        compact: CompactBlock,
+    ) -> Result<Vec<Envelope>> {
+        let CompactBlock {
+            header,
+            salt,
+            short_ids,
+        } = compact;
+        if self.blockchain.position(&header.hash).is_some()
+            || self.orphans.contains(&header.hash)
+            || self.pending_blocks.contains_key(&header.hash)
+        {
+            return Ok(vec![]);
+        }
+        if short_ids.len() > MAX_TRANSACTIONS_PER_BLOCK {
+            Err(Error::InvalidBlock(
+                header.index,
+                ValidationError::TooManyTransactions(short_ids.len(), MAX_TRANSACTIONS_PER_BLOCK),
+            ))?;
+        }
+        let Some(parent) = self.blockchain.position(&header.previous_hash) else {
+            return Ok(vec![Envelope::new(
+                from,
+                Message::GetHeaders(self.locator()),
+            )]);
+        };
+        if header.index != parent as u64 + 1 {
+            Err(Error::InvalidBlock(
+                header.index,
+                ValidationError::IndexNotContinuous(header.index, parent as u64 + 1),
+            ))?;
+        }
+        // The hash covers the transactions, it is only verified once the
+        // block is rebuilt, but it must claim the work before anything is
+        // requested for the block
+        let difficulty = self.blockchain.difficulty();
+        if header.hash.len() != 64 || !header.hash.bytes().take(difficulty).all(|b| b == b'0') {
+            Err(Error::InvalidBlock(
+                header.index,
+                ValidationError::UnsatisfiedDifficulty(difficulty),
+            ))?;
+        }
+        self.compact_stats.received += 1;
+
+        // Short ids shared by several mempool transactions match none of them
+        let mut mempool: HashMap<u64, Option<&Transaction>> = HashMap::new();
+        for (id, tx) in self.mempool.with_ids() {
+            mempool
+                .entry(short_id(salt, id))
+                .and_modify(|t| *t = None)
+                .or_insert(Some(tx));
+        }
+        let pending = PendingBlock {
+            transactions: short_ids
+                .iter()
+                .map(|id| mempool.get(id).copied().flatten().cloned())
+                .collect(),
+            header,
+            from: from.to_owned(),
+            received_at: self.blockchain.clock().now(),
+        };
+        let missing = pending.missing();
+        if missing.is_empty() {
+            return self.complete_block(from, pending, false);
+        }
+        debug!(
+            "Block {} misses {} of {} transactions",
+            pending.header.hash,
+            missing.len(),
+            short_ids.len()
+        );
+        let from_peer = self.pending_blocks.values().filter(|p| p.from == from);
+        if from_peer.count() >= MAX_PENDING_BLOCKS_PER_PEER {
+            self.evict_pending_block(|p| p.from == from);
+        } else if self.pending_blocks.len() >= MAX_PENDING_BLOCKS {
+            self.evict_pending_block(|_| true);
+        }
+        let hash = pending.header.hash.clone();
+        self.pending_blocks.insert(hash.clone(), pending);
+        Ok(vec![Envelope::new(
+            from,
+            Message::GetBlockTxn(hash, missing),
+        )])
+    }
+
+    /// Forgets the pending block received first among those `filter`
+    /// selects.
+    fn evict_pending_block(&mut self, filter: impl Fn(&PendingBlock) -> bool) {
+        if let Some(oldest) = self
+            .pending_blocks
+            .iter()
+            .filter(|(_, p)| filter(p))
+            .min_by_key(|(_, p)| p.received_at)
+            .map(|(hash, _)| hash.clone())
+        {
+            self.pending_blocks.remove(&oldest);
+        }
+    }
+
+    /// Fills a pending block with the transactions it missed, which only the
+    /// peer they were requested from may send.
+    pub(crate) fn handle_block_txn(
+        &mut self,
+        from: &str,
+        hash: &str,
+        transactions: Vec<Transaction>,
+    ) -> Result<Vec<Envelope>> {
+        let Some(pending) = self.pending_blocks.get(hash) else {
+            return Ok(vec![]);
+        };
+        if pending.from != from {
+            Err(Error::InvalidMessage(format!(
+                "transactions of block {hash} were requested from {}, not {from}",
+                pending.from
+            )))?;
+        }
+        let mut pending = self.pending_blocks.remove(hash).unwrap();
+        let missing = pending.missing();
+        if missing.len() != transactions.len() {
+            Err(Error::InvalidMessage(format!(
+                "{} transactions for block {hash} missing {}",
+                transactions.len(),
+                missing.len()
+            )))?;
+        }
+        for (index, tx) in missing.into_iter().zip(transactions) {
+            pending.transactions[index as usize] = Some(tx);
+        }
+        self.complete_block(from, pending, true)
+    }
+
+    fn complete_block(
+        &mut self,
+        from: &str,
+        pending: PendingBlock,
+        fetched: bool,
+    ) -> Result<Vec<Envelope>> {
+        let hash = pending.header.hash.clone();
+        let transactions = pending.transactions.into_iter().flatten().collect();
+        let Some(block) = assemble(pending.header, transactions) else {
+            info!("Failed to rebuild block {hash}, requesting it in full");
+            self.compact_stats.failed += 1;
+            return Ok(vec![Envelope::new(
+                from,
+                Message::GetData(vec![Inventory::block(&hash)]),
+            )]);
+        };
+        match fetched {
+            true => self.compact_stats.fetched += 1,
+            false => self.compact_stats.reconstructed += 1,
+        }
+        self.handle_block(from, block)
+    }
+
+    /// Transactions of a chain block at `indexes`, for a peer rebuilding it.
+    pub(crate) fn block_transactions(
+        &self,
+        hash: &str,
+        indexes: &[u32],
+    ) -> Result<Option<Message>> {
+        let Some(position) = self.blockchain.position(hash) else {
+            return Ok(None);
+        };
+        let block = &self.blockchain.blocks()[position];
+        let transactions = indexes
+            .iter()
+            .map(|&i| {
+                block.transactions.get(i as usize).cloned().ok_or_else(|| {
+                    Error::InvalidMessage(format!("block {hash} has no transaction {i}"))
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Some(Message::BlockTxn(hash.to_owned(), transactions)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::protocol::tests::{connect, exchange, nodes};
+
+    fn transaction(amount: i64) -> Transaction {
+        Transaction {
+            from: "A".into(),
+            to: "B".into(),
+            amount,
+            ..Default::default()
+        }
+    }
+
+    fn tip(node: &Node) -> &Block {
+        node.blockchain.blocks().last().unwrap()
+    }
+
+    #[test]
+    fn test_short_ids_depend_on_salt() {
+        let id = transaction(1).id();
+        assert_eq!(short_id(1, &id), short_id(1, &id));
+        assert_ne!(short_id(1, &id), short_id(2, &id));
+        assert!(short_id(1, &id) < 1 << 48);
+    }
+
+    #[test]
+    fn test_block_is_rebuilt_from_mempool() {
+        let (_, mut nodes) = nodes(2);
+        connect(&mut nodes, 0, 1);
+        for amount in 1..=2 {
+            let outbound = nodes[0]
+                .accept_transaction(transaction(amount), None)
+                .unwrap();
+            exchange(&mut nodes, 0, outbound);
+        }
+        assert_eq!(nodes[1].mempool.len(), 2);
+        nodes[0].mine_pending().unwrap();
+        let outbound = nodes[0].announce_tip();
+        assert!(matches!(
+            &outbound[0].message,
+            Message::CompactBlock(c) if c.short_ids.len() == 2
+        ));
+
+        // Compact block only, nothing is requested
+        assert_eq!(exchange(&mut nodes, 0, outbound), 1);
+        assert_eq!(tip(&nodes[1]), tip(&nodes[0]));
+        assert!(nodes[1].mempool.is_empty());
+        let stats = nodes[1].compact_stats;
+        assert_eq!((stats.received, stats.reconstructed), (1, 1));
+        assert_eq!(stats.success_rate(), Some(1.0));
+    }
+
+    #[test]
+    fn test_only_missing_transactions_are_fetched() {
+        let (_, mut nodes) = nodes(2);
+        connect(&mut nodes, 0, 1);
+        for amount in 1..=3 {
+            nodes[0].submit_transaction(transaction(amount)).unwrap();
+        }
+        nodes[1].submit_transaction(transaction(2)).unwrap();
+        nodes[0].mine_pending().unwrap();
+        let compact = CompactBlock::new(tip(&nodes[0]), 5);
+
+        let request = nodes[1]
+            .handle_message("0", Message::CompactBlock(compact))
+            .unwrap();
+        let hash = tip(&nodes[0]).hash.clone();
+        assert_eq!(
+            request,
+            vec![Envelope::new("0", Message::GetBlockTxn(hash, vec![0, 2]))]
+        );
+        let reply = nodes[0]
+            .handle_message("1", request[0].message.clone())
+            .unwrap();
+        assert!(matches!(
+            &reply[0].message,
+            Message::BlockTxn(_, txs) if *txs == vec![transaction(1), transaction(3)]
+        ));
+        exchange(&mut nodes, 0, reply);
+        assert_eq!(tip(&nodes[1]), tip(&nodes[0]));
+        let stats = nodes[1].compact_stats;
+        assert_eq!(
+            (stats.received, stats.reconstructed, stats.fetched),
+            (1, 0, 1)
+        );
+        assert_eq!(stats.success_rate(), Some(0.0));
+        assert!(nodes[1].pending_blocks.is_empty());
+    }
+
+    #[test]
+    fn test_peer_missing_transactions_is_sent_the_full_block() {
+        let (_, mut nodes) = nodes(2);
+        connect(&mut nodes, 0, 1);
+        nodes[0].submit_transaction(transaction(1)).unwrap();
+        nodes[0].mine_pending().unwrap();
+        let outbound = nodes[0].announce_tip();
+        assert_eq!(
+            outbound,
+            vec![Envelope::new("1", Message::Block(tip(&nodes[0]).clone()))]
+        );
+
+        assert_eq!(exchange(&mut nodes, 0, outbound), 1);
+        assert_eq!(tip(&nodes[1]), tip(&nodes[0]));
+        assert_eq!(nodes[1].compact_stats.received, 0);
+    }
+
+    #[test]
+    fn test_block_failing_to_rebuild_is_requested_in_full() {
+        let (_, mut nodes) = nodes(2);
+        connect(&mut nodes, 0, 1);
+        nodes[0].submit_transaction(transaction(1)).unwrap();
+        nodes[0].mine_pending().unwrap();
+        let mut compact = CompactBlock::new(tip(&nodes[0]), 9);
+        // Node 1 holds another transaction with the short id
+        nodes[1].submit_transaction(transaction(2)).unwrap();
+        compact.short_ids = vec![short_id(9, &transaction(2).id())];
+
+        let outbound = nodes[1]
+            .handle_message("0", Message::CompactBlock(compact))
+            .unwrap();
+        let hash = tip(&nodes[0]).hash.clone();
+        assert_eq!(
+            outbound,
+            vec![Envelope::new(
+                "0",
+                Message::GetData(vec![Inventory::block(&hash)])
+            )]
+        );
+        exchange(&mut nodes, 1, outbound);
+        assert_eq!(tip(&nodes[1]).hash, hash);
+        assert_eq!(nodes[1].compact_stats.failed, 1);
+    }
+
+    /// Block on top of the genesis block of `node` paying `amount`, which
+    /// peers don't have in their mempool.
+    fn block_paying(node: &Node, amount: i64) -> Block {
+        let genesis = &node.blockchain.blocks()[0];
+        let clock = node.blockchain.clock();
+        let mut block = Block::new(
+            1,
+            genesis.hash.clone(),
+            vec![transaction(amount)],
+            clock.as_ref(),
+        );
+        block.mine_block(1).unwrap();
+        block
+    }
+
+    #[test]
+    fn test_compact_block_is_checked_before_transactions_are_requested() {
+        let (_, mut nodes) = nodes(2);
+        connect(&mut nodes, 0, 1);
+        nodes[0].add_block(vec![transaction(1)]).unwrap();
+        let mut compact = CompactBlock::new(tip(&nodes[0]), 1);
+        compact.header.hash = "f".repeat(64);
+        assert!(matches!(
+            nodes[1].handle_message("0", Message::CompactBlock(compact)),
+            Err(Error::InvalidBlock(
+                1,
+                ValidationError::UnsatisfiedDifficulty(1)
+            ))
+        ));
+
+        let mut compact = CompactBlock::new(tip(&nodes[0]), 1);
+        compact.header.index = 2;
+        assert!(matches!(
+            nodes[1].handle_message("0", Message::CompactBlock(compact)),
+            Err(Error::InvalidBlock(
+                2,
+                ValidationError::IndexNotContinuous(2, 1)
+            ))
+        ));
+
+        // Parent is not in the chain, headers are synced first
+        nodes[0].add_block(vec![]).unwrap();
+        let compact = CompactBlock::new(tip(&nodes[0]), 1);
+        assert_eq!(
+            nodes[1]
+                .handle_message("0", Message::CompactBlock(compact))
+                .unwrap(),
+            vec![Envelope::new("0", Message::GetHeaders(nodes[1].locator()))]
+        );
+        assert_eq!(nodes[1].compact_stats.received, 0);
+        assert!(nodes[1].pending_blocks.is_empty());
+    }
+
+    #[test]
+    fn test_compact_block_claiming_work_it_lacks_is_not_connected() {
+        let (_, mut nodes) = nodes(2);
+        connect(&mut nodes, 0, 1);
+        nodes[0].submit_transaction(transaction(1)).unwrap();
+        nodes[1].submit_transaction(transaction(1)).unwrap();
+        nodes[0].mine_pending().unwrap();
+        let mut compact = CompactBlock::new(tip(&nodes[0]), 1);
+        // Satisfies the difficulty, but is not the hash of the block
+        compact.header.hash = format!("0{}", "f".repeat(63));
+
+        let outbound = nodes[1]
+            .handle_message("0", Message::CompactBlock(compact.clone()))
+            .unwrap();
+        assert_eq!(
+            outbound,
+            vec![Envelope::new(
+                "0",
+                Message::GetData(vec![Inventory::block(&compact.header.hash)])
+            )]
+        );
+        assert_eq!(nodes[1].compact_stats.failed, 1);
+        assert_eq!(nodes[1].blockchain.blocks().len(), 1);
+    }
+
+    #[test]
+    fn test_pending_blocks_are_limited_per_peer() {
+        let (clock, mut nodes) = nodes(3);
+        connect(&mut nodes, 0, 1);
+        connect(&mut nodes, 2, 1);
+        let blocks: Vec<_> = (1..=MAX_PENDING_BLOCKS_PER_PEER as i64 + 1)
+            .map(|amount| block_paying(&nodes[0], amount))
+            .collect();
+        for block in &blocks {
+            clock.advance(1);
+            let compact = CompactBlock::new(block, 1);
+            nodes[1]
+                .handle_message("0", Message::CompactBlock(compact))
+                .unwrap();
+        }
+        assert_eq!(nodes[1].pending_blocks.len(), MAX_PENDING_BLOCKS_PER_PEER);
+        assert!(!nodes[1].pending_blocks.contains_key(&blocks[0].hash));
+
+        let other = block_paying(&nodes[2], 100);
+        nodes[1]
+            .handle_message("2", Message::CompactBlock(CompactBlock::new(&other, 1)))
+            .unwrap();
+        assert_eq!(
+            nodes[1].pending_blocks.len(),
+            MAX_PENDING_BLOCKS_PER_PEER + 1
+        );
+    }
+
+    #[test]
+    fn test_missing_transactions_are_only_taken_from_the_requested_peer() {
+        let (_, mut nodes) = nodes(3);
+        connect(&mut nodes, 0, 1);
+        connect(&mut nodes, 2, 1);
+        let block = block_paying(&nodes[0], 1);
+        nodes[1]
+            .handle_message("0", Message::CompactBlock(CompactBlock::new(&block, 1)))
+            .unwrap();
+
+        let reply = Message::BlockTxn(block.hash.clone(), block.transactions.clone());
+        assert!(matches!(
+            nodes[1].handle_message("2", reply.clone()),
+            Err(Error::InvalidMessage(_))
+        ));
+        assert!(nodes[1].pending_blocks.contains_key(&block.hash));
+        nodes[1].handle_message("0", reply).unwrap();
+        assert_eq!(tip(&nodes[1]), &block);
+    }
+}
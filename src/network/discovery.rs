@@ -0,0 +1,677 @@
+//! Learning about other nodes through address gossip and picking the ones to
+//! connect to.
+
+use super::protocol::MAX_ADDRESSES;
+use super::{Envelope, Message, NetAddress};
+use crate::crypto::sha256_hex;
+use crate::errors::{Error, Result};
+use crate::node::Node;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, instrument, warn};
+
+/// Most addresses kept in the [`AddressBook`].
+pub const MAX_KNOWN_ADDRESSES: usize = 1_000;
+/// Seconds a received address is remembered and not relayed again.
+pub const ADDR_SEEN_TTL: u64 = 10 * 60;
+/// Addresses not seen alive for this many seconds are ignored.
+pub const MAX_ADDRESS_AGE: u64 = 7 * 24 * 60 * 60;
+/// Seconds an address may claim to be seen in the future.
+pub const MAX_ADDRESS_DRIFT: u64 = 10 * 60;
+/// Peers each new address is relayed to.
+pub const ADDR_RELAY_FANOUT: usize = 2;
+/// Only messages with at most this many addresses are relayed, larger ones
+/// answer a handshake and are not news.
+pub const MAX_RELAYED_ADDRESSES: usize = 10;
+/// Seconds before an address that was dialed is tried again.
+pub const DIAL_RETRY_INTERVAL: u64 = 10 * 60;
+/// Failed connection attempts in a row after which an address is forgotten.
+pub const MAX_ADDRESS_FAILURES: u32 = 10;
+
+/// What the node knows about an address it learned from peers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KnownAddress {
+    /// Unix timestamp the node at the address was last known to be alive.
+    pub last_seen: u64,
+    /// Peer the address was learned from.
+    pub source: String,
+    /// Node listening at the address, once known from a handshake or a
+    /// signed record.
+    pub node_id: Option<String>,
+    /// Latest record of the address signed by its node, the one gossiped.
+    pub record: Option<NetAddress>,
+    /// Handshakes completed with the node at the address.
+    pub successes: u32,
+    /// Failed connection attempts since the last successful one.
+    pub failures: u32,
+    /// Unix timestamp of the last completed handshake.
+    pub last_success: Option<u64>,
+    /// Unix timestamp of the last attempt to connect to it, not kept across
+    /// restarts so that a restarted node dials its peers at once.
+    #[serde(skip)]
+    pub last_attempt: Option<u64>,
+}
+
+impl KnownAddress {
+    fn new(last_seen: u64, source: &str) -> Self {
+        Self {
+            last_seen,
+            source: source.to_owned(),
+            node_id: None,
+            record: None,
+            successes: 0,
+            failures: 0,
+            last_success: None,
+            last_attempt: None,
+        }
+    }
+}
+
+/// Listening addresses of other nodes, connected or not, bounded to
+/// [`MAX_KNOWN_ADDRESSES`] by forgetting the least recently seen ones.
+///
+/// When opened from a file, connection results are stored at once and
+/// gossip is stored by [`AddressBook::save`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AddressBook {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    addresses: BTreeMap<String, KnownAddress>,
+    /// Whether there are changes not stored yet.
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl AddressBook {
+    /// Loads addresses from `path` or starts with none if the file doesn't
+    /// exist.
+    #[instrument(level = "debug")]
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut book = if path.exists() {
+            serde_json::from_slice(&std::fs::read(path)?)?
+        } else {
+            Self::default()
+        };
+        book.path = Some(path.to_owned());
+        Ok(book)
+    }
+
+    /// Stores the addresses if they changed since they were last stored.
+    pub fn save(&mut self) -> Result<()> {
+        if let Some(path) = &self.path
+            && self.dirty
+        {
+            std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Adds `address` learned from `source` or refreshes when it was last
+    /// seen, returns whether the address is new.
+    pub fn add(&mut self, address: &str, last_seen: u64, source: &str) -> bool {
+        self.dirty = true;
+        if let Some(known) = self.addresses.get_mut(address) {
+            known.last_seen = known.last_seen.max(last_seen);
+            return false;
+        }
+        if self.addresses.len() >= MAX_KNOWN_ADDRESSES {
+            let oldest = self
+                .addresses
+                .iter()
+                .min_by_key(|(_, known)| known.last_seen)
+                .map(|(address, _)| address.clone());
+            if let Some(oldest) = oldest {
+                self.addresses.remove(&oldest);
+            }
+        }
+        self.addresses
+            .insert(address.to_owned(), KnownAddress::new(last_seen, source));
+        true
+    }
+
+    /// Adds the signed `record` learned from `source` and keeps it for
+    /// gossip unless a newer one is known, returns whether the address is
+    /// new.
+    pub fn add_record(&mut self, record: &NetAddress, source: &str) -> bool {
+        let new = self.add(&record.address, record.last_seen, source);
+        if let Some(known) = self.addresses.get_mut(&record.address)
+            && known
+                .record
+                .as_ref()
+                .is_none_or(|r| r.last_seen < record.last_seen)
+        {
+            known.node_id = Some(record.node_id.clone());
+            known.record = Some(record.clone());
+        }
+        new
+    }
+
+    pub fn remove(&mut self, address: &str) -> bool {
+        self.dirty = true;
+        self.addresses.remove(address).is_some()
+    }
+
+    pub fn get(&self, address: &str) -> Option<&KnownAddress> {
+        self.addresses.get(address)
+    }
+
+    #[allow(unused)]
+    pub fn contains(&self, address: &str) -> bool {
+        self.addresses.contains_key(address)
+    }
+
+    #[allow(unused)]
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    #[allow(unused)]
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &KnownAddress)> {
+        self.addresses.iter()
+    }
+
+    pub fn mark_attempt(&mut self, address: &str, now: u64) {
+        if let Some(known) = self.addresses.get_mut(address) {
+            known.last_attempt = Some(now);
+        }
+    }
+
+    /// Records a completed handshake with node `node_id` at `address`,
+    /// adding the address if it is new.
+    pub fn record_success(&mut self, address: &str, node_id: &str, now: u64) -> Result<()> {
+        if !self.addresses.contains_key(address) {
+            self.add(address, now, address);
+        }
+        if let Some(known) = self.addresses.get_mut(address) {
+            if known.node_id.as_deref() != Some(node_id) {
+                // Another node took over the address
+                known.record = None;
+            }
+            known.node_id = Some(node_id.to_owned());
+            known.last_seen = now;
+            known.successes += 1;
+            known.failures = 0;
+            known.last_success = Some(now);
+        }
+        self.dirty = true;
+        self.save()
+    }
+
+    /// Records a failed attempt to connect to `address`, forgetting it after
+    /// [`MAX_ADDRESS_FAILURES`] failures in a row.
+    pub fn record_failure(&mut self, address: &str) -> Result<()> {
+        let Some(known) = self.addresses.get_mut(address) else {
+            return Ok(());
+        };
+        known.failures += 1;
+        if known.failures >= MAX_ADDRESS_FAILURES {
+            self.addresses.remove(address);
+        }
+        self.dirty = true;
+        self.save()
+    }
+
+    /// Up to `limit` signed records, the most recently seen first.
+    pub fn freshest(&self, limit: usize) -> Vec<NetAddress> {
+        let mut addresses: Vec<_> = self
+            .addresses
+            .values()
+            .filter_map(|known| known.record.clone())
+            .collect();
+        addresses.sort_by_key(|a| Reverse(a.last_seen));
+        addresses.truncate(limit);
+        addresses
+    }
+}
+
+impl Node {
+    /// Whether `address` is one this node listens on.
+    pub fn is_local_address(&self, address: &str) -> bool {
+        address == self.address || self.local_addresses.contains(address)
+    }
+
+    /// Remembers that dialing `address` led back to this node.
+    pub(crate) fn add_local_address(&mut self, address: &str) {
+        if self.local_addresses.insert(address.to_owned()) {
+            info!("Address {address} belongs to this node");
+        }
+        self.address_book.remove(address);
+    }
+
+    /// Records a completed handshake with the outbound peer at `address`.
+    pub(crate) fn record_connection_success(&mut self, address: &str, node_id: &str) {
+        let now = self.blockchain.clock().now();
+        if let Err(e) = self.address_book.record_success(address, node_id, now) {
+            warn!("Failed to store address {address}: {e}");
+        }
+    }
+
+    /// Records that dialing `address` or the handshake with it failed.
+    pub fn record_connection_failure(&mut self, address: &str) {
+        if let Err(e) = self.address_book.record_failure(address) {
+            warn!("Failed to store address {address}: {e}");
+        }
+    }
+
+    /// Stores the signed address records a peer told about and passes the
+    /// new ones on to a few other peers.
+    pub(crate) fn handle_addr(
+        &mut self,
+        from: &str,
+        addresses: Vec<NetAddress>,
+    ) -> Result<Vec<Envelope>> {
+        if addresses.len() > MAX_ADDRESSES {
+            Err(Error::InvalidMessage(format!(
+                "{} addresses, limit {MAX_ADDRESSES}",
+                addresses.len()
+            )))?;
+        }
+        let now = self.blockchain.clock().now();
+        self.seen_addresses
+            .retain(|_, seen_at| now.saturating_sub(*seen_at) < ADDR_SEEN_TTL);
+        if let Some(forged) = addresses.iter().find(|a| !a.verify()) {
+            warn!("Peer {from} sent a forged record of {}", forged.address);
+            Err(Error::InvalidPeerSignature(from.to_owned()))?;
+        }
+        let relay = addresses.len() <= MAX_RELAYED_ADDRESSES;
+        let mut news = vec![];
+        for address in addresses {
+            if address.node_id == self.node_id() {
+                // Only this node can have signed it
+                if address.address != self.address {
+                    self.add_local_address(&address.address);
+                }
+                continue;
+            }
+            if address.address.is_empty()
+                || self.is_local_address(&address.address)
+                || address.last_seen > now + MAX_ADDRESS_DRIFT
+                || address.last_seen + MAX_ADDRESS_AGE < now
+            {
+                continue;
+            }
+            self.address_book.add_record(&address, from);
+            if !self.seen_addresses.contains_key(&address.address) {
+                self.seen_addresses.insert(address.address.clone(), now);
+                news.push(address);
+            }
+        }
+        if !relay {
+            return Ok(vec![]);
+        }
+        Ok(self.relay_addresses(from, news))
+    }
+
+    /// Sends each record to [`ADDR_RELAY_FANOUT`] peers other than `from`
+    /// and the node it belongs to.
+    ///
+    /// Peers are picked by hashing the address so that every node relays an
+    /// address to the same peers until their connections change.
+    pub(crate) fn relay_addresses(&self, from: &str, addresses: Vec<NetAddress>) -> Vec<Envelope> {
+        let mut batches: BTreeMap<&str, Vec<NetAddress>> = BTreeMap::new();
+        for address in addresses {
+            let mut targets: Vec<_> = self
+                .peers
+                .values()
+                .filter(|peer| peer.address != from && peer.node_id != address.node_id)
+                .map(|peer| peer.address.as_str())
+                .collect();
+            targets.sort_by_cached_key(|peer| sha256_hex(format!("{}{peer}", address.address)));
+            for peer in targets.into_iter().take(ADDR_RELAY_FANOUT) {
+                batches.entry(peer).or_default().push(address.clone());
+            }
+        }
+        if !batches.is_empty() {
+            debug!("Relaying addresses to {} peers", batches.len());
+        }
+        batches
+            .into_iter()
+            .map(|(peer, addresses)| Envelope::new(peer, Message::Addr(addresses)))
+            .collect()
+    }
+
+    /// Whether the node has or is opening a connection to the node
+    /// listening at `address`, or waits to reconnect to it.
+    fn is_connected_to(&self, address: &str) -> bool {
+        self.peers
+            .values()
+            .any(|peer| peer.address == address || peer.listen_addr == address)
+            || self.handshakes.contains_key(address)
+            || self.reconnects.contains_key(address)
+    }
+
+    /// Addresses to dial to fill the free outbound slots and the seed nodes
+    /// once the node has no peers and nothing else to try.
+    ///
+    /// The best known addresses come first: the ones that failed least
+    /// often since their last success, then the most recently connected
+    /// and the most recently seen.
+    pub(crate) fn discovery_dials(&mut self) -> Vec<String> {
+        let now = self.blockchain.clock().now();
+        let outbound = self.peers.values().filter(|peer| !peer.inbound).count()
+            + self.handshakes.values().filter(|h| !h.inbound).count()
+            + self.reconnects.len();
+        let free = self
+            .connections
+            .limits()
+            .max_outbound
+            .saturating_sub(outbound);
+        if free == 0 {
+            return vec![];
+        }
+
+        let mut candidates: Vec<_> = self
+            .address_book
+            .iter()
+            .filter(|(address, known)| {
+                known
+                    .last_attempt
+                    .is_none_or(|at| now.saturating_sub(at) >= DIAL_RETRY_INTERVAL)
+                    && !self.is_local_address(address)
+                    && !self.is_connected_to(address)
+                    && !self.is_banned(address)
+            })
+            .map(|(address, known)| {
+                let rank = (
+                    known.failures,
+                    Reverse(known.last_success),
+                    Reverse(known.last_seen),
+                );
+                (rank, address.clone())
+            })
+            .collect();
+        candidates.sort();
+        let dials: Vec<_> = candidates
+            .into_iter()
+            .take(free)
+            .map(|(_, address)| address)
+            .collect();
+        for address in &dials {
+            self.address_book.mark_attempt(address, now);
+        }
+        if !dials.is_empty() || !self.peers.is_empty() || !self.handshakes.is_empty() {
+            return dials;
+        }
+        self.seeds
+            .iter()
+            .filter(|seed| !self.is_local_address(seed) && !self.is_banned(seed))
+            .take(free)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::MIN_BLOCK_TIMESTAMP;
+    use crate::clock::{Clock, ManualClock};
+    use crate::network::bans::BAN_DURATION;
+    use crate::network::identity::NodeIdentity;
+    use crate::network::protocol::tests::handshake;
+    use std::sync::Arc;
+
+    const NOW: u64 = MIN_BLOCK_TIMESTAMP;
+
+    fn node(clock: &Arc<ManualClock>, address: &str) -> Node {
+        let mut node = Node::with_clock(address, 1, clock.clone()).unwrap();
+        node.address = address.to_owned();
+        node
+    }
+
+    /// Records of `addresses`, each signed by a node of its own.
+    fn addr(addresses: &[&str], last_seen: u64) -> Message {
+        Message::Addr(
+            addresses
+                .iter()
+                .map(|a| NetAddress::signed(a, last_seen, &NodeIdentity::generate()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_address_book_is_bounded() {
+        let mut book = AddressBook::default();
+        for i in 0..MAX_KNOWN_ADDRESSES as u64 {
+            assert!(book.add(&format!("10.0.0.{i}:4000"), NOW + i, "seed"));
+        }
+        assert!(!book.add("10.0.0.0:4000", NOW + 5, "seed"));
+        assert_eq!(book.get("10.0.0.0:4000").unwrap().last_seen, NOW + 5);
+
+        // The least recently seen address makes room
+        assert!(book.add("new:4000", NOW, "seed"));
+        assert_eq!(book.len(), MAX_KNOWN_ADDRESSES);
+        assert!(!book.contains("10.0.0.1:4000"));
+        assert!(book.contains("10.0.0.999:4000"));
+        // Only signed records are gossiped
+        assert!(book.freshest(1).is_empty());
+    }
+
+    #[test]
+    fn test_new_addresses_are_relayed_once_to_few_peers() {
+        let clock = Arc::new(ManualClock::new(NOW));
+        let mut node = node(&clock, "a");
+        for peer in ["b", "c", "d", "e"] {
+            handshake(&mut node, peer, false);
+        }
+
+        let outbound = node.handle_message("b", addr(&["x", "y"], NOW)).unwrap();
+        assert!(node.address_book.contains("x"));
+        let relayed: usize = outbound
+            .iter()
+            .map(|e| match &e.message {
+                Message::Addr(addresses) => addresses.len(),
+                m => panic!("Unexpected message {m:?}"),
+            })
+            .sum();
+        assert_eq!(relayed, 2 * ADDR_RELAY_FANOUT);
+        assert!(outbound.iter().all(|e| e.to != "b"));
+
+        // Addresses seen before are not relayed again until they expire
+        assert!(
+            node.handle_message("c", addr(&["x"], NOW))
+                .unwrap()
+                .is_empty()
+        );
+        clock.advance(ADDR_SEEN_TTL);
+        assert_eq!(
+            node.handle_message("c", addr(&["x"], NOW)).unwrap().len(),
+            ADDR_RELAY_FANOUT
+        );
+    }
+
+    #[test]
+    fn test_stale_own_and_bulk_addresses_are_not_relayed() {
+        let clock = Arc::new(ManualClock::new(NOW + MAX_ADDRESS_AGE));
+        let mut node = node(&clock, "a");
+        handshake(&mut node, "b", false);
+        handshake(&mut node, "c", false);
+
+        let now = clock.now();
+        assert!(
+            node.handle_message("b", addr(&["old"], NOW - 1))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            node.handle_message("b", addr(&["future"], now + MAX_ADDRESS_DRIFT + 1))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            node.handle_message("b", addr(&["a", ""], now))
+                .unwrap()
+                .is_empty()
+        );
+        // Only the peers themselves are known
+        assert_eq!(node.address_book.len(), 2);
+
+        let bulk: Vec<_> = (0..=MAX_RELAYED_ADDRESSES).map(|i| i.to_string()).collect();
+        let bulk: Vec<_> = bulk.iter().map(String::as_str).collect();
+        assert!(
+            node.handle_message("b", addr(&bulk, now))
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(node.address_book.len(), MAX_RELAYED_ADDRESSES + 3);
+
+        let record = NetAddress::signed("x", now, &NodeIdentity::generate());
+        let too_many = vec![record; MAX_ADDRESSES + 1];
+        assert!(matches!(
+            node.handle_message("b", Message::Addr(too_many)),
+            Err(Error::InvalidMessage(_))
+        ));
+    }
+
+    #[test]
+    fn test_connection_to_itself_marks_address_as_local() {
+        let clock = Arc::new(ManualClock::new(NOW));
+        let mut node = node(&clock, "a");
+        handshake(&mut node, "b", false);
+        node.handle_message("b", addr(&["127.0.0.1:4000"], NOW))
+            .unwrap();
+        assert_eq!(node.discovery_dials(), vec!["127.0.0.1:4000".to_owned()]);
+
+        // Both ends of the connection are this node, each verack reaches the
+        // other end
+        let mut outbound = node.on_connected("127.0.0.1:4000", false);
+        outbound.extend(node.on_connected("127.0.0.1:5000", true));
+        let mut veracks = vec![];
+        for (from, to) in [("127.0.0.1:4000", 1), ("127.0.0.1:5000", 0)] {
+            let reply = node
+                .handle_message(from, outbound[to].message.clone())
+                .unwrap();
+            veracks.push(reply[0].message.clone());
+        }
+        assert!(
+            node.handle_message("127.0.0.1:5000", veracks.remove(0))
+                .is_err()
+        );
+        assert!(matches!(
+            node.handle_message("127.0.0.1:4000", veracks.remove(0)),
+            Err(Error::IncompatiblePeer(..))
+        ));
+        assert!(node.is_local_address("127.0.0.1:4000"));
+        assert!(!node.address_book.contains("127.0.0.1:4000"));
+        assert!(node.peers.keys().eq(["b"]));
+        node.handle_message("b", addr(&["127.0.0.1:4000"], NOW))
+            .unwrap();
+        assert!(!node.address_book.contains("127.0.0.1:4000"));
+    }
+
+    #[test]
+    fn test_own_record_marks_address_as_local() {
+        let clock = Arc::new(ManualClock::new(NOW));
+        let mut node = node(&clock, "a");
+        handshake(&mut node, "b", false);
+        let record = NetAddress::signed("10.0.0.1:4000", NOW, &node.identity);
+        node.handle_message("b", Message::Addr(vec![record]))
+            .unwrap();
+        assert!(node.is_local_address("10.0.0.1:4000"));
+    }
+
+    #[test]
+    fn test_forged_records_are_rejected() {
+        let clock = Arc::new(ManualClock::new(NOW));
+        let mut node = node(&clock, "a");
+        handshake(&mut node, "b", false);
+        let Message::Addr(mut records) = addr(&["x"], NOW) else {
+            unreachable!()
+        };
+        records[0].address = "y".into();
+        assert!(matches!(
+            node.handle_message("b", Message::Addr(records)),
+            Err(Error::InvalidPeerSignature(_))
+        ));
+        assert!(!node.address_book.contains("y"));
+    }
+
+    #[test]
+    fn test_dials_known_addresses_then_seeds() {
+        let clock = Arc::new(ManualClock::new(NOW));
+        let mut node = node(&clock, "a");
+        node.seeds = vec!["seed".into(), "a".into()];
+        assert_eq!(node.discovery_dials(), vec!["seed".to_owned()]);
+
+        node.address_book.add("old", NOW - 10, "b");
+        node.address_book.add("new", NOW, "b");
+        node.address_book.add("banned", NOW, "b");
+        node.bans.ban("banned", NOW + BAN_DURATION, "test").unwrap();
+        assert_eq!(
+            node.discovery_dials(),
+            vec!["new".to_owned(), "old".to_owned()]
+        );
+
+        // Recently dialed addresses wait before they are tried again
+        handshake(&mut node, "new", false);
+        assert!(node.discovery_dials().is_empty());
+        clock.advance(DIAL_RETRY_INTERVAL);
+        assert_eq!(node.discovery_dials(), vec!["old".to_owned()]);
+    }
+
+    #[test]
+    fn test_best_known_addresses_are_dialed_first() {
+        let clock = Arc::new(ManualClock::new(NOW));
+        let mut node = node(&clock, "a");
+        node.address_book.add("fresh", NOW, "b");
+        node.address_book.add("failing", NOW, "b");
+        node.address_book.add("connected", NOW - 10, "b");
+        node.address_book
+            .record_success("connected", "n0de", NOW - 5)
+            .unwrap();
+        node.record_connection_failure("failing");
+        assert_eq!(
+            node.discovery_dials(),
+            vec![
+                "connected".to_owned(),
+                "fresh".to_owned(),
+                "failing".to_owned()
+            ]
+        );
+
+        for _ in 1..MAX_ADDRESS_FAILURES {
+            node.record_connection_failure("failing");
+        }
+        assert!(!node.address_book.contains("failing"));
+    }
+
+    #[test]
+    fn test_outbound_handshake_is_recorded() {
+        let clock = Arc::new(ManualClock::new(NOW));
+        let mut node = node(&clock, "a");
+        handshake(&mut node, "b", false);
+        handshake(&mut node, "c", true);
+        let b = node.address_book.get("b").unwrap();
+        assert_eq!((b.successes, b.last_success), (1, Some(NOW)));
+        assert_eq!(node.address_book.get("c").unwrap().successes, 0);
+        assert_eq!(node.address_book.get("c").unwrap().source, "c");
+
+        node.handle_message("b", addr(&["d"], NOW)).unwrap();
+        assert_eq!(node.address_book.get("d").unwrap().source, "b");
+    }
+
+    #[test]
+    fn test_address_book_is_persisted() {
+        let path = std::env::temp_dir().join(format!("addresses-{}.json", uuid::Uuid::new_v4()));
+        let mut book = AddressBook::open(&path).unwrap();
+        book.add("gossip", NOW, "b");
+        assert!(!path.exists());
+        book.record_success("b", "n0de", NOW + 1).unwrap();
+        book.mark_attempt("b", NOW + 1);
+
+        let reopened = AddressBook::open(&path).unwrap();
+        assert_eq!(reopened.len(), 2);
+        let b = reopened.get("b").unwrap();
+        assert_eq!(b.last_success, Some(NOW + 1));
+        assert_eq!(b.last_attempt, None);
+        assert_eq!(reopened.get("gossip").unwrap().source, "b");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+//! Keypair a node is known by to its peers.
+//!
+//! The node id is the hex encoded public key. Peers prove they hold the
+//! private key of the id they claim by signing the nonce of the other side's
+//! [`Version`](super::Version) in their [`Message::Verack`](super::Message),
+//! and nodes sign the records of their own listening addresses before they
+//! are gossiped.
+
+use super::NetAddress;
+use crate::crypto::{self, generate_signing_key, parse_public_key};
+use crate::errors::{Error, Result};
+use ed25519_dalek::SigningKey;
+use std::path::Path;
+use tracing::{info, instrument};
+
+#[derive(Clone)]
+pub struct NodeIdentity {
+    key: SigningKey,
+    node_id: String,
+}
+
+impl std::fmt::Debug for NodeIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeIdentity")
+            .field("node_id", &self.node_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl NodeIdentity {
+    pub fn generate() -> Self {
+        Self::from_key(generate_signing_key())
+    }
+
+    fn from_key(key: SigningKey) -> Self {
+        let node_id = hex::encode(key.verifying_key().as_bytes());
+        Self { key, node_id }
+    }
+
+    /// Loads the private key stored hex encoded at `path` or generates one
+    /// and stores it there.
+    #[instrument(level = "debug")]
+    pub fn open(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let secret = std::fs::read_to_string(path)?;
+            let bytes: [u8; 32] = hex::decode(secret.trim())
+                .ok()
+                .and_then(|b| b.try_into().ok())
+                .ok_or_else(|| {
+                    Error::InvalidIdentity(format!("{} holds no private key", path.display()))
+                })?;
+            return Ok(Self::from_key(SigningKey::from_bytes(&bytes)));
+        }
+        let identity = Self::generate();
+        std::fs::write(path, hex::encode(identity.key.to_bytes()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        info!("Generated node id {}", identity.node_id);
+        Ok(identity)
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn sign(&self, message: &[u8]) -> String {
+        crypto::sign(&self.key, message)
+    }
+}
+
+/// Whether `signature` of `message` was made with the key of `node_id`.
+pub fn verify(node_id: &str, message: &[u8], signature: &str) -> bool {
+    parse_public_key(node_id).is_ok_and(|key| crypto::verify(&key, message, signature))
+}
+
+/// Bytes signed in a [`Message::Verack`](super::Message) answering a version
+/// with `nonce`.
+pub fn handshake_payload(network_id: &str, nonce: u64) -> Vec<u8> {
+    format!("verack:{network_id}:{nonce}").into_bytes()
+}
+
+impl NetAddress {
+    /// Record of an address of the node with `identity`, signed by it.
+    pub fn signed(address: &str, last_seen: u64, identity: &NodeIdentity) -> Self {
+        let mut record = Self {
+            address: address.to_owned(),
+            last_seen,
+            node_id: identity.node_id().to_owned(),
+            signature: String::new(),
+        };
+        record.signature = identity.sign(&record.payload());
+        record
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        format!("addr:{}:{}", self.address, self.last_seen).into_bytes()
+    }
+
+    /// Whether the record was signed by the node it names.
+    pub fn verify(&self) -> bool {
+        verify(&self.node_id, &self.payload(), &self.signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_is_persisted() {
+        let path = std::env::temp_dir().join(format!("identity-{}.key", uuid::Uuid::new_v4()));
+        let identity = NodeIdentity::open(&path).unwrap();
+        assert_eq!(identity.node_id().len(), 64);
+        assert_eq!(
+            NodeIdentity::open(&path).unwrap().node_id(),
+            identity.node_id()
+        );
+        assert_ne!(NodeIdentity::generate().node_id(), identity.node_id());
+
+        std::fs::write(&path, "not a key").unwrap();
+        assert!(matches!(
+            NodeIdentity::open(&path),
+            Err(Error::InvalidIdentity(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_address_records_are_signed_by_their_node() {
+        let identity = NodeIdentity::generate();
+        let record = NetAddress::signed("127.0.0.1:4000", 10, &identity);
+        assert!(record.verify());
+
+        let mut moved = record.clone();
+        moved.address = "127.0.0.1:4001".into();
+        assert!(!moved.verify());
+        let mut impersonated = record.clone();
+        impersonated.node_id = NodeIdentity::generate().node_id().to_owned();
+        assert!(!impersonated.verify());
+        let mut garbage = record;
+        garbage.node_id = "x".into();
+        assert!(!garbage.verify());
+    }
+}
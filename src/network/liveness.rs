@@ -0,0 +1,298 @@
+//! Keeping track of which peers are alive, dropping the ones that aren't and
+//! reconnecting to lost outbound peers.
+
+use super::{Envelope, Message, Version};
+use crate::node::Node;
+use tracing::{info, warn};
+
+/// Seconds between two rounds of [`Node::maintain_peers`].
+pub const PING_INTERVAL: u64 = 30;
+/// Unanswered pings after which a peer is evicted.
+pub const MAX_PEER_FAILURES: u32 = 3;
+/// Seconds a new connection has to complete the version handshake.
+pub const HANDSHAKE_TIMEOUT: u64 = 30;
+/// Delay before the first attempt to reconnect to a lost outbound peer,
+/// doubled with every further attempt.
+pub const RECONNECT_BASE_DELAY: u64 = 10;
+pub const MAX_RECONNECT_DELAY: u64 = 60 * 60;
+/// Attempts after which a lost outbound peer is given up.
+pub const MAX_RECONNECT_ATTEMPTS: u32 = 8;
+
+/// Connection that has not completed the version handshake yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub inbound: bool,
+    pub started_at: u64,
+    /// Challenge sent in this node's version, the peer signs it.
+    pub nonce: u64,
+    /// Version received from the peer, kept until its signature arrives.
+    pub version: Option<Version>,
+    /// Node id the peer must present, the one it proved on an encrypted
+    /// channel or the one it was dialed as.
+    pub expected_id: Option<String>,
+    pub encrypted: bool,
+}
+
+impl Handshake {
+    pub fn new(inbound: bool, started_at: u64) -> Self {
+        Self {
+            inbound,
+            started_at,
+            nonce: rand::random(),
+            version: None,
+            expected_id: None,
+            encrypted: false,
+        }
+    }
+}
+
+/// Outbound peer the node lost and dials again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reconnect {
+    pub attempts: u32,
+    /// Unix timestamp of the next attempt.
+    pub next_attempt: u64,
+}
+
+/// What the transport does after a round of peer maintenance.
+#[derive(Debug, Default, PartialEq)]
+pub struct Maintenance {
+    pub outbound: Vec<Envelope>,
+    /// Connections to close.
+    pub evict: Vec<String>,
+    /// Addresses to dial.
+    pub dial: Vec<String>,
+}
+
+/// Delay before reconnection attempt number `attempt`, counted from one.
+pub fn reconnect_delay(attempt: u32) -> u64 {
+    RECONNECT_BASE_DELAY
+        .saturating_mul(1 << attempt.saturating_sub(1).min(32))
+        .min(MAX_RECONNECT_DELAY)
+}
+
+impl Node {
+    /// Pings all peers, evicts the ones that stopped answering or never
+    /// completed the handshake, picks lost peers due for reconnection and
+    /// known addresses to fill the free outbound slots and stores the
+    /// addresses learned since the last round.
+    pub fn maintain_peers(&mut self) -> Maintenance {
+        let now = self.blockchain.clock().now();
+        let now_millis = self.blockchain.clock().now_millis();
+        let mut maintenance = Maintenance::default();
+
+        for (address, peer) in &mut self.peers {
+            if peer.ping_nonce.is_some() {
+                peer.failures += 1;
+            }
+            if peer.failures >= MAX_PEER_FAILURES {
+                warn!("Evicting peer {address} after {} failures", peer.failures);
+                maintenance.evict.push(address.clone());
+                continue;
+            }
+            peer.ping_nonce = Some(now_millis);
+            maintenance
+                .outbound
+                .push(Envelope::new(address, Message::Ping(now_millis)));
+        }
+        for (address, handshake) in &self.handshakes {
+            if now.saturating_sub(handshake.started_at) > HANDSHAKE_TIMEOUT {
+                warn!("Evicting peer {address} that did not complete the handshake");
+                maintenance.evict.push(address.clone());
+            }
+        }
+
+        let mut given_up = vec![];
+        self.reconnects.retain(|address, reconnect| {
+            if reconnect.next_attempt > now {
+                return true;
+            }
+            if reconnect.attempts >= MAX_RECONNECT_ATTEMPTS {
+                warn!("Giving up on peer {address} after {MAX_RECONNECT_ATTEMPTS} attempts");
+                given_up.push(address.clone());
+                return false;
+            }
+            reconnect.attempts += 1;
+            reconnect.next_attempt = now + reconnect_delay(reconnect.attempts + 1);
+            maintenance.dial.push(address.clone());
+            true
+        });
+        for address in given_up {
+            self.address_book.remove(&address);
+        }
+        let dials = self.discovery_dials();
+        maintenance.dial.extend(dials);
+        if let Err(e) = self.address_book.save() {
+            warn!("Failed to store the address book: {e}");
+        }
+        maintenance
+    }
+
+    /// Records the answer to a ping sent by [`Node::maintain_peers`].
+    pub(crate) fn handle_pong(&mut self, from: &str, nonce: u64) {
+        let now_millis = self.blockchain.clock().now_millis();
+        let Some(peer) = self.peers.get_mut(from) else {
+            return;
+        };
+        // The nonce is the time the ping was sent at
+        if peer.ping_nonce == Some(nonce) {
+            peer.ping_nonce = None;
+            peer.failures = 0;
+            peer.latency_ms = Some(now_millis.saturating_sub(nonce));
+        }
+    }
+
+    /// Forgets the peer and stops reconnecting to it, returns whether the
+    /// node knew it.
+    pub fn remove_peer(&mut self, address: &str) -> bool {
+        let peer = self.peers.remove(address).is_some();
+        let handshake = self.handshakes.remove(address).is_some();
+        let reconnect = self.reconnects.remove(address).is_some();
+        if peer {
+            info!("Removed peer {address}");
+        }
+        peer || handshake || reconnect
+    }
+
+    /// Schedules reconnection to a lost outbound peer.
+    pub(crate) fn schedule_reconnect(&mut self, address: &str) {
+        let now = self.blockchain.clock().now();
+        self.reconnects
+            .entry(address.to_owned())
+            .or_insert(Reconnect {
+                attempts: 0,
+                next_attempt: now + reconnect_delay(1),
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::MIN_BLOCK_TIMESTAMP;
+    use crate::clock::{Clock, ManualClock};
+    use crate::network::protocol::tests::handshake;
+    use std::sync::Arc;
+
+    fn node(clock: &Arc<ManualClock>) -> Node {
+        Node::with_clock("A", 1, clock.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_reconnect_delay_grows_exponentially() {
+        assert_eq!(reconnect_delay(1), RECONNECT_BASE_DELAY);
+        assert_eq!(reconnect_delay(2), 2 * RECONNECT_BASE_DELAY);
+        assert_eq!(reconnect_delay(4), 8 * RECONNECT_BASE_DELAY);
+        assert_eq!(reconnect_delay(100), MAX_RECONNECT_DELAY);
+    }
+
+    #[test]
+    fn test_pong_records_latency() {
+        let clock = Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP));
+        let mut node = node(&clock);
+        handshake(&mut node, "b", false);
+
+        let maintenance = node.maintain_peers();
+        let nonce = MIN_BLOCK_TIMESTAMP * 1000;
+        assert_eq!(
+            maintenance.outbound,
+            vec![Envelope::new("b", Message::Ping(nonce))]
+        );
+        clock.advance(1);
+        node.handle_message("b", Message::Pong(nonce)).unwrap();
+
+        let peer = &node.peers["b"];
+        assert_eq!(peer.latency_ms, Some(1000));
+        assert_eq!(peer.failures, 0);
+        assert_eq!(peer.last_seen, MIN_BLOCK_TIMESTAMP + 1);
+    }
+
+    #[test]
+    fn test_unresponsive_peer_is_evicted_and_reconnected_with_backoff() {
+        let clock = Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP));
+        let mut node = node(&clock);
+        handshake(&mut node, "b", false);
+        handshake(&mut node, "c", true);
+
+        for _ in 0..MAX_PEER_FAILURES {
+            assert!(node.maintain_peers().evict.is_empty());
+            clock.advance(PING_INTERVAL);
+        }
+        assert_eq!(node.peers["b"].failures, MAX_PEER_FAILURES - 1);
+        let maintenance = node.maintain_peers();
+        assert_eq!(maintenance.evict, vec!["b".to_owned(), "c".to_owned()]);
+
+        // Only the outbound peer is reconnected, with growing delays
+        let lost_at = clock.now();
+        node.on_disconnected("b");
+        node.on_disconnected("c");
+        assert!(node.peers.is_empty());
+        let mut dials = vec![];
+        for _ in 0..2000 {
+            clock.advance(1);
+            if node.maintain_peers().dial.contains(&"b".to_owned()) {
+                dials.push(clock.now());
+            }
+        }
+        assert_eq!(dials[0], lost_at + RECONNECT_BASE_DELAY);
+        let delays: Vec<_> = dials.windows(2).map(|w| w[1] - w[0]).collect();
+        assert_eq!(delays, [20, 40, 80, 160, 320, 640]);
+        assert_eq!(node.reconnects["b"].attempts, 7);
+        assert!(!node.reconnects.contains_key("c"));
+    }
+
+    #[test]
+    fn test_reconnecting_gives_up_after_max_attempts() {
+        let clock = Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP));
+        let mut node = node(&clock);
+        handshake(&mut node, "b", false);
+        node.on_disconnected("b");
+
+        let mut dials = 0;
+        while node.reconnects.contains_key("b") {
+            clock.advance(MAX_RECONNECT_DELAY);
+            dials += node.maintain_peers().dial.len();
+        }
+        assert_eq!(dials, MAX_RECONNECT_ATTEMPTS as usize);
+        assert!(!node.address_book.contains("b"));
+    }
+
+    #[test]
+    fn test_successful_handshake_stops_reconnecting() {
+        let clock = Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP));
+        let mut node = node(&clock);
+        handshake(&mut node, "b", false);
+        node.on_disconnected("b");
+        clock.advance(RECONNECT_BASE_DELAY);
+        assert_eq!(node.maintain_peers().dial, vec!["b".to_owned()]);
+
+        handshake(&mut node, "b", false);
+        assert!(node.reconnects.is_empty());
+    }
+
+    #[test]
+    fn test_stalled_handshake_is_evicted() {
+        let clock = Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP));
+        let mut node = node(&clock);
+        node.on_connected("b", true);
+        assert!(node.maintain_peers().evict.is_empty());
+
+        clock.advance(HANDSHAKE_TIMEOUT + 1);
+        assert_eq!(node.maintain_peers().evict, vec!["b".to_owned()]);
+        node.on_disconnected("b");
+        assert!(node.handshakes.is_empty());
+        assert!(node.reconnects.is_empty());
+    }
+
+    #[test]
+    fn test_removed_peer_is_not_reconnected() {
+        let clock = Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP));
+        let mut node = node(&clock);
+        handshake(&mut node, "b", false);
+
+        assert!(node.remove_peer("b"));
+        node.on_disconnected("b");
+        assert!(node.reconnects.is_empty());
+        assert!(!node.remove_peer("b"));
+    }
+}
@@ -0,0 +1,166 @@
+pub mod bans;
+pub mod compact;
+pub mod discovery;
+pub mod identity;
+pub mod liveness;
+pub mod p2p;
+pub mod protocol;
+pub mod relay;
+pub mod secure;
+pub mod transport;
+pub mod wire;
+
+use crate::block::{Block, BlockHeader, Transaction};
+use relay::{KnownInventory, RelayWindow};
+use serde::{Deserialize, Serialize};
+
+/// Version of the peer-to-peer protocol spoken by this node.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest protocol version of peers this node still talks to.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+/// Network nodes join unless configured otherwise.
+pub const DEFAULT_NETWORK_ID: &str = "main";
+
+/// Message exchanged between peers over a long-lived connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// First message on every connection, introducing the sender.
+    Version(Version),
+    /// Acknowledges the peer's [`Message::Version`] with the signature of
+    /// its nonce, see [`identity::handshake_payload`].
+    Verack(String),
+    Ping(u64),
+    Pong(u64),
+    /// Announces blocks or transactions the sender has.
+    Inv(Vec<Inventory>),
+    /// Requests the full blocks or transactions of announced inventory.
+    GetData(Vec<Inventory>),
+    Block(Block),
+    Tx(Transaction),
+    /// Requests headers following the first locator hash the peer knows.
+    GetHeaders(Vec<String>),
+    Headers(Vec<BlockHeader>),
+    /// Listening addresses of other nodes.
+    Addr(Vec<NetAddress>),
+    /// Announces a new block, see [`compact`].
+    CompactBlock(CompactBlock),
+    /// Requests the transactions of a block at the given indexes.
+    GetBlockTxn(String, Vec<u32>),
+    /// Transactions of a block requested with [`Message::GetBlockTxn`].
+    BlockTxn(String, Vec<Transaction>),
+}
+
+/// Block header with a short id of every transaction of the block, see
+/// [`compact::short_id`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    /// Salt of the short ids, picked by the sender.
+    pub salt: u64,
+    pub short_ids: Vec<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Version {
+    pub version: u32,
+    /// Network the sender belongs to, peers of other networks are rejected.
+    pub network_id: String,
+    pub genesis_hash: String,
+    /// Public key of the sender, see [`identity`].
+    pub node_id: String,
+    /// Index of the sender's chain tip.
+    pub best_height: u64,
+    /// Address the sender accepts peer connections on, empty if it doesn't.
+    pub listen_addr: String,
+    /// Random challenge the peer signs in its [`Message::Verack`].
+    pub nonce: u64,
+}
+
+/// Listening address of a node and when it was last known to be alive,
+/// signed by the node, see [`NetAddress::signed`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetAddress {
+    pub address: String,
+    /// Unix timestamp.
+    pub last_seen: u64,
+    pub node_id: String,
+    pub signature: String,
+}
+
+/// Peer that completed the version handshake.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerInfo {
+    /// Address of the connection to the peer.
+    pub address: String,
+    pub node_id: String,
+    pub version: u32,
+    /// Index of the peer's chain tip, updated as it relays blocks.
+    pub best_height: u64,
+    pub listen_addr: String,
+    /// Whether the peer connected to this node.
+    pub inbound: bool,
+    /// Unix timestamp of the handshake.
+    pub connected_at: u64,
+    /// Unix timestamp of the last message received from the peer.
+    pub last_seen: u64,
+    /// Pings the peer left unanswered in a row.
+    pub failures: u32,
+    /// Round trip time of the last answered ping.
+    pub latency_ms: Option<u64>,
+    /// Nonce of the unanswered ping, the time it was sent at in milliseconds.
+    #[serde(skip)]
+    pub ping_nonce: Option<u64>,
+    /// Whether the connection to the peer is encrypted.
+    pub encrypted: bool,
+    /// Transactions the peer is known to have.
+    #[serde(skip)]
+    pub known_inventory: KnownInventory,
+    /// Transactions the peer relayed lately.
+    #[serde(skip)]
+    pub relayed: RelayWindow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InventoryKind {
+    Block,
+    Transaction,
+}
+
+/// Reference to a block by its hash or to a transaction by its id.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Inventory {
+    pub kind: InventoryKind,
+    pub hash: String,
+}
+
+impl Inventory {
+    pub fn block(hash: &str) -> Self {
+        Self {
+            kind: InventoryKind::Block,
+            hash: hash.to_owned(),
+        }
+    }
+
+    pub fn transaction(id: &str) -> Self {
+        Self {
+            kind: InventoryKind::Transaction,
+            hash: id.to_owned(),
+        }
+    }
+}
+
+/// Message addressed to a peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub to: String,
+    pub message: Message,
+}
+
+impl Envelope {
+    pub fn new(to: &str, message: Message) -> Self {
+        Self {
+            to: to.to_owned(),
+            message,
+        }
+    }
+}
@@ -0,0 +1,787 @@
+//! Long-lived TCP connections between nodes.
+
+use super::Message;
+use super::bans::host;
+use super::protocol::MAX_INVENTORY;
+use super::secure::{Channel, open_channel};
+use super::transport::{PeerClient, deliver};
+use crate::actor::NodeHandle;
+use crate::errors::{Error, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Notify, mpsc};
+use tracing::{info, instrument, warn};
+
+type TcpChannel = Channel<OwnedReadHalf, OwnedWriteHalf>;
+
+/// Most messages waiting to be written to a peer, enough for the reply to a
+/// full [`Message::GetData`]. A peer that doesn't read them is disconnected.
+pub const MAX_QUEUED_MESSAGES: usize = 2 * MAX_INVENTORY;
+
+#[derive(Debug)]
+struct Connection {
+    sender: mpsc::Sender<Message>,
+    inbound: bool,
+    /// Wakes the reader of the connection when it is closed locally.
+    closing: Arc<Notify>,
+}
+
+/// Most connections a node keeps in each direction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerLimits {
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+}
+
+impl Default for PeerLimits {
+    fn default() -> Self {
+        Self {
+            max_inbound: 32,
+            max_outbound: 8,
+        }
+    }
+}
+
+/// Open peer connections of a node, each identified by the remote address.
+#[derive(Debug, Default)]
+pub struct ConnectionManager {
+    connections: Mutex<HashMap<String, Connection>>,
+    limits: PeerLimits,
+}
+
+impl ConnectionManager {
+    pub fn new(limits: PeerLimits) -> Self {
+        Self {
+            connections: Mutex::default(),
+            limits,
+        }
+    }
+
+    fn insert(
+        &self,
+        peer: &str,
+        sender: mpsc::Sender<Message>,
+        inbound: bool,
+    ) -> Result<Arc<Notify>> {
+        let mut connections = self.lock();
+        let limit = self.limit(inbound);
+        if connections
+            .values()
+            .filter(|c| c.inbound == inbound)
+            .count()
+            >= limit
+        {
+            return Err(Error::TooManyPeers(direction(inbound).to_owned(), limit));
+        }
+        let closing = Arc::new(Notify::new());
+        connections.insert(
+            peer.to_owned(),
+            Connection {
+                sender,
+                inbound,
+                closing: closing.clone(),
+            },
+        );
+        Ok(closing)
+    }
+
+    /// The map stays consistent when a holder panics, so poisoning is
+    /// ignored.
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Connection>> {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn limits(&self) -> PeerLimits {
+        self.limits
+    }
+
+    fn limit(&self, inbound: bool) -> usize {
+        match inbound {
+            true => self.limits.max_inbound,
+            false => self.limits.max_outbound,
+        }
+    }
+
+    /// Whether another connection in the direction fits the limits.
+    pub fn has_room(&self, inbound: bool) -> bool {
+        self.lock()
+            .values()
+            .filter(|c| c.inbound == inbound)
+            .count()
+            < self.limit(inbound)
+    }
+
+    fn remove(&self, peer: &str) {
+        self.lock().remove(peer);
+    }
+
+    pub fn is_connected(&self, peer: &str) -> bool {
+        self.lock().contains_key(peer)
+    }
+
+    /// Addresses of connected peers and whether they connected to us.
+    #[allow(unused)]
+    pub fn connected(&self) -> Vec<(String, bool)> {
+        let mut peers: Vec<_> = self
+            .lock()
+            .iter()
+            .map(|(peer, c)| (peer.clone(), c.inbound))
+            .collect();
+        peers.sort();
+        peers
+    }
+
+    /// Closes the connection, its tasks stop and the node is told about it.
+    pub fn disconnect(&self, peer: &str) -> bool {
+        match self.lock().remove(peer) {
+            Some(connection) => {
+                connection.closing.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Closes all connections to peers at the IP address `ip`.
+    pub fn disconnect_host(&self, ip: &str) {
+        self.lock().retain(|peer, connection| {
+            let keep = host(peer) != ip;
+            if !keep {
+                connection.closing.notify_one();
+            }
+            keep
+        });
+    }
+}
+
+fn direction(inbound: bool) -> &'static str {
+    if inbound { "inbound" } else { "outbound" }
+}
+
+#[async_trait]
+impl PeerClient for ConnectionManager {
+    async fn send(&self, to: &str, message: Message) -> Result<()> {
+        let mut connections = self.lock();
+        let connection = connections
+            .get(to)
+            .ok_or_else(|| Error::PeerNotConnected(to.to_owned()))?;
+        match connection.sender.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!("Disconnecting peer {to}, it doesn't read its messages");
+                connection.closing.notify_one();
+                connections.remove(to);
+                Err(Error::PeerNotConnected(to.to_owned()))
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                Err(Error::PeerNotConnected(to.to_owned()))
+            }
+        }
+    }
+}
+
+/// Accepts peer connections on `addr` in a background task and returns the
+/// bound address, which becomes the node's advertised address.
+pub async fn listen(node: NodeHandle, addr: &str) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local = listener.local_addr()?;
+    let address = local.to_string();
+    node.update(move |node| node.address = address).await?;
+    info!("Accepting peers at {local}");
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, remote)) => {
+                    let peer = remote.to_string();
+                    tokio::spawn(accept(node.clone(), stream, peer));
+                }
+                Err(e) => warn!("Failed to accept peer connection: {e}"),
+            }
+        }
+    });
+    Ok(local)
+}
+
+async fn accept(node: NodeHandle, stream: TcpStream, peer: String) {
+    let address = peer.clone();
+    let setup = node
+        .update(move |node| {
+            let banned = node.is_banned(&address);
+            (banned, node.identity.clone(), node.encryption)
+        })
+        .await;
+    let (identity, mode) = match setup {
+        Ok((false, identity, mode)) => (identity, mode),
+        Ok((true, ..)) => {
+            info!("Refusing banned peer {peer}");
+            return;
+        }
+        Err(e) => {
+            warn!("Refusing peer {peer}: {e}");
+            return;
+        }
+    };
+    match open_channel(stream, &identity, mode, true).await {
+        Ok(channel) => run_connection(node, channel, peer, true, None).await,
+        Err(e) => {
+            warn!("Failed to open channel with peer {peer}: {e}");
+            punish(&node, &peer, e).await;
+        }
+    }
+}
+
+/// Opens a connection to the peer listening on `addr`. Given as
+/// `node_id@host:port` only the node with that id is accepted there,
+/// otherwise the node id known from an earlier connection if any.
+#[instrument(skip(node), level = "info")]
+pub async fn connect(node: &NodeHandle, addr: &str) -> Result<()> {
+    let (pinned, addr) = match addr.split_once('@') {
+        Some((node_id, addr)) => (Some(node_id.to_owned()), addr),
+        None => (None, addr),
+    };
+    let connections = node.snapshot().connections.clone();
+    if connections.is_connected(addr) {
+        return Ok(());
+    }
+    let address = addr.to_owned();
+    let (banned, identity, mode, known) = node
+        .update(move |node| {
+            let known = node
+                .address_book
+                .get(&address)
+                .and_then(|a| a.node_id.clone());
+            let banned = node.is_banned(&address);
+            (banned, node.identity.clone(), node.encryption, known)
+        })
+        .await?;
+    if banned {
+        return Err(Error::PeerBanned(addr.to_owned()));
+    }
+    if !connections.has_room(false) {
+        return Err(Error::TooManyPeers(
+            direction(false).to_owned(),
+            connections.limits.max_outbound,
+        ));
+    }
+    let stream = match TcpStream::connect(addr).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            record_connection_failure(node, addr).await;
+            return Err(e.into());
+        }
+    };
+    let expected_id = pinned.or(known);
+    let channel = open_channel(stream, &identity, mode, false)
+        .await
+        .and_then(|channel| match (&expected_id, &channel.peer_id) {
+            (Some(expected), Some(actual)) if expected != actual => {
+                Err(Error::UnexpectedNodeId(addr.to_owned(), expected.clone()))
+            }
+            _ => Ok(channel),
+        });
+    let channel = match channel {
+        Ok(channel) => channel,
+        Err(e) => {
+            record_connection_failure(node, addr).await;
+            return Err(e);
+        }
+    };
+    tokio::spawn(run_connection(
+        node.clone(),
+        channel,
+        addr.to_owned(),
+        false,
+        expected_id,
+    ));
+    Ok(())
+}
+
+async fn record_connection_failure(node: &NodeHandle, addr: &str) {
+    let addr = addr.to_owned();
+    let _ = node
+        .update(move |node| node.record_connection_failure(&addr))
+        .await;
+}
+
+/// Runs the protocol over an open channel until either side closes it. The
+/// peer must present the node id it proved on an encrypted channel, or
+/// `expected_id` on a plaintext one.
+async fn run_connection(
+    node: NodeHandle,
+    channel: TcpChannel,
+    peer: String,
+    inbound: bool,
+    expected_id: Option<String>,
+) {
+    let encrypted = channel.is_encrypted();
+    let Channel {
+        mut reader,
+        mut writer,
+        peer_id,
+    } = channel;
+    let (sender, mut queue) = mpsc::channel(MAX_QUEUED_MESSAGES);
+    let address = peer.clone();
+    let connected = node
+        .update(move |node| {
+            let closing = node.connections.insert(&address, sender, inbound)?;
+            let outbound = node.on_connected(&address, inbound);
+            if let Some(handshake) = node.handshakes.get_mut(&address) {
+                handshake.encrypted = encrypted;
+                handshake.expected_id = peer_id.or(expected_id);
+            }
+            Ok((node.connections.clone(), closing, outbound))
+        })
+        .await
+        .and_then(|connected| connected);
+    let (connections, closing, outbound) = match connected {
+        Ok(connected) => connected,
+        Err(e) => {
+            warn!("Refusing peer {peer}: {e}");
+            return;
+        }
+    };
+    info!("Connected to peer {peer}, inbound: {inbound}, encrypted: {encrypted}");
+    deliver(connections.as_ref(), outbound).await;
+
+    let writer_peer = peer.clone();
+    let writer_task = tokio::spawn(async move {
+        while let Some(message) = queue.recv().await {
+            if let Err(e) = writer.write(&message).await {
+                warn!("Failed to write to peer {writer_peer}: {e}");
+                break;
+            }
+        }
+    });
+
+    loop {
+        let read = tokio::select! {
+            read = reader.read() => read,
+            _ = closing.notified() => break,
+        };
+        let message = match read {
+            Ok(message) => message,
+            Err(Error::IO(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                warn!("Failed to read from peer {peer}: {e}");
+                punish(&node, &peer, e).await;
+                break;
+            }
+        };
+        let from = peer.clone();
+        let handled = node
+            .update(move |node| node.handle_message(&from, message))
+            .await
+            .and_then(|handled| handled);
+        let outbound = match handled {
+            Ok(outbound) => outbound,
+            Err(e) => {
+                let fatal = matches!(
+                    e,
+                    Error::IncompatiblePeer(..)
+                        | Error::HandshakeRequired(_)
+                        | Error::InvalidPeerSignature(_)
+                        | Error::PeerBanned(_)
+                        | Error::UnexpectedNodeId(..)
+                        | Error::NodeUnavailable
+                );
+                warn!("Message from peer {peer} rejected: {e}");
+                punish(&node, &peer, e).await;
+                if fatal {
+                    break;
+                }
+                continue;
+            }
+        };
+        deliver(connections.as_ref(), outbound).await;
+    }
+
+    writer_task.abort();
+    connections.remove(&peer);
+    let address = peer.clone();
+    let _ = node
+        .update(move |node| node.on_disconnected(&address))
+        .await;
+    info!("Disconnected from peer {peer}");
+}
+
+/// Scores the misbehavior of a peer and closes its connections once it is
+/// banned.
+async fn punish(node: &NodeHandle, peer: &str, error: Error) {
+    let peer = peer.to_owned();
+    let _ = node
+        .update(move |node| {
+            if let Some(ban) = node.record_misbehavior(&peer, &error) {
+                warn!("Banned {} until {}: {}", ban.ip, ban.until, ban.reason);
+                node.disconnect_banned(&ban);
+            }
+        })
+        .await;
+}
+
+/// Runs [`Node::maintain_peers`] every `interval` in a background task,
+/// closing evicted connections, dialing lost peers again and connecting to
+/// newly discovered ones.
+pub fn spawn_maintenance(node: NodeHandle, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            maintain(&node).await;
+        }
+    });
+}
+
+async fn maintain(node: &NodeHandle) {
+    let maintained = node
+        .update(|node| (node.connections.clone(), node.maintain_peers()))
+        .await;
+    let Ok((connections, maintenance)) = maintained else {
+        return;
+    };
+    for peer in &maintenance.evict {
+        connections.disconnect(peer);
+    }
+    deliver(connections.as_ref(), maintenance.outbound).await;
+    for addr in maintenance.dial {
+        if let Err(e) = connect(node, &addr).await {
+            warn!("Failed to connect to peer {addr}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::MIN_BLOCK_TIMESTAMP;
+    use crate::clock::ManualClock;
+    use crate::network::identity::{NodeIdentity, handshake_payload};
+    use crate::network::liveness::RECONNECT_BASE_DELAY;
+    use crate::network::secure::EncryptionMode;
+    use crate::network::wire::{read_message, write_message};
+    use crate::node::Node;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn node(name: &str) -> NodeHandle {
+        NodeHandle::spawn(Node::new(name, 1).unwrap())
+    }
+
+    async fn eventually(condition: impl AsyncFn() -> bool) {
+        for _ in 0..100 {
+            if condition().await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("condition not met in time");
+    }
+
+    /// Mines a block on `node` and announces it to its peers.
+    async fn mine_and_announce(node: &NodeHandle) {
+        let outbound = node
+            .update(|n| {
+                n.add_block(vec![]).unwrap();
+                n.announce_tip()
+            })
+            .await
+            .unwrap();
+        deliver(node.snapshot().connections.as_ref(), outbound).await;
+    }
+
+    async fn set_encryption(node: &NodeHandle, mode: EncryptionMode) {
+        node.update(move |n| n.encryption = mode).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_nodes_handshake_and_relay_blocks_over_tcp() {
+        let (a, b) = (node("A"), node("B"));
+        listen(a.clone(), "127.0.0.1:0").await.unwrap();
+        let b_addr = listen(b.clone(), "127.0.0.1:0").await.unwrap();
+
+        connect(&a, &b_addr.to_string()).await.unwrap();
+        eventually(async || a.snapshot().peers.len() == 1 && b.snapshot().peers.len() == 1).await;
+        assert_eq!(
+            a.snapshot().connections.connected(),
+            vec![(b_addr.to_string(), false)]
+        );
+
+        mine_and_announce(&a).await;
+        eventually(async || b.snapshot().blockchain.height() == 1).await;
+        assert_eq!(
+            a.snapshot().blockchain.blocks(),
+            b.snapshot().blockchain.blocks()
+        );
+    }
+
+    /// Forwards a connection to `target` and records the bytes passing
+    /// through in both directions.
+    async fn sniffing_proxy(target: String) -> (String, Arc<Mutex<Vec<u8>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let captured = Arc::new(Mutex::new(vec![]));
+        let recorded = captured.clone();
+        tokio::spawn(async move {
+            let (client, _) = listener.accept().await.unwrap();
+            let server = TcpStream::connect(target).await.unwrap();
+            let (client_reader, client_writer) = client.into_split();
+            let (server_reader, server_writer) = server.into_split();
+            tokio::join!(
+                pipe(client_reader, server_writer, recorded.clone()),
+                pipe(server_reader, client_writer, recorded)
+            );
+        });
+        (addr, captured)
+    }
+
+    async fn pipe(mut from: OwnedReadHalf, mut to: OwnedWriteHalf, recorded: Arc<Mutex<Vec<u8>>>) {
+        let mut buffer = [0; 4096];
+        while let Ok(n @ 1..) = from.read(&mut buffer).await {
+            recorded.lock().unwrap().extend_from_slice(&buffer[..n]);
+            if to.write_all(&buffer[..n]).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Connects `a` to `b` through a proxy and returns what went over the
+    /// wire until `b` received a block from `a`.
+    async fn exchange_block(a: &NodeHandle, b: &NodeHandle) -> Vec<u8> {
+        let b_addr = listen(b.clone(), "127.0.0.1:0").await.unwrap().to_string();
+        let (proxy_addr, captured) = sniffing_proxy(b_addr).await;
+        connect(a, &proxy_addr).await.unwrap();
+        eventually(async || a.snapshot().peers.len() == 1 && b.snapshot().peers.len() == 1).await;
+        mine_and_announce(a).await;
+        eventually(async || b.snapshot().blockchain.height() == 1).await;
+        captured.lock().unwrap().clone()
+    }
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|w| w == needle.as_bytes())
+    }
+
+    #[tokio::test]
+    async fn test_peers_exchange_messages_over_encrypted_channels() {
+        let (a, b) = (node("A"), node("B"));
+        let captured = exchange_block(&a, &b).await;
+        let block_hash = a.snapshot().blockchain.blocks()[1].hash.clone();
+        assert!(!contains(&captured, &block_hash));
+        assert!(!contains(&captured, &b.snapshot().node_id));
+        assert!(a.snapshot().peers.values().all(|p| p.encrypted));
+        assert!(b.snapshot().peers.values().all(|p| p.encrypted));
+
+        let (a, b) = (node("A"), node("B"));
+        set_encryption(&a, EncryptionMode::Disabled).await;
+        let captured = exchange_block(&a, &b).await;
+        let block_hash = a.snapshot().blockchain.blocks()[1].hash.clone();
+        assert!(contains(&captured, &block_hash));
+        assert!(b.snapshot().peers.values().all(|p| !p.encrypted));
+    }
+
+    #[tokio::test]
+    async fn test_peer_with_unexpected_node_id_is_rejected() {
+        let (a, b) = (node("A"), node("B"));
+        let b_addr = listen(b.clone(), "127.0.0.1:0").await.unwrap().to_string();
+        let seed = b_addr.clone();
+        a.update(move |n| n.address_book.add(&seed, MIN_BLOCK_TIMESTAMP, "seed"))
+            .await
+            .unwrap();
+        let failures = async || {
+            let addr = b_addr.clone();
+            a.update(move |n| n.address_book.get(&addr).unwrap().failures)
+                .await
+                .unwrap()
+        };
+        let other = NodeIdentity::generate();
+        let pinned = format!("{}@{b_addr}", other.node_id());
+        assert!(matches!(
+            connect(&a, &pinned).await,
+            Err(Error::UnexpectedNodeId(addr, node_id)) if addr == b_addr && node_id == other.node_id()
+        ));
+        assert!(a.snapshot().connections.connected().is_empty());
+        assert_eq!(failures().await, 1);
+
+        // Plaintext peers are checked when they sign the handshake
+        set_encryption(&a, EncryptionMode::Disabled).await;
+        connect(&a, &pinned).await.unwrap();
+        eventually(async || failures().await == 2).await;
+        eventually(async || a.snapshot().connections.connected().is_empty()).await;
+        eventually(async || b.snapshot().peers.is_empty()).await;
+        assert!(a.snapshot().peers.is_empty());
+
+        let b_id = b.snapshot().node_id.clone();
+        connect(&a, &format!("{b_id}@{b_addr}")).await.unwrap();
+        eventually(async || a.snapshot().peers.contains_key(&b_addr)).await;
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_peers_are_refused_when_encryption_is_required() {
+        let (a, b, c) = (node("A"), node("B"), node("C"));
+        set_encryption(&b, EncryptionMode::Required).await;
+        set_encryption(&a, EncryptionMode::Disabled).await;
+        let b_addr = listen(b.clone(), "127.0.0.1:0").await.unwrap().to_string();
+
+        connect(&a, &b_addr).await.unwrap();
+        eventually(async || a.snapshot().connections.connected().is_empty()).await;
+        assert!(b.snapshot().peers.is_empty());
+
+        connect(&c, &b_addr).await.unwrap();
+        eventually(async || b.snapshot().peers.len() == 1).await;
+        assert!(b.snapshot().peers.values().all(|p| p.encrypted));
+    }
+
+    #[tokio::test]
+    async fn test_peer_of_other_network_is_dropped() {
+        let (a, b) = (node("A"), node("B"));
+        b.update(|n| n.network_id = "test".into()).await.unwrap();
+        let b_addr = listen(b.clone(), "127.0.0.1:0").await.unwrap().to_string();
+
+        connect(&a, &b_addr).await.unwrap();
+        eventually(async || {
+            a.snapshot().connections.connected().is_empty()
+                && b.snapshot().connections.connected().is_empty()
+        })
+        .await;
+        assert!(a.snapshot().peers.is_empty());
+        assert!(b.snapshot().peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_closed_connection_removes_peer() {
+        let (a, b) = (node("A"), node("B"));
+        let b_addr = listen(b.clone(), "127.0.0.1:0").await.unwrap().to_string();
+        connect(&a, &b_addr).await.unwrap();
+        eventually(async || b.snapshot().peers.len() == 1).await;
+
+        assert!(a.snapshot().connections.disconnect(&b_addr));
+        eventually(async || b.snapshot().peers.is_empty()).await;
+        eventually(async || a.snapshot().peers.is_empty()).await;
+        assert!(b.snapshot().connections.connected().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connection_limits() {
+        let (a, b, c) = (node("A"), node("B"), node("C"));
+        b.update(|n| {
+            n.connections = Arc::new(ConnectionManager::new(PeerLimits {
+                max_inbound: 1,
+                max_outbound: 0,
+            }))
+        })
+        .await
+        .unwrap();
+        let b_addr = listen(b.clone(), "127.0.0.1:0").await.unwrap().to_string();
+        let c_addr = listen(c.clone(), "127.0.0.1:0").await.unwrap().to_string();
+
+        connect(&a, &b_addr).await.unwrap();
+        eventually(async || b.snapshot().peers.len() == 1).await;
+        connect(&c, &b_addr).await.unwrap();
+        eventually(async || c.snapshot().connections.connected().is_empty()).await;
+        assert_eq!(b.snapshot().peers.len(), 1);
+
+        assert!(matches!(
+            connect(&b, &c_addr).await,
+            Err(Error::TooManyPeers(direction, 0)) if direction == "outbound"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_peer_not_reading_its_messages_is_disconnected() {
+        let connections = ConnectionManager::default();
+        let (sender, _queue) = mpsc::channel(MAX_QUEUED_MESSAGES);
+        let closing = connections.insert("peer", sender, true).unwrap();
+
+        for nonce in 0..MAX_QUEUED_MESSAGES as u64 {
+            connections
+                .send("peer", Message::Ping(nonce))
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            connections.send("peer", Message::Ping(0)).await,
+            Err(Error::PeerNotConnected(_))
+        ));
+        assert!(!connections.is_connected("peer"));
+        tokio::time::timeout(Duration::from_secs(1), closing.notified())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lost_outbound_peer_is_reconnected() {
+        let clock = Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP));
+        let a = NodeHandle::spawn(Node::with_clock("A", 1, clock.clone()).unwrap());
+        let b = node("B");
+        let b_addr = listen(b.clone(), "127.0.0.1:0").await.unwrap().to_string();
+        connect(&a, &b_addr).await.unwrap();
+        eventually(async || a.snapshot().peers.contains_key(&b_addr)).await;
+
+        let reconnecting = async || {
+            let addr = b_addr.clone();
+            a.update(move |n| n.reconnects.contains_key(&addr))
+                .await
+                .unwrap()
+        };
+        a.snapshot().connections.disconnect(&b_addr);
+        eventually(reconnecting).await;
+        maintain(&a).await;
+        assert!(!a.snapshot().connections.is_connected(&b_addr));
+
+        clock.advance(RECONNECT_BASE_DELAY);
+        maintain(&a).await;
+        eventually(async || a.snapshot().peers.contains_key(&b_addr)).await;
+        assert!(!reconnecting().await);
+    }
+
+    #[tokio::test]
+    async fn test_peer_sending_invalid_block_is_banned() {
+        /// Completes the handshake as `node` over a plaintext channel.
+        async fn open(addr: &str, node: &Node) -> TcpStream {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_u8(0).await.unwrap();
+            write_message(&mut stream, &Message::Version(node.version(1)))
+                .await
+                .unwrap();
+            let Ok(Message::Version(theirs)) = read_message(&mut stream).await else {
+                return stream;
+            };
+            let payload = handshake_payload(&theirs.network_id, theirs.nonce);
+            write_message(&mut stream, &Message::Verack(node.identity.sign(&payload)))
+                .await
+                .unwrap();
+            stream
+        }
+
+        let (a, b) = (node("A"), Node::new("B", 1).unwrap());
+        let a_addr = listen(a.clone(), "127.0.0.1:0").await.unwrap().to_string();
+        let mut stream = open(&a_addr, &b).await;
+        let mut block = a.snapshot().blockchain.blocks()[0].clone();
+        block.hash = format!("0{}", "f".repeat(63));
+        write_message(&mut stream, &Message::Block(block))
+            .await
+            .unwrap();
+
+        eventually(async || a.snapshot().bans.is_identity_banned(b.node_id(), 0)).await;
+        eventually(async || a.snapshot().peers.is_empty()).await;
+        // The banned node is refused, other nodes at its IP address are not
+        let mut refused = open(&a_addr, &b).await;
+        let closed = loop {
+            match read_message(&mut refused).await {
+                Ok(Message::Verack(_)) => continue,
+                other => break other,
+            }
+        };
+        assert!(matches!(closed, Err(Error::IO(_))));
+        assert!(!a.snapshot().is_banned(&a_addr));
+        let _other = open(&a_addr, &Node::new("C", 1).unwrap()).await;
+        eventually(async || a.snapshot().peers.len() == 1).await;
+    }
+}
@@ -0,0 +1,1011 @@
+//! How a node reacts to messages of its peers.
+
+use super::identity::{self, handshake_payload};
+use super::liveness::Handshake;
+use super::relay::{KnownInventory, RelayWindow};
+use super::{
+    Envelope, Inventory, InventoryKind, MIN_PROTOCOL_VERSION, Message, NetAddress,
+    PROTOCOL_VERSION, PeerInfo, Version,
+};
+use crate::block::{Block, BlockHeader};
+use crate::errors::{Error, Result, ValidationError};
+use crate::node::Node;
+use std::collections::HashMap;
+use tracing::{info, instrument, warn};
+
+/// Most headers sent in reply to a single [`Message::GetHeaders`].
+pub const MAX_HEADERS: usize = 500;
+/// Most blocks kept around while their chain is not longer than ours or
+/// their parents are still being downloaded.
+pub const MAX_ORPHAN_BLOCKS: usize = 2_000;
+/// Most of those blocks received from a single peer, enough for a branch
+/// as long as one reply to [`Message::GetHeaders`].
+pub const MAX_ORPHANS_PER_PEER: usize = MAX_HEADERS;
+/// Most addresses sent in a single [`Message::Addr`].
+pub const MAX_ADDRESSES: usize = 1_000;
+/// Most items in a single [`Message::Inv`] or [`Message::GetData`].
+pub const MAX_INVENTORY: usize = 1_000;
+
+/// Outcome of receiving a block from a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStatus {
+    /// Block is already part of the chain.
+    Known,
+    /// Block extended the chain or made another branch the longest one.
+    Connected,
+    /// Block connects to the chain but its branch is not longer.
+    Stored,
+    /// Parent of the block is unknown.
+    Orphan,
+}
+
+/// Block that is not part of the chain, see [`Node::accept_block`].
+#[derive(Debug, Clone)]
+struct Orphan {
+    block: Block,
+    /// Peer that sent the block.
+    from: String,
+    /// Order the orphans arrived in.
+    arrival: u64,
+}
+
+/// Blocks kept until their branch connects to the chain and is longer than
+/// it, at most [`MAX_ORPHAN_BLOCKS`] and [`MAX_ORPHANS_PER_PEER`] from each
+/// peer. The blocks that arrived first are evicted first.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OrphanPool {
+    orphans: HashMap<String, Orphan>,
+    arrivals: u64,
+}
+
+impl OrphanPool {
+    pub(crate) fn insert(&mut self, from: &str, block: Block) {
+        if self.orphans.contains_key(&block.hash) {
+            return;
+        }
+        let from_peer = self.orphans.values().filter(|o| o.from == from).count();
+        if from_peer >= MAX_ORPHANS_PER_PEER {
+            self.evict_oldest(|o| o.from == from);
+        } else if self.orphans.len() >= MAX_ORPHAN_BLOCKS {
+            self.evict_oldest(|_| true);
+        }
+        self.arrivals += 1;
+        let orphan = Orphan {
+            block,
+            from: from.to_owned(),
+            arrival: self.arrivals,
+        };
+        self.orphans.insert(orphan.block.hash.clone(), orphan);
+    }
+
+    fn evict_oldest(&mut self, filter: impl Fn(&Orphan) -> bool) {
+        if let Some(oldest) = self
+            .orphans
+            .values()
+            .filter(|o| filter(o))
+            .min_by_key(|o| o.arrival)
+            .map(|o| o.block.hash.clone())
+        {
+            self.orphans.remove(&oldest);
+        }
+    }
+
+    pub(crate) fn contains(&self, hash: &str) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    fn get(&self, hash: &str) -> Option<&Block> {
+        self.orphans.get(hash).map(|o| &o.block)
+    }
+
+    /// An orphan whose parent is the block with given hash.
+    fn child_of(&self, hash: &str) -> Option<&Block> {
+        self.orphans
+            .values()
+            .map(|o| &o.block)
+            .find(|b| b.previous_hash == hash)
+    }
+
+    fn remove(&mut self, hash: &str) {
+        self.orphans.remove(hash);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    #[allow(unused)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+}
+
+impl Node {
+    /// Messages opening a new connection to `peer`.
+    pub fn on_connected(&mut self, peer: &str, inbound: bool) -> Vec<Envelope> {
+        let handshake = Handshake::new(inbound, self.blockchain.clock().now());
+        let version = self.version(handshake.nonce);
+        self.handshakes.insert(peer.to_owned(), handshake);
+        vec![Envelope::new(peer, Message::Version(version))]
+    }
+
+    /// Forgets the closed connection, lost outbound peers are dialed again.
+    pub fn on_disconnected(&mut self, peer: &str) {
+        self.handshakes.remove(peer);
+        if let Some(info) = self.peers.remove(peer)
+            && !info.inbound
+        {
+            self.schedule_reconnect(peer);
+        }
+    }
+
+    pub(crate) fn version(&self, nonce: u64) -> Version {
+        Version {
+            version: PROTOCOL_VERSION,
+            network_id: self.network_id.clone(),
+            genesis_hash: self.blockchain.genesis_hash().to_owned(),
+            node_id: self.node_id().to_owned(),
+            best_height: self.blockchain.height(),
+            listen_addr: self.address.clone(),
+            nonce,
+        }
+    }
+
+    /// Applies a message received from peer `from` and returns the messages
+    /// the node sends in reaction.
+    #[instrument(skip(self, message), fields(node_name = self.name), level = "debug")]
+    pub fn handle_message(&mut self, from: &str, message: Message) -> Result<Vec<Envelope>> {
+        let reply = |message| vec![Envelope::new(from, message)];
+        let now = self.blockchain.clock().now();
+        let handshaken = match self.peers.get_mut(from) {
+            Some(peer) => {
+                peer.last_seen = now;
+                true
+            }
+            None => false,
+        };
+        match message {
+            Message::Version(_) | Message::Verack(_) if handshaken => Err(Error::InvalidMessage(
+                format!("peer {from} repeated the handshake"),
+            )),
+            Message::Version(version) => self.handle_version(from, version),
+            Message::Verack(signature) => self.handle_verack(from, &signature),
+            _ if !handshaken => Err(Error::HandshakeRequired(from.to_owned())),
+            Message::Pong(nonce) => {
+                self.handle_pong(from, nonce);
+                Ok(vec![])
+            }
+            Message::Ping(nonce) => Ok(reply(Message::Pong(nonce))),
+            Message::Inv(items) => {
+                check_inventory_len(&items)?;
+                for item in &items {
+                    if item.kind == InventoryKind::Transaction {
+                        self.mark_known(from, &item.hash);
+                    }
+                }
+                let missing: Vec<_> = items
+                    .into_iter()
+                    .filter(|item| !self.has_inventory(item))
+                    .collect();
+                if missing.is_empty() {
+                    return Ok(vec![]);
+                }
+                Ok(reply(Message::GetData(missing)))
+            }
+            Message::GetData(items) => {
+                check_inventory_len(&items)?;
+                let mut outbound = vec![];
+                for item in &items {
+                    let Some(message) = self.inventory_data(item) else {
+                        continue;
+                    };
+                    if item.kind == InventoryKind::Transaction {
+                        self.mark_known(from, &item.hash);
+                    }
+                    outbound.push(Envelope::new(from, message));
+                }
+                Ok(outbound)
+            }
+            Message::Block(block) => self.handle_block(from, block),
+            Message::Tx(tx) => self.accept_transaction(tx, Some(from)),
+            Message::GetHeaders(locator) => {
+                Ok(reply(Message::Headers(self.headers_after(&locator))))
+            }
+            Message::Headers(headers) => self.handle_headers(from, headers),
+            Message::Addr(addresses) => self.handle_addr(from, addresses),
+            Message::CompactBlock(compact) => self.handle_compact_block(from, compact),
+            Message::GetBlockTxn(hash, indexes) => Ok(self
+                .block_transactions(&hash, &indexes)?
+                .map(reply)
+                .unwrap_or_default()),
+            Message::BlockTxn(hash, transactions) => {
+                self.handle_block_txn(from, &hash, transactions)
+            }
+        }
+    }
+
+    /// Adds a block received from peer `from` and announces it to the other
+    /// peers once it extends the chain.
+    pub(crate) fn handle_block(&mut self, from: &str, block: Block) -> Result<Vec<Envelope>> {
+        match self.accept_block(from, block)? {
+            BlockStatus::Connected => {
+                let height = self.blockchain.height();
+                if let Some(peer) = self.peers.get_mut(from) {
+                    peer.best_height = peer.best_height.max(height);
+                }
+                Ok(self.announce_tip_except(from))
+            }
+            BlockStatus::Orphan => Ok(vec![Envelope::new(
+                from,
+                Message::GetHeaders(self.locator()),
+            )]),
+            BlockStatus::Known | BlockStatus::Stored => Ok(vec![]),
+        }
+    }
+
+    /// Rejects peers this node can't share a chain with.
+    fn check_version(&self, from: &str, version: &Version) -> Result<()> {
+        let incompatible = |reason| Err(Error::IncompatiblePeer(from.to_owned(), reason));
+        if version.version < MIN_PROTOCOL_VERSION {
+            return incompatible(format!(
+                "protocol version {} is older than {MIN_PROTOCOL_VERSION}",
+                version.version
+            ));
+        }
+        if version.network_id != self.network_id {
+            return incompatible(format!(
+                "network '{}' differs from '{}'",
+                version.network_id, self.network_id
+            ));
+        }
+        if version.genesis_hash != self.blockchain.genesis_hash() {
+            return incompatible(format!(
+                "genesis block '{}' differs from '{}'",
+                version.genesis_hash,
+                self.blockchain.genesis_hash()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the peer's version and proves this node's identity by signing
+    /// its nonce, the peer is registered once it does the same.
+    fn handle_version(&mut self, from: &str, version: Version) -> Result<Vec<Envelope>> {
+        let mut outbound = match self.handshakes.contains_key(from) {
+            true => vec![],
+            // Connection the transport did not announce, introduce this node
+            false => self.on_connected(from, true),
+        };
+        let handshake = &self.handshakes[from];
+        if handshake.version.is_some() {
+            Err(Error::InvalidMessage(format!(
+                "peer {from} sent a second version message"
+            )))?;
+        }
+        if let Err(e) = self.check_version(from, &version) {
+            if !handshake.inbound {
+                self.record_connection_failure(from);
+            }
+            return Err(e);
+        }
+        let signature = self
+            .identity
+            .sign(&handshake_payload(&self.network_id, version.nonce));
+        outbound.push(Envelope::new(from, Message::Verack(signature)));
+        if let Some(handshake) = self.handshakes.get_mut(from) {
+            handshake.version = Some(version);
+        }
+        Ok(outbound)
+    }
+
+    /// Registers the peer once it proved it holds the key of its node id and
+    /// shares known addresses with it.
+    fn handle_verack(&mut self, from: &str, signature: &str) -> Result<Vec<Envelope>> {
+        let Some(Handshake {
+            inbound,
+            nonce,
+            version: Some(version),
+            expected_id,
+            encrypted,
+            ..
+        }) = self.handshakes.get(from).cloned()
+        else {
+            return Err(Error::HandshakeRequired(from.to_owned()));
+        };
+        let payload = handshake_payload(&self.network_id, nonce);
+        if !identity::verify(&version.node_id, &payload, signature) {
+            return Err(Error::InvalidPeerSignature(from.to_owned()));
+        }
+        self.handshakes.remove(from);
+        if let Some(expected) = expected_id
+            && expected != version.node_id
+        {
+            if !inbound {
+                self.record_connection_failure(from);
+            }
+            return Err(Error::UnexpectedNodeId(from.to_owned(), expected));
+        }
+        if version.node_id == self.node_id() {
+            self.add_local_address(from);
+            return Err(Error::IncompatiblePeer(
+                from.to_owned(),
+                "connection to itself".to_owned(),
+            ));
+        }
+        let now = self.blockchain.clock().now();
+        if self.bans.is_identity_banned(&version.node_id, now) {
+            return Err(Error::PeerBanned(version.node_id));
+        }
+        info!(
+            "Peer {from} speaks version {} at height {}",
+            version.version, version.best_height
+        );
+        self.register_peer(PeerInfo {
+            address: from.to_owned(),
+            node_id: version.node_id.clone(),
+            version: version.version,
+            best_height: version.best_height,
+            listen_addr: version.listen_addr.clone(),
+            inbound,
+            connected_at: now,
+            last_seen: now,
+            failures: 0,
+            latency_ms: None,
+            ping_nonce: None,
+            encrypted,
+            known_inventory: KnownInventory::default(),
+            relayed: RelayWindow::default(),
+        })?;
+        self.reconnects.remove(from);
+        if !inbound {
+            self.record_connection_success(from, &version.node_id);
+        }
+
+        let mut outbound = vec![];
+        let addresses: Vec<_> = self
+            .address_book
+            .freshest(MAX_ADDRESSES + 1)
+            .into_iter()
+            .filter(|a| a.node_id != version.node_id)
+            .take(MAX_ADDRESSES)
+            .collect();
+        if !addresses.is_empty() {
+            outbound.push(Envelope::new(from, Message::Addr(addresses)));
+        }
+        // On its own so that the peer relays it to others
+        if !self.address.is_empty() {
+            let record = NetAddress::signed(&self.address, now, &self.identity);
+            outbound.push(Envelope::new(from, Message::Addr(vec![record])));
+        }
+        if version.best_height > self.blockchain.height() {
+            outbound.push(Envelope::new(from, Message::GetHeaders(self.locator())));
+        }
+        Ok(outbound)
+    }
+
+    fn handle_headers(&mut self, from: &str, headers: Vec<BlockHeader>) -> Result<Vec<Envelope>> {
+        if headers.len() > MAX_HEADERS {
+            Err(Error::InvalidMessage(format!(
+                "{} headers, limit {MAX_HEADERS}",
+                headers.len()
+            )))?;
+        }
+        for pair in headers.windows(2) {
+            if pair[1].previous_hash != pair[0].hash {
+                Err(Error::HeadersNotLinked)?;
+            }
+        }
+        let missing: Vec<_> = headers
+            .iter()
+            .map(|h| Inventory::block(&h.hash))
+            .filter(|item| !self.has_inventory(item))
+            .collect();
+        let mut outbound = vec![];
+        if !missing.is_empty() {
+            outbound.push(Envelope::new(from, Message::GetData(missing)));
+        }
+        if let (MAX_HEADERS, Some(last)) = (headers.len(), headers.last()) {
+            // Peer has more headers than fit in one message
+            let mut locator = vec![last.hash.clone()];
+            locator.extend(self.locator());
+            outbound.push(Envelope::new(from, Message::GetHeaders(locator)));
+        }
+        Ok(outbound)
+    }
+
+    fn has_inventory(&self, item: &Inventory) -> bool {
+        match item.kind {
+            InventoryKind::Block => {
+                self.blockchain.position(&item.hash).is_some() || self.orphans.contains(&item.hash)
+            }
+            InventoryKind::Transaction => {
+                self.seen_transactions.contains(&item.hash) || self.mempool.contains(&item.hash)
+            }
+        }
+    }
+
+    fn inventory_data(&self, item: &Inventory) -> Option<Message> {
+        match item.kind {
+            InventoryKind::Block => self
+                .blockchain
+                .position(&item.hash)
+                .map(|i| Message::Block(self.blockchain.blocks()[i].clone())),
+            InventoryKind::Transaction => self
+                .mempool
+                .get(&item.hash)
+                .map(|tx| Message::Tx(tx.clone())),
+        }
+    }
+
+    /// Hashes of chain blocks from the tip backwards, densely at first and
+    /// then exponentially sparser, ending with the genesis block. A peer
+    /// finds the latest block both chains share in it.
+    pub fn locator(&self) -> Vec<String> {
+        let blocks = self.blockchain.blocks();
+        let mut locator = vec![];
+        let mut index = blocks.len() - 1;
+        let mut step = 1;
+        while index > 0 {
+            locator.push(blocks[index].hash.clone());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = index.saturating_sub(step);
+        }
+        locator.push(blocks[0].hash.clone());
+        locator
+    }
+
+    /// Headers of the blocks following the first locator hash found in the
+    /// chain, or from the genesis block if none is.
+    pub fn headers_after(&self, locator: &[String]) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.blockchain.position(hash))
+            .map_or(0, |i| i + 1);
+        self.blockchain
+            .blocks()
+            .iter()
+            .skip(start)
+            .take(MAX_HEADERS)
+            .map(Block::header)
+            .collect()
+    }
+
+    /// Connects a block received from peer `from`. Blocks whose branch is
+    /// not longer than the chain are kept, so that the chain can switch to
+    /// the branch once it grows.
+    pub fn accept_block(&mut self, from: &str, block: Block) -> Result<BlockStatus> {
+        if self.blockchain.position(&block.hash).is_some() {
+            return Ok(BlockStatus::Known);
+        }
+        block.validate_proof_of_work(self.blockchain.difficulty())?;
+        let hash = block.hash.clone();
+        self.orphans.insert(from, block);
+
+        // Walk back to a block of the chain, giving up at the genesis of
+        // another one
+        let mut branch = vec![];
+        let mut cursor = hash;
+        let fork = loop {
+            let Some(block) = self.orphans.get(&cursor) else {
+                return Ok(BlockStatus::Orphan);
+            };
+            if branch.len() > self.orphans.len() {
+                return Ok(BlockStatus::Orphan);
+            }
+            branch.push(block.clone());
+            if block.index == 0 {
+                break None;
+            }
+            if let Some(position) = self.blockchain.position(&block.previous_hash) {
+                break Some(position + 1);
+            }
+            cursor = block.previous_hash.clone();
+        };
+        branch.reverse();
+        // Blocks that arrived before their parent continue the branch
+        while let Some(next) = self.orphans.child_of(&branch.last().unwrap().hash) {
+            branch.push(next.clone());
+        }
+        let Some(fork) = fork else {
+            warn!(
+                "Branch ending with {} has another genesis",
+                branch.last().unwrap().hash
+            );
+            for block in &branch {
+                self.orphans.remove(&block.hash);
+            }
+            return Err(Error::InvalidBlock(
+                0,
+                ValidationError::UnknownGenesis(branch[0].hash.clone()),
+            ));
+        };
+
+        if fork + branch.len() <= self.blockchain.blocks().len() {
+            return Ok(BlockStatus::Stored);
+        }
+        for block in &branch {
+            self.orphans.remove(&block.hash);
+        }
+        let tip = branch.last().unwrap().hash.clone();
+        let now = self.blockchain.clock().now();
+        match self.blockchain.with_branch(fork, branch, now) {
+            Ok(candidate) => {
+                self.adopt_chain(candidate);
+                Ok(BlockStatus::Connected)
+            }
+            Err(e) => {
+                warn!("Branch ending with {tip} is invalid: {e}");
+                Err(e)
+            }
+        }
+    }
+
+    /// Announces the chain tip to all peers.
+    pub fn announce_tip(&self) -> Vec<Envelope> {
+        self.announce_tip_except("")
+    }
+
+    fn announce_tip_except(&self, except: &str) -> Vec<Envelope> {
+        self.announce_compact_block(except)
+    }
+}
+
+fn check_inventory_len(items: &[Inventory]) -> Result<()> {
+    if items.len() > MAX_INVENTORY {
+        Err(Error::InvalidMessage(format!(
+            "{} inventory items, limit {MAX_INVENTORY}",
+            items.len()
+        )))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::block::{MIN_BLOCK_TIMESTAMP, Transaction};
+    use crate::clock::{Clock, ManualClock};
+    use crate::network::bans::Misbehavior;
+    use std::sync::Arc;
+
+    fn payment(amount: i64) -> Transaction {
+        Transaction {
+            from: "A".into(),
+            to: "B".into(),
+            amount,
+            ..Default::default()
+        }
+    }
+
+    pub(in crate::network) fn nodes(count: usize) -> (Arc<ManualClock>, Vec<Node>) {
+        let clock = Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP));
+        let nodes = (0..count)
+            .map(|i| {
+                let mut node = Node::with_clock(&format!("{i}"), 1, clock.clone()).unwrap();
+                node.address = format!("{i}");
+                node
+            })
+            .collect();
+        (clock, nodes)
+    }
+
+    /// Completes the handshake of `node` with a new node listening at
+    /// `address`, that connected to it if `inbound`, and returns the new node.
+    pub(in crate::network) fn handshake(node: &mut Node, address: &str, inbound: bool) -> Node {
+        let mut peer = Node::with_clock(address, 1, node.blockchain.clock().clone()).unwrap();
+        peer.address = address.to_owned();
+        let messages = |outbound: Vec<Envelope>, to: &str| -> Vec<Message> {
+            outbound
+                .into_iter()
+                .filter(|e| e.to == to)
+                .map(|e| e.message)
+                .collect()
+        };
+        let mut to_peer = messages(node.on_connected(address, inbound), address);
+        let mut to_node = messages(peer.on_connected(&node.address, !inbound), &node.address);
+        while !to_peer.is_empty() || !to_node.is_empty() {
+            for message in std::mem::take(&mut to_node) {
+                let outbound = node.handle_message(address, message).unwrap();
+                to_peer.extend(messages(outbound, address));
+            }
+            for message in std::mem::take(&mut to_peer) {
+                let outbound = peer.handle_message(&node.address, message).unwrap();
+                to_node.extend(messages(outbound, &node.address));
+            }
+        }
+        peer
+    }
+
+    /// Delivers messages until there are none left and returns how many were sent.
+    pub(in crate::network) fn exchange(
+        nodes: &mut [Node],
+        from: usize,
+        outbound: Vec<Envelope>,
+    ) -> usize {
+        run(nodes, outbound.into_iter().map(|e| (from, e)).collect())
+    }
+
+    fn run(nodes: &mut [Node], mut queue: Vec<(usize, Envelope)>) -> usize {
+        let mut sent = 0;
+        while !queue.is_empty() {
+            let (from, Envelope { to, message }) = queue.remove(0);
+            sent += 1;
+            let to: usize = to.parse().unwrap();
+            let replies = nodes[to]
+                .handle_message(&from.to_string(), message)
+                .unwrap();
+            queue.extend(replies.into_iter().map(|e| (to, e)));
+        }
+        sent
+    }
+
+    /// Both sides send their version before reading, like over TCP.
+    pub(in crate::network) fn connect(nodes: &mut [Node], a: usize, b: usize) {
+        let mut queue: Vec<_> = nodes[a]
+            .on_connected(&b.to_string(), false)
+            .into_iter()
+            .map(|e| (a, e))
+            .collect();
+        queue.extend(
+            nodes[b]
+                .on_connected(&a.to_string(), true)
+                .into_iter()
+                .map(|e| (b, e)),
+        );
+        run(nodes, queue);
+    }
+
+    fn tip(node: &Node) -> String {
+        node.blockchain.blocks().last().unwrap().hash.clone()
+    }
+
+    #[test]
+    fn test_version_handshake_registers_peers_and_shares_addresses() {
+        let (_, mut nodes) = nodes(3);
+        nodes[1].add_block(vec![]).unwrap();
+        connect(&mut nodes, 0, 1);
+        let info = &nodes[0].peers["1"];
+        assert_eq!(info.node_id, nodes[1].node_id());
+        assert_eq!(info.version, PROTOCOL_VERSION);
+        assert_eq!(info.best_height, 1);
+        assert_eq!(info.listen_addr, "1");
+        assert_eq!(info.connected_at, MIN_BLOCK_TIMESTAMP);
+        assert!(nodes[1].peers.contains_key("0"));
+        // The lower peer asked for the missing block during the handshake
+        assert_eq!(tip(&nodes[0]), tip(&nodes[1]));
+
+        connect(&mut nodes, 0, 2);
+        assert!(nodes[2].address_book.contains("1"));
+        assert!(!nodes[2].address_book.contains("2"));
+        // Node 1 heard about node 2 from node 0
+        assert!(nodes[1].address_book.contains("2"));
+    }
+
+    fn assert_incompatible(node: &mut Node, version: Version) {
+        match node.handle_message("x", Message::Version(version)) {
+            Err(Error::IncompatiblePeer(peer, _)) => assert_eq!(peer, "x"),
+            v => panic!("Expected error IncompatiblePeer, actual {v:?}"),
+        }
+        assert!(node.peers.is_empty());
+        node.on_disconnected("x");
+    }
+
+    #[test]
+    fn test_incompatible_peers_are_rejected() {
+        let (_, mut nodes) = nodes(2);
+        let compatible = nodes[1].version(1);
+
+        let mut other_network = compatible.clone();
+        other_network.network_id = "test".into();
+        assert_incompatible(&mut nodes[0], other_network);
+
+        let mut other_genesis = compatible.clone();
+        other_genesis.genesis_hash = Node::new("y", 2).unwrap().blockchain.genesis_hash().into();
+        assert_incompatible(&mut nodes[0], other_genesis);
+
+        let mut old_version = compatible.clone();
+        old_version.version = MIN_PROTOCOL_VERSION - 1;
+        assert_incompatible(&mut nodes[0], old_version);
+
+        let outbound = nodes[0]
+            .handle_message("x", Message::Version(compatible))
+            .unwrap();
+        // Unannounced connection is answered with a version and the verack
+        assert!(matches!(outbound[0].message, Message::Version(_)));
+        assert!(matches!(outbound[1].message, Message::Verack(_)));
+        assert!(nodes[0].handshakes.contains_key("x"));
+    }
+
+    /// Opens a connection of node `a` to node `b` at `address` and returns
+    /// what `b` signs in its verack.
+    fn open(nodes: &mut [Node], a: usize, b: usize, address: &str) -> Vec<u8> {
+        let outbound = nodes[a].on_connected(address, false);
+        let Message::Version(version) = &outbound[0].message else {
+            panic!("expected a version");
+        };
+        let payload = handshake_payload(&nodes[a].network_id, version.nonce);
+        let theirs = nodes[b].version(1);
+        nodes[a]
+            .handle_message(address, Message::Version(theirs))
+            .unwrap();
+        payload
+    }
+
+    #[test]
+    fn test_verack_must_be_signed_by_the_peer() {
+        let (_, mut nodes) = nodes(3);
+        let payload = open(&mut nodes, 0, 1, "1");
+
+        // Node 2 signs for the id node 1 claimed
+        let forged = nodes[2].identity.sign(&payload);
+        assert!(matches!(
+            nodes[0].handle_message("1", Message::Verack(forged)),
+            Err(Error::InvalidPeerSignature(_))
+        ));
+        let replayed = nodes[1]
+            .identity
+            .sign(&handshake_payload(&nodes[0].network_id, 0));
+        assert!(matches!(
+            nodes[0].handle_message("1", Message::Verack(replayed)),
+            Err(Error::InvalidPeerSignature(_))
+        ));
+        assert!(nodes[0].peers.is_empty());
+
+        let signed = nodes[1].identity.sign(&payload);
+        nodes[0]
+            .handle_message("1", Message::Verack(signed))
+            .unwrap();
+        assert_eq!(nodes[0].peers["1"].node_id, nodes[1].node_id());
+    }
+
+    #[test]
+    fn test_node_id_is_connected_only_once() {
+        let (_, mut nodes) = nodes(2);
+        connect(&mut nodes, 0, 1);
+        let payload = open(&mut nodes, 0, 1, "1b");
+        let signed = nodes[1].identity.sign(&payload);
+        assert!(matches!(
+            nodes[0].handle_message("1b", Message::Verack(signed)),
+            Err(Error::IncompatiblePeer(..))
+        ));
+        assert_eq!(nodes[0].peers.len(), 1);
+    }
+
+    #[test]
+    fn test_banned_node_id_is_rejected() {
+        let (clock, mut nodes) = nodes(2);
+        let node_id = nodes[1].node_id().to_owned();
+        nodes[0]
+            .bans
+            .record(
+                "10.0.0.1",
+                Some(&node_id),
+                Misbehavior::Impersonation,
+                clock.now(),
+            )
+            .unwrap();
+        let payload = open(&mut nodes, 0, 1, "1");
+        let signed = nodes[1].identity.sign(&payload);
+        assert!(matches!(
+            nodes[0].handle_message("1", Message::Verack(signed)),
+            Err(Error::PeerBanned(_))
+        ));
+        assert!(nodes[0].peers.is_empty());
+    }
+
+    #[test]
+    fn test_messages_before_handshake_are_rejected() {
+        let (_, mut nodes) = nodes(2);
+        assert!(matches!(
+            nodes[0].handle_message("1", Message::Ping(7)),
+            Err(Error::HandshakeRequired(peer)) if peer == "1"
+        ));
+
+        connect(&mut nodes, 0, 1);
+        let version = nodes[1].version(1);
+        assert!(matches!(
+            nodes[0].handle_message("1", Message::Version(version)),
+            Err(Error::InvalidMessage(_))
+        ));
+        assert!(matches!(
+            nodes[0].handle_message("1", Message::Verack("5ig".into())),
+            Err(Error::InvalidMessage(_))
+        ));
+    }
+
+    #[test]
+    fn test_ping_is_answered_with_pong() {
+        let (_, mut nodes) = nodes(2);
+        connect(&mut nodes, 0, 1);
+        assert_eq!(
+            nodes[0].handle_message("1", Message::Ping(7)).unwrap(),
+            vec![Envelope::new("1", Message::Pong(7))]
+        );
+    }
+
+    #[test]
+    fn test_new_block_is_announced_fetched_and_relayed() {
+        let (_, mut nodes) = nodes(3);
+        connect(&mut nodes, 0, 1);
+        connect(&mut nodes, 1, 2);
+
+        nodes[0].add_block(vec![payment(1)]).unwrap();
+        let outbound = nodes[0].announce_tip();
+        assert_eq!(outbound.len(), 1);
+        exchange(&mut nodes, 0, outbound);
+
+        assert_eq!(tip(&nodes[1]), tip(&nodes[0]));
+        assert_eq!(tip(&nodes[2]), tip(&nodes[0]));
+    }
+
+    #[test]
+    fn test_lagging_peer_syncs_through_headers() {
+        let (clock, mut nodes) = nodes(2);
+        for _ in 0..5 {
+            clock.advance(60);
+            nodes[0].add_block(vec![]).unwrap();
+        }
+        connect(&mut nodes, 1, 0);
+        assert_eq!(tip(&nodes[1]), tip(&nodes[0]));
+        assert!(nodes[1].orphans.is_empty());
+    }
+
+    #[test]
+    fn test_longer_branch_replaces_chain() {
+        let (clock, mut nodes) = nodes(2);
+        clock.advance(60);
+        nodes[1].add_block(vec![]).unwrap();
+        for _ in 0..3 {
+            clock.advance(60);
+            nodes[0].add_block(vec![payment(1)]).unwrap();
+        }
+        let fork_tip = tip(&nodes[1]);
+
+        // Blocks of the other branch arrive out of order
+        let branch: Vec<_> = nodes[0].blockchain.blocks()[1..].to_vec();
+        assert_eq!(
+            nodes[1].accept_block("0", branch[2].clone()).unwrap(),
+            BlockStatus::Orphan
+        );
+        assert_eq!(
+            nodes[1].accept_block("0", branch[0].clone()).unwrap(),
+            BlockStatus::Stored
+        );
+        assert_eq!(
+            nodes[1].accept_block("0", branch[1].clone()).unwrap(),
+            BlockStatus::Connected
+        );
+
+        assert_eq!(tip(&nodes[1]), tip(&nodes[0]));
+        assert_ne!(tip(&nodes[1]), fork_tip);
+        assert!(nodes[1].orphans.is_empty());
+        assert_eq!(
+            nodes[1].accept_block("0", branch[1].clone()).unwrap(),
+            BlockStatus::Known
+        );
+    }
+
+    #[test]
+    fn test_orphans_are_evicted_in_arrival_order_and_limited_per_peer() {
+        let genesis = Block::genesis(1).unwrap();
+        // Later blocks have lower indexes, which must not matter
+        let block = |i: usize| Block {
+            index: (2 * MAX_ORPHAN_BLOCKS - i) as u64,
+            hash: format!("{i}"),
+            ..genesis.clone()
+        };
+        let mut pool = OrphanPool::default();
+        for i in 0..=MAX_ORPHANS_PER_PEER {
+            pool.insert("a", block(i));
+        }
+        assert_eq!(pool.len(), MAX_ORPHANS_PER_PEER);
+        assert!(!pool.contains("0"));
+        assert!(pool.contains("1"));
+
+        for i in MAX_ORPHANS_PER_PEER + 1..=MAX_ORPHAN_BLOCKS {
+            pool.insert(&(i % 10).to_string(), block(i));
+        }
+        assert_eq!(pool.len(), MAX_ORPHAN_BLOCKS);
+        pool.insert("b", block(MAX_ORPHAN_BLOCKS + 1));
+        assert_eq!(pool.len(), MAX_ORPHAN_BLOCKS);
+        assert!(!pool.contains("1"));
+        assert!(pool.contains("2"));
+    }
+
+    #[test]
+    fn test_invalid_block_is_rejected_and_dropped() {
+        let (_, mut nodes) = nodes(2);
+        nodes[0].add_block(vec![]).unwrap();
+        let mut block = nodes[0].blockchain.blocks()[1].clone();
+        block.nonce += 1;
+
+        assert!(nodes[1].handle_message("0", Message::Block(block)).is_err());
+        assert!(nodes[1].orphans.is_empty());
+        assert_eq!(nodes[1].blockchain.blocks().len(), 1);
+    }
+
+    #[test]
+    fn test_branch_from_another_genesis_is_rejected() {
+        let (clock, mut nodes) = nodes(2);
+        let mut genesis = nodes[0].blockchain.blocks()[0].clone();
+        genesis.timestamp += 1;
+        genesis.mine_block(1).unwrap();
+        let mut block = Block::new(1, genesis.hash.clone(), vec![], clock.as_ref());
+        block.mine_block(1).unwrap();
+
+        assert_eq!(
+            nodes[1].accept_block("0", block).unwrap(),
+            BlockStatus::Orphan
+        );
+        match nodes[1].accept_block("0", genesis.clone()) {
+            Err(Error::InvalidBlock(0, ValidationError::UnknownGenesis(hash))) => {
+                assert_eq!(hash, genesis.hash)
+            }
+            v => panic!("Expected error UnknownGenesis, actual {v:?}"),
+        }
+        assert!(nodes[1].orphans.is_empty());
+        assert_eq!(nodes[1].blockchain.blocks().len(), 1);
+    }
+
+    #[test]
+    fn test_locator_and_headers() {
+        let (clock, mut nodes) = nodes(1);
+        for _ in 0..30 {
+            clock.advance(60);
+            nodes[0].add_block(vec![]).unwrap();
+        }
+        let locator = nodes[0].locator();
+        let blocks = nodes[0].blockchain.blocks();
+        assert_eq!(locator[0], blocks[30].hash);
+        assert_eq!(locator[9], blocks[21].hash);
+        assert_eq!(locator.last(), Some(&blocks[0].hash));
+        assert!(locator.len() < 20);
+
+        let headers = nodes[0].headers_after(&["unknown".into(), blocks[27].hash.clone()]);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers[0], blocks[28].header());
+        assert_eq!(nodes[0].headers_after(&[]).len(), 31);
+    }
+
+    #[test]
+    fn test_transactions_are_served_from_mempool() {
+        let (_, mut nodes) = nodes(2);
+        let tx = payment(3);
+        nodes[0].submit_transaction(tx.clone()).unwrap();
+        connect(&mut nodes, 0, 1);
+
+        let item = Inventory::transaction(&tx.id());
+        let outbound = nodes[1]
+            .handle_message("0", Message::Inv(vec![item.clone()]))
+            .unwrap();
+        assert_eq!(
+            outbound,
+            vec![Envelope::new("0", Message::GetData(vec![item]))]
+        );
+        exchange(&mut nodes, 1, outbound);
+        assert_eq!(nodes[1].mempool, vec![tx]);
+    }
+
+    #[test]
+    fn test_oversized_inventory_is_misbehavior() {
+        let (_, mut nodes) = nodes(2);
+        connect(&mut nodes, 0, 1);
+        let items = vec![Inventory::transaction("unknown"); MAX_INVENTORY + 1];
+
+        for message in [Message::Inv(items.clone()), Message::GetData(items)] {
+            let error = nodes[1].handle_message("0", message).unwrap_err();
+            assert!(matches!(error, Error::InvalidMessage(_)), "{error:?}");
+            assert_eq!(Misbehavior::of(&error), Some(Misbehavior::MalformedMessage));
+        }
+        assert!(
+            nodes[1]
+                .handle_message(
+                    "0",
+                    Message::Inv(vec![Inventory::transaction("a"); MAX_INVENTORY])
+                )
+                .is_ok()
+        );
+    }
+}
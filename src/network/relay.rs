@@ -0,0 +1,284 @@
+//! Relay of mempool transactions between peers.
+//!
+//! Transactions entering the mempool are announced to peers by id, peers
+//! request the ones they miss with [`Message::GetData`], validate them and
+//! announce them in turn. A node remembers the transactions it has seen
+//! lately and announces each of them once. For every peer it also remembers
+//! the transactions the peer is known to have, because it announced, sent or
+//! requested them, so that they are not echoed back. Peers relaying more
+//! transactions than [`MAX_RELAYED_TRANSACTIONS`] a minute, or ones this
+//! node rejects for reasons other than a nonce or balance conflicting with
+//! its view, are scored as spamming.
+
+use super::{Envelope, Inventory, Message};
+use crate::block::Transaction;
+use crate::errors::{Error, Result};
+use crate::node::Node;
+use std::collections::{HashSet, VecDeque};
+use tracing::{debug, instrument};
+
+/// Most transaction ids remembered for every peer.
+pub const MAX_KNOWN_INVENTORY: usize = 5000;
+/// Most transaction ids a node remembers having seen.
+pub const MAX_SEEN_TRANSACTIONS: usize = 50_000;
+/// Most transactions accepted from a peer in [`RELAY_WINDOW`] seconds.
+pub const MAX_RELAYED_TRANSACTIONS: u32 = 1000;
+pub const RELAY_WINDOW: u64 = 60;
+
+/// Count of the transactions a peer relayed since the window started.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RelayWindow {
+    start: u64,
+    count: u32,
+}
+
+impl RelayWindow {
+    /// Counts a transaction relayed at `now`, returns whether it is within
+    /// the limit.
+    pub fn admit(&mut self, now: u64) -> bool {
+        if now >= self.start + RELAY_WINDOW {
+            *self = Self {
+                start: now,
+                count: 0,
+            };
+        }
+        self.count = self.count.saturating_add(1);
+        self.count <= MAX_RELAYED_TRANSACTIONS
+    }
+}
+
+/// Set of inventory hashes that forgets the oldest ones when full.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownInventory {
+    hashes: HashSet<String>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl Default for KnownInventory {
+    fn default() -> Self {
+        Self::new(MAX_KNOWN_INVENTORY)
+    }
+}
+
+impl KnownInventory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            hashes: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Adds the hash, returns whether it is new.
+    pub fn insert(&mut self, hash: &str) -> bool {
+        if self.hashes.contains(hash) {
+            return false;
+        }
+        if self.order.len() >= self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.hashes.remove(&oldest);
+        }
+        self.hashes.insert(hash.to_owned());
+        self.order.push_back(hash.to_owned());
+        true
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    #[allow(unused)]
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    #[allow(unused)]
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+impl Node {
+    /// Notes that `peer` has the transaction, it is not announced to it.
+    pub(crate) fn mark_known(&mut self, peer: &str, id: &str) {
+        if let Some(info) = self.peers.get_mut(peer) {
+            info.known_inventory.insert(id);
+        }
+    }
+
+    /// Adds a transaction submitted by a client, or received from peer
+    /// `from`, to the mempool and announces it to the peers that don't have
+    /// it. Transactions seen before are ignored.
+    #[instrument(skip(self, transaction), fields(node_name = self.name), level = "debug")]
+    pub fn accept_transaction(
+        &mut self,
+        transaction: Transaction,
+        from: Option<&str>,
+    ) -> Result<Vec<Envelope>> {
+        let id = transaction.id();
+        if let Some(from) = from {
+            self.mark_known(from, &id);
+            let now = self.blockchain.clock().now();
+            if let Some(info) = self.peers.get_mut(from)
+                && !info.relayed.admit(now)
+            {
+                Err(Error::RelayLimitExceeded(
+                    from.to_owned(),
+                    MAX_RELAYED_TRANSACTIONS,
+                ))?;
+            }
+        }
+        if self.seen_transactions.contains(&id) {
+            debug!("Transaction {id} was seen before");
+            return Ok(vec![]);
+        }
+        self.submit_transaction(transaction)?;
+        self.seen_transactions.insert(&id);
+        Ok(self.announce_transaction(&id))
+    }
+
+    fn announce_transaction(&mut self, id: &str) -> Vec<Envelope> {
+        self.peers
+            .iter_mut()
+            .filter_map(|(address, info)| {
+                info.known_inventory
+                    .insert(id)
+                    .then(|| Envelope::new(address, Message::Inv(vec![Inventory::transaction(id)])))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::InventoryKind;
+    use crate::network::bans::Misbehavior;
+    use crate::network::protocol::tests::{connect, exchange, nodes};
+
+    fn transaction(amount: i64) -> Transaction {
+        Transaction {
+            from: "A".into(),
+            to: "B".into(),
+            amount,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_known_inventory_is_bounded() {
+        let mut known = KnownInventory::new(2);
+        assert!(known.insert("a"));
+        assert!(!known.insert("a"));
+        known.insert("b");
+        known.insert("c");
+        assert_eq!(known.len(), 2);
+        assert!(!known.contains("a"));
+        assert!(known.contains("b") && known.contains("c"));
+    }
+
+    #[test]
+    fn test_transaction_reaches_all_nodes_and_is_relayed_once() {
+        let (_, mut nodes) = nodes(4);
+        // Square of nodes, every transaction arrives at node 2 twice
+        for (a, b) in [(0, 1), (1, 2), (2, 3), (3, 0)] {
+            connect(&mut nodes, a, b);
+        }
+        let tx = transaction(5);
+        let outbound = nodes[0].accept_transaction(tx.clone(), None).unwrap();
+        assert_eq!(outbound.len(), 2);
+
+        let mut announcements = 0;
+        let mut queue: Vec<_> = outbound.into_iter().map(|e| (0, e)).collect();
+        while !queue.is_empty() {
+            let (from, Envelope { to, message }) = queue.remove(0);
+            if matches!(&message, Message::Inv(items) if items[0].kind == InventoryKind::Transaction)
+            {
+                announcements += 1;
+            }
+            let to: usize = to.parse().unwrap();
+            let replies = nodes[to]
+                .handle_message(&from.to_string(), message)
+                .unwrap();
+            queue.extend(replies.into_iter().map(|e| (to, e)));
+        }
+        for node in &nodes {
+            assert_eq!(node.mempool, vec![tx.clone()], "mempool of {}", node.name);
+        }
+        // From node 0 to its two peers and from them to node 2, which
+        // doesn't announce it back
+        assert_eq!(announcements, 4);
+        assert!(nodes.iter().all(|n| n.seen_transactions.contains(&tx.id())));
+    }
+
+    #[test]
+    fn test_transactions_are_not_echoed_to_peers_that_have_them() {
+        let (_, mut nodes) = nodes(3);
+        connect(&mut nodes, 0, 1);
+        connect(&mut nodes, 1, 2);
+        let tx = transaction(5);
+        let outbound = nodes[0].accept_transaction(tx.clone(), None).unwrap();
+        assert_eq!(
+            outbound,
+            vec![Envelope::new(
+                "1",
+                Message::Inv(vec![Inventory::transaction(&tx.id())])
+            )]
+        );
+        let outbound = nodes[1]
+            .handle_message("0", Message::Tx(tx.clone()))
+            .unwrap();
+        // Announced to node 2 only, node 0 sent it
+        assert_eq!(
+            outbound,
+            vec![Envelope::new(
+                "2",
+                Message::Inv(vec![Inventory::transaction(&tx.id())])
+            )]
+        );
+        exchange(&mut nodes, 1, outbound);
+        assert_eq!(nodes[2].mempool, vec![tx.clone()]);
+
+        // Seen transactions are neither requested nor relayed again
+        nodes[1].mine_pending().unwrap();
+        let item = Inventory::transaction(&tx.id());
+        assert!(
+            nodes[1]
+                .handle_message("2", Message::Inv(vec![item]))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            nodes[1]
+                .handle_message("2", Message::Tx(tx))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(nodes[1].mempool.is_empty());
+    }
+
+    #[test]
+    fn test_peers_relaying_too_much_or_rejected_transactions_are_spamming() {
+        let (clock, mut nodes) = nodes(2);
+        connect(&mut nodes, 0, 1);
+        for amount in 1..=MAX_RELAYED_TRANSACTIONS as i64 {
+            nodes[1]
+                .handle_message("0", Message::Tx(transaction(amount)))
+                .unwrap();
+        }
+        let error = nodes[1]
+            .handle_message("0", Message::Tx(transaction(0)))
+            .unwrap_err();
+        assert!(matches!(error, Error::RelayLimitExceeded(..)));
+        assert_eq!(Misbehavior::of(&error), Some(Misbehavior::Spam));
+
+        clock.advance(RELAY_WINDOW);
+        let error = nodes[1]
+            .handle_message("0", Message::Tx(transaction(0)))
+            .unwrap_err();
+        assert!(matches!(error, Error::InvalidAmount(..)));
+        assert_eq!(Misbehavior::of(&error), Some(Misbehavior::Spam));
+    }
+}
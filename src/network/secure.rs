@@ -0,0 +1,339 @@
+//! Encrypted and authenticated channels between peers.
+//!
+//! The dialer starts every connection with one byte telling whether it
+//! encrypts. If it does, both sides send an ephemeral x25519 public key,
+//! derive one ChaCha20-Poly1305 key per direction from the shared secret and
+//! the hash of both keys, and prove their node id by signing that hash with
+//! their identity key in the first encrypted frame. A peer in the middle
+//! can't complete the exchange without the identity key of the node it
+//! pretends to be.
+//!
+//! Encrypted frames are like the plain ones of [`super::wire`], holding the
+//! ciphertext of the encoded message. Nonces count the frames sent in each
+//! direction.
+
+use super::Message;
+use super::identity::{self, NodeIdentity};
+use super::wire::{MAX_MESSAGE_SIZE, decode, encode, read_message, write_message};
+use crate::errors::{Error, Result};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+/// Seconds the peers have to set up the channel.
+pub const CHANNEL_TIMEOUT: u64 = 10;
+/// Bytes the authentication tag adds to every encrypted frame.
+const TAG_SIZE: usize = 16;
+
+const PLAINTEXT: u8 = 0;
+const ENCRYPTED: u8 = 1;
+
+/// Whether a node encrypts its peer connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EncryptionMode {
+    /// Dials peers in plaintext, accepts encrypted peers too.
+    Disabled,
+    /// Dials peers encrypted, accepts plaintext peers too.
+    #[default]
+    Optional,
+    /// Only talks to encrypted peers, for networks that enforce encryption.
+    Required,
+}
+
+/// One direction of an encrypted channel.
+struct Cipher {
+    cipher: ChaCha20Poly1305,
+    frames: u64,
+}
+
+impl Cipher {
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            frames: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Result<Nonce> {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.frames.to_be_bytes());
+        self.frames = self
+            .frames
+            .checked_add(1)
+            .ok_or_else(|| Error::InvalidMessage("channel ran out of nonces".into()))?;
+        Ok(nonce.into())
+    }
+
+    async fn write_frame<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        body: &[u8],
+    ) -> Result<()> {
+        let nonce = self.next_nonce()?;
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, body)
+            .map_err(|_| Error::InvalidMessage("failed to encrypt frame".into()))?;
+        writer.write_u32(ciphertext.len() as u32).await?;
+        writer.write_all(&ciphertext).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    async fn read_frame<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> Result<Vec<u8>> {
+        let len = reader.read_u32().await? as usize;
+        if len > MAX_MESSAGE_SIZE + TAG_SIZE {
+            Err(Error::MessageTooLarge(len, MAX_MESSAGE_SIZE + TAG_SIZE))?;
+        }
+        let mut ciphertext = vec![0; len];
+        reader.read_exact(&mut ciphertext).await?;
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .decrypt(&nonce, ciphertext.as_slice())
+            .map_err(|_| Error::InvalidMessage("frame failed authentication".into()))
+    }
+}
+
+/// Reading half of a peer connection.
+pub struct MessageReader<R> {
+    reader: R,
+    cipher: Option<Cipher>,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    pub async fn read(&mut self) -> Result<Message> {
+        match &mut self.cipher {
+            Some(cipher) => decode(&cipher.read_frame(&mut self.reader).await?),
+            None => read_message(&mut self.reader).await,
+        }
+    }
+}
+
+/// Writing half of a peer connection.
+pub struct MessageWriter<W> {
+    writer: W,
+    cipher: Option<Cipher>,
+}
+
+impl<W: AsyncWrite + Unpin> MessageWriter<W> {
+    pub async fn write(&mut self, message: &Message) -> Result<()> {
+        let Some(cipher) = &mut self.cipher else {
+            return write_message(&mut self.writer, message).await;
+        };
+        let body = encode(message);
+        if body.len() > MAX_MESSAGE_SIZE {
+            Err(Error::MessageTooLarge(body.len(), MAX_MESSAGE_SIZE))?;
+        }
+        cipher.write_frame(&mut self.writer, &body).await
+    }
+}
+
+/// Peer connection ready to exchange messages.
+pub struct Channel<R, W> {
+    pub reader: MessageReader<R>,
+    pub writer: MessageWriter<W>,
+    /// Node id the peer proved, `None` for plaintext channels.
+    pub peer_id: Option<String>,
+}
+
+impl<R, W> Channel<R, W> {
+    pub fn is_encrypted(&self) -> bool {
+        self.peer_id.is_some()
+    }
+}
+
+/// Sets up the channel on a new TCP connection, encrypted unless the dialer
+/// has encryption disabled.
+pub async fn open_channel(
+    stream: TcpStream,
+    identity: &NodeIdentity,
+    mode: EncryptionMode,
+    inbound: bool,
+) -> Result<Channel<OwnedReadHalf, OwnedWriteHalf>> {
+    let peer = stream.peer_addr()?.to_string();
+    // The handshake is a few small writes, each waiting for the previous
+    // one to be acknowledged otherwise
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+    let setup = open(reader, writer, identity, mode, inbound, &peer);
+    tokio::time::timeout(Duration::from_secs(CHANNEL_TIMEOUT), setup)
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))?
+}
+
+async fn open<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    mut reader: R,
+    mut writer: W,
+    identity: &NodeIdentity,
+    mode: EncryptionMode,
+    inbound: bool,
+    peer: &str,
+) -> Result<Channel<R, W>> {
+    let encrypted = if inbound {
+        match reader.read_u8().await? {
+            ENCRYPTED => true,
+            PLAINTEXT if mode == EncryptionMode::Required => {
+                Err(Error::EncryptionRequired(peer.to_owned()))?
+            }
+            PLAINTEXT => false,
+            byte => Err(Error::InvalidMessage(format!(
+                "unknown channel kind {byte}"
+            )))?,
+        }
+    } else {
+        let encrypted = mode != EncryptionMode::Disabled;
+        writer
+            .write_u8(if encrypted { ENCRYPTED } else { PLAINTEXT })
+            .await?;
+        encrypted
+    };
+    if !encrypted {
+        return Ok(Channel {
+            reader: MessageReader {
+                reader,
+                cipher: None,
+            },
+            writer: MessageWriter {
+                writer,
+                cipher: None,
+            },
+            peer_id: None,
+        });
+    }
+
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let ours = PublicKey::from(&secret);
+    writer.write_all(ours.as_bytes()).await?;
+    writer.flush().await?;
+    let mut theirs = [0; 32];
+    reader.read_exact(&mut theirs).await?;
+    let theirs = PublicKey::from(theirs);
+    let shared = secret.diffie_hellman(&theirs);
+    if !shared.was_contributory() {
+        Err(Error::InvalidMessage(format!(
+            "peer {peer} sent a weak key"
+        )))?;
+    }
+
+    let (dialer, listener) = match inbound {
+        true => (theirs, ours),
+        false => (ours, theirs),
+    };
+    let transcript = Sha256::new()
+        .chain_update(b"p2p-channel")
+        .chain_update(dialer.as_bytes())
+        .chain_update(listener.as_bytes())
+        .finalize();
+    let keys = Hkdf::<Sha256>::new(Some(&transcript), shared.as_bytes());
+    let (mut dialer_key, mut listener_key) = ([0; 32], [0; 32]);
+    keys.expand(b"dialer", &mut dialer_key)
+        .and_then(|_| keys.expand(b"listener", &mut listener_key))
+        .map_err(|_| Error::InvalidMessage("failed to derive channel keys".into()))?;
+    let (mut sending, mut receiving) = match inbound {
+        true => (Cipher::new(&listener_key), Cipher::new(&dialer_key)),
+        false => (Cipher::new(&dialer_key), Cipher::new(&listener_key)),
+    };
+
+    // Each side signs the transcript together with its role, so that a
+    // proof can't be reflected back to its sender
+    let proof = identity.sign(&proof_payload(&transcript, inbound));
+    let auth = format!("{}:{proof}", identity.node_id());
+    sending.write_frame(&mut writer, auth.as_bytes()).await?;
+    let auth = receiving.read_frame(&mut reader).await?;
+    let auth = String::from_utf8_lossy(&auth);
+    let (peer_id, proof) = auth.split_once(':').unwrap_or_default();
+    if !identity::verify(peer_id, &proof_payload(&transcript, !inbound), proof) {
+        Err(Error::InvalidPeerSignature(peer.to_owned()))?;
+    }
+    Ok(Channel {
+        reader: MessageReader {
+            reader,
+            cipher: Some(receiving),
+        },
+        writer: MessageWriter {
+            writer,
+            cipher: Some(sending),
+        },
+        peer_id: Some(peer_id.to_owned()),
+    })
+}
+
+fn proof_payload(transcript: &[u8], listener: bool) -> Vec<u8> {
+    let role: &[u8] = if listener { b"listener" } else { b"dialer" };
+    [b"p2p-channel:", role, b":", transcript].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf, split};
+
+    type TestChannel = Channel<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>;
+
+    async fn pair(
+        dialer: &NodeIdentity,
+        dialer_mode: EncryptionMode,
+        listener: &NodeIdentity,
+        listener_mode: EncryptionMode,
+    ) -> (Result<TestChannel>, Result<TestChannel>) {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let ((ar, aw), (br, bw)) = (split(a), split(b));
+        tokio::join!(
+            open(ar, aw, dialer, dialer_mode, false, "b"),
+            open(br, bw, listener, listener_mode, true, "a")
+        )
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_channel_authenticates_both_sides() {
+        let (a, b) = (NodeIdentity::generate(), NodeIdentity::generate());
+        let (dialer, listener) =
+            pair(&a, EncryptionMode::Optional, &b, EncryptionMode::Disabled).await;
+        let (mut dialer, mut listener) = (dialer.unwrap(), listener.unwrap());
+        assert_eq!(dialer.peer_id.as_deref(), Some(b.node_id()));
+        assert_eq!(listener.peer_id.as_deref(), Some(a.node_id()));
+
+        for nonce in 0..3 {
+            dialer.writer.write(&Message::Ping(nonce)).await.unwrap();
+            assert_eq!(listener.reader.read().await.unwrap(), Message::Ping(nonce));
+            listener.writer.write(&Message::Pong(nonce)).await.unwrap();
+            assert_eq!(dialer.reader.read().await.unwrap(), Message::Pong(nonce));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_peers_are_refused_when_encryption_is_required() {
+        let (a, b) = (NodeIdentity::generate(), NodeIdentity::generate());
+        let (dialer, listener) =
+            pair(&a, EncryptionMode::Disabled, &b, EncryptionMode::Optional).await;
+        assert!(!dialer.unwrap().is_encrypted());
+        assert!(!listener.unwrap().is_encrypted());
+
+        let (_, listener) = pair(&a, EncryptionMode::Disabled, &b, EncryptionMode::Required).await;
+        assert!(matches!(listener, Err(Error::EncryptionRequired(_))));
+    }
+
+    #[tokio::test]
+    async fn test_tampered_frames_are_rejected() {
+        let key = [7; 32];
+        let mut frames = vec![];
+        Cipher::new(&key)
+            .write_frame(&mut frames, &encode(&Message::Ping(1)))
+            .await
+            .unwrap();
+        let mut receiving = Cipher::new(&key);
+        let last = frames.len() - 1;
+        frames[last] ^= 1;
+        assert!(matches!(
+            receiving.read_frame(&mut frames.as_slice()).await,
+            Err(Error::InvalidMessage(_))
+        ));
+    }
+}
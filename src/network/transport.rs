@@ -0,0 +1,136 @@
+use super::{Envelope, Message};
+use crate::actor::NodeHandle;
+use crate::errors::{Error, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, PoisonError, RwLock};
+use tracing::{debug, warn};
+
+/// Delivers messages to peers.
+#[async_trait]
+pub trait PeerClient: Send + Sync {
+    async fn send(&self, to: &str, message: Message) -> Result<()>;
+}
+
+pub type SharedPeerClient = Arc<dyn PeerClient>;
+
+/// Sends all messages, logging the ones that could not be delivered.
+pub async fn deliver(client: &dyn PeerClient, outbound: Vec<Envelope>) {
+    for Envelope { to, message } in outbound {
+        match client.send(&to, message).await {
+            Ok(()) => debug!("Delivered message to peer {to}"),
+            Err(err) => warn!("Failed to deliver message to {to}: {err}"),
+        }
+    }
+}
+
+/// In-process network handing messages directly to registered nodes.
+#[allow(unused)]
+#[derive(Default)]
+pub struct MemoryNetwork {
+    nodes: RwLock<HashMap<String, NodeHandle>>,
+}
+
+#[allow(unused)]
+impl MemoryNetwork {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Makes `node` reachable at its address.
+    pub fn register(&self, node: NodeHandle) {
+        let address = node.snapshot().address.clone();
+        self.nodes
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(address, node);
+    }
+
+    /// Client sending messages on behalf of the node at `address`.
+    pub fn client(self: &Arc<Self>, address: &str) -> MemoryPeerClient {
+        MemoryPeerClient {
+            from: address.to_owned(),
+            network: self.clone(),
+        }
+    }
+}
+
+#[allow(unused)]
+pub struct MemoryPeerClient {
+    from: String,
+    network: Arc<MemoryNetwork>,
+}
+
+#[async_trait]
+impl PeerClient for MemoryPeerClient {
+    async fn send(&self, to: &str, message: Message) -> Result<()> {
+        let node = self
+            .network
+            .nodes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(to)
+            .cloned()
+            .ok_or_else(|| Error::PeerNotConnected(to.to_owned()))?;
+        let from = self.from.clone();
+        let outbound = node
+            .update(move |node| node.handle_message(&from, message))
+            .await??;
+        deliver(&self.network.client(to), outbound).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+
+    fn node(address: &str) -> NodeHandle {
+        let mut node = Node::new(address, 1).unwrap();
+        node.address = address.to_string();
+        NodeHandle::spawn(node)
+    }
+
+    async fn connect(network: &Arc<MemoryNetwork>, nodes: &[NodeHandle], a: usize, b: usize) {
+        for (from, to, inbound) in [(a, b, false), (b, a, true)] {
+            let (from, to) = (&nodes[from], nodes[to].snapshot().address.clone());
+            let outbound = from
+                .update(move |node| node.on_connected(&to, inbound))
+                .await
+                .unwrap();
+            deliver(&network.client(&from.snapshot().address), outbound).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_network_relays_blocks() {
+        let network = MemoryNetwork::new();
+        let nodes: Vec<_> = ["a", "b", "c"].into_iter().map(node).collect();
+        for node in &nodes {
+            network.register(node.clone());
+        }
+        connect(&network, &nodes, 0, 1).await;
+        connect(&network, &nodes, 1, 2).await;
+        assert_eq!(nodes[1].snapshot().peers.len(), 2);
+
+        let outbound = nodes[2]
+            .update(|c| {
+                c.add_block(vec![]).unwrap();
+                c.announce_tip()
+            })
+            .await
+            .unwrap();
+        deliver(&network.client("c"), outbound).await;
+        assert!(nodes.iter().all(|n| n.snapshot().blockchain.height() == 1));
+    }
+
+    #[tokio::test]
+    async fn test_memory_peer_unknown_address() {
+        let network = MemoryNetwork::new();
+        assert!(matches!(
+            network.client("a").send("nobody", Message::Ping(1)).await,
+            Err(Error::PeerNotConnected(_))
+        ));
+    }
+}
@@ -0,0 +1,560 @@
+//! Binary encoding of peer messages.
+//!
+//! Every message is sent as a frame: a big-endian `u32` length followed by
+//! that many bytes holding a one byte command and the command's payload.
+//! Integers are big-endian, strings and byte arrays are prefixed with their
+//! `u32` length, lists with their `u32` element count and optional values
+//! with a `0`/`1` byte.
+
+use super::{CompactBlock, Inventory, InventoryKind, Message, NetAddress, Version};
+use crate::block::{
+    Block, BlockHeader, KeySignature, LockTime, MAX_BLOCK_SIZE, Multisig, ScriptSpend, Transaction,
+};
+use crate::errors::{Error, Result};
+use crate::script::Script;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest frame accepted from a peer, leaves room for a full block.
+pub const MAX_MESSAGE_SIZE: usize = 2 * MAX_BLOCK_SIZE;
+
+const VERSION: u8 = 0;
+const VERACK: u8 = 1;
+const PING: u8 = 2;
+const PONG: u8 = 3;
+const INV: u8 = 4;
+const GET_DATA: u8 = 5;
+const BLOCK: u8 = 6;
+const TX: u8 = 7;
+const GET_HEADERS: u8 = 8;
+const HEADERS: u8 = 9;
+const ADDR: u8 = 10;
+const COMPACT_BLOCK: u8 = 11;
+const GET_BLOCK_TXN: u8 = 12;
+const BLOCK_TXN: u8 = 13;
+
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &Message) -> Result<()> {
+    let body = encode(message);
+    if body.len() > MAX_MESSAGE_SIZE {
+        Err(Error::MessageTooLarge(body.len(), MAX_MESSAGE_SIZE))?;
+    }
+    writer.write_u32(body.len() as u32).await?;
+    writer.write_all(&body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Message> {
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_MESSAGE_SIZE {
+        Err(Error::MessageTooLarge(len, MAX_MESSAGE_SIZE))?;
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await?;
+    decode(&body)
+}
+
+pub fn encode(message: &Message) -> Vec<u8> {
+    let mut w = Writer::default();
+    match message {
+        Message::Version(v) => {
+            w.u8(VERSION);
+            w.u32(v.version);
+            w.str(&v.network_id);
+            w.str(&v.genesis_hash);
+            w.str(&v.node_id);
+            w.u64(v.best_height);
+            w.str(&v.listen_addr);
+            w.u64(v.nonce);
+        }
+        Message::Verack(signature) => {
+            w.u8(VERACK);
+            w.str(signature);
+        }
+        Message::Ping(nonce) => {
+            w.u8(PING);
+            w.u64(*nonce);
+        }
+        Message::Pong(nonce) => {
+            w.u8(PONG);
+            w.u64(*nonce);
+        }
+        Message::Inv(items) => {
+            w.u8(INV);
+            w.list(items, Writer::inventory);
+        }
+        Message::GetData(items) => {
+            w.u8(GET_DATA);
+            w.list(items, Writer::inventory);
+        }
+        Message::Block(block) => {
+            w.u8(BLOCK);
+            w.block(block);
+        }
+        Message::Tx(tx) => {
+            w.u8(TX);
+            w.transaction(tx);
+        }
+        Message::GetHeaders(locator) => {
+            w.u8(GET_HEADERS);
+            w.list(locator, |w, hash| w.str(hash));
+        }
+        Message::Headers(headers) => {
+            w.u8(HEADERS);
+            w.list(headers, Writer::header);
+        }
+        Message::Addr(addresses) => {
+            w.u8(ADDR);
+            w.list(addresses, |w, address| {
+                w.str(&address.address);
+                w.u64(address.last_seen);
+                w.str(&address.node_id);
+                w.str(&address.signature);
+            });
+        }
+        Message::CompactBlock(compact) => {
+            w.u8(COMPACT_BLOCK);
+            w.header(&compact.header);
+            w.u64(compact.salt);
+            w.list(&compact.short_ids, |w, id| w.u64(*id));
+        }
+        Message::GetBlockTxn(hash, indexes) => {
+            w.u8(GET_BLOCK_TXN);
+            w.str(hash);
+            w.list(indexes, |w, index| w.u32(*index));
+        }
+        Message::BlockTxn(hash, transactions) => {
+            w.u8(BLOCK_TXN);
+            w.str(hash);
+            w.list(transactions, Writer::transaction);
+        }
+    }
+    w.0
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Message> {
+    let mut r = Reader { bytes };
+    let message = match r.u8()? {
+        VERSION => Message::Version(Version {
+            version: r.u32()?,
+            network_id: r.str()?,
+            genesis_hash: r.str()?,
+            node_id: r.str()?,
+            best_height: r.u64()?,
+            listen_addr: r.str()?,
+            nonce: r.u64()?,
+        }),
+        VERACK => Message::Verack(r.str()?),
+        PING => Message::Ping(r.u64()?),
+        PONG => Message::Pong(r.u64()?),
+        INV => Message::Inv(r.list(Reader::inventory)?),
+        GET_DATA => Message::GetData(r.list(Reader::inventory)?),
+        BLOCK => Message::Block(r.block()?),
+        TX => Message::Tx(r.transaction()?),
+        GET_HEADERS => Message::GetHeaders(r.list(Reader::str)?),
+        HEADERS => Message::Headers(r.list(Reader::header)?),
+        ADDR => Message::Addr(r.list(|r| {
+            Ok(NetAddress {
+                address: r.str()?,
+                last_seen: r.u64()?,
+                node_id: r.str()?,
+                signature: r.str()?,
+            })
+        })?),
+        COMPACT_BLOCK => Message::CompactBlock(CompactBlock {
+            header: r.header()?,
+            salt: r.u64()?,
+            short_ids: r.list(Reader::u64)?,
+        }),
+        GET_BLOCK_TXN => Message::GetBlockTxn(r.str()?, r.list(Reader::u32)?),
+        BLOCK_TXN => Message::BlockTxn(r.str()?, r.list(Reader::transaction)?),
+        command => Err(invalid(format!("unknown command {command}")))?,
+    };
+    if !r.bytes.is_empty() {
+        Err(invalid(format!("{} trailing bytes", r.bytes.len())))?;
+    }
+    Ok(message)
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidMessage(reason.into())
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend(value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend(value.to_be_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.0.extend(value.to_be_bytes());
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value);
+    }
+
+    fn str(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+
+    fn option<T>(&mut self, value: &Option<T>, write: impl FnOnce(&mut Self, &T)) {
+        match value {
+            None => self.u8(0),
+            Some(value) => {
+                self.u8(1);
+                write(self, value);
+            }
+        }
+    }
+
+    fn list<T>(&mut self, values: &[T], mut write: impl FnMut(&mut Self, &T)) {
+        self.u32(values.len() as u32);
+        for value in values {
+            write(self, value);
+        }
+    }
+
+    fn inventory(&mut self, inventory: &Inventory) {
+        self.u8(match inventory.kind {
+            InventoryKind::Block => 0,
+            InventoryKind::Transaction => 1,
+        });
+        self.str(&inventory.hash);
+    }
+
+    fn header(&mut self, header: &BlockHeader) {
+        self.u64(header.index);
+        self.u64(header.timestamp);
+        self.str(&header.previous_hash);
+        self.str(&header.hash);
+        self.u64(header.nonce);
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.u64(block.index);
+        self.u64(block.timestamp);
+        self.str(&block.previous_hash);
+        self.str(&block.hash);
+        self.list(&block.transactions, Self::transaction);
+        self.u64(block.nonce);
+    }
+
+    fn transaction(&mut self, tx: &Transaction) {
+        self.str(&tx.from);
+        self.str(&tx.to);
+        self.i64(tx.amount);
+        self.u64(tx.nonce);
+        self.option(&tx.public_key, |w, key| w.str(key));
+        self.option(&tx.signature, |w, signature| w.str(signature));
+        self.option(&tx.multisig, |w, multisig| {
+            w.u64(multisig.threshold as u64);
+            w.list(&multisig.public_keys, |w, key| w.str(key));
+            w.list(&multisig.signatures, |w, signature| {
+                w.str(&signature.public_key);
+                w.str(&signature.signature);
+            });
+        });
+        self.option(&tx.lock_until, |w, lock| match lock {
+            LockTime::Height(height) => {
+                w.u8(0);
+                w.u64(*height);
+            }
+            LockTime::Timestamp(timestamp) => {
+                w.u8(1);
+                w.u64(*timestamp);
+            }
+        });
+        self.option(&tx.script, |w, script| {
+            w.bytes(script.locking.as_bytes());
+            w.bytes(script.unlocking.as_bytes());
+        });
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            Err(invalid("unexpected end of message"))?;
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn str(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| invalid("string is not UTF-8"))
+    }
+
+    fn option<T>(&mut self, read: impl FnOnce(&mut Self) -> Result<T>) -> Result<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(read(self)?)),
+            flag => Err(invalid(format!("invalid option flag {flag}"))),
+        }
+    }
+
+    fn list<T>(&mut self, mut read: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let len = self.u32()? as usize;
+        // Every element takes at least one byte, which bounds the length
+        if len > self.bytes.len() {
+            Err(invalid("list is longer than the message"))?;
+        }
+        // Decoded elements can be far larger than their encoding, so no more
+        // is reserved up front than the rest of the message takes
+        let mut values = Vec::with_capacity(len.min(self.bytes.len() / size_of::<T>().max(1)));
+        for _ in 0..len {
+            values.push(read(self)?);
+        }
+        Ok(values)
+    }
+
+    fn inventory(&mut self) -> Result<Inventory> {
+        let kind = match self.u8()? {
+            0 => InventoryKind::Block,
+            1 => InventoryKind::Transaction,
+            kind => Err(invalid(format!("unknown inventory kind {kind}")))?,
+        };
+        Ok(Inventory {
+            kind,
+            hash: self.str()?,
+        })
+    }
+
+    fn header(&mut self) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            index: self.u64()?,
+            timestamp: self.u64()?,
+            previous_hash: self.str()?,
+            hash: self.str()?,
+            nonce: self.u64()?,
+        })
+    }
+
+    fn block(&mut self) -> Result<Block> {
+        Ok(Block {
+            index: self.u64()?,
+            timestamp: self.u64()?,
+            previous_hash: self.str()?,
+            hash: self.str()?,
+            transactions: self.list(Self::transaction)?,
+            nonce: self.u64()?,
+        })
+    }
+
+    fn transaction(&mut self) -> Result<Transaction> {
+        Ok(Transaction {
+            from: self.str()?,
+            to: self.str()?,
+            amount: self.i64()?,
+            nonce: self.u64()?,
+            public_key: self.option(Self::str)?,
+            signature: self.option(Self::str)?,
+            multisig: self.option(|r| {
+                Ok(Multisig {
+                    threshold: r.u64()? as usize,
+                    public_keys: r.list(Self::str)?,
+                    signatures: r.list(|r| {
+                        Ok(KeySignature {
+                            public_key: r.str()?,
+                            signature: r.str()?,
+                        })
+                    })?,
+                })
+            })?,
+            lock_until: self.option(|r| match r.u8()? {
+                0 => Ok(LockTime::Height(r.u64()?)),
+                1 => Ok(LockTime::Timestamp(r.u64()?)),
+                kind => Err(invalid(format!("unknown lock kind {kind}"))),
+            })?,
+            script: self.option(|r| {
+                Ok(ScriptSpend {
+                    locking: Script::from_bytes(r.bytes()?),
+                    unlocking: Script::from_bytes(r.bytes()?),
+                })
+            })?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::crypto;
+    use crate::network::identity::NodeIdentity;
+
+    fn roundtrip(message: Message) {
+        let bytes = encode(&message);
+        assert_eq!(decode(&bytes).unwrap(), message);
+    }
+
+    fn transactions() -> Vec<Transaction> {
+        let key = crypto::generate_signing_key();
+        let mut signed = Transaction {
+            from: crypto::address_from_public_key(&key.verifying_key()),
+            to: "B".into(),
+            amount: -5,
+            nonce: 7,
+            lock_until: Some(LockTime::Timestamp(1_800_000_000)),
+            ..Default::default()
+        };
+        signed.sign(&key);
+        let multisig = Transaction {
+            multisig: Some(Multisig {
+                threshold: 1,
+                public_keys: vec!["aa".into(), "bb".into()],
+                signatures: vec![KeySignature {
+                    public_key: "aa".into(),
+                    signature: "cc".into(),
+                }],
+            }),
+            lock_until: Some(LockTime::Height(3)),
+            ..Default::default()
+        };
+        let script = Transaction {
+            script: Some(ScriptSpend {
+                locking: Script::from_asm("OP_TRUE").unwrap(),
+                unlocking: Script::default(),
+            }),
+            ..Default::default()
+        };
+        vec![Transaction::default(), signed, multisig, script]
+    }
+
+    #[test]
+    fn test_messages_roundtrip() {
+        let clock = ManualClock::new(1_800_000_000);
+        let block = Block::new(4, "ab".repeat(32), transactions(), &clock);
+
+        roundtrip(Message::Version(Version {
+            version: 1,
+            network_id: "main".into(),
+            genesis_hash: "00ab".into(),
+            node_id: "f00d".into(),
+            best_height: 42,
+            listen_addr: "127.0.0.1:4000".into(),
+            nonce: 7,
+        }));
+        roundtrip(Message::Verack("5ig".into()));
+        roundtrip(Message::Ping(u64::MAX));
+        roundtrip(Message::Pong(0));
+        roundtrip(Message::Inv(vec![
+            Inventory::block(&block.hash),
+            Inventory::transaction("ff"),
+        ]));
+        roundtrip(Message::GetData(vec![]));
+        roundtrip(Message::Block(block.clone()));
+        for tx in transactions() {
+            roundtrip(Message::Tx(tx));
+        }
+        roundtrip(Message::GetHeaders(vec![block.hash.clone(), "0".into()]));
+        roundtrip(Message::Headers(vec![block.header()]));
+        roundtrip(Message::CompactBlock(CompactBlock::new(&block, 3)));
+        roundtrip(Message::GetBlockTxn(block.hash.clone(), vec![0, 2]));
+        roundtrip(Message::BlockTxn(block.hash.clone(), transactions()));
+        let identity = NodeIdentity::generate();
+        roundtrip(Message::Addr(vec![NetAddress::signed(
+            "127.0.0.1:4001",
+            1_800_000_000,
+            &identity,
+        )]));
+    }
+
+    #[test]
+    fn test_decoded_block_keeps_its_hash_valid() {
+        let clock = ManualClock::new(1_800_000_000);
+        let mut block = Block::new(1, "0".repeat(64), transactions(), &clock);
+        block.mine_block(1).unwrap();
+
+        let Message::Block(decoded) = decode(&encode(&Message::Block(block.clone()))).unwrap()
+        else {
+            panic!("expected a block");
+        };
+        assert_eq!(decoded, block);
+        assert_eq!(decoded.compute_hash(), block.hash);
+    }
+
+    #[test]
+    fn test_malformed_messages_are_rejected() {
+        let mut bytes = encode(&Message::Ping(1));
+        assert!(matches!(decode(&bytes[..5]), Err(Error::InvalidMessage(_))));
+        bytes.push(0);
+        assert!(matches!(decode(&bytes), Err(Error::InvalidMessage(_))));
+        assert!(matches!(decode(&[200]), Err(Error::InvalidMessage(_))));
+        assert!(matches!(decode(&[]), Err(Error::InvalidMessage(_))));
+        // A huge list length must not allocate before failing
+        assert!(matches!(
+            decode(&[ADDR, 0xff, 0xff, 0xff, 0xff]),
+            Err(Error::InvalidMessage(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_frames_over_a_stream() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let sent = vec![
+            Message::Ping(1),
+            Message::Addr(vec![NetAddress::signed(
+                &"a".repeat(100),
+                1,
+                &NodeIdentity::generate(),
+            )]),
+        ];
+        let expected = sent.clone();
+        let writer = tokio::spawn(async move {
+            for message in &sent {
+                write_message(&mut client, message).await.unwrap();
+            }
+        });
+        for message in expected {
+            assert_eq!(read_message(&mut server).await.unwrap(), message);
+        }
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_rejected() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_u32(MAX_MESSAGE_SIZE as u32 + 1).await.unwrap();
+        assert!(matches!(
+            read_message(&mut server).await,
+            Err(Error::MessageTooLarge(_, MAX_MESSAGE_SIZE))
+        ));
+    }
+}
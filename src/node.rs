@@ -1,25 +1,94 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
 
-use crate::block::Transaction;
-use crate::errors::Result;
+use crate::block::{MAX_TRANSACTIONS_PER_BLOCK, Transaction};
+use crate::clock::{self, SharedClock};
+use crate::errors::{Error, Result};
+use crate::mempool::Mempool;
+use crate::network::bans::BanList;
+use crate::network::compact::{CompactBlockStats, PendingBlock};
+use crate::network::discovery::AddressBook;
+use crate::network::identity::NodeIdentity;
+use crate::network::liveness::{Handshake, Reconnect};
+use crate::network::protocol::OrphanPool;
+use crate::network::relay::{KnownInventory, MAX_SEEN_TRANSACTIONS};
+use crate::network::secure::EncryptionMode;
+use crate::network::{DEFAULT_NETWORK_ID, PeerInfo, p2p::ConnectionManager};
 use crate::{block::Block, blockchain::Blockchain};
 use tracing::{error, info, instrument};
 
+/// Most transactions waiting in the mempool.
+pub const MAX_MEMPOOL_TRANSACTIONS: usize = 5000;
+
 pub struct Node {
     pub address: String,
     pub name: String,
+    /// Keypair the node is known by to its peers.
+    pub identity: NodeIdentity,
+    /// Network the node belongs to, it only talks to peers of the same one.
+    pub network_id: String,
+    /// Whether peer connections are encrypted.
+    pub encryption: EncryptionMode,
     pub blockchain: Blockchain,
-    pub peers: HashSet<String>,
+    /// Peers that completed the version handshake, by connection address.
+    pub peers: BTreeMap<String, PeerInfo>,
+    /// Connections still waiting for the peer's version.
+    pub handshakes: HashMap<String, Handshake>,
+    /// Lost outbound peers to dial again.
+    pub reconnects: BTreeMap<String, Reconnect>,
+    /// IP addresses of peers that broke the rules too often.
+    pub bans: BanList,
+    /// Listening addresses of other nodes learned from peers.
+    pub address_book: AddressBook,
+    /// Addresses received from peers lately, by the time they were first
+    /// seen, so that gossip doesn't go round in circles.
+    pub(crate) seen_addresses: HashMap<String, u64>,
+    /// Addresses that turned out to lead back to this node.
+    pub local_addresses: BTreeSet<String>,
+    /// Nodes to connect to when no other peer is known.
+    pub seeds: Vec<String>,
+    pub mempool: Mempool,
+    /// Ids of transactions received lately, each is relayed once.
+    pub(crate) seen_transactions: KnownInventory,
+    /// Received blocks that are not part of the chain, see
+    /// [`Node::accept_block`].
+    pub(crate) orphans: OrphanPool,
+    /// Compact blocks waiting for transactions missing from the mempool.
+    pub(crate) pending_blocks: HashMap<String, PendingBlock>,
+    pub compact_stats: CompactBlockStats,
+    pub connections: Arc<ConnectionManager>,
 }
 
 impl Node {
-    #[instrument(name = "create_new_node", level = "info")]
     pub fn new(name: &str, difficulty: usize) -> Result<Self> {
+        Self::with_clock(name, difficulty, clock::system())
+    }
+
+    /// Creates a node that reads the current time from `clock` when mining
+    /// and validating blocks.
+    #[instrument(name = "create_new_node", level = "info")]
+    pub fn with_clock(name: &str, difficulty: usize, clock: SharedClock) -> Result<Self> {
         Ok(Self {
             address: String::new(),
             name: name.to_string(),
-            blockchain: Blockchain::new(difficulty)?,
-            peers: HashSet::new(),
+            identity: NodeIdentity::generate(),
+            network_id: DEFAULT_NETWORK_ID.to_string(),
+            encryption: EncryptionMode::default(),
+            blockchain: Blockchain::with_clock(difficulty, clock)?,
+            peers: BTreeMap::new(),
+            handshakes: HashMap::new(),
+            reconnects: BTreeMap::new(),
+            bans: BanList::default(),
+            address_book: AddressBook::default(),
+            seen_addresses: HashMap::new(),
+            local_addresses: BTreeSet::new(),
+            seeds: Vec::new(),
+            mempool: Mempool::default(),
+            seen_transactions: KnownInventory::new(MAX_SEEN_TRANSACTIONS),
+            orphans: OrphanPool::default(),
+            pending_blocks: HashMap::new(),
+            compact_stats: CompactBlockStats::default(),
+            connections: Arc::default(),
         })
     }
 
@@ -28,22 +97,129 @@ impl Node {
         self.blockchain.add_block(transactions)
     }
 
+    /// Nonce the next signed transaction from `address` must carry, counting
+    /// transactions still waiting in the mempool.
+    #[allow(unused)]
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        pending_nonce(&self.blockchain, &self.mempool, address)
+    }
+
+    #[instrument(skip(self), fields(node_name = self.name), level = "info")]
+    pub fn submit_transaction(&mut self, transaction: Transaction) -> Result<()> {
+        if self.mempool.len() >= MAX_MEMPOOL_TRANSACTIONS {
+            Err(Error::MempoolFull(MAX_MEMPOOL_TRANSACTIONS))?;
+        }
+        self.blockchain
+            .check_pending_transaction(&self.mempool, &transaction)?;
+        self.mempool.push(transaction);
+        Ok(())
+    }
+
+    /// Picks the mempool transactions that can be included in a block with
+    /// given index and timestamp, returns them along with whether each
+    /// mempool transaction is held until its lock expires or until its
+    /// script conditions are met. Signed transactions following a held one
+    /// of the same sender are held too to keep nonces in order.
+    fn ready_transactions(&self, height: u64, timestamp: u64) -> (Vec<Transaction>, Vec<bool>) {
+        let mut blocked = HashSet::new();
+        let (mut ready, mut held) = (vec![], vec![]);
+        for tx in self.mempool.iter() {
+            let sender_blocked = tx.is_signed() && blocked.contains(&tx.from);
+            let unlocked =
+                tx.is_final(height, timestamp) && tx.verify_at(height, timestamp).is_ok();
+            let block_full = ready.len() >= MAX_TRANSACTIONS_PER_BLOCK;
+            let hold = sender_blocked || !unlocked || block_full;
+            if hold {
+                if tx.is_signed() {
+                    blocked.insert(tx.from.clone());
+                }
+            } else {
+                ready.push(tx.clone());
+            }
+            held.push(hold);
+        }
+        (ready, held)
+    }
+
+    /// Mines a block with mempool transactions whose lock has expired, up to
+    /// the per block transaction limit.
+    #[instrument(skip(self), fields(node_name = self.name), level = "info")]
+    pub fn mine_pending(&mut self) -> Result<&Block> {
+        let height = self.blockchain.blocks().len() as u64;
+        let (ready, held) = self.ready_transactions(height, self.blockchain.clock().now());
+        let block = self.blockchain.add_block(ready)?;
+        let mut held = held.into_iter();
+        self.mempool.retain(|_, _| held.next().unwrap_or(false));
+        if !self.mempool.is_empty() {
+            info!(
+                "{} locked transactions are held in mempool",
+                self.mempool.len()
+            );
+        }
+        Ok(block)
+    }
+
     #[allow(unused)]
     #[instrument(skip_all, fields(node_name = self.name), level = "info")]
     pub fn replace_chain(&mut self, other: Blockchain) -> Result<bool> {
+        let fork = self.blockchain.fork_point(&other);
         let replaced = self.blockchain.replace_chain(other);
         match replaced {
             Err(ref e) => error!("Failed to replace chain {:?}", e),
             Ok(false) => info!("Node {} new chain is not longer", self.name),
-            _ => {}
+            Ok(true) => self.prune_mempool(fork),
         }
         replaced
     }
 
-    #[allow(unused)]
+    /// Adopts a longer chain validated beforehand, see [`Blockchain::adopt`].
+    pub fn adopt_chain(&mut self, other: Blockchain) -> bool {
+        let fork = self.blockchain.fork_point(&other);
+        let adopted = self.blockchain.adopt(other);
+        if adopted {
+            self.prune_mempool(fork);
+        }
+        adopted
+    }
+
+    /// Drops mempool transactions that are in the blocks of the chain from
+    /// `fork` on or no longer valid on top of it, e.g. because they were
+    /// mined by another node.
+    fn prune_mempool(&mut self, fork: usize) {
+        let mined: HashSet<_> = self.blockchain.blocks()[fork..]
+            .iter()
+            .flat_map(|b| &b.transactions)
+            .map(Transaction::id)
+            .collect();
+        self.mempool.retain(|id, _| !mined.contains(id));
+        let mut valid = self.blockchain.valid_in_order(&self.mempool).into_iter();
+        self.mempool.retain(|_, _| valid.next().unwrap_or(false));
+    }
+
+    /// Public key the node is known by, see [`NodeIdentity`].
+    pub fn node_id(&self) -> &str {
+        self.identity.node_id()
+    }
+
+    /// Stores the handshake result of a peer, returns whether it is new.
+    /// Only one connection per node id is kept, so a node can't be
+    /// impersonated by a peer claiming its id.
     #[instrument(skip(self), fields(node_name = self.name), level = "info")]
-    pub fn register_peer(&mut self, peer: String) -> bool {
-        self.peers.insert(peer)
+    pub fn register_peer(&mut self, peer: PeerInfo) -> Result<bool> {
+        if let Some(other) = self
+            .peers
+            .values()
+            .find(|p| p.node_id == peer.node_id && p.address != peer.address)
+        {
+            return Err(Error::IncompatiblePeer(
+                peer.address,
+                format!(
+                    "node {} is already connected as {}",
+                    other.node_id, other.address
+                ),
+            ));
+        }
+        Ok(self.peers.insert(peer.address.clone(), peer).is_none())
     }
 
     #[allow(unused)]
@@ -56,13 +232,27 @@ impl Node {
     }
 }
 
+/// See [`Node::next_nonce`].
+pub(crate) fn pending_nonce(
+    blockchain: &Blockchain,
+    mempool: &[Transaction],
+    address: &str,
+) -> u64 {
+    let pending = mempool
+        .iter()
+        .filter(|t| t.is_signed() && t.from == address)
+        .count() as u64;
+    blockchain.next_nonce(address) + pending
+}
+
 #[cfg(test)]
 mod tests {
     use crate::block::Transaction;
     use serde_json::json;
 
     use super::*;
-    use crate::errors::Error;
+    use crate::errors::{Error, ValidationError};
+    use crate::test_utils::{funding, signed_transaction};
 
     #[test]
     fn test_create_new_node() {
@@ -80,12 +270,14 @@ mod tests {
             from: "A".to_string(),
             to: "B".to_string(),
             amount: 100,
+            ..Default::default()
         }])
         .unwrap();
         node.add_block(vec![Transaction {
             from: "B".to_string(),
             to: "C".to_string(),
             amount: 100,
+            ..Default::default()
         }])
         .unwrap();
         assert_eq!(node.blockchain.blocks().len(), 3);
@@ -95,6 +287,7 @@ mod tests {
                 from: "B".to_string(),
                 to: "C".to_string(),
                 amount: 100,
+                ..Default::default()
             }]
         );
     }
@@ -108,6 +301,7 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }])
             .unwrap();
 
@@ -117,6 +311,7 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }])
             .unwrap();
         longer_chain
@@ -124,6 +319,7 @@ mod tests {
                 from: "B".to_string(),
                 to: "C".to_string(),
                 amount: 100,
+                ..Default::default()
             }])
             .unwrap();
 
@@ -141,6 +337,7 @@ mod tests {
                 from: "A".to_string(),
                 to: "B".to_string(),
                 amount: 100,
+                ..Default::default()
             }])
             .unwrap();
 
@@ -158,29 +355,18 @@ mod tests {
             from: "A".to_string(),
             to: "B".to_string(),
             amount: 100,
+            ..Default::default()
         }])
         .unwrap();
 
+        let genesis = Block::genesis(2).unwrap();
         let fake_chain: Blockchain = serde_json::from_value(json!({
             "chain":[
-                {
-                    "index" : 0,
-                    "timestamp": 0,
-                    "previous_hash": "000",
-                    "hash": "000",
-                    "transactions" :[
-                        {
-                            "from" : "A",
-                            "to" : "B",
-                            "amount" : 100
-                        }
-                    ],
-                    "nonce" :0
-                },
+                genesis,
                 {
                     "index" : 1,
                     "timestamp": 0,
-                    "previous_hash": "000",
+                    "previous_hash": genesis.hash,
                     "hash": "00056712531",
                     "transactions" :[
                         {
@@ -212,8 +398,8 @@ mod tests {
 
         let result = node.replace_chain(fake_chain);
         match result {
-            Err(Error::BlockHasInvalidHash(1, _)) => {}
-            v => panic!("Expected error BlockHasInvalidHash, actual {v:?}"),
+            Err(Error::InvalidBlock(1, ValidationError::MalformedHash(_))) => {}
+            v => panic!("Expected error MalformedHash, actual {v:?}"),
         }
 
         assert_eq!(node.blockchain.blocks().len(), 2); // chain remains unchanged
@@ -226,8 +412,178 @@ mod tests {
             from: "A".to_string(),
             to: "B".to_string(),
             amount: 100,
+            ..Default::default()
         }])
         .unwrap();
         node.print_chain(); // smoke test
     }
+
+    #[test]
+    fn test_submit_transaction_to_mempool() {
+        let key = crate::crypto::generate_signing_key();
+        let from = crate::crypto::address_from_public_key(&key.verifying_key());
+        let mut node = Node::new("Node", 1).unwrap();
+        node.blockchain.add_block(vec![funding(&key, 100)]).unwrap();
+
+        node.submit_transaction(signed_transaction(&key, 10, 0))
+            .unwrap();
+        node.submit_transaction(signed_transaction(&key, 10, 1))
+            .unwrap();
+        assert_eq!(node.mempool.len(), 2);
+        assert_eq!(node.next_nonce(&from), 2);
+
+        let result = node.submit_transaction(signed_transaction(&key, 10, 1));
+        assert!(matches!(result, Err(Error::InvalidTransactionNonce(..))));
+        assert_eq!(node.mempool.len(), 2);
+    }
+
+    #[test]
+    fn test_mempool_is_bounded() {
+        let mut node = Node::new("Node", 1).unwrap();
+        node.mempool = (1..=MAX_MEMPOOL_TRANSACTIONS as i64)
+            .map(|amount| Transaction {
+                from: "A".to_string(),
+                to: "B".to_string(),
+                amount,
+                ..Default::default()
+            })
+            .collect();
+
+        let result = node.submit_transaction(Transaction {
+            from: "A".to_string(),
+            to: "B".to_string(),
+            amount: 1,
+            nonce: 1,
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(Error::MempoolFull(_))));
+        assert_eq!(node.mempool.len(), MAX_MEMPOOL_TRANSACTIONS);
+    }
+
+    #[test]
+    fn test_mine_pending_drains_mempool() {
+        let key = crate::crypto::generate_signing_key();
+        let from = crate::crypto::address_from_public_key(&key.verifying_key());
+        let mut node = Node::new("Node", 1).unwrap();
+        node.blockchain.add_block(vec![funding(&key, 100)]).unwrap();
+        node.submit_transaction(signed_transaction(&key, 10, 0))
+            .unwrap();
+
+        let block = node.mine_pending().unwrap().clone();
+        assert_eq!(block.transactions.len(), 1);
+        assert!(node.mempool.is_empty());
+        assert_eq!(node.next_nonce(&from), 1);
+        assert_eq!(node.blockchain.get_balance(&from), 90);
+    }
+
+    #[test]
+    fn test_mine_pending_respects_block_transaction_limit() {
+        let mut node = Node::new("Node", 1).unwrap();
+        node.mempool = vec![
+            Transaction {
+                from: "A".to_string(),
+                to: "B".to_string(),
+                amount: 1,
+                ..Default::default()
+            };
+            MAX_TRANSACTIONS_PER_BLOCK + 5
+        ]
+        .into();
+
+        let block = node.mine_pending().unwrap().clone();
+        assert_eq!(block.transactions.len(), MAX_TRANSACTIONS_PER_BLOCK);
+        assert_eq!(node.mempool.len(), 5);
+    }
+
+    #[test]
+    fn test_mine_pending_holds_locked_transactions() {
+        let key = crate::crypto::generate_signing_key();
+        let mut node = Node::new("Node", 1).unwrap();
+        node.blockchain.add_block(vec![funding(&key, 100)]).unwrap();
+        let mut locked = Transaction {
+            from: crate::crypto::address_from_public_key(&key.verifying_key()),
+            to: "B".to_string(),
+            amount: 10,
+            lock_until: Some(crate::block::LockTime::Height(3)),
+            ..Default::default()
+        };
+        locked.sign(&key);
+        node.submit_transaction(locked).unwrap();
+        node.submit_transaction(signed_transaction(&key, 10, 1))
+            .unwrap();
+        node.submit_transaction(Transaction {
+            from: "C".to_string(),
+            to: "D".to_string(),
+            amount: 1,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let block = node.mine_pending().unwrap().clone();
+        assert_eq!(block.transactions.len(), 1);
+        assert_eq!(block.transactions[0].from, "C");
+        assert_eq!(node.mempool.len(), 2);
+
+        let block = node.mine_pending().unwrap().clone();
+        assert_eq!(block.index, 3);
+        assert_eq!(block.transactions.len(), 2);
+        assert!(node.mempool.is_empty());
+        assert!(node.blockchain.validate().is_ok());
+    }
+
+    #[test]
+    fn test_mine_pending_releases_time_locked_transactions_as_clock_advances() {
+        let start = crate::block::MIN_BLOCK_TIMESTAMP;
+        let clock = std::sync::Arc::new(crate::clock::ManualClock::new(start));
+        let mut node = Node::with_clock("Node", 1, clock.clone()).unwrap();
+        node.submit_transaction(Transaction {
+            from: "A".to_string(),
+            to: "B".to_string(),
+            amount: 10,
+            lock_until: Some(crate::block::LockTime::Timestamp(start + 3600)),
+            ..Default::default()
+        })
+        .unwrap();
+
+        clock.advance(600);
+        assert!(node.mine_pending().unwrap().transactions.is_empty());
+        assert_eq!(node.mempool.len(), 1);
+
+        clock.advance(3000);
+        let block = node.mine_pending().unwrap().clone();
+        assert_eq!(block.timestamp, start + 3600);
+        assert_eq!(block.transactions.len(), 1);
+        assert!(node.blockchain.validate().is_ok());
+    }
+
+    #[test]
+    fn test_mine_pending_holds_transactions_until_script_unlocks() {
+        let locking = crate::script::Script::from_asm("3 OP_CHECKLOCKTIMEVERIFY").unwrap();
+        let mut node = Node::new("Node", 1).unwrap();
+        node.blockchain
+            .add_block(vec![Transaction {
+                from: "A".to_string(),
+                to: locking.address(),
+                amount: 100,
+                ..Default::default()
+            }])
+            .unwrap();
+        node.submit_transaction(Transaction {
+            from: locking.address(),
+            to: "B".to_string(),
+            amount: 10,
+            script: Some(crate::block::ScriptSpend {
+                locking,
+                unlocking: Default::default(),
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert!(node.mine_pending().unwrap().transactions.is_empty());
+        assert_eq!(node.mempool.len(), 1);
+        assert_eq!(node.mine_pending().unwrap().transactions.len(), 1);
+        assert!(node.mempool.is_empty());
+        assert!(node.blockchain.validate().is_ok());
+    }
 }
@@ -0,0 +1,751 @@
+use crate::crypto;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+/// Largest number of instructions a script pair is allowed to execute.
+pub const MAX_STEPS: usize = 1_000;
+pub const MAX_STACK_SIZE: usize = 100;
+pub const MAX_ELEMENT_SIZE: usize = 255;
+/// Lock times below this value are block heights, the rest are unix timestamps.
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
+
+const OP_PUSHDATA1: u8 = 0x4c;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum ScriptError {
+    #[error("script size {0} exceeds limit")]
+    TooLarge(usize),
+    #[error("push at position {0} runs past the end of script")]
+    TruncatedPush(usize),
+    #[error("unknown opcode 0x{0:02x}")]
+    UnknownOpcode(u8),
+    #[error("step limit exceeded")]
+    StepLimitExceeded,
+    #[error("stack size limit exceeded")]
+    StackOverflow,
+    #[error("stack element is larger than {MAX_ELEMENT_SIZE} bytes")]
+    ElementTooLarge,
+    #[error("{0} needs more stack items")]
+    StackUnderflow(Opcode),
+    #[error("{0} failed")]
+    VerifyFailed(Opcode),
+    #[error("unbalanced OP_IF/OP_ELSE/OP_ENDIF")]
+    UnbalancedConditional,
+    #[error("unlocking script may only push data")]
+    UnlockingNotPushOnly,
+    #[error("stack item is not a valid number")]
+    InvalidNumber,
+    #[error("lock time {0} is not reached")]
+    LockTimeNotReached(u64),
+    #[error("script finished with false on top of the stack")]
+    EvaluatedToFalse,
+    #[error("invalid script assembly '{0}'")]
+    InvalidAsm(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Opcode {
+    False = 0x00,
+    True = 0x51,
+    If = 0x63,
+    Else = 0x67,
+    EndIf = 0x68,
+    Verify = 0x69,
+    Drop = 0x75,
+    Dup = 0x76,
+    Equal = 0x87,
+    EqualVerify = 0x88,
+    Not = 0x91,
+    BoolAnd = 0x9a,
+    BoolOr = 0x9b,
+    Sha256 = 0xa8,
+    CheckSig = 0xac,
+    CheckSigVerify = 0xad,
+    CheckLockTimeVerify = 0xb1,
+}
+
+const OPCODES: [Opcode; 17] = [
+    Opcode::False,
+    Opcode::True,
+    Opcode::If,
+    Opcode::Else,
+    Opcode::EndIf,
+    Opcode::Verify,
+    Opcode::Drop,
+    Opcode::Dup,
+    Opcode::Equal,
+    Opcode::EqualVerify,
+    Opcode::Not,
+    Opcode::BoolAnd,
+    Opcode::BoolOr,
+    Opcode::Sha256,
+    Opcode::CheckSig,
+    Opcode::CheckSigVerify,
+    Opcode::CheckLockTimeVerify,
+];
+
+impl Opcode {
+    fn from_byte(byte: u8) -> Option<Self> {
+        OPCODES.into_iter().find(|op| *op as u8 == byte)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Opcode::False => "OP_FALSE",
+            Opcode::True => "OP_TRUE",
+            Opcode::If => "OP_IF",
+            Opcode::Else => "OP_ELSE",
+            Opcode::EndIf => "OP_ENDIF",
+            Opcode::Verify => "OP_VERIFY",
+            Opcode::Drop => "OP_DROP",
+            Opcode::Dup => "OP_DUP",
+            Opcode::Equal => "OP_EQUAL",
+            Opcode::EqualVerify => "OP_EQUALVERIFY",
+            Opcode::Not => "OP_NOT",
+            Opcode::BoolAnd => "OP_BOOLAND",
+            Opcode::BoolOr => "OP_BOOLOR",
+            Opcode::Sha256 => "OP_SHA256",
+            Opcode::CheckSig => "OP_CHECKSIG",
+            Opcode::CheckSigVerify => "OP_CHECKSIGVERIFY",
+            Opcode::CheckLockTimeVerify => "OP_CHECKLOCKTIMEVERIFY",
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction<'a> {
+    Push(&'a [u8]),
+    Op(Opcode),
+}
+
+/// Bytecode of a spending condition, serialized as hex.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Script(Vec<u8>);
+
+impl TryFrom<String> for Script {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        hex::decode(&value)
+            .map(Script)
+            .map_err(|e| format!("invalid script hex: {e}"))
+    }
+}
+
+impl From<Script> for String {
+    fn from(value: Script) -> Self {
+        hex::encode(value.0)
+    }
+}
+
+impl Script {
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Address of the account guarded by this script.
+    pub fn address(&self) -> String {
+        let mut address = crypto::sha256_hex([b"script:".as_slice(), &self.0].concat());
+        address.truncate(crypto::ADDRESS_LEN);
+        address
+    }
+
+    pub fn push_op(mut self, op: Opcode) -> Self {
+        self.0.push(op as u8);
+        self
+    }
+
+    /// Appends a push of `data`, which can't be larger than
+    /// [`MAX_ELEMENT_SIZE`].
+    pub fn push_data(self, data: &[u8]) -> Result<Self, ScriptError> {
+        if data.len() > MAX_ELEMENT_SIZE {
+            return Err(ScriptError::ElementTooLarge);
+        }
+        Ok(self.push(data))
+    }
+
+    pub fn push_number(self, number: u64) -> Self {
+        // Numbers take at most 9 bytes
+        self.push(&encode_number(number))
+    }
+
+    fn push(mut self, data: &[u8]) -> Self {
+        if data.len() < OP_PUSHDATA1 as usize {
+            self.0.push(data.len() as u8);
+        } else {
+            self.0.extend([OP_PUSHDATA1, data.len() as u8]);
+        }
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    pub fn instructions(&self) -> Result<Vec<Instruction<'_>>, ScriptError> {
+        if self.0.len() > MAX_SCRIPT_SIZE {
+            return Err(ScriptError::TooLarge(self.0.len()));
+        }
+        let mut instructions = vec![];
+        let mut pos = 0;
+        while pos < self.0.len() {
+            let byte = self.0[pos];
+            let (len, start) = match byte {
+                0x01..OP_PUSHDATA1 => (byte as usize, pos + 1),
+                OP_PUSHDATA1 => {
+                    let len = *self.0.get(pos + 1).ok_or(ScriptError::TruncatedPush(pos))?;
+                    (len as usize, pos + 2)
+                }
+                _ => {
+                    let op = Opcode::from_byte(byte).ok_or(ScriptError::UnknownOpcode(byte))?;
+                    instructions.push(Instruction::Op(op));
+                    pos += 1;
+                    continue;
+                }
+            };
+            let data = self
+                .0
+                .get(start..start + len)
+                .ok_or(ScriptError::TruncatedPush(pos))?;
+            instructions.push(Instruction::Push(data));
+            pos = start + len;
+        }
+        Ok(instructions)
+    }
+
+    /// Parses space separated opcode names, `0x` prefixed hex data and decimal numbers.
+    pub fn from_asm(asm: &str) -> Result<Self, ScriptError> {
+        let mut script = Script::default();
+        for token in asm.split_whitespace() {
+            let invalid = || ScriptError::InvalidAsm(token.to_owned());
+            script = if let Some(data) = token.strip_prefix("0x") {
+                let data = hex::decode(data).map_err(|_| invalid())?;
+                script.push_data(&data).map_err(|_| invalid())?
+            } else if let Ok(number) = token.parse::<u64>() {
+                script.push_number(number)
+            } else {
+                let op = OPCODES
+                    .into_iter()
+                    .find(|op| op.name() == token)
+                    .ok_or_else(invalid)?;
+                script.push_op(op)
+            };
+        }
+        Ok(script)
+    }
+
+    pub fn disassemble(&self) -> Result<String, ScriptError> {
+        let tokens: Vec<String> = self
+            .instructions()?
+            .into_iter()
+            .map(|i| match i {
+                Instruction::Push(data) => format!("0x{}", hex::encode(data)),
+                Instruction::Op(op) => op.name().to_owned(),
+            })
+            .collect();
+        Ok(tokens.join(" "))
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.disassemble() {
+            Ok(asm) => f.write_str(&asm),
+            Err(_) => write!(f, "<invalid script {}>", hex::encode(&self.0)),
+        }
+    }
+}
+
+/// Minimal little-endian encoding, zero is an empty element.
+pub fn encode_number(number: u64) -> Vec<u8> {
+    let bytes = number.to_le_bytes();
+    let len = 8 - number.leading_zeros() as usize / 8;
+    bytes[..len].to_vec()
+}
+
+fn decode_number(data: &[u8]) -> Result<u64, ScriptError> {
+    if data.len() > 8 || data.last() == Some(&0) {
+        return Err(ScriptError::InvalidNumber);
+    }
+    let mut bytes = [0u8; 8];
+    bytes[..data.len()].copy_from_slice(data);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn is_true(data: &[u8]) -> bool {
+    data.iter().any(|b| *b != 0)
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value { vec![1] } else { vec![] }
+}
+
+/// Data scripts are evaluated against: the signed transaction payload and
+/// the block the transaction is included in.
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptContext<'a> {
+    pub payload: &'a [u8],
+    pub height: u64,
+    pub timestamp: u64,
+}
+
+/// Runs the push-only unlocking script and then the locking script on the
+/// same stack. Spending is allowed if the top item is true afterwards.
+pub fn evaluate(
+    unlocking: &Script,
+    locking: &Script,
+    ctx: &ScriptContext,
+) -> Result<(), ScriptError> {
+    let unlocking = unlocking.instructions()?;
+    if unlocking.iter().any(|i| {
+        !matches!(
+            i,
+            Instruction::Push(_) | Instruction::Op(Opcode::False | Opcode::True)
+        )
+    }) {
+        return Err(ScriptError::UnlockingNotPushOnly);
+    }
+    let mut machine = Machine::new(ctx);
+    machine.run(&unlocking)?;
+    machine.run(&locking.instructions()?)?;
+    match machine.stack.last() {
+        Some(top) if is_true(top) => Ok(()),
+        _ => Err(ScriptError::EvaluatedToFalse),
+    }
+}
+
+struct Machine<'a> {
+    ctx: &'a ScriptContext<'a>,
+    stack: Vec<Vec<u8>>,
+    steps: usize,
+}
+
+impl<'a> Machine<'a> {
+    fn new(ctx: &'a ScriptContext<'a>) -> Self {
+        Self {
+            ctx,
+            stack: vec![],
+            steps: 0,
+        }
+    }
+
+    fn push(&mut self, item: Vec<u8>) -> Result<(), ScriptError> {
+        if item.len() > MAX_ELEMENT_SIZE {
+            return Err(ScriptError::ElementTooLarge);
+        }
+        if self.stack.len() >= MAX_STACK_SIZE {
+            return Err(ScriptError::StackOverflow);
+        }
+        self.stack.push(item);
+        Ok(())
+    }
+
+    fn pop(&mut self, op: Opcode) -> Result<Vec<u8>, ScriptError> {
+        self.stack.pop().ok_or(ScriptError::StackUnderflow(op))
+    }
+
+    fn run(&mut self, instructions: &[Instruction]) -> Result<(), ScriptError> {
+        // Conditions of enclosing OP_IFs, flipped by OP_ELSE. Instructions
+        // are executed only if all of them are true.
+        let mut branches: Vec<bool> = vec![];
+        for instruction in instructions {
+            self.steps += 1;
+            if self.steps > MAX_STEPS {
+                return Err(ScriptError::StepLimitExceeded);
+            }
+            let executing = branches.iter().all(|b| *b);
+            match *instruction {
+                Instruction::Push(data) if executing => self.push(data.to_vec())?,
+                Instruction::Push(_) => {}
+                Instruction::Op(Opcode::If) => {
+                    let condition = executing && is_true(&self.pop(Opcode::If)?);
+                    branches.push(condition);
+                }
+                Instruction::Op(Opcode::Else) => {
+                    let branch = branches
+                        .last_mut()
+                        .ok_or(ScriptError::UnbalancedConditional)?;
+                    *branch = !*branch;
+                }
+                Instruction::Op(Opcode::EndIf) => {
+                    branches.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                }
+                Instruction::Op(op) if executing => self.execute(op)?,
+                Instruction::Op(_) => {}
+            }
+        }
+        if !branches.is_empty() {
+            return Err(ScriptError::UnbalancedConditional);
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, op: Opcode) -> Result<(), ScriptError> {
+        match op {
+            Opcode::False => self.push(vec![])?,
+            Opcode::True => self.push(vec![1])?,
+            Opcode::Verify => {
+                if !is_true(&self.pop(op)?) {
+                    return Err(ScriptError::VerifyFailed(op));
+                }
+            }
+            Opcode::Drop => {
+                self.pop(op)?;
+            }
+            Opcode::Dup => {
+                let top = self.stack.last().ok_or(ScriptError::StackUnderflow(op))?;
+                self.push(top.clone())?;
+            }
+            Opcode::Equal | Opcode::EqualVerify => {
+                let (a, b) = (self.pop(op)?, self.pop(op)?);
+                if op == Opcode::EqualVerify {
+                    if a != b {
+                        return Err(ScriptError::VerifyFailed(op));
+                    }
+                } else {
+                    self.push(encode_bool(a == b))?;
+                }
+            }
+            Opcode::Not => {
+                let value = is_true(&self.pop(op)?);
+                self.push(encode_bool(!value))?;
+            }
+            Opcode::BoolAnd | Opcode::BoolOr => {
+                let (a, b) = (is_true(&self.pop(op)?), is_true(&self.pop(op)?));
+                let value = if op == Opcode::BoolAnd {
+                    a && b
+                } else {
+                    a || b
+                };
+                self.push(encode_bool(value))?;
+            }
+            Opcode::Sha256 => {
+                let data = self.pop(op)?;
+                self.push(Sha256::digest(data).to_vec())?;
+            }
+            Opcode::CheckSig | Opcode::CheckSigVerify => {
+                let public_key = self.pop(op)?;
+                let signature = self.pop(op)?;
+                let valid = crypto::parse_public_key(&hex::encode(public_key))
+                    .map(|key| crypto::verify(&key, self.ctx.payload, &hex::encode(signature)))
+                    .unwrap_or(false);
+                if op == Opcode::CheckSigVerify {
+                    if !valid {
+                        return Err(ScriptError::VerifyFailed(op));
+                    }
+                } else {
+                    self.push(encode_bool(valid))?;
+                }
+            }
+            Opcode::CheckLockTimeVerify => {
+                let top = self.stack.last().ok_or(ScriptError::StackUnderflow(op))?;
+                let lock_time = decode_number(top)?;
+                let current = if lock_time < LOCKTIME_THRESHOLD {
+                    self.ctx.height
+                } else {
+                    self.ctx.timestamp
+                };
+                if current < lock_time {
+                    return Err(ScriptError::LockTimeNotReached(lock_time));
+                }
+            }
+            Opcode::If | Opcode::Else | Opcode::EndIf => unreachable!("handled by run"),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ScriptError::*;
+
+    const HEIGHT: u64 = 100;
+    const TIMESTAMP: u64 = 1_700_000_000;
+
+    fn ctx() -> ScriptContext<'static> {
+        ScriptContext {
+            payload: b"payload",
+            height: HEIGHT,
+            timestamp: TIMESTAMP,
+        }
+    }
+
+    fn run(unlocking: &str, locking: &str) -> Result<(), ScriptError> {
+        evaluate(
+            &Script::from_asm(unlocking).unwrap(),
+            &Script::from_asm(locking).unwrap(),
+            &ctx(),
+        )
+    }
+
+    #[test]
+    fn test_valid_scripts() {
+        let preimage_hash = format!("0x{}", hex::encode(Sha256::digest(b"secret")));
+        let corpus = [
+            ("", "OP_TRUE"),
+            ("OP_TRUE", ""),
+            ("0x01", "OP_VERIFY OP_TRUE"),
+            ("0x02", "0x02 OP_EQUAL"),
+            ("0x0203", "0x0203 OP_EQUALVERIFY OP_TRUE"),
+            ("OP_FALSE", "OP_NOT"),
+            ("0x00", "OP_NOT"),
+            ("0x0000", "OP_NOT"),
+            ("0x01 0x00", "OP_BOOLOR"),
+            ("0x01 0x01", "OP_BOOLAND"),
+            ("0x01 0x00", "OP_BOOLAND OP_NOT"),
+            ("0x05", "OP_DUP OP_EQUAL"),
+            ("0x05 0x06", "OP_DROP 0x05 OP_EQUAL"),
+            (
+                "0x736563726574",
+                &format!("OP_SHA256 {preimage_hash} OP_EQUAL"),
+            ),
+            ("OP_TRUE", "OP_IF OP_TRUE OP_ELSE OP_FALSE OP_ENDIF"),
+            ("OP_FALSE", "OP_IF OP_FALSE OP_ELSE OP_TRUE OP_ENDIF"),
+            (
+                "OP_TRUE OP_FALSE",
+                "OP_IF OP_FALSE OP_ELSE OP_IF OP_TRUE OP_ENDIF OP_ENDIF",
+            ),
+            (
+                "OP_FALSE",
+                "OP_IF OP_IF OP_FALSE OP_ENDIF OP_ELSE OP_TRUE OP_ENDIF",
+            ),
+            ("", "100 OP_CHECKLOCKTIMEVERIFY"),
+            ("", "99 OP_CHECKLOCKTIMEVERIFY OP_DROP OP_TRUE"),
+            ("", "1700000000 OP_CHECKLOCKTIMEVERIFY"),
+        ];
+        for (unlocking, locking) in corpus {
+            assert_eq!(
+                run(unlocking, locking),
+                Ok(()),
+                "'{unlocking}' / '{locking}' should succeed"
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_scripts() {
+        let corpus = [
+            ("", "", EvaluatedToFalse),
+            ("OP_FALSE", "", EvaluatedToFalse),
+            ("0x0000", "", EvaluatedToFalse),
+            ("0x01", "0x02 OP_EQUAL", EvaluatedToFalse),
+            (
+                "0x01",
+                "0x02 OP_EQUALVERIFY OP_TRUE",
+                VerifyFailed(Opcode::EqualVerify),
+            ),
+            (
+                "OP_FALSE",
+                "OP_VERIFY OP_TRUE",
+                VerifyFailed(Opcode::Verify),
+            ),
+            ("", "OP_DUP", StackUnderflow(Opcode::Dup)),
+            ("", "OP_DROP OP_TRUE", StackUnderflow(Opcode::Drop)),
+            ("0x01", "OP_EQUAL", StackUnderflow(Opcode::Equal)),
+            ("0x01", "OP_BOOLAND", StackUnderflow(Opcode::BoolAnd)),
+            ("", "OP_SHA256", StackUnderflow(Opcode::Sha256)),
+            ("0x01", "OP_CHECKSIG", StackUnderflow(Opcode::CheckSig)),
+            ("", "OP_IF OP_TRUE OP_ENDIF", StackUnderflow(Opcode::If)),
+            ("OP_TRUE", "OP_IF OP_TRUE", UnbalancedConditional),
+            ("", "OP_ELSE OP_TRUE", UnbalancedConditional),
+            ("", "OP_TRUE OP_ENDIF", UnbalancedConditional),
+            (
+                "OP_TRUE",
+                "OP_IF OP_FALSE OP_ELSE OP_TRUE OP_ENDIF",
+                EvaluatedToFalse,
+            ),
+            ("OP_TRUE OP_DUP", "OP_TRUE", UnlockingNotPushOnly),
+            ("0x01 OP_VERIFY", "OP_TRUE", UnlockingNotPushOnly),
+            ("", "101 OP_CHECKLOCKTIMEVERIFY", LockTimeNotReached(101)),
+            (
+                "",
+                "1700000001 OP_CHECKLOCKTIMEVERIFY",
+                LockTimeNotReached(1_700_000_001),
+            ),
+            ("", "0x0100 OP_CHECKLOCKTIMEVERIFY", InvalidNumber),
+            (
+                "",
+                "0x010203040506070809 OP_CHECKLOCKTIMEVERIFY",
+                InvalidNumber,
+            ),
+            (
+                "",
+                "OP_CHECKLOCKTIMEVERIFY",
+                StackUnderflow(Opcode::CheckLockTimeVerify),
+            ),
+            ("0x01 0x01", "OP_CHECKSIG", EvaluatedToFalse),
+            (
+                "0x01 0x01",
+                "OP_CHECKSIGVERIFY OP_TRUE",
+                VerifyFailed(Opcode::CheckSigVerify),
+            ),
+        ];
+        for (unlocking, locking, expected) in corpus {
+            assert_eq!(
+                run(unlocking, locking),
+                Err(expected.clone()),
+                "'{unlocking}' / '{locking}' should fail with {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_signature() {
+        let key = crypto::generate_signing_key();
+        let public_key = format!("0x{}", hex::encode(key.verifying_key().as_bytes()));
+        let signature = format!("0x{}", crypto::sign(&key, b"payload"));
+        let other = format!("0x{}", crypto::sign(&key, b"other payload"));
+        let locking = format!("{public_key} OP_CHECKSIG");
+
+        assert_eq!(run(&signature, &locking), Ok(()));
+        assert_eq!(run(&other, &locking), Err(EvaluatedToFalse));
+        assert_eq!(
+            run(
+                &signature,
+                &format!("{public_key} OP_CHECKSIGVERIFY OP_TRUE")
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_hash_time_locked_contract() {
+        // Receiver spends with the preimage, sender refunds after height 150
+        let receiver = crypto::generate_signing_key();
+        let sender = crypto::generate_signing_key();
+        let locking = format!(
+            "OP_IF OP_SHA256 0x{} OP_EQUALVERIFY 0x{} OP_ELSE 150 OP_CHECKLOCKTIMEVERIFY OP_DROP 0x{} OP_ENDIF OP_CHECKSIG",
+            hex::encode(Sha256::digest(b"secret")),
+            hex::encode(receiver.verifying_key().as_bytes()),
+            hex::encode(sender.verifying_key().as_bytes()),
+        );
+        let receiver_sig = format!("0x{}", crypto::sign(&receiver, b"payload"));
+        let sender_sig = format!("0x{}", crypto::sign(&sender, b"payload"));
+
+        let claim = format!("{receiver_sig} 0x{} OP_TRUE", hex::encode(b"secret"));
+        assert_eq!(run(&claim, &locking), Ok(()));
+        let wrong_preimage = format!("{receiver_sig} 0x{} OP_TRUE", hex::encode(b"guess"));
+        assert_eq!(
+            run(&wrong_preimage, &locking),
+            Err(VerifyFailed(Opcode::EqualVerify))
+        );
+        let refund = format!("{sender_sig} OP_FALSE");
+        assert_eq!(run(&refund, &locking), Err(LockTimeNotReached(150)));
+        let later = ScriptContext {
+            height: 150,
+            ..ctx()
+        };
+        assert_eq!(
+            evaluate(
+                &Script::from_asm(&refund).unwrap(),
+                &Script::from_asm(&locking).unwrap(),
+                &later
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_malformed_scripts() {
+        let cases = [
+            (vec![0x02, 0x01], TruncatedPush(0)),
+            (vec![0x51, OP_PUSHDATA1], TruncatedPush(1)),
+            (vec![OP_PUSHDATA1, 0x02, 0x01], TruncatedPush(0)),
+            (vec![0xff], UnknownOpcode(0xff)),
+            (
+                vec![0x51; MAX_SCRIPT_SIZE + 1],
+                TooLarge(MAX_SCRIPT_SIZE + 1),
+            ),
+        ];
+        for (bytes, expected) in cases {
+            let script = Script::from_bytes(bytes);
+            assert_eq!(script.instructions(), Err(expected.clone()));
+            assert_eq!(evaluate(&Script::default(), &script, &ctx()), Err(expected));
+        }
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let steps =
+            Script::from_bytes([Opcode::True as u8, Opcode::Drop as u8].repeat(MAX_STEPS / 2 + 1));
+        assert_eq!(
+            evaluate(&Script::default(), &steps, &ctx()),
+            Err(StepLimitExceeded)
+        );
+
+        let stack = Script::from_bytes(vec![Opcode::True as u8; MAX_STACK_SIZE + 1]);
+        assert_eq!(
+            evaluate(&Script::default(), &stack, &ctx()),
+            Err(StackOverflow)
+        );
+
+        // Skipped branches still count towards the step limit
+        let mut skipped = vec![Opcode::False as u8, Opcode::If as u8];
+        skipped.extend(vec![Opcode::Dup as u8; MAX_STEPS]);
+        skipped.push(Opcode::EndIf as u8);
+        assert_eq!(
+            evaluate(&Script::default(), &Script::from_bytes(skipped), &ctx()),
+            Err(StepLimitExceeded)
+        );
+    }
+
+    #[test]
+    fn test_asm_roundtrip_and_disassembler() {
+        let asm = "OP_DUP OP_SHA256 0x0102 OP_EQUALVERIFY OP_CHECKSIG";
+        let script = Script::from_asm(asm).unwrap();
+        assert_eq!(hex::encode(script.as_bytes()), "76a802010288ac");
+        assert_eq!(script.disassemble().unwrap(), asm);
+        assert_eq!(script.to_string(), asm);
+        assert_eq!(
+            Script::from_asm("0 1 256").unwrap().disassemble().unwrap(),
+            "OP_FALSE 0x01 0x0001"
+        );
+
+        let long = format!("0x{}", "ab".repeat(100));
+        assert_eq!(
+            Script::from_asm(&long).unwrap().disassemble().unwrap(),
+            long
+        );
+        assert_eq!(
+            Script::default().push_data(&[0xab; MAX_ELEMENT_SIZE + 1]),
+            Err(ElementTooLarge)
+        );
+        assert_eq!(
+            Script::from_asm("OP_NOPE"),
+            Err(InvalidAsm("OP_NOPE".to_owned()))
+        );
+        assert_eq!(
+            Script::from_bytes(vec![0xff]).to_string(),
+            "<invalid script ff>"
+        );
+    }
+
+    #[test]
+    fn test_script_serializes_as_hex() {
+        let script = Script::from_asm("OP_TRUE").unwrap();
+        assert_eq!(serde_json::to_string(&script).unwrap(), "\"51\"");
+        assert_eq!(serde_json::from_str::<Script>("\"51\"").unwrap(), script);
+        assert!(serde_json::from_str::<Script>("\"zz\"").is_err());
+    }
+
+    #[test]
+    fn test_number_encoding() {
+        assert_eq!(encode_number(0), Vec::<u8>::new());
+        assert_eq!(encode_number(1), vec![1]);
+        assert_eq!(encode_number(256), vec![0, 1]);
+        assert_eq!(decode_number(&encode_number(u64::MAX)), Ok(u64::MAX));
+        assert_eq!(decode_number(&[]), Ok(0));
+    }
+}
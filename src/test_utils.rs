@@ -0,0 +1,28 @@
+//! Fixtures shared by the unit tests of several modules.
+
+use crate::block::Transaction;
+use crate::crypto;
+use ed25519_dalek::SigningKey;
+
+/// Payment of `amount` from the address of `key` to `B`, signed by `key`.
+pub fn signed_transaction(key: &SigningKey, amount: i64, nonce: u64) -> Transaction {
+    let mut tx = Transaction {
+        from: crypto::address_from_public_key(&key.verifying_key()),
+        to: "B".to_string(),
+        amount,
+        nonce,
+        ..Default::default()
+    };
+    tx.sign(key);
+    tx
+}
+
+/// Unsigned payment of `amount` from `A` funding the address of `key`.
+pub fn funding(key: &SigningKey, amount: i64) -> Transaction {
+    Transaction {
+        from: "A".to_string(),
+        to: crypto::address_from_public_key(&key.verifying_key()),
+        amount,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,356 @@
+use super::{Keystore, NodeClient, Wallet, hd, multisig::PartiallySignedTransaction};
+use crate::block::{LockTime, Multisig};
+use crate::errors::{Error, Result};
+use crate::script::Script;
+use clap::{Args, Subcommand};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+#[derive(Debug, Args)]
+pub struct WalletArgs {
+    /// Path to the keystore file
+    #[arg(long, env = "WALLET_KEYSTORE", default_value = "wallet.json")]
+    pub keystore: PathBuf,
+    /// Base URL of the node API
+    #[arg(long, env = "WALLET_NODE", default_value = "http://127.0.0.1:3000")]
+    pub node: String,
+    #[command(subcommand)]
+    pub command: WalletCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum WalletCommand {
+    /// Generate a mnemonic and initialize the keystore seed from it
+    Init {
+        #[arg(long, default_value_t = 12)]
+        words: usize,
+        /// Optional BIP39 passphrase extending the mnemonic
+        #[arg(long, default_value = "")]
+        passphrase: String,
+    },
+    /// Restore keys from a mnemonic read from stdin, scanning the node for
+    /// used addresses
+    Restore {
+        #[arg(long, default_value = "")]
+        passphrase: String,
+        #[arg(long, default_value_t = hd::DEFAULT_GAP_LIMIT)]
+        gap_limit: u32,
+    },
+    /// Create a new key, derived from the seed if the keystore has one
+    New {
+        #[arg(long, default_value = "default")]
+        name: String,
+    },
+    /// List addresses stored in the keystore
+    List,
+    /// Show balance of an address, the first keystore address by default
+    Balance { address: Option<String> },
+    /// Sign a transfer and submit it to the node
+    Send {
+        #[arg(long)]
+        from: Option<String>,
+        #[arg(long)]
+        to: String,
+        #[arg(long)]
+        amount: i64,
+        /// Don't include the transfer before the block with this index
+        #[arg(long, conflicts_with = "lock_until_time")]
+        lock_until_height: Option<u64>,
+        /// Don't include the transfer before this time, e.g. 2026-01-01T00:00:00Z
+        #[arg(long)]
+        lock_until_time: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    /// Work with m-of-n multisig addresses
+    #[command(subcommand)]
+    Multisig(MultisigCommand),
+    /// Work with addresses guarded by spending scripts
+    #[command(subcommand)]
+    Script(ScriptCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ScriptCommand {
+    /// Print the address guarded by the locking script
+    Address {
+        /// Locking script assembly, e.g. "0x<public key> OP_CHECKSIG"
+        #[arg(long)]
+        locking: String,
+    },
+    /// Print assembly of a hex encoded script
+    Disasm { hex: String },
+    /// Spend from a script address
+    Send {
+        #[arg(long)]
+        locking: String,
+        /// Push-only unlocking script assembly
+        #[arg(long, default_value = "")]
+        unlocking: String,
+        /// Keystore address whose signature is pushed before the unlocking script
+        #[arg(long)]
+        sign_with: Option<String>,
+        #[arg(long)]
+        to: String,
+        #[arg(long)]
+        amount: i64,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct PolicyArgs {
+    /// Number of signatures required to spend
+    #[arg(long)]
+    pub threshold: usize,
+    /// Hex encoded public key of a co-signer, repeated for every co-signer
+    #[arg(long = "key", required = true)]
+    pub keys: Vec<String>,
+}
+
+impl PolicyArgs {
+    fn multisig(&self) -> Result<Multisig> {
+        Multisig::new(self.threshold, self.keys.clone())
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MultisigCommand {
+    /// Print the address defined by the policy
+    Address {
+        #[command(flatten)]
+        policy: PolicyArgs,
+    },
+    /// Create an unsigned transfer from the multisig address
+    Create {
+        #[command(flatten)]
+        policy: PolicyArgs,
+        #[arg(long)]
+        to: String,
+        #[arg(long)]
+        amount: i64,
+        /// File to write the partially signed transaction to
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Sign the transaction with keystore keys that are part of the policy
+    Sign {
+        #[arg(long)]
+        file: PathBuf,
+    },
+    /// Merge signatures from files of other co-signers into `file`
+    Combine {
+        #[arg(long)]
+        file: PathBuf,
+        #[arg(long = "other", required = true)]
+        others: Vec<PathBuf>,
+    },
+    /// Submit a fully signed transaction to the node
+    Submit {
+        #[arg(long)]
+        file: PathBuf,
+    },
+}
+
+pub async fn run(args: WalletArgs) -> Result<()> {
+    let keystore = Keystore::open(&args.keystore)?;
+    let mut wallet = Wallet::new(keystore, NodeClient::new(&args.node));
+
+    match args.command {
+        WalletCommand::Init { words, passphrase } => {
+            let mnemonic = hd::generate_mnemonic(words)?;
+            let password = read_password()?;
+            wallet
+                .keystore
+                .set_seed(&mnemonic.to_seed(passphrase), &password)?;
+            let address = wallet
+                .keystore
+                .derive_key("key-0", &password)?
+                .address
+                .clone();
+            wallet.keystore.save()?;
+            println!("Write down the mnemonic, it is the only backup of the wallet:");
+            println!("{mnemonic}");
+            println!("{address}");
+        }
+        WalletCommand::Restore {
+            passphrase,
+            gap_limit,
+        } => {
+            let mnemonic = hd::parse_mnemonic(&read_mnemonic()?)?;
+            wallet
+                .restore(&mnemonic, &passphrase, &read_password()?, gap_limit)
+                .await?;
+            wallet.keystore.save()?;
+            for key in wallet.keystore.keys() {
+                println!("{}\t{}", key.name, key.address);
+            }
+        }
+        WalletCommand::New { name } => {
+            let password = read_password()?;
+            let address = if wallet.keystore.has_seed() {
+                wallet.keystore.derive_key(&name, &password)?
+            } else {
+                wallet.keystore.generate_key(&name, &password)
+            }
+            .address
+            .clone();
+            wallet.keystore.save()?;
+            println!("{address}");
+        }
+        WalletCommand::List => {
+            for key in wallet.keystore.keys() {
+                println!("{}\t{}\t{}", key.name, key.address, key.public_key);
+            }
+        }
+        WalletCommand::Balance { address } => {
+            let address = default_address(&wallet, address)?;
+            println!("{}", wallet.client.balance(&address).await?);
+        }
+        WalletCommand::Send {
+            from,
+            to,
+            amount,
+            lock_until_height,
+            lock_until_time,
+        } => {
+            let from = default_address(&wallet, from)?;
+            let lock_until = lock_until_height
+                .map(LockTime::Height)
+                .or(lock_until_time.map(|t| LockTime::Timestamp(t.timestamp() as u64)));
+            let transaction = match lock_until {
+                Some(_) => {
+                    wallet
+                        .send_locked(&from, &to, amount, lock_until, &read_password()?)
+                        .await?
+                }
+                None => wallet.send(&from, &to, amount, &read_password()?).await?,
+            };
+            println!("{}", serde_json::to_string_pretty(&transaction)?);
+        }
+        WalletCommand::Multisig(command) => run_multisig(&wallet, command).await?,
+        WalletCommand::Script(command) => run_script(&wallet, command).await?,
+    }
+    Ok(())
+}
+
+async fn run_multisig(wallet: &Wallet, command: MultisigCommand) -> Result<()> {
+    match command {
+        MultisigCommand::Address { policy } => println!("{}", policy.multisig()?.address()),
+        MultisigCommand::Create {
+            policy,
+            to,
+            amount,
+            out,
+        } => {
+            let multisig = policy.multisig()?;
+            let nonce = wallet.client.nonce(&multisig.address()).await?;
+            PartiallySignedTransaction::new(multisig, &to, amount, nonce).save(&out)?;
+        }
+        MultisigCommand::Sign { file } => {
+            let mut psbt = PartiallySignedTransaction::load(&file)?;
+            let signers = wallet.keystore.multisig_signers(&psbt.transaction);
+            if signers.is_empty() {
+                let keys: Vec<_> = wallet
+                    .keystore
+                    .keys()
+                    .iter()
+                    .map(|k| k.public_key.as_str())
+                    .collect();
+                if keys.is_empty() {
+                    return Err(Error::WalletIsEmpty);
+                }
+                return Err(Error::KeyNotInMultisig(keys.join(", ")));
+            }
+            let password = read_password()?;
+            for address in signers {
+                psbt.sign(&wallet.keystore.signing_key(&address, &password)?)?;
+                println!("Signed by {address}");
+            }
+            psbt.save(&file)?;
+        }
+        MultisigCommand::Combine { file, others } => {
+            let mut psbt = PartiallySignedTransaction::load(&file)?;
+            for other in others {
+                psbt.combine(&PartiallySignedTransaction::load(&other)?)?;
+            }
+            psbt.save(&file)?;
+            println!("Complete: {}", psbt.is_complete());
+        }
+        MultisigCommand::Submit { file } => {
+            let psbt = PartiallySignedTransaction::load(&file)?;
+            psbt.transaction.verify()?;
+            wallet.client.submit_transaction(&psbt.transaction).await?;
+            println!("{}", serde_json::to_string_pretty(&psbt.transaction)?);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the mnemonic from stdin, prompting for it on a terminal, so that it
+/// doesn't show in the process list or shell history.
+fn read_mnemonic() -> Result<String> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        eprint!("Mnemonic: ");
+        io::stderr().flush()?;
+    }
+    let mut mnemonic = String::new();
+    stdin.lock().read_line(&mut mnemonic)?;
+    Ok(mnemonic.trim().to_owned())
+}
+
+/// Password encrypting the keystore keys, from the `WALLET_PASSWORD`
+/// environment variable or prompted for on a terminal, so that it doesn't
+/// show in the process list or shell history.
+fn read_password() -> Result<String> {
+    if let Ok(password) = std::env::var("WALLET_PASSWORD") {
+        return Ok(password);
+    }
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return Err(Error::WalletPasswordRequired);
+    }
+    eprint!("Password: ");
+    io::stderr().flush()?;
+    let mut password = String::new();
+    stdin.lock().read_line(&mut password)?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+fn default_address(wallet: &Wallet, address: Option<String>) -> Result<String> {
+    address
+        .or_else(|| wallet.keystore.default_address().map(str::to_owned))
+        .ok_or(Error::WalletIsEmpty)
+}
+
+async fn run_script(wallet: &Wallet, command: ScriptCommand) -> Result<()> {
+    let parse = |asm: &str| Script::from_asm(asm).map_err(|e| Error::InvalidScript(e.to_string()));
+    match command {
+        ScriptCommand::Address { locking } => println!("{}", parse(&locking)?.address()),
+        ScriptCommand::Disasm { hex } => {
+            let script = Script::try_from(hex).map_err(Error::InvalidScript)?;
+            println!("{script}");
+        }
+        ScriptCommand::Send {
+            locking,
+            unlocking,
+            sign_with,
+            to,
+            amount,
+        } => {
+            let signer = match sign_with {
+                Some(address) => Some(wallet.keystore.signing_key(&address, &read_password()?)?),
+                None => None,
+            };
+            let transaction = wallet
+                .send_script(
+                    parse(&locking)?,
+                    parse(&unlocking)?,
+                    signer.as_ref(),
+                    &to,
+                    amount,
+                )
+                .await?;
+            println!("{}", serde_json::to_string_pretty(&transaction)?);
+        }
+    }
+    Ok(())
+}
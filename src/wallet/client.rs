@@ -0,0 +1,61 @@
+use crate::block::Transaction;
+use crate::errors::{Error, Result};
+use reqwest::Response;
+use serde::de::DeserializeOwned;
+use tracing::instrument;
+
+/// HTTP client for the node API used by the wallet.
+#[derive(Debug, Clone)]
+pub struct NodeClient {
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl NodeClient {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    #[instrument(skip(self), level = "debug")]
+    pub async fn balance(&self, address: &str) -> Result<i64> {
+        self.get(&format!("balance/{address}")).await
+    }
+
+    #[instrument(skip(self), level = "debug")]
+    pub async fn nonce(&self, address: &str) -> Result<u64> {
+        self.get(&format!("nonce/{address}")).await
+    }
+
+    #[instrument(skip_all, level = "debug")]
+    pub async fn submit_transaction(&self, transaction: &Transaction) -> Result<()> {
+        let resp = self
+            .client
+            .post(format!("{}/transaction", self.base_url))
+            .json(transaction)
+            .send()
+            .await?;
+        check_status(resp).await?;
+        Ok(())
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let resp = self
+            .client
+            .get(format!("{}/{path}", self.base_url))
+            .send()
+            .await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+}
+
+async fn check_status(resp: Response) -> Result<Response> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let body = resp.text().await.unwrap_or_default();
+    Err(Error::NodeRequestFailed(status.as_u16(), body))
+}
@@ -0,0 +1,148 @@
+use crate::errors::{Error, Result};
+use bip39::Mnemonic;
+use ed25519_dalek::SigningKey;
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+
+const HARDENED: u32 = 0x8000_0000;
+const PURPOSE: u32 = 44;
+const COIN_TYPE: u32 = 1;
+
+/// Number of consecutive unused addresses after which restore stops scanning.
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+pub fn generate_mnemonic(word_count: usize) -> Result<Mnemonic> {
+    Mnemonic::generate(word_count).map_err(|e| Error::InvalidMnemonic(e.to_string()))
+}
+
+pub fn parse_mnemonic(phrase: &str) -> Result<Mnemonic> {
+    Mnemonic::parse(phrase).map_err(|e| Error::InvalidMnemonic(e.to_string()))
+}
+
+/// Ed25519 extended private key derived with SLIP-0010, which only supports
+/// hardened children.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtendedKey {
+    pub key: [u8; 32],
+    pub chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    pub fn from_seed(seed: &[u8]) -> Self {
+        Self::from_hmac(b"ed25519 seed", &[seed])
+    }
+
+    fn from_hmac(key: &[u8], data: &[&[u8]]) -> Self {
+        let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC accepts any key length");
+        for chunk in data {
+            mac.update(chunk);
+        }
+        let out = mac.finalize().into_bytes();
+        Self {
+            key: out[..32].try_into().unwrap(),
+            chain_code: out[32..].try_into().unwrap(),
+        }
+    }
+
+    pub fn derive_child(&self, index: u32) -> Self {
+        let index = (index | HARDENED).to_be_bytes();
+        Self::from_hmac(&self.chain_code, &[&[0], &self.key, &index])
+    }
+
+    pub fn derive_path(&self, path: &[u32]) -> Self {
+        path.iter()
+            .fold(self.clone(), |key, index| key.derive_child(*index))
+    }
+
+    pub fn signing_key(&self) -> SigningKey {
+        SigningKey::from_bytes(&self.key)
+    }
+}
+
+/// Path `m/44'/1'/0'/0'/index'` of the address with given index.
+pub fn address_path(index: u32) -> [u32; 5] {
+    [PURPOSE, COIN_TYPE, 0, 0, index]
+}
+
+pub fn derive_signing_key(seed: &[u8], index: u32) -> SigningKey {
+    ExtendedKey::from_seed(seed)
+        .derive_path(&address_path(index))
+        .signing_key()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+
+    #[test]
+    fn test_bip39_seed_vector() {
+        let mnemonic = parse_mnemonic(
+            "abandon abandon abandon abandon abandon abandon \
+             abandon abandon abandon abandon abandon about",
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(mnemonic.to_seed("TREZOR")),
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e5349553\
+             1f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+        );
+    }
+
+    #[test]
+    fn test_invalid_mnemonic_checksum() {
+        let result = parse_mnemonic(
+            "abandon abandon abandon abandon abandon abandon \
+             abandon abandon abandon abandon abandon abandon",
+        );
+        assert!(matches!(result, Err(Error::InvalidMnemonic(_))));
+    }
+
+    #[test]
+    fn test_slip10_ed25519_vector() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedKey::from_seed(&seed);
+        assert_eq!(
+            hex::encode(master.key),
+            "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7"
+        );
+        assert_eq!(
+            hex::encode(master.chain_code),
+            "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb"
+        );
+        assert_eq!(
+            hex::encode(master.signing_key().verifying_key().as_bytes()),
+            "a4b2856bfec510abab89753fac1ac0e1112364e7d250545963f135f2a33188ed"
+        );
+
+        let child = master.derive_child(0);
+        assert_eq!(
+            hex::encode(child.key),
+            "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3"
+        );
+        assert_eq!(
+            hex::encode(child.chain_code),
+            "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69"
+        );
+        assert_eq!(master.derive_path(&[0]), child);
+    }
+
+    #[test]
+    fn test_derived_addresses_are_deterministic() {
+        let seed = parse_mnemonic(
+            "abandon abandon abandon abandon abandon abandon \
+             abandon abandon abandon abandon abandon about",
+        )
+        .unwrap()
+        .to_seed("");
+        let first = derive_signing_key(&seed, 0);
+        let second = derive_signing_key(&seed, 1);
+
+        assert_eq!(first.as_bytes(), derive_signing_key(&seed, 0).as_bytes());
+        assert_ne!(first.as_bytes(), second.as_bytes());
+        assert_ne!(
+            crypto::address_from_public_key(&first.verifying_key()),
+            crypto::address_from_public_key(&second.verifying_key())
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use super::hd;
+use crate::block::Transaction;
+use crate::crypto;
+use crate::errors::{Error, Result};
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit,
+    aead::{Aead, AeadCore},
+};
+use ed25519_dalek::SigningKey;
+use rand::{RngCore, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::{Path, PathBuf};
+use tracing::instrument;
+
+const KDF_ROUNDS: u32 = 100_000;
+
+/// Secret encrypted with a key derived from the wallet password.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Sealed {
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+impl Sealed {
+    pub fn seal(password: &str, plaintext: &[u8]) -> Self {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let cipher = cipher(password, &salt);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext)
+            .expect("encryption into a Vec can't fail");
+        Self {
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        }
+    }
+
+    pub fn open(&self, password: &str) -> Result<Vec<u8>> {
+        let decode = |v: &str| hex::decode(v).map_err(|_| Error::InvalidWalletPassword);
+        let nonce = decode(&self.nonce)?;
+        if nonce.len() != 12 {
+            return Err(Error::InvalidWalletPassword);
+        }
+        cipher(password, &decode(&self.salt)?)
+            .decrypt(nonce[..].into(), &decode(&self.ciphertext)?[..])
+            .map_err(|_| Error::InvalidWalletPassword)
+    }
+}
+
+fn cipher(password: &str, salt: &[u8]) -> ChaCha20Poly1305 {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, KDF_ROUNDS, &mut key);
+    ChaCha20Poly1305::new(&key.into())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StoredKey {
+    pub name: String,
+    pub address: String,
+    pub public_key: String,
+    pub secret: Sealed,
+    /// Derivation index for keys derived from the wallet seed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<u32>,
+}
+
+/// Keys of the wallet, stored as JSON with secret keys and seed encrypted.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Keystore {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<Sealed>,
+    keys: Vec<StoredKey>,
+}
+
+impl Keystore {
+    /// Loads keystore from `path` or creates an empty one if the file doesn't exist.
+    #[instrument(level = "debug")]
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut keystore = if path.exists() {
+            serde_json::from_slice(&std::fs::read(path)?)?
+        } else {
+            Self::default()
+        };
+        keystore.path = path.to_owned();
+        Ok(keystore)
+    }
+
+    #[instrument(skip(self), fields(path = ?self.path), level = "debug")]
+    pub fn save(&self) -> Result<()> {
+        std::fs::write(&self.path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn keys(&self) -> &[StoredKey] {
+        &self.keys
+    }
+
+    pub fn default_address(&self) -> Option<&str> {
+        self.keys.first().map(|k| k.address.as_str())
+    }
+
+    pub fn add_key(&mut self, name: &str, key: &SigningKey, password: &str) -> &StoredKey {
+        self.push_key(name, key, password, None)
+    }
+
+    fn push_key(
+        &mut self,
+        name: &str,
+        key: &SigningKey,
+        password: &str,
+        index: Option<u32>,
+    ) -> &StoredKey {
+        self.keys.push(StoredKey {
+            name: name.to_owned(),
+            address: crypto::address_from_public_key(&key.verifying_key()),
+            public_key: hex::encode(key.verifying_key().as_bytes()),
+            secret: Sealed::seal(password, key.as_bytes()),
+            index,
+        });
+        self.keys.last().unwrap()
+    }
+
+    pub fn has_seed(&self) -> bool {
+        self.seed.is_some()
+    }
+
+    /// Stores the seed keys are derived from. The seed of a keystore can't be replaced.
+    pub fn set_seed(&mut self, seed: &[u8], password: &str) -> Result<()> {
+        if self.has_seed() {
+            return Err(Error::WalletSeedAlreadyExists);
+        }
+        self.seed = Some(Sealed::seal(password, seed));
+        Ok(())
+    }
+
+    pub fn seed(&self, password: &str) -> Result<Vec<u8>> {
+        self.seed
+            .as_ref()
+            .ok_or(Error::WalletHasNoSeed)?
+            .open(password)
+    }
+
+    /// Index of the next key to derive from the seed.
+    pub fn next_index(&self) -> u32 {
+        self.keys
+            .iter()
+            .filter_map(|k| k.index)
+            .max()
+            .map_or(0, |i| i + 1)
+    }
+
+    pub fn add_derived_key(
+        &mut self,
+        name: &str,
+        index: u32,
+        seed: &[u8],
+        password: &str,
+    ) -> &StoredKey {
+        let key = hd::derive_signing_key(seed, index);
+        self.push_key(name, &key, password, Some(index))
+    }
+
+    /// Derives the next key from the seed.
+    pub fn derive_key(&mut self, name: &str, password: &str) -> Result<&StoredKey> {
+        let seed = self.seed(password)?;
+        let index = self.next_index();
+        Ok(self.add_derived_key(name, index, &seed, password))
+    }
+
+    pub fn generate_key(&mut self, name: &str, password: &str) -> &StoredKey {
+        self.add_key(name, &crypto::generate_signing_key(), password)
+    }
+
+    /// Addresses of stored keys that are co-signers of the multisig transaction.
+    pub fn multisig_signers(&self, transaction: &Transaction) -> Vec<String> {
+        let Some(multisig) = &transaction.multisig else {
+            return vec![];
+        };
+        self.keys
+            .iter()
+            .filter(|k| multisig.public_keys.contains(&k.public_key))
+            .map(|k| k.address.clone())
+            .collect()
+    }
+
+    pub fn signing_key(&self, address: &str, password: &str) -> Result<SigningKey> {
+        let stored = self
+            .keys
+            .iter()
+            .find(|k| k.address == address)
+            .ok_or_else(|| Error::WalletKeyNotFound(address.to_owned()))?;
+        let secret: [u8; 32] = stored
+            .secret
+            .open(password)?
+            .try_into()
+            .map_err(|_| Error::InvalidWalletPassword)?;
+        Ok(SigningKey::from_bytes(&secret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{name}-{}.json", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_sealed_roundtrip() {
+        let sealed = Sealed::seal("secret", b"payload");
+        assert_eq!(sealed.open("secret").unwrap(), b"payload");
+        assert!(matches!(
+            sealed.open("wrong"),
+            Err(Error::InvalidWalletPassword)
+        ));
+    }
+
+    #[test]
+    fn test_keystore_persists_encrypted_keys() {
+        let path = temp_path("keystore");
+        let mut keystore = Keystore::open(&path).unwrap();
+        let address = keystore.generate_key("main", "secret").address.clone();
+        keystore.save().unwrap();
+
+        let raw = std::fs::read_to_string(&path).unwrap();
+        let key = keystore.signing_key(&address, "secret").unwrap();
+        assert!(!raw.contains(&hex::encode(key.as_bytes())));
+
+        let reopened = Keystore::open(&path).unwrap();
+        assert_eq!(reopened.keys(), keystore.keys());
+        assert_eq!(reopened.default_address(), Some(address.as_str()));
+        assert_eq!(
+            reopened.signing_key(&address, "secret").unwrap().as_bytes(),
+            key.as_bytes()
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_keystore_derives_keys_from_seed() {
+        let mut keystore = Keystore::open(&temp_path("keystore")).unwrap();
+        assert!(matches!(
+            keystore.derive_key("first", "secret"),
+            Err(Error::WalletHasNoSeed)
+        ));
+
+        let seed = [7u8; 64];
+        keystore.set_seed(&seed, "secret").unwrap();
+        assert!(matches!(
+            keystore.set_seed(&seed, "secret"),
+            Err(Error::WalletSeedAlreadyExists)
+        ));
+        let first = keystore.derive_key("first", "secret").unwrap().clone();
+        let second = keystore.derive_key("second", "secret").unwrap().clone();
+
+        assert_eq!((first.index, second.index), (Some(0), Some(1)));
+        assert_eq!(keystore.seed("secret").unwrap(), seed);
+        let expected = hd::derive_signing_key(&seed, 1);
+        assert_eq!(
+            keystore
+                .signing_key(&second.address, "secret")
+                .unwrap()
+                .as_bytes(),
+            expected.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_signing_key_for_unknown_address() {
+        let keystore = Keystore::open(&temp_path("keystore")).unwrap();
+        assert!(matches!(
+            keystore.signing_key("unknown", "secret"),
+            Err(Error::WalletKeyNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,140 @@
+mod cli;
+mod client;
+pub mod hd;
+pub mod keystore;
+pub mod multisig;
+
+pub use cli::{WalletArgs, run};
+pub use client::NodeClient;
+pub use keystore::Keystore;
+
+use crate::block::{LockTime, ScriptSpend, Transaction};
+use crate::crypto;
+use crate::errors::{Error, Result};
+use crate::script::Script;
+use bip39::Mnemonic;
+use ed25519_dalek::SigningKey;
+use tracing::{info, instrument};
+
+/// Keystore combined with the node it sends transactions to.
+pub struct Wallet {
+    pub keystore: Keystore,
+    pub client: NodeClient,
+}
+
+impl Wallet {
+    pub fn new(keystore: Keystore, client: NodeClient) -> Self {
+        Self { keystore, client }
+    }
+
+    /// Signs a transfer with the key of `from` using the next nonce known by
+    /// the node and submits it to the node mempool.
+    #[instrument(skip(self, password), level = "info")]
+    pub async fn send(
+        &self,
+        from: &str,
+        to: &str,
+        amount: i64,
+        password: &str,
+    ) -> Result<Transaction> {
+        self.send_locked(from, to, amount, None, password).await
+    }
+
+    /// Same as [`Wallet::send`], but the transfer is held by the node until `lock_until`.
+    #[instrument(skip(self, password), level = "info")]
+    pub async fn send_locked(
+        &self,
+        from: &str,
+        to: &str,
+        amount: i64,
+        lock_until: Option<LockTime>,
+        password: &str,
+    ) -> Result<Transaction> {
+        let key = self.keystore.signing_key(from, password)?;
+        let mut transaction = Transaction {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            amount,
+            nonce: self.client.nonce(from).await?,
+            lock_until,
+            ..Default::default()
+        };
+        transaction.sign(&key);
+        self.client.submit_transaction(&transaction).await?;
+        Ok(transaction)
+    }
+
+    /// Spends from the address guarded by `locking`. When `signer` is given,
+    /// its signature of the transaction is pushed before `unlocking` data.
+    #[instrument(skip_all, level = "info")]
+    pub async fn send_script(
+        &self,
+        locking: Script,
+        unlocking: Script,
+        signer: Option<&SigningKey>,
+        to: &str,
+        amount: i64,
+    ) -> Result<Transaction> {
+        let from = locking.address();
+        let mut transaction = Transaction {
+            nonce: self.client.nonce(&from).await?,
+            from,
+            to: to.to_owned(),
+            amount,
+            ..Default::default()
+        };
+        let unlocking = match signer {
+            Some(key) => {
+                let mut bytes = Script::default()
+                    .push_data(&transaction.script_signature(key))
+                    .map_err(|e| Error::InvalidScript(e.to_string()))?
+                    .as_bytes()
+                    .to_vec();
+                bytes.extend_from_slice(unlocking.as_bytes());
+                Script::from_bytes(bytes)
+            }
+            None => unlocking,
+        };
+        transaction.script = Some(ScriptSpend { locking, unlocking });
+        self.client.submit_transaction(&transaction).await?;
+        Ok(transaction)
+    }
+
+    /// Address is used if it has ever received or sent funds.
+    async fn is_used(&self, address: &str) -> Result<bool> {
+        Ok(self.client.nonce(address).await? > 0 || self.client.balance(address).await? != 0)
+    }
+
+    /// Initializes the keystore seed from `mnemonic` and restores derived keys
+    /// up to the last address used on chain. Scanning stops after `gap_limit`
+    /// consecutive unused addresses. Returns number of restored keys.
+    #[instrument(skip_all, level = "info")]
+    pub async fn restore(
+        &mut self,
+        mnemonic: &Mnemonic,
+        passphrase: &str,
+        password: &str,
+        gap_limit: u32,
+    ) -> Result<u32> {
+        let seed = mnemonic.to_seed(passphrase);
+        self.keystore.set_seed(&seed, password)?;
+
+        let mut scanned = 0;
+        let mut last_used = None;
+        while scanned < last_used.map_or(0, |i| i + 1) + gap_limit {
+            let key = hd::derive_signing_key(&seed, scanned);
+            let address = crypto::address_from_public_key(&key.verifying_key());
+            if self.is_used(&address).await? {
+                last_used = Some(scanned);
+            }
+            scanned += 1;
+        }
+        let restored = last_used.map_or(1, |i| i + 1);
+        for index in 0..restored {
+            self.keystore
+                .add_derived_key(&format!("key-{index}"), index, &seed, password);
+        }
+        info!("Restored {restored} keys after scanning {scanned} addresses");
+        Ok(restored)
+    }
+}
@@ -0,0 +1,173 @@
+use crate::block::{Multisig, Transaction};
+use crate::errors::{Error, Result};
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Multisig transaction passed between co-signers until it collects
+/// enough signatures to be submitted.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PartiallySignedTransaction {
+    pub transaction: Transaction,
+}
+
+impl PartiallySignedTransaction {
+    pub fn new(multisig: Multisig, to: &str, amount: i64, nonce: u64) -> Self {
+        Self {
+            transaction: Transaction {
+                from: multisig.address(),
+                to: to.to_owned(),
+                amount,
+                nonce,
+                multisig: Some(multisig),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    fn multisig(&self) -> Result<&Multisig> {
+        self.transaction
+            .multisig
+            .as_ref()
+            .ok_or_else(|| Error::InvalidMultisigPolicy("transaction is not multisig".into()))
+    }
+
+    pub fn sign(&mut self, key: &SigningKey) -> Result<()> {
+        self.transaction.sign_multisig(key)
+    }
+
+    /// Merges signatures collected by another co-signer for the same
+    /// transaction. Invalid signatures are skipped, so they never replace a
+    /// valid one of the same key.
+    pub fn combine(&mut self, other: &Self) -> Result<()> {
+        let mut unsigned = other.transaction.clone();
+        if let Some(multisig) = unsigned.multisig.as_mut() {
+            multisig.signatures.clear();
+        }
+        let mut ours = self.transaction.clone();
+        if let Some(multisig) = ours.multisig.as_mut() {
+            multisig.signatures.clear();
+        }
+        if unsigned != ours {
+            return Err(Error::InvalidMultisigPolicy(
+                "partially signed transactions differ".into(),
+            ));
+        }
+        let incoming: Vec<_> = other
+            .multisig()?
+            .signatures
+            .iter()
+            .filter(|s| self.transaction.is_valid_multisig_signature(s))
+            .cloned()
+            .collect();
+        let multisig = self.transaction.multisig.as_mut().unwrap();
+        for signature in incoming {
+            if !multisig.signatures.contains(&signature) {
+                multisig
+                    .signatures
+                    .retain(|s| s.public_key != signature.public_key);
+                multisig.signatures.push(signature);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.transaction.verify().is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+
+    fn policy(keys: &[SigningKey], threshold: usize) -> Multisig {
+        Multisig::new(
+            threshold,
+            keys.iter()
+                .map(|k| hex::encode(k.verifying_key().as_bytes()))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cosigners_combine_partial_signatures() {
+        let keys: Vec<_> = (0..3).map(|_| crypto::generate_signing_key()).collect();
+        let unsigned = PartiallySignedTransaction::new(policy(&keys, 2), "B", 10, 0);
+
+        let mut first = unsigned.clone();
+        first.sign(&keys[0]).unwrap();
+        let mut second = unsigned.clone();
+        second.sign(&keys[2]).unwrap();
+        assert!(!first.is_complete());
+
+        first.combine(&second).unwrap();
+        first.combine(&second).unwrap();
+        assert_eq!(
+            first
+                .transaction
+                .multisig
+                .as_ref()
+                .unwrap()
+                .signatures
+                .len(),
+            2
+        );
+        assert!(first.is_complete());
+    }
+
+    #[test]
+    fn test_combine_keeps_valid_signature_over_invalid_one() {
+        let keys: Vec<_> = (0..2).map(|_| crypto::generate_signing_key()).collect();
+        let mut ours = PartiallySignedTransaction::new(policy(&keys, 2), "B", 10, 0);
+        ours.sign(&keys[0]).unwrap();
+        let mut theirs = ours.clone();
+        theirs.sign(&keys[1]).unwrap();
+        let signatures = &mut theirs.transaction.multisig.as_mut().unwrap().signatures;
+        signatures[0].signature = signatures[1].signature.clone();
+
+        ours.combine(&theirs).unwrap();
+        let signatures = &ours.transaction.multisig.as_ref().unwrap().signatures;
+        assert_eq!(signatures.len(), 2);
+        assert!(
+            signatures
+                .iter()
+                .all(|s| ours.transaction.is_valid_multisig_signature(s))
+        );
+        assert!(ours.is_complete());
+    }
+
+    #[test]
+    fn test_combine_rejects_different_transactions() {
+        let keys: Vec<_> = (0..2).map(|_| crypto::generate_signing_key()).collect();
+        let mut first = PartiallySignedTransaction::new(policy(&keys, 2), "B", 10, 0);
+        let other = PartiallySignedTransaction::new(policy(&keys, 2), "B", 11, 0);
+        assert!(matches!(
+            first.combine(&other),
+            Err(Error::InvalidMultisigPolicy(_))
+        ));
+    }
+
+    #[test]
+    fn test_partially_signed_transaction_file_roundtrip() {
+        let keys: Vec<_> = (0..2).map(|_| crypto::generate_signing_key()).collect();
+        let mut psbt = PartiallySignedTransaction::new(policy(&keys, 1), "B", 10, 3);
+        psbt.sign(&keys[1]).unwrap();
+        let path = std::env::temp_dir().join(format!("psbt-{}.json", uuid::Uuid::new_v4()));
+
+        psbt.save(&path).unwrap();
+        assert_eq!(PartiallySignedTransaction::load(&path).unwrap(), psbt);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+mod common;
+
+use reqwest::{Client, StatusCode};
+use rust_blockchain::actor::NodeHandle;
+use rust_blockchain::block::{Block, Transaction};
+use rust_blockchain::{errors::Result, node::Node};
+use serde_json::Value;
+use std::time::Duration;
+
+/// Starts a node serving its API and returns its base URL.
+async fn start_node() -> String {
+    common::serve(NodeHandle::spawn(Node::new("A", 2).unwrap())).await
+}
+
+#[tokio::test]
+async fn test_blocks_are_queried_by_range_height_and_hash() -> Result<()> {
+    common::init_tracing();
+    let url = start_node().await;
+    let client = Client::new();
+    for _ in 0..4 {
+        let res = client
+            .post(format!("{url}/add_block"))
+            .json(&Vec::<Value>::new())
+            .send();
+        assert!(res.await?.status().is_success());
+    }
+
+    let mut heights = vec![];
+    let mut cursor: Option<String> = None;
+    loop {
+        let mut request = client.get(format!("{url}/blocks?from=1&limit=2"));
+        if let Some(cursor) = &cursor {
+            request = request.query(&[("cursor", cursor)]);
+        }
+        let page: Value = request.send().await?.json().await?;
+        let blocks: Vec<Block> = serde_json::from_value(page["blocks"].clone())?;
+        heights.extend(blocks.iter().map(|b| b.index));
+        match page["next_cursor"].as_str() {
+            Some(next) => cursor = Some(next.to_owned()),
+            None => break,
+        }
+    }
+    assert_eq!(heights, vec![1, 2, 3, 4]);
+
+    let latest: Block = client
+        .get(format!("{url}/blocks/latest"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(latest.index, 4);
+    let by_height: Block = client
+        .get(format!("{url}/blocks/height/4"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let by_hash: Block = client
+        .get(format!("{url}/blocks/hash/{}", latest.hash))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(by_height, latest);
+    assert_eq!(by_hash, latest);
+
+    let info: Value = client
+        .get(format!("{url}/chain/info"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(info["height"], 4);
+    assert_eq!(info["tip_hash"], latest.hash.as_str());
+    assert_eq!(info["difficulty"], 2);
+    assert_eq!(info["cumulative_work"], "1280");
+
+    let missing = client.get(format!("{url}/blocks/height/5")).send().await?;
+    assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    let invalid = client.get(format!("{url}/blocks?limit=0")).send().await?;
+    assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_address_history_lists_payments_with_running_balance() -> Result<()> {
+    common::init_tracing();
+    let url = start_node().await;
+    let client = Client::new();
+    for (to, amount) in [("B", 10), ("C", 4)] {
+        let tx = Transaction {
+            from: "A".into(),
+            to: to.into(),
+            amount,
+            ..Default::default()
+        };
+        let res = client.post(format!("{url}/add_block")).json(&vec![tx]);
+        assert!(res.send().await?.status().is_success());
+    }
+
+    let history: Value = client
+        .get(format!("{url}/addresses/A/transactions?limit=1"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let latest = &history["transactions"][0];
+    assert_eq!(latest["height"], 2);
+    assert_eq!(latest["direction"], "sent");
+    assert_eq!(latest["balance"], -14);
+    assert_eq!(latest["transaction"]["to"], "C");
+
+    let cursor = history["next_cursor"].as_str().unwrap();
+    let history: Value = client
+        .get(format!("{url}/addresses/A/transactions"))
+        .query(&[("cursor", cursor)])
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(history["transactions"][0]["balance"], -10);
+    assert_eq!(history["next_cursor"], Value::Null);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mined_blocks_are_streamed_over_sse_and_websocket() -> Result<()> {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{self, Message},
+    };
+
+    async fn next_event(
+        ws: &mut (impl futures_util::Stream<Item = tungstenite::Result<Message>> + Unpin),
+    ) -> Result<Value> {
+        let message = tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("no event sent")
+            .unwrap()
+            .unwrap();
+        Ok(serde_json::from_str(message.to_text().unwrap())?)
+    }
+
+    common::init_tracing();
+    let url = start_node().await;
+    let client = Client::new();
+    let rejected = client.get(format!("{url}/events?types=blocks")).send();
+    assert_eq!(rejected.await?.status(), StatusCode::BAD_REQUEST);
+
+    let mut sse = client
+        .get(format!("{url}/events?types=new_block"))
+        .send()
+        .await?;
+    assert!(sse.status().is_success());
+    let (mut ws, _) = connect_async(format!("{}/ws?types=mining", url.replace("http", "ws")))
+        .await
+        .unwrap();
+    ws.send(Message::text(r#"{"types": ["new_block"]}"#))
+        .await
+        .unwrap();
+    assert_eq!(next_event(&mut ws).await?["type"], "subscribed");
+
+    let block: Block = client
+        .post(format!("{url}/add_block"))
+        .json(&Vec::<Value>::new())
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    let mut received = String::new();
+    while !received.contains("\n\n") {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), sse.chunk())
+            .await
+            .expect("no event streamed")?
+            .expect("stream ended");
+        received.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+    assert!(received.starts_with("event: new_block\n"));
+    let data = received.lines().find_map(|l| l.strip_prefix("data: "));
+    let event: Value = serde_json::from_str(data.unwrap())?;
+    assert_eq!(event["block"]["hash"], block.hash.as_str());
+
+    let event = next_event(&mut ws).await?;
+    assert_eq!(event["type"], "new_block");
+    assert_eq!(event["block"]["index"], 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_json_rpc_calls_and_batches_over_http() -> Result<()> {
+    common::init_tracing();
+    let url = start_node().await;
+    let client = Client::new();
+    let rpc = |body: &'static str| {
+        client
+            .post(format!("{url}/rpc"))
+            .header("content-type", "application/json")
+            .body(body)
+            .send()
+    };
+
+    let res =
+        rpc(r#"{"jsonrpc": "2.0", "method": "getBlockByHeight", "params": [0], "id": 1}"#).await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    let reply: Value = res.json().await?;
+    let genesis: Block = serde_json::from_value(reply["result"].clone())?;
+    assert_eq!((genesis.index, &reply["id"]), (0, &Value::from(1)));
+
+    let replies: Value = rpc(r#"[
+            {"jsonrpc": "2.0", "method": "getBalance", "params": {"address": "B"}, "id": "b"},
+            {"jsonrpc": "2.0", "method": "getBlockByHeight", "params": [7], "id": 2},
+            {"jsonrpc": "2.0", "method": "getPeers"}
+        ]"#)
+    .await?
+    .json()
+    .await?;
+    assert_eq!(replies[0]["result"], 0);
+    assert_eq!(replies[1]["error"]["code"], -32001);
+    assert_eq!(replies[1]["id"], 2);
+    assert!(replies.get(2).is_none());
+
+    let reply: Value = rpc("{").await?.json().await?;
+    assert_eq!(reply["error"]["code"], -32700);
+    let res = rpc(r#"{"jsonrpc": "2.0", "method": "getMempool"}"#).await?;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    Ok(())
+}
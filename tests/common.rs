@@ -1,4 +1,10 @@
+use rust_blockchain::actor::NodeHandle;
+use rust_blockchain::config::Config;
 use std::sync::Once;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
 static INIT: Once = Once::new();
 
 pub fn init_tracing() {
@@ -9,3 +15,43 @@ pub fn init_tracing() {
             .init();
     });
 }
+
+/// Serves the API of `node` on a free port and returns its base URL. The port
+/// is bound when this returns, so requests wait until the node serves them.
+#[allow(dead_code)]
+pub async fn serve(node: NodeHandle) -> String {
+    serve_with(node, Config::default()).await.0
+}
+
+/// Like [`serve`] with `conf`, whose `port` is ignored, also returning the
+/// server task.
+#[allow(dead_code)]
+pub async fn serve_with(node: NodeHandle, conf: Config) -> (String, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        rust_blockchain::api::serve_http(node, conf, listener)
+            .await
+            .unwrap();
+    });
+    (url, server)
+}
+
+/// Address `node` accepts peer connections on once its server listens.
+#[allow(dead_code)]
+pub async fn p2p_address(node: &NodeHandle) -> String {
+    eventually(async || !node.snapshot().address.is_empty()).await;
+    node.snapshot().address.clone()
+}
+
+/// Waits until `condition` holds, panics if it doesn't in five seconds.
+#[allow(dead_code)]
+pub async fn eventually(condition: impl AsyncFn() -> bool) {
+    for _ in 0..250 {
+        if condition().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("condition not met in time");
+}
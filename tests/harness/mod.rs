@@ -0,0 +1,279 @@
+//! Deterministic in-process network of nodes for gossip and sync tests.
+//!
+//! Messages are not delivered when sent but queued and handed to the
+//! receiving node by [`Network::step`], which makes it possible to delay,
+//! drop, reorder and partition them. All randomness comes from a seeded RNG
+//! and all nodes share a manual clock, so a run is fully reproducible.
+#![allow(dead_code)]
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rust_blockchain::block::{Block, MIN_BLOCK_TIMESTAMP, Transaction};
+use rust_blockchain::clock::{Clock, ManualClock};
+use rust_blockchain::network::{Envelope, Message};
+use rust_blockchain::node::Node;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+/// Simulated seconds that pass with every step.
+pub const STEP_SECONDS: u64 = 10;
+const DIFFICULTY: usize = 1;
+
+struct InFlight {
+    from: usize,
+    to: usize,
+    deliver_at: u64,
+    message: Message,
+}
+
+pub struct Network {
+    pub clock: Arc<ManualClock>,
+    nodes: Vec<Node>,
+    in_flight: Vec<InFlight>,
+    rng: StdRng,
+    step: u64,
+    drop_rate: f64,
+    delay: RangeInclusive<u64>,
+    reorder: bool,
+    /// Partition group of every node, `None` when the network is whole.
+    groups: Option<Vec<usize>>,
+    pub delivered: usize,
+    pub dropped: usize,
+    pub rejected: usize,
+}
+
+pub fn address(index: usize) -> String {
+    format!("node-{index}")
+}
+
+impl Network {
+    pub fn new(size: usize, seed: u64) -> Self {
+        let clock = Arc::new(ManualClock::new(MIN_BLOCK_TIMESTAMP));
+        let nodes = (0..size)
+            .map(|i| {
+                let mut node = Node::with_clock(&address(i), DIFFICULTY, clock.clone()).unwrap();
+                node.address = address(i);
+                node
+            })
+            .collect();
+        Self {
+            clock,
+            nodes,
+            in_flight: vec![],
+            rng: StdRng::seed_from_u64(seed),
+            step: 0,
+            drop_rate: 0.0,
+            delay: 1..=1,
+            reorder: false,
+            groups: None,
+            delivered: 0,
+            dropped: 0,
+            rejected: 0,
+        }
+    }
+
+    pub fn node(&self, index: usize) -> &Node {
+        &self.nodes[index]
+    }
+
+    pub fn node_mut(&mut self, index: usize) -> &mut Node {
+        &mut self.nodes[index]
+    }
+
+    /// Probability with which every message is lost.
+    pub fn set_drop_rate(&mut self, rate: f64) {
+        self.drop_rate = rate;
+    }
+
+    /// Range of steps a message takes to reach its receiver.
+    pub fn set_delay(&mut self, steps: RangeInclusive<u64>) {
+        assert!(*steps.start() >= 1, "messages take at least one step");
+        self.delay = steps;
+    }
+
+    /// Shuffles messages delivered within the same step.
+    pub fn set_reorder(&mut self, reorder: bool) {
+        self.reorder = reorder;
+    }
+
+    /// Splits the network so that messages only pass between nodes of the
+    /// same group. Nodes not listed form a group of their own.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        let mut assignment = vec![groups.len(); self.nodes.len()];
+        for (group, members) in groups.iter().enumerate() {
+            for member in *members {
+                assignment[*member] = group;
+            }
+        }
+        self.groups = Some(assignment);
+    }
+
+    pub fn heal(&mut self) {
+        self.groups = None;
+    }
+
+    fn reachable(&self, from: usize, to: usize) -> bool {
+        self.groups.as_ref().is_none_or(|g| g[from] == g[to])
+    }
+
+    /// Opens a connection between `node` and `peer`, both sides start the
+    /// version handshake.
+    pub fn connect(&mut self, node: usize, peer: usize) {
+        for (from, to, inbound) in [(node, peer, false), (peer, node, true)] {
+            let outbound = self.nodes[from].on_connected(&address(to), inbound);
+            self.send(from, outbound);
+        }
+    }
+
+    /// Connects every node to the first one, the others learn about each
+    /// other through address gossip.
+    pub fn connect_all(&mut self) {
+        for peer in 1..self.nodes.len() {
+            self.connect(0, peer);
+        }
+    }
+
+    /// Connects every node to the known addresses it has no connection to
+    /// yet and returns how many connections were opened.
+    pub fn dial_known(&mut self) -> usize {
+        let mut dials = vec![];
+        for (node, n) in self.nodes.iter().enumerate() {
+            for (known, _) in n.address_book.iter() {
+                let Some(peer) = self.index_of(known) else {
+                    continue;
+                };
+                if peer != node && !n.peers.contains_key(known) && !dials.contains(&(peer, node)) {
+                    dials.push((node, peer));
+                }
+            }
+        }
+        for &(node, peer) in &dials {
+            self.connect(node, peer);
+        }
+        dials.len()
+    }
+
+    pub fn mine(&mut self, index: usize, transactions: Vec<Transaction>) -> Block {
+        let block = self.nodes[index].add_block(transactions).unwrap().clone();
+        self.announce(index);
+        block
+    }
+
+    /// Submits the transaction to the node, which announces it to its peers.
+    pub fn submit(&mut self, index: usize, transaction: Transaction) {
+        let outbound = self.nodes[index]
+            .accept_transaction(transaction, None)
+            .unwrap();
+        self.send(index, outbound);
+    }
+
+    /// Makes the node announce its tip to all its peers.
+    pub fn announce(&mut self, index: usize) {
+        let outbound = self.nodes[index].announce_tip();
+        self.send(index, outbound);
+    }
+
+    fn send(&mut self, from: usize, outbound: Vec<Envelope>) {
+        for Envelope { to, message } in outbound {
+            let Some(to) = self.index_of(&to) else {
+                self.dropped += 1;
+                continue;
+            };
+            if self.rng.gen_bool(self.drop_rate) {
+                self.dropped += 1;
+                continue;
+            }
+            let deliver_at = self.step + self.rng.gen_range(self.delay.clone());
+            self.in_flight.push(InFlight {
+                from,
+                to,
+                deliver_at,
+                message,
+            });
+        }
+    }
+
+    fn index_of(&self, address: &str) -> Option<usize> {
+        self.nodes.iter().position(|n| n.address == address)
+    }
+
+    /// Advances the clock and delivers all messages due at the new step.
+    pub fn step(&mut self) {
+        self.step += 1;
+        self.clock.advance(STEP_SECONDS);
+        let (mut due, pending) = std::mem::take(&mut self.in_flight)
+            .into_iter()
+            .partition::<Vec<_>, _>(|m| m.deliver_at <= self.step);
+        self.in_flight = pending;
+        if self.reorder {
+            due.shuffle(&mut self.rng);
+        }
+        for InFlight {
+            from, to, message, ..
+        } in due
+        {
+            if !self.reachable(from, to) {
+                self.dropped += 1;
+                continue;
+            }
+            self.delivered += 1;
+            match self.nodes[to].handle_message(&address(from), message) {
+                Ok(outbound) => self.send(to, outbound),
+                Err(_) => self.rejected += 1,
+            }
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    /// Steps until no message is in flight, at most `max_steps` times.
+    pub fn run_until_idle(&mut self, max_steps: u64) {
+        for _ in 0..max_steps {
+            if self.is_idle() {
+                return;
+            }
+            self.step();
+        }
+    }
+
+    pub fn tips(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .map(|n| n.blockchain.blocks().last().unwrap().hash.clone())
+            .collect()
+    }
+
+    pub fn is_converged(&self) -> bool {
+        self.tips().windows(2).all(|w| w[0] == w[1])
+    }
+
+    /// Steps until all nodes share the same tip and returns the number of
+    /// steps it took, or `None` if they did not within `max_steps`.
+    pub fn run_until_converged(&mut self, max_steps: u64) -> Option<u64> {
+        for steps in 0..=max_steps {
+            if self.is_converged() {
+                return Some(steps);
+            }
+            self.step();
+        }
+        None
+    }
+
+    #[track_caller]
+    pub fn assert_converges_within(&mut self, max_steps: u64) -> u64 {
+        match self.run_until_converged(max_steps) {
+            Some(steps) => steps,
+            None => panic!(
+                "nodes did not converge within {max_steps} steps, tips: {:?}",
+                self.tips()
+            ),
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+}
@@ -0,0 +1,194 @@
+mod harness;
+
+use harness::Network;
+use rust_blockchain::block::Transaction;
+
+fn transaction(amount: i64) -> Transaction {
+    Transaction {
+        from: "A".into(),
+        to: "B".into(),
+        amount,
+        ..Default::default()
+    }
+}
+
+fn connected(size: usize, seed: u64) -> Network {
+    let mut network = Network::new(size, seed);
+    network.connect_all();
+    network.run_until_idle(100);
+    network.dial_known();
+    network.run_until_idle(100);
+    assert!(network.is_idle());
+    network
+}
+
+#[test]
+fn test_gossip_builds_full_mesh() {
+    let network = connected(5, 1);
+    for i in 0..5 {
+        assert_eq!(network.node(i).peers.len(), 4, "peers of node {i}");
+    }
+}
+
+#[test]
+fn test_address_gossip_spreads_along_a_line_and_stops() {
+    let mut network = Network::new(8, 7);
+    for i in 1..8 {
+        network.connect(i - 1, i);
+        network.run_until_idle(100);
+        assert!(network.is_idle());
+    }
+    for i in 0..8 {
+        assert_eq!(
+            network.node(i).address_book.len(),
+            7,
+            "addresses of node {i}"
+        );
+    }
+    // Six handshake messages per link, and no address crosses a link twice
+    // in the same direction
+    assert!(network.delivered < 8 * 7 * 2 + 7 * 6);
+}
+
+#[test]
+fn test_mined_block_reaches_all_nodes() {
+    let mut network = connected(4, 2);
+    let block = network.mine(2, vec![transaction(1)]);
+
+    network.assert_converges_within(2);
+    assert_eq!(network.tips(), vec![block.hash; 4]);
+    assert_eq!(network.node(0).blockchain.get_balance("B"), 1);
+}
+
+#[test]
+fn test_submitted_transaction_reaches_all_mempools_and_is_mined_once() {
+    let mut network = connected(5, 6);
+    let tx = transaction(7);
+    network.submit(3, tx.clone());
+    let delivered = network.delivered;
+    network.run_until_idle(20);
+    for i in 0..5 {
+        assert_eq!(
+            network.node(i).mempool,
+            vec![tx.clone()],
+            "mempool of node {i}"
+        );
+    }
+    // Announced, requested and sent to the four peers of node 3, which
+    // announce it to each other once and request nothing
+    assert_eq!(network.delivered - delivered, 4 * 3 + 4 * 3);
+    assert_eq!(network.rejected, 0);
+
+    let outbound = network.node_mut(1).mine_pending().unwrap().clone();
+    assert_eq!(outbound.transactions, vec![tx]);
+    network.announce(1);
+    network.assert_converges_within(10);
+    network.run_until_idle(20);
+    for i in 0..5 {
+        assert!(network.node(i).mempool.is_empty(), "mempool of node {i}");
+    }
+    assert_eq!(network.node(0).blockchain.get_balance("B"), 7);
+}
+
+#[test]
+fn test_blocks_of_relayed_transactions_are_rebuilt_from_mempools() {
+    let mut network = connected(4, 8);
+    for amount in 1..=3 {
+        network.submit(amount as usize, transaction(amount));
+    }
+    network.run_until_idle(20);
+    network.node_mut(0).mine_pending().unwrap();
+    network.announce(0);
+
+    // The compact block alone is enough
+    network.assert_converges_within(1);
+    for i in 1..4 {
+        let stats = network.node(i).compact_stats;
+        assert_eq!(stats.received, 1, "compact blocks of node {i}");
+        assert_eq!(stats.success_rate(), Some(1.0), "success rate of node {i}");
+    }
+    assert_eq!(network.node(3).blockchain.get_balance("B"), 6);
+}
+
+#[test]
+fn test_converges_with_delays_and_reordering() {
+    let mut network = connected(5, 3);
+    network.set_delay(1..=5);
+    network.set_reorder(true);
+
+    for amount in 1..=3 {
+        network.mine(0, vec![transaction(amount)]);
+        network.step();
+    }
+    network.assert_converges_within(20);
+    assert_eq!(network.node(4).blockchain.blocks().len(), 4);
+
+    // Concurrently mined blocks tie until one side extends its chain
+    network.mine(1, vec![transaction(10)]);
+    network.mine(3, vec![transaction(20)]);
+    network.run_until_idle(20);
+    network.mine(1, vec![transaction(11)]);
+    network.assert_converges_within(20);
+    assert_eq!(
+        network.node(3).blockchain.get_balance("B"),
+        1 + 2 + 3 + 10 + 11
+    );
+}
+
+#[test]
+fn test_partitioned_network_converges_after_heal() {
+    let mut network = connected(4, 4);
+    network.partition(&[&[0, 1], &[2, 3]]);
+
+    network.mine(0, vec![transaction(1)]);
+    network.mine(0, vec![transaction(2)]);
+    network.mine(2, vec![transaction(3)]);
+    network.run_until_idle(10);
+
+    let tips = network.tips();
+    assert_eq!(tips[0], tips[1]);
+    assert_eq!(tips[2], tips[3]);
+    assert_ne!(tips[0], tips[2]);
+    assert!(network.dropped > 0);
+
+    network.heal();
+    for i in 0..4 {
+        network.announce(i);
+    }
+    network.assert_converges_within(5);
+    assert_eq!(network.tips()[3], tips[0]);
+}
+
+#[test]
+fn test_lost_messages_are_recovered_by_announcing_again() {
+    let mut network = connected(3, 5);
+    network.set_drop_rate(1.0);
+    network.mine(0, vec![transaction(1)]);
+    assert!(network.run_until_converged(10).is_none());
+
+    network.set_drop_rate(0.0);
+    network.announce(0);
+    network.assert_converges_within(2);
+}
+
+#[test]
+fn test_simulation_is_deterministic() {
+    let run = || {
+        let mut network = connected(5, 42);
+        network.set_delay(1..=3);
+        network.set_reorder(true);
+        network.set_drop_rate(0.2);
+        for i in 0..5 {
+            network.mine(i, vec![transaction(i as i64 + 1)]);
+            network.step();
+        }
+        network.run_until_idle(50);
+        (
+            network.tips(),
+            network.delivered,
+            network.dropped,
+            network.now(),
+        )
+    };
+    assert_eq!(run(), run());
+}
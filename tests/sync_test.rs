@@ -1,36 +1,20 @@
 mod common;
 
+use common::{eventually, p2p_address};
 use reqwest::Client;
-use rust_blockchain::block::Transaction;
+use rust_blockchain::actor::NodeHandle;
+use rust_blockchain::block::{Block, Transaction};
 use rust_blockchain::blockchain::Blockchain;
+use rust_blockchain::clock;
 use rust_blockchain::config::Config;
 use rust_blockchain::{errors::Result, node::Node};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::task;
 
 #[tokio::test]
 async fn test_manual_node_sync_between_two_servers() -> Result<()> {
     common::init_tracing();
 
-    let conf_a = Config { port: 3001 };
-    let conf_b = Config { port: 3002 };
-    let node_a = Arc::new(Mutex::new(Node::new("A", 2)?));
-    let node_b = Arc::new(Mutex::new(Node::new("B", 2)?));
-
-    let _ = task::spawn(async move {
-        rust_blockchain::api::start_http_server(node_a, conf_a)
-            .await
-            .unwrap();
-    });
-
-    let _ = task::spawn(async move {
-        rust_blockchain::api::start_http_server(node_b, conf_b)
-            .await
-            .unwrap();
-    });
-
-    tokio::time::sleep(Duration::from_millis(500)).await;
+    let url_a = common::serve(NodeHandle::spawn(Node::new("A", 2)?)).await;
+    let url_b = common::serve(NodeHandle::spawn(Node::new("B", 2)?)).await;
 
     let client = Client::new();
 
@@ -38,9 +22,10 @@ async fn test_manual_node_sync_between_two_servers() -> Result<()> {
         from: "A".into(),
         to: "B".into(),
         amount: 1,
+        ..Default::default()
     };
     let res = client
-        .post("http://localhost:3001/add_block")
+        .post(format!("{url_a}/add_block"))
         .json(&vec![tx.clone()])
         .send()
         .await
@@ -48,7 +33,7 @@ async fn test_manual_node_sync_between_two_servers() -> Result<()> {
     assert!(res.status().is_success());
 
     let chain_a: Blockchain = client
-        .get("http://localhost:3001/chain")
+        .get(format!("{url_a}/chain"))
         .send()
         .await
         .unwrap()
@@ -57,7 +42,7 @@ async fn test_manual_node_sync_between_two_servers() -> Result<()> {
         .unwrap();
 
     let res = client
-        .post("http://localhost:3002/sync")
+        .post(format!("{url_b}/sync"))
         .json(&chain_a)
         .send()
         .await
@@ -65,7 +50,7 @@ async fn test_manual_node_sync_between_two_servers() -> Result<()> {
     assert!(res.status().is_success());
 
     let chain_b: Blockchain = client
-        .get("http://localhost:3002/chain")
+        .get(format!("{url_b}/chain"))
         .send()
         .await
         .unwrap()
@@ -86,24 +71,11 @@ async fn test_manual_node_sync_between_two_servers() -> Result<()> {
 async fn test_autosync_between_two_servers() -> Result<()> {
     common::init_tracing();
 
-    let conf_a = Config { port: 3003 };
-    let conf_b = Config { port: 3004 };
-    let node_a = Arc::new(Mutex::new(Node::new("A", 2)?));
-    let node_b = Arc::new(Mutex::new(Node::new("B", 2)?));
-
-    let _ = task::spawn(async move {
-        rust_blockchain::api::start_http_server(node_a, conf_a)
-            .await
-            .unwrap();
-    });
-
-    let _ = task::spawn(async move {
-        rust_blockchain::api::start_http_server(node_b, conf_b)
-            .await
-            .unwrap();
-    });
-
-    tokio::time::sleep(Duration::from_millis(500)).await;
+    let node_a = NodeHandle::spawn(Node::new("A", 2)?);
+    let node_b = NodeHandle::spawn(Node::new("B", 2)?);
+    let url_a = common::serve(node_a.clone()).await;
+    common::serve(node_b.clone()).await;
+    let addr_b = p2p_address(&node_b).await;
 
     let client = Client::new();
 
@@ -111,34 +83,64 @@ async fn test_autosync_between_two_servers() -> Result<()> {
         from: "A".into(),
         to: "B".into(),
         amount: 1,
+        ..Default::default()
     };
     let res = client
-        .post("http://localhost:3003/peer")
-        .json("http://localhost:3004")
+        .post(format!("{url_a}/peer"))
+        .json(&addr_b)
         .send()
         .await
         .unwrap();
     assert!(res.status().is_success());
 
     let res = client
-        .post("http://localhost:3003/add_block")
+        .post(format!("{url_a}/add_block"))
         .json(&vec![tx.clone()])
         .send()
         .await
         .unwrap();
     assert!(res.status().is_success());
 
-    let chain_b: Blockchain = client
-        .get("http://localhost:3004/chain")
+    // The block reaches B asynchronously over the peer connection
+    eventually(async || node_b.snapshot().blockchain.height() == 1).await;
+    let chain_b = node_b.snapshot().blockchain.clone();
+    assert_eq!(chain_b.blocks().last().unwrap().transactions, vec![tx]);
+
+    let peers: Vec<serde_json::Value> = client
+        .get(format!("{url_a}/peers"))
         .send()
         .await
         .unwrap()
         .json()
         .await
         .unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0]["address"], addr_b.as_str());
+    assert_eq!(peers[0]["listen_addr"], addr_b.as_str());
+    assert_eq!(peers[0]["best_height"], 0);
+    assert_eq!(peers[0]["inbound"], false);
 
-    let last_block_txs = &chain_b.blocks().last().unwrap().transactions;
-    assert_eq!(last_block_txs, &vec![tx]);
+    let res = client
+        .delete(format!("{url_a}/peer/{addr_b}"))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    let peers: Vec<serde_json::Value> = client
+        .get(format!("{url_a}/peers"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(peers.is_empty());
+    let res = client
+        .delete(format!("{url_a}/peer/{addr_b}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
 
     Ok(())
 }
@@ -147,16 +149,7 @@ async fn test_autosync_between_two_servers() -> Result<()> {
 async fn test_get_balance() {
     common::init_tracing();
 
-    let conf_a = Config { port: 3005 };
-    let node_a = Arc::new(Mutex::new(Node::new("A", 2).unwrap()));
-
-    let _ = task::spawn(async move {
-        rust_blockchain::api::start_http_server(node_a, conf_a)
-            .await
-            .unwrap();
-    });
-
-    tokio::time::sleep(Duration::from_millis(500)).await;
+    let url = common::serve(NodeHandle::spawn(Node::new("A", 2).unwrap())).await;
 
     let client = Client::new();
 
@@ -165,27 +158,30 @@ async fn test_get_balance() {
             from: "A".into(),
             to: "B".into(),
             amount: 100,
+            ..Default::default()
         },
         Transaction {
             from: "A".into(),
             to: "B".into(),
             amount: 10,
+            ..Default::default()
         },
         Transaction {
             from: "B".into(),
             to: "A".into(),
             amount: 50,
+            ..Default::default()
         },
     ];
     let _ = client
-        .post("http://localhost:3005/add_block")
+        .post(format!("{url}/add_block"))
         .json(&txs)
         .send()
         .await
         .unwrap();
 
     let balance_b: i64 = client
-        .get("http://localhost:3005/balance/B")
+        .get(format!("{url}/balance/B"))
         .send()
         .await
         .unwrap()
@@ -195,7 +191,7 @@ async fn test_get_balance() {
     assert_eq!(balance_b, 60);
 
     let balance_c: i64 = client
-        .get("http://localhost:3005/balance/C")
+        .get(format!("{url}/balance/C"))
         .send()
         .await
         .unwrap()
@@ -205,3 +201,243 @@ async fn test_get_balance() {
 
     assert_eq!(balance_c, 0);
 }
+
+#[tokio::test]
+async fn test_peer_posting_invalid_chain_gets_banned() {
+    common::init_tracing();
+
+    let data_dir = std::env::temp_dir().join(format!("node-{}", uuid::Uuid::new_v4()));
+    let conf = Config {
+        data_dir: Some(data_dir.clone()),
+        ..Default::default()
+    };
+    let node = NodeHandle::spawn(Node::new("A", 2).unwrap());
+    let (url, _) = common::serve_with(node, conf).await;
+
+    let client = Client::new();
+    let mut chain: serde_json::Value = client
+        .get(format!("{url}/chain"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let mut forged = chain["chain"][0].clone();
+    forged["index"] = 1.into();
+    forged["previous_hash"] = chain["chain"][0]["hash"].clone();
+    chain["chain"].as_array_mut().unwrap().push(forged);
+
+    let res = client
+        .post(format!("{url}/sync"))
+        .json(&chain)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+    let res = client
+        .post(format!("{url}/sync"))
+        .json(&chain)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+
+    let bans: Vec<serde_json::Value> = client
+        .get(format!("{url}/bans"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(bans.len(), 1);
+    assert_eq!(bans[0]["ip"], "127.0.0.1");
+    assert!(data_dir.join("bans.json").exists());
+
+    let res = client
+        .delete(format!("{url}/bans/127.0.0.1"))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    let res = client
+        .delete(format!("{url}/bans/127.0.0.1"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+
+    let res = client
+        .post(format!("{url}/bans"))
+        .json(&serde_json::json!({ "ip": "10.0.0.1", "duration": 60 }))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    let res = client
+        .post(format!("{url}/bans"))
+        .json(&serde_json::json!({ "ip": "not an ip" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+    let res = client
+        .post(format!("{url}/bans"))
+        .json(&serde_json::json!({ "ip": "10.0.0.2", "duration": u64::MAX }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+    // The node keeps serving writes
+    let res = client
+        .post(format!("{url}/bans"))
+        .json(&serde_json::json!({ "ip": "10.0.0.2" }))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+
+    std::fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_restarted_node_reconnects_to_known_peers() {
+    common::init_tracing();
+
+    let data_dir = std::env::temp_dir().join(format!("node-{}", uuid::Uuid::new_v4()));
+    let start = async |name: &str, data_dir| {
+        let conf = Config {
+            data_dir,
+            ..Default::default()
+        };
+        let node = NodeHandle::spawn(Node::new(name, 2).unwrap());
+        let (url, server) = common::serve_with(node.clone(), conf).await;
+        (node, url, server)
+    };
+    let (node_a, _, _) = start("A", None).await;
+    let addr_a = p2p_address(&node_a).await;
+    let (_, url_b, first_run) = start("B", Some(data_dir.clone())).await;
+
+    let client = Client::new();
+    let res = client
+        .post(format!("{url_b}/peer"))
+        .json(&addr_a)
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    let path = data_dir.join("addresses.json");
+    // The address book is written in the background, poll until it parses
+    eventually(async || {
+        std::fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<serde_json::Value>(&bytes).ok())
+            .is_some_and(|addresses| addresses["addresses"][&addr_a]["successes"] == 1)
+    })
+    .await;
+
+    // Peer connections outlive the aborted server task, close them so that A
+    // accepts the same node id again
+    let res = client
+        .delete(format!("{url_b}/peer/{addr_a}"))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    first_run.abort();
+    eventually(async || node_a.snapshot().peers.is_empty()).await;
+
+    // Same data directory, no seeds and no POST /peer
+    let (node_b, _, _) = start("B", Some(data_dir.clone())).await;
+    eventually(async || !node_b.snapshot().peers.is_empty()).await;
+    let peers = node_b.snapshot().peers.clone();
+    assert_eq!(peers.len(), 1);
+    let peer = peers.values().next().unwrap();
+    assert_eq!(peer.address, addr_a);
+    assert!(!peer.inbound);
+    std::fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_submitted_transaction_is_relayed_to_peers() -> Result<()> {
+    common::init_tracing();
+
+    let node_a = NodeHandle::spawn(Node::new("A", 2)?);
+    let node_b = NodeHandle::spawn(Node::new("B", 2)?);
+    let url_a = common::serve(node_a).await;
+    common::serve(node_b.clone()).await;
+    let addr_b = p2p_address(&node_b).await;
+
+    let client = Client::new();
+    let res = client
+        .post(format!("{url_a}/peer"))
+        .json(&addr_b)
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    eventually(async || !node_b.snapshot().peers.is_empty()).await;
+
+    let tx = Transaction {
+        from: "A".into(),
+        to: "B".into(),
+        amount: 4,
+        ..Default::default()
+    };
+    let res = client
+        .post(format!("{url_a}/transaction"))
+        .json(&tx)
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    eventually(async || !node_b.snapshot().mempool.is_empty()).await;
+    assert_eq!(*node_b.snapshot().mempool, vec![tx]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_synced_chain_is_validated_with_the_node_difficulty() -> Result<()> {
+    common::init_tracing();
+    let url = common::serve(NodeHandle::spawn(Node::new("A", 2)?)).await;
+
+    let client = Client::new();
+    let mut chain: serde_json::Value = client
+        .get(format!("{url}/chain"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let genesis: Block = serde_json::from_value(chain["chain"][0].clone())?;
+    let mut block = Block::new(1, genesis.hash, vec![], clock::system().as_ref());
+    block.mine_block(1)?;
+    while block.hash.starts_with("00") {
+        block.nonce += 1;
+        block.mine_block(1)?;
+    }
+    chain["chain"]
+        .as_array_mut()
+        .unwrap()
+        .push(serde_json::to_value(block)?);
+    chain["difficulty"] = 1.into();
+
+    let res = client
+        .post(format!("{url}/sync"))
+        .json(&chain)
+        .send()
+        .await?;
+    assert!(!res.status().is_success());
+    let info: serde_json::Value = client
+        .get(format!("{url}/chain/info"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(
+        (info["height"].as_u64(), info["difficulty"].as_u64()),
+        (Some(0), Some(2))
+    );
+    Ok(())
+}
@@ -0,0 +1,146 @@
+mod common;
+
+use reqwest::Client;
+use rust_blockchain::actor::NodeHandle;
+use rust_blockchain::block::{Block, Multisig, Transaction};
+use rust_blockchain::crypto;
+use rust_blockchain::wallet::multisig::PartiallySignedTransaction;
+use rust_blockchain::wallet::{Keystore, NodeClient, Wallet, hd};
+use rust_blockchain::{errors::Result, node::Node};
+
+#[tokio::test]
+async fn test_wallet_sends_signed_transactions() -> Result<()> {
+    common::init_tracing();
+
+    let url = common::serve(NodeHandle::spawn(Node::new("A", 2)?)).await;
+
+    let path = std::env::temp_dir().join(format!("wallet-{}.json", uuid::Uuid::new_v4()));
+    let mut keystore = Keystore::open(&path)?;
+    let from = keystore.generate_key("main", "secret").address.clone();
+    keystore.save()?;
+    let wallet = Wallet::new(keystore, NodeClient::new(&url));
+    let client = Client::new();
+    let funding = vec![Transaction {
+        from: "A".into(),
+        to: from.clone(),
+        amount: 20,
+        ..Default::default()
+    }];
+    let res = client
+        .post(format!("{url}/add_block"))
+        .json(&funding)
+        .send();
+    assert!(res.await?.status().is_success());
+
+    let first = wallet.send(&from, "B", 10, "secret").await?;
+    let second = wallet.send(&from, "B", 5, "secret").await?;
+    assert_eq!((first.nonce, second.nonce), (0, 1));
+    assert!(wallet.send(&from, "B", 5, "wrong").await.is_err());
+    assert!(wallet.send(&from, "B", 6, "secret").await.is_err());
+
+    let block: Block = client
+        .post(format!("{url}/mine"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(block.transactions, vec![first, second]);
+
+    assert_eq!(wallet.client.balance("B").await?, 15);
+    assert_eq!(wallet.client.balance(&from).await?, 5);
+    assert_eq!(wallet.client.nonce(&from).await?, 2);
+
+    let mut forged = block.transactions[0].clone();
+    forged.amount = 1;
+    forged.nonce = 2;
+    let res = client
+        .post(format!("{url}/transaction"))
+        .json(&forged)
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_wallet_restores_used_addresses_from_mnemonic() -> Result<()> {
+    common::init_tracing();
+
+    let url = common::serve(NodeHandle::spawn(Node::new("A", 2)?)).await;
+
+    let mnemonic = hd::parse_mnemonic(
+        "abandon abandon abandon abandon abandon abandon \
+         abandon abandon abandon abandon abandon about",
+    )?;
+    let seed = mnemonic.to_seed("");
+    let used = crypto::address_from_public_key(&hd::derive_signing_key(&seed, 3).verifying_key());
+
+    let res = Client::new()
+        .post(format!("{url}/add_block"))
+        .json(&vec![Transaction {
+            from: "A".into(),
+            to: used.clone(),
+            amount: 10,
+            ..Default::default()
+        }])
+        .send()
+        .await?;
+    assert!(res.status().is_success());
+
+    let path = std::env::temp_dir().join(format!("wallet-{}.json", uuid::Uuid::new_v4()));
+    let mut wallet = Wallet::new(Keystore::open(&path)?, NodeClient::new(&url));
+    let restored = wallet.restore(&mnemonic, "", "secret", 5).await?;
+
+    assert_eq!(restored, 4);
+    let addresses: Vec<_> = wallet.keystore.keys().iter().map(|k| &k.address).collect();
+    assert_eq!(addresses.len(), 4);
+    assert_eq!(addresses[3], &used);
+    assert_eq!(wallet.keystore.next_index(), 4);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multisig_transaction_needs_threshold_signatures() -> Result<()> {
+    common::init_tracing();
+
+    let url = common::serve(NodeHandle::spawn(Node::new("A", 2)?)).await;
+
+    let keys: Vec<_> = (0..3).map(|_| crypto::generate_signing_key()).collect();
+    let multisig = Multisig::new(
+        2,
+        keys.iter()
+            .map(|k| hex::encode(k.verifying_key().as_bytes()))
+            .collect(),
+    )?;
+    let address = multisig.address();
+    let client = NodeClient::new(&url);
+    let funding = vec![Transaction {
+        from: "A".into(),
+        to: address.clone(),
+        amount: 10,
+        ..Default::default()
+    }];
+    let res = Client::new()
+        .post(format!("{url}/add_block"))
+        .json(&funding)
+        .send();
+    assert!(res.await?.status().is_success());
+
+    let mut psbt =
+        PartiallySignedTransaction::new(multisig, "B", 10, client.nonce(&address).await?);
+    psbt.sign(&keys[0])?;
+    assert!(client.submit_transaction(&psbt.transaction).await.is_err());
+
+    let mut cosigned = psbt.clone();
+    cosigned.sign(&keys[1])?;
+    psbt.combine(&cosigned)?;
+    client.submit_transaction(&psbt.transaction).await?;
+
+    let res = Client::new().post(format!("{url}/mine")).send().await?;
+    assert!(res.status().is_success());
+    assert_eq!(client.balance("B").await?, 10);
+    assert_eq!(client.nonce(&address).await?, 1);
+    Ok(())
+}